@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
+
+use nostr::prelude::{ ClientMessage, Timestamp };
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::chats::{ Chat, ChatType, DmMode };
+use crate::contacts::ContactList;
+use crate::lists::List;
+use crate::monitor::Monitor;
+use crate::presence::Presence;
+use crate::relays::Connections;
+use crate::rules::RulesEngine;
+use crate::signer::Signer;
+use crate::subscriptions::SUBSCRIPTIONS;
+use crate::tabs::{ Tab, Tabs };
+use crate::{ outbox, receipts, ui };
+use crate::Config;
+
+/// Notified with Ctrl-Z to cancel the message counting down to be sent.
+pub static CANCEL_SEND: Notify = Notify::const_new();
+
+/// State of a running session, which the commands of the chat prompt act on.
+pub struct App {
+    pub config: Config,
+    pub signer: Signer,
+    /// Relay used for lookups
+    pub relay: String,
+    pub screen: ui::ChatScreen,
+    pub tabs: Tabs,
+    pub connections: Connections,
+    /// Chat of the active tab. Changes to it are stored back in the tab when another one is shown.
+    pub chat: ChatType,
+    /// Chats offered by the quick switcher
+    pub known_chats: Vec<ChatType>,
+    /// DM modes detected this session, keyed by the contact's npub
+    pub detected_dm_modes: HashMap<String, DmMode>,
+    pub monitor: Monitor,
+    pub presence: Presence,
+    pub rules: Arc<RulesEngine>,
+    /// Kinds of the kind handlers, subscribed to alongside chat messages
+    pub extra_kinds: Vec<u64>,
+    /// Show outgoing events for confirmation before they are sent
+    pub preview_mode: bool,
+    pub started: Instant,
+    /// Contact list as fetched at startup and updated since, None if it couldn't be fetched
+    pub contacts: Option<ContactList>,
+    /// NIP-51 list of joined channels as fetched at startup and updated since, None if it couldn't be fetched
+    pub channels_list: Option<List>,
+    /// NIP-51 set of pinned private chats, likewise
+    pub pinned_chats: Option<List>,
+}
+
+impl App {
+    /// Subscribes to the chat of the tab at `index` on fresh connections, replacing its previous subscription.
+    /// Fails if the relays can't be connected to, leaving the tab without one.
+    pub async fn subscribe(&mut self, index: usize) -> Result<(), String> {
+        if let Some(task) = self.tabs.tabs[index].task.take() {
+            task.abort();
+        }
+        self.leave(&self.tabs.tabs[index].chat.get_id()).await;
+        let printing_handler = crate::tab_printing_handler(&self.config, self.signer.public_key(), self.rules.clone(), &self.tabs, index);
+        let (connection, task) = crate::connect_chat(&self.config.relays, &self.tabs.tabs[index].chat, printing_handler, &self.extra_kinds).await?;
+        self.tabs.tabs[index].task = Some(task);
+        self.connections.lock().await.insert(self.tabs.tabs[index].chat.get_id(), connection);
+        Ok(())
+    }
+
+    /// Closes the subscriptions of the chat with `chat_id` on the relays and drops its connection.
+    pub async fn leave(&self, chat_id: &str) {
+        if let Some(mut connection) = self.connections.lock().await.remove(chat_id) {
+            let closes = SUBSCRIPTIONS.lock().unwrap().leave(chat_id);
+            // The connection tasks send what was queued before they notice the pool is gone
+            for close in closes {
+                connection.writer.send(close).await.ok();
+            }
+        }
+    }
+
+    /// Opens `chat` in a new tab after the others and subscribes to it, without showing it. Returns its index.
+    /// If it can't be subscribed to, the tab is closed again so the current chat stays as it is.
+    pub async fn open_tab(&mut self, mut chat: ChatType) -> Result<usize, String> {
+        crate::apply_detected_dm_mode(&self.config, &self.relay, &mut chat, &mut self.detected_dm_modes, false).await;
+        crate::apply_outgoing_tags(&self.config, &mut chat);
+        crate::start_ratchet_session(&self.relay, &mut chat).await;
+        crate::apply_inbox_relays(&self.relay, &mut chat).await;
+        if !self.known_chats.iter().any(|known| known.get_id() == chat.get_id()) {
+            self.known_chats.push(chat.clone());
+        }
+        let index = self.tabs.add(Tab::new(chat, self.config.flood_limit.clone()));
+        if let Err(why) = self.subscribe(index).await {
+            self.tabs.remove(index);
+            return Err(why);
+        }
+        Ok(index)
+    }
+
+    /// Shows the tab at `index`, storing the chat shown until now back in its own tab.
+    pub fn show_tab(&mut self, index: usize) {
+        self.tabs.active_mut().chat = self.chat.clone();
+        self.tabs.activate(index, &self.screen);
+        self.chat = self.tabs.active().chat.clone();
+        self.monitor.set_active(self.chat.get_id());
+        self.presence.set_active(&self.chat);
+        self.update_status();
+    }
+
+    pub fn update_status(&self) {
+        self.screen.set_status(crate::chat_status(&self.chat, &self.relay, self.preview_mode, &self.presence));
+    }
+
+    /// Whether `msgs` should be sent: always, unless preview mode is on and they are discarded.
+    pub async fn confirm(&mut self, msgs: &[Message]) -> bool {
+        !self.preview_mode || crate::confirm_events(msgs, &mut self.screen).await
+    }
+
+    /// Holds a composed message for the configured undo window, counting down above the input. Returns false
+    /// if it was cancelled with Ctrl-Z meanwhile, as it can't be taken back once a relay has it.
+    pub async fn hold_send(&self) -> bool {
+        let cancelled = CANCEL_SEND.notified();
+        tokio::pin!(cancelled);
+        for remaining in (1 ..= self.config.undo_send).rev() {
+            ui::show_pending(format!("Sending in {}s… (Ctrl-Z cancels)", remaining));
+            tokio::select! {
+                _ = &mut cancelled => {
+                    ui::show_pending(String::new());
+                    ui::print("Cancelled, the message wasn't sent.".to_string());
+                    return false;
+                },
+                _ = tokio::time::sleep(Duration::from_secs(1)) => {},
+            }
+        }
+        ui::show_pending(String::new());
+        true
+    }
+
+    /// Asks `question` in the chat screen and returns the trimmed answer.
+    pub async fn ask(&mut self, question: &str) -> String {
+        ui::print(question.to_string());
+        self.screen.next_line().await.trim().to_string()
+    }
+
+    /// Sends `msgs` over the connection of the shown chat.
+    pub async fn send(&self, msgs: Vec<Message>) -> Result<(), String> {
+        self.send_to(&self.chat.get_id(), msgs).await
+    }
+
+    /// Sends `msgs` over the connection of the chat with `chat_id`. Fails if the chat has no connection, as
+    /// when subscribing to it again failed, or the relays can't be written to.
+    pub async fn send_to(&self, chat_id: &str, msgs: Vec<Message>) -> Result<(), String> {
+        let mut connections = self.connections.lock().await;
+        let connection = connections.get_mut(chat_id).ok_or("the chat isn't connected, /close it and open it again")?;
+        // Messages of private chats are tracked until the recipient has seen them
+        let private = self.tabs.find(chat_id).is_some_and(|index| matches!(self.tabs.tabs[index].chat, ChatType::PrivateChat(_)));
+        if private {
+            receipts::queued(chat_id, &msgs);
+        }
+        let pool_relays = connection.writer.relays();
+        outbox::queued(chat_id, &pool_relays, &msgs);
+        for msg in &msgs {
+            connection.writer.send(msg.clone()).await?;
+        }
+        // The contact answers a ratchet message to the key it was signed with, which the subscription is extended by
+        if let Some(ChatType::PrivateChat(private_chat)) = self.tabs.find(chat_id).map(|index| &self.tabs.tabs[index].chat) {
+            let filter = private_chat.build_filter(&self.extra_kinds);
+            if private_chat.mode == DmMode::Ratchet && filter != connection.filter {
+                // A REQ with the id of an open subscription replaces it
+                let req = ClientMessage::new_req(connection.subscription_id.clone(), vec![filter.clone().since(Timestamp::now())]).as_json();
+                if connection.writer.send(Message::Text(req)).await.is_ok() {
+                    connection.filter = filter;
+                }
+            }
+        }
+        // The recipient reads from the relays of their NIP-65 list, which the pool may not include
+        if let Some(ChatType::PrivateChat(private_chat)) = self.tabs.find(chat_id).map(|index| &self.tabs.tabs[index].chat) {
+            let known: Vec<&str> = pool_relays.iter().map(|relay| relay.trim_end_matches('/')).collect();
+            let inboxes: Vec<String> = private_chat.inbox_relays.iter().filter(|relay| !known.contains(&relay.as_str())).cloned().collect();
+            let events = private_chat.inbox_events(&msgs);
+            if !inboxes.is_empty() && !events.is_empty() {
+                outbox::expect(&events, &inboxes);
+                tokio::spawn(crate::chats::deliver_to_inboxes(private_chat.name.clone(), inboxes, events));
+            }
+        }
+        if private {
+            receipts::sent(chat_id, &msgs);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use colored::Colorize;
+use image::DynamicImage;
+use image::imageops::FilterType;
+use nostr::prelude::XOnlyPublicKey;
+use sha2::{ Digest, Sha256 };
+
+/// Width of rendered avatars in terminal columns. Every text row shows two rows of pixels.
+const AVATAR_SIZE: u32 = 16;
+
+/// Pictures bigger than this aren't downloaded.
+const MAX_PICTURE_BYTES: usize = 5 * 1024 * 1024;
+
+fn cache_path(picture_url: &str) -> PathBuf {
+    let dir = crate::data_dir().join("avatars");
+    fs::create_dir_all(&dir).expect("Couldn't create avatar cache directory!");
+    dir.join(format!("{}.txt", hex::encode(Sha256::digest(picture_url.as_bytes()))))
+}
+
+/// Downloads the picture at `picture_url` and renders it with colored half blocks. Renders are cached
+/// on disk by url, so each picture is only downloaded once.
+pub async fn render(picture_url: &str) -> Option<String> {
+    let path = cache_path(picture_url);
+    if let Ok(cached) = fs::read_to_string(&path) {
+        return Some(cached);
+    }
+
+    let picture = download(picture_url).await?;
+    let rendered = half_blocks(&picture.resize_exact(AVATAR_SIZE, AVATAR_SIZE, FilterType::Triangle));
+    if let Err(why) = fs::write(&path, &rendered) {
+        crate::ui::print_error(format!("Couldn't cache avatar: {}", why));
+    }
+    Some(rendered)
+}
+
+/// Downloads and decodes the picture at `picture_url`, unless it is too big.
+pub async fn download(picture_url: &str) -> Option<DynamicImage> {
+    let client = reqwest::Client::builder().timeout(Duration::from_secs(10)).build().ok()?;
+    let mut response = client.get(picture_url).send().await.ok()?;
+    if response.content_length().unwrap_or(0) as usize > MAX_PICTURE_BYTES {
+        return None;
+    }
+    // The length may be missing or wrong, so the body is read in chunks and given up on once it is too big
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await.ok()? {
+        if bytes.len() + chunk.len() > MAX_PICTURE_BYTES {
+            return None;
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    image::load_from_memory(&bytes).ok()
+}
+
+/// `picture` drawn with colored half blocks, a column per pixel and a row per two.
+pub fn half_blocks(picture: &DynamicImage) -> String {
+    let pixels = picture.to_rgb8();
+    let mut rows = Vec::new();
+    for y in (0 .. pixels.height()).step_by(2) {
+        let mut row = String::new();
+        for x in 0 .. pixels.width() {
+            let top = pixels.get_pixel(x, y);
+            // An odd last row leaves the lower halves black
+            let bottom = match y + 1 < pixels.height() {
+                true => *pixels.get_pixel(x, y + 1),
+                false => image::Rgb([0, 0, 0]),
+            };
+            row += &"▀".truecolor(top[0], top[1], top[2]).on_truecolor(bottom[0], bottom[1], bottom[2]).to_string();
+        }
+        rows.push(row);
+    }
+    rows.join("\n")
+}
+
+/// Deterministic avatar for users without a picture: a horizontally mirrored 5x5 pattern in a
+/// color derived from the hash of the public key, drawn two columns per cell.
+pub fn identicon(public_key: &XOnlyPublicKey) -> String {
+    let hash = Sha256::digest(public_key.serialize());
+    let (red, green, blue) = (hash[0] / 2 + 96, hash[1] / 2 + 96, hash[2] / 2 + 96);
+    let mut rows = Vec::new();
+    for y in 0 .. 5 {
+        let mut row = String::new();
+        for x in 0 .. 5 {
+            // Only the left three columns come from the hash, the rest mirrors them
+            let column = if x > 2 { 4 - x } else { x };
+            let filled = hash[3 + y * 3 + column] % 2 == 0;
+            row += &if filled {
+                "██".truecolor(red, green, blue).to_string()
+            } else {
+                "  ".to_string()
+            };
+        }
+        rows.push(row);
+    }
+    rows.join("\n")
+}
+
+/// Rendered picture of a user if avatars are enabled and it can be loaded, otherwise their identicon.
+pub async fn render_or_identicon(public_key: &XOnlyPublicKey, picture_url: Option<&str>) -> String {
+    if let Some(picture_url) = picture_url {
+        if let Some(rendered) = render(picture_url).await {
+            return rendered;
+        }
+    }
+    identicon(public_key)
+}
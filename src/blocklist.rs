@@ -0,0 +1,54 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{ LazyLock, Mutex };
+
+/// Hex public keys whose events are dropped in every chat, kept between sessions.
+static BLOCKED: LazyLock<Mutex<BTreeSet<String>>> = LazyLock::new(|| Mutex::new(BTreeSet::new()));
+
+fn blocklist_path() -> PathBuf {
+    crate::data_dir().join("blocked.json")
+}
+
+/// Fills the block list with the public keys blocked in earlier sessions.
+pub fn load() {
+    let blocked: BTreeSet<String> = fs::read_to_string(blocklist_path()).ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    *BLOCKED.lock().unwrap() = blocked;
+}
+
+fn save(blocked: &BTreeSet<String>) {
+    if let Err(why) = fs::write(blocklist_path(), serde_json::to_string(blocked).unwrap()) {
+        crate::ui::print_error(format!("Couldn't save the block list: {}", why));
+    }
+}
+
+/// Blocks the hex `public_key`. Returns false if it was blocked already.
+pub fn block(public_key: &str) -> bool {
+    let mut blocked = BLOCKED.lock().unwrap();
+    let added = blocked.insert(public_key.to_string());
+    if added {
+        save(&blocked);
+    }
+    added
+}
+
+/// Unblocks the hex `public_key`. Returns false if it wasn't blocked.
+pub fn unblock(public_key: &str) -> bool {
+    let mut blocked = BLOCKED.lock().unwrap();
+    let removed = blocked.remove(public_key);
+    if removed {
+        save(&blocked);
+    }
+    removed
+}
+
+pub fn is_blocked(public_key: &str) -> bool {
+    BLOCKED.lock().unwrap().contains(public_key)
+}
+
+/// The blocked hex public keys, sorted.
+pub fn blocked() -> Vec<String> {
+    BLOCKED.lock().unwrap().iter().cloned().collect()
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::DateTime;
+use colored::Colorize;
+use nostr::prelude::Timestamp;
+use rustyline::ExternalPrinter;
+use serde::{ Deserialize, Serialize };
+
+use crate::relays::format_bytes;
+use crate::storage;
+
+/// How often the background task prunes the cache.
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long cached events are kept and how much space each chat's cache may take. Events beyond
+/// either limit are pruned, oldest first.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RetentionPolicy {
+    pub max_age_days: Option<u64>,
+    pub max_size_mb: Option<u64>,
+}
+
+/// Retention of the local event cache, with overrides per chat keyed by the chat's id.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Retention {
+    #[serde(flatten)]
+    pub default: RetentionPolicy,
+    #[serde(default)]
+    pub chats: HashMap<String, RetentionPolicy>,
+}
+
+impl Retention {
+    /// Policy of the chat with `chat_id`. Limits the override leaves out are taken from the default.
+    pub fn policy(&self, chat_id: &str) -> RetentionPolicy {
+        let chat = self.chats.get(chat_id).cloned().unwrap_or_default();
+        RetentionPolicy {
+            max_age_days: chat.max_age_days.or(self.default.max_age_days),
+            max_size_mb: chat.max_size_mb.or(self.default.max_size_mb),
+        }
+    }
+}
+
+/// Directory of the local event cache, which holds the database of every chat's events.
+pub fn cache_dir() -> PathBuf {
+    crate::data_dir().join("events")
+}
+
+/// Number of events, size in bytes and oldest event time of every cached chat, named with `names` where known.
+pub fn stats(names: &HashMap<String, String>) -> String {
+    let chats = storage::chat_sizes();
+    if chats.is_empty() {
+        return "The local cache is empty".to_string();
+    }
+    let mut lines = Vec::new();
+    let mut total_events = 0;
+    let mut total_bytes = 0;
+    for (chat_id, events, bytes, oldest) in chats {
+        let oldest = oldest.and_then(|time| DateTime::from_timestamp(time as i64, 0))
+            .map_or("-".to_string(), |time| time.format("%Y-%m-%d").to_string());
+        let name = names.get(&chat_id).cloned().unwrap_or_else(|| chat_id[.. 8.min(chat_id.len())].to_string());
+        lines.push(format!("{} {} events, {}, since {}", name.green(), events, format_bytes(bytes), oldest));
+        total_events += events;
+        total_bytes += bytes;
+    }
+    lines.push(format!("Total: {} events, {}", total_events, format_bytes(total_bytes)));
+    lines.join("\n")
+}
+
+/// Prunes every cached chat by its policy in `retention`. Returns the number of events and bytes removed.
+pub fn prune(retention: &Retention) -> (usize, u64) {
+    let mut removed_events = 0;
+    let mut removed_bytes = 0;
+    for (chat_id, _, _, _) in storage::chat_sizes() {
+        let policy = retention.policy(&chat_id);
+        let oldest_kept = policy.max_age_days.map(|days| Timestamp::now().as_u64().saturating_sub(days * 24 * 60 * 60));
+        match storage::prune_chat(&chat_id, oldest_kept, policy.max_size_mb.map(|megabytes| megabytes * 1024 * 1024)) {
+            Ok((events, bytes)) => {
+                removed_events += events;
+                removed_bytes += bytes;
+            },
+            Err(why) => crate::ui::print_error(format!("Couldn't prune the cache of {}: {}", chat_id, why)),
+        }
+    }
+    (removed_events, removed_bytes)
+}
+
+/// Prunes the cache by `retention` every PRUNE_INTERVAL, reporting what was removed.
+pub async fn prune_periodically<T: ExternalPrinter>(retention: Retention, mut printer: T) {
+    let mut prune_check = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        prune_check.tick().await;
+        let (events, bytes) = tokio::task::block_in_place(|| prune(&retention));
+        if events > 0 {
+            printer.print(format!("[{}] Pruned {} events ({})", "CACHE".blue(), events, format_bytes(bytes))).ok();
+        }
+    }
+}
@@ -1,10 +1,17 @@
 use std::str::FromStr;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::{ HashMap, HashSet };
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{ Arc, LazyLock, Mutex };
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
 use rand::{ rngs::SmallRng, SeedableRng, Rng };
 
-use serde_json::Value;
-use tokio_tungstenite::{tungstenite::protocol::Message, WebSocketStream};
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use tokio_tungstenite::tungstenite::protocol::Message;
 use enum_dispatch::enum_dispatch;
 use colored::Colorize;
 use chrono::NaiveDateTime;
@@ -13,12 +20,36 @@ use rustyline::ExternalPrinter;
 use nostr::prelude::*;
 use nostr::prelude::secp256k1::PublicKey;
 
-use futures_util::{StreamExt, SinkExt};
-use futures::stream::SplitStream;
 use futures::stream::SplitSink;
 use async_trait::async_trait;
+use tokio::sync::Notify;
 
-use crate::crypto::{ RatchetProfile };
+use crate::crypto::{ DecryptError, Handshake, MessageHeader, PROTOCOL_VERSION, RatchetProfile };
+use crate::error::{ self, parse_relay_message, Error };
+use crate::flood::FloodControl;
+use crate::nip59;
+use crate::ordering::OrderingBuffer;
+use crate::pool::PoolReader;
+use crate::storage::ChatStore;
+use crate::ui::{ Scrollback, ShownMessage };
+use crate::rules::{ is_mention, RuleContext, RulesEngine };
+use crate::signer::Signer;
+
+/// Hex characters of its event id shown before each message, enough to pick it with /reply and the like.
+pub const SHORT_ID_LENGTH: usize = 4;
+/// Replies nested deeper than this are indented like those at this depth.
+const MAX_REPLY_DEPTH: usize = 4;
+/// How often the progress of a history load is brought up to date.
+const LOADING_REFRESH: Duration = Duration::from_millis(100);
+
+/// Notified to cut the history loads in progress short, so their chats start with what was received so far.
+pub static SKIP_HISTORY: Notify = Notify::const_new();
+/// Messages echoed right after we sent them, as chat id and JSON-quoted content, until the relays send them back.
+static ECHOED: LazyLock<Mutex<Vec<(String, String)>>> = LazyLock::new(|| Mutex::new(Vec::new()));
+/// Contacts told already that a message of theirs couldn't be decrypted, so it is only said once per chat.
+static UNDECRYPTABLE_NOTED: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+/// Whether a relay has sent a binary frame yet, which is only said once.
+static BINARY_NOTED: AtomicBool = AtomicBool::new(false);
 
 #[derive(Clone)]
 #[enum_dispatch(Chat)] 
@@ -27,37 +58,80 @@ pub enum ChatType {
     PrivateChat(PrivateChat),
 }
 
+impl ChatType {
+    /// Copy of the chat to sign a message with that may still be discarded, which leaves the chat as it is.
+    pub fn draft(&self) -> ChatType {
+        match self {
+            ChatType::PrivateChat(private_chat) => ChatType::PrivateChat(PrivateChat { ratchet_profile: private_chat.ratchet_profile.draft(), ..private_chat.clone() }),
+            chat => chat.clone(),
+        }
+    }
+
+    /// Becomes the `draft` made by `draft`, once its message is sent. A private chat's ratchet takes what the
+    /// message changed and stays shared with the other copies of the chat.
+    pub fn keep_draft(&mut self, draft: ChatType) {
+        match (self, draft) {
+            (ChatType::PrivateChat(private_chat), ChatType::PrivateChat(mut draft)) => {
+                private_chat.ratchet_profile.adopt(&draft.ratchet_profile);
+                draft.ratchet_profile = private_chat.ratchet_profile.clone();
+                *private_chat = draft;
+            },
+            (chat, draft) => *chat = draft,
+        }
+    }
+}
+
 #[async_trait]
 #[enum_dispatch] 
 pub trait Chat {
-    async fn print_incoming_events<T: ExternalPrinter + std::marker::Send + std::marker::Sync>(mut self, printing_helper: PrintingHandler<T>, reader: SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>>);
+    /// Prints the `cached` events of the chat right away, then its history and live events from `reader`.
+    async fn print_incoming_events<T: ChatPrinter + std::marker::Send + std::marker::Sync>(mut self, printing_helper: PrintingHandler<T>, reader: PoolReader, cached: Vec<Value>);
 
-    fn build_request_message(&self) -> Message;
+    /// Filter selecting the events of this chat. `extra_kinds` are subscribed to in addition to chat
+    /// messages, where the chat has a context they can appear in.
+    fn build_filter(&self, extra_kinds: &[u64]) -> Filter;
+
+    /// Builds the REQ for this chat, with a fresh subscription id.
+    fn build_request_message(&self, extra_kinds: &[u64]) -> Message {
+        let req = ClientMessage::new_req(SubscriptionId::generate(), vec![self.build_filter(extra_kinds)]).as_json();
+        Message::Text(req)
+    }
 
     fn get_name(self) -> String;
 
+    /// Name shown in the chat lists, with whatever identifies the chat further.
+    fn get_label(self) -> String where Self: Sized {
+        self.get_name()
+    }
+
+    /// Stable identifier of the chat, used to persist per-chat state
+    fn get_id(&self) -> String;
+
     fn get_info_table(&self, relay: &str) -> String;
 
-    fn message_from(&mut self, input: String, secret_key: SecretKey) -> Message;
+    /// Events publishing `input` in this chat. Usually one, but a gift wrapped message also goes to ourselves.
+    async fn message_from(&mut self, input: String, signer: &Signer) -> Result<Vec<Message>, String>;
 
-    async fn get_next_message(&self, reader: &mut SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>>) -> Result<Value, ()> {
-        let message = match reader.next().await.unwrap() {
-            Ok(val) => val,
-            Err(why) => {
-                panic!("Error while receiving message from Websocket. {}", why);
+    /// Events publishing `input` in this chat as a reply to the message `parent`.
+    async fn reply_from(&mut self, input: String, parent: &Value, signer: &Signer) -> Result<Vec<Message>, String>;
+
+    /// Next message from the relays, skipping empty frames. Fails for good with Error::Disconnected.
+    async fn get_next_message(&self, reader: &mut PoolReader) -> error::Result<Value> {
+        loop {
+            let message = reader.next().await.ok_or(Error::Disconnected)?;
+            match &message {
+                Message::Text(text) if text.trim().is_empty() => continue,
+                Message::Text(_) => return parse_relay_message(&message),
+                // NIP-01 only uses text frames, a relay sending binary ones is told about once
+                Message::Binary(data) => {
+                    if !BINARY_NOTED.swap(true, std::sync::atomic::Ordering::Relaxed) {
+                        crate::ui::print_error(format!("A relay sent a binary frame of {} bytes, which nostr doesn't use. Ignoring it and any further ones.", data.len()));
+                    }
+                },
+                // Control frames are answered by the websocket itself, closed connections are noticed by the pool
+                Message::Ping(_) | Message::Pong(_) | Message::Close(_) | Message::Frame(_) => continue,
             }
-        }.to_string();
-        if message.is_empty() {
-            return Err(());
         }
-        let json_val: Value = match serde_json::from_str(&message) {
-            Ok(val) => val,
-            Err(why) => {
-                eprintln!("Invalid JSON. {}", why);
-                return Err(());
-            }
-        };
-        Ok(json_val)
     }
 }
 
@@ -65,53 +139,229 @@ pub trait Chat {
 pub struct PublicChannel {
     pub root_event: Event,
     pub metadata: Metadata,
+    /// Tags added to the messages we send, applied from the config when the channel is opened
+    pub outgoing: OutgoingTags,
+}
+
+/// The [outgoing] config: tags added to our channel messages besides the ones the protocol needs.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct OutgoingConfig {
+    /// Relay given in the root tag, where clients can find the channel
+    pub relay_hint: Option<String>,
+    /// Name put in a client tag
+    pub client: Option<String>,
+    /// Hashtags added as t tags, keyed by channel id or name
+    #[serde(default)]
+    pub hashtags: HashMap<String, Vec<String>>,
+}
+
+impl OutgoingConfig {
+    pub fn for_channel(&self, channel: &PublicChannel) -> OutgoingTags {
+        let hashtags = self.hashtags.get(&channel.root_event.id.to_hex())
+            .or_else(|| self.hashtags.get(channel.metadata.name.as_deref().unwrap_or_default()))
+            .cloned()
+            .unwrap_or_default();
+        OutgoingTags { relay_hint: self.relay_hint.clone(), client: self.client.clone(), hashtags }
+    }
+}
+
+/// Extra tags of the messages sent to one channel.
+#[derive(Clone, Debug, Default)]
+pub struct OutgoingTags {
+    pub relay_hint: Option<String>,
+    pub client: Option<String>,
+    pub hashtags: Vec<String>,
+}
+
+impl PublicChannel {
+    pub fn new(root_event: Event, metadata: Metadata) -> PublicChannel {
+        PublicChannel { root_event, metadata, outgoing: OutgoingTags::default() }
+    }
+
+    /// Tags every message sent to the channel has: the root tag first, then the configured ones and the
+    /// hashtags of `content`.
+    fn message_tags(&self, content: &str) -> Vec<Tag> {
+        let relay_hint = self.outgoing.relay_hint.as_ref().and_then(|relay| UncheckedUrl::from_str(relay).ok());
+        let mut tags = vec![Tag::Event(self.root_event.id, relay_hint, Some(Marker::Root))];
+        if let Some(client) = &self.outgoing.client {
+            tags.push(Tag::Generic(TagKind::Custom("client".to_string()), vec![client.clone()]));
+        }
+        let mut hashtags: Vec<String> = self.outgoing.hashtags.iter().map(|hashtag| hashtag.trim_start_matches('#').to_lowercase()).collect();
+        for hashtag in parse_hashtags(content) {
+            if !hashtags.contains(&hashtag) {
+                hashtags.push(hashtag);
+            }
+        }
+        tags.extend(hashtags.into_iter().map(Tag::Hashtag));
+        tags
+    }
+
+    /// Filter for the kind 41 metadata updates of `channels` by their creators.
+    pub fn metadata_updates_filter(channels: &[PublicChannel]) -> Filter {
+        Filter::new()
+            .kind(Kind::ChannelMetadata)
+            .events(channels.iter().map(|channel| channel.root_event.id).collect())
+            .authors(channels.iter().map(|channel| channel.root_event.pubkey.to_string()).collect())
+    }
+
+    /// Replaces the metadata of the kind 40 event with that of the newest of `updates` updating this channel,
+    /// a kind 41 event of its creator tagging it. As in NIP-28, updates by anyone else are ignored.
+    /// Returns whether one was applied.
+    pub fn apply_metadata_updates(&mut self, updates: &[Value]) -> bool {
+        let creator = self.root_event.pubkey.to_string();
+        let id = self.root_event.id.to_hex();
+        let latest = updates.iter()
+            .filter(|event| event["kind"].as_u64() == Some(Kind::ChannelMetadata.as_u64()) && event["pubkey"] == creator.as_str())
+            .filter(|event| event["tags"].as_array().is_some_and(|tags| tags.iter().any(|tag| tag[0] == "e" && tag[1] == id.as_str())))
+            .filter(|event| crate::export::verify_event(event).is_ok())
+            .max_by_key(|event| event["created_at"].as_u64().unwrap_or_default());
+        match latest.and_then(|event| Metadata::from_json(event["content"].as_str()?).ok()) {
+            Some(metadata) => {
+                self.metadata = metadata;
+                true
+            },
+            None => false,
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct PrivateChat {
     pub name: String,
     pub recipient_public_key: XOnlyPublicKey,
-    pub secret_key: SecretKey,
+    pub signer: Signer,
     pub ratchet_profile: RatchetProfile, 
+    pub mode: DmMode,
+    /// Relays the recipient reads from by their NIP-65 relay list, which their messages are published to too
+    pub inbox_relays: Vec<String>,
+}
+
+/// How a private chat's messages are encrypted and published.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DmMode {
+    /// Nostrachat's own kind 420 events, encrypted with the ratchet
+    Ratchet,
+    /// Standard NIP-04 kind 4 direct messages, understood by most clients
+    Nip04,
+    /// Kind 4 direct messages with NIP-44 v2 encrypted content
+    #[default]
+    Nip44,
+    /// NIP-17 chat messages, sealed and gift wrapped as in NIP-59, hiding who talks to whom
+    #[serde(rename = "nip17")]
+    GiftWrap,
+}
+
+impl FromStr for DmMode {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<DmMode, String> {
+        match input {
+            "ratchet" => Ok(DmMode::Ratchet),
+            "nip04" => Ok(DmMode::Nip04),
+            "nip44" => Ok(DmMode::Nip44),
+            "nip17" => Ok(DmMode::GiftWrap),
+            _ => Err(format!("Unknown DM mode {}, expected ratchet, nip04, nip44 or nip17", input)),
+        }
+    }
 }
 
 #[async_trait]
 impl Chat for PublicChannel {
-    async fn print_incoming_events<T: ExternalPrinter + std::marker::Send + std::marker::Sync>(mut self, mut printing_helper: PrintingHandler<T>, mut reader: SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>>) {
-            let mut history: Vec<Value> = Vec::new();
+    async fn print_incoming_events<T: ChatPrinter + std::marker::Send + std::marker::Sync>(mut self, mut printing_helper: PrintingHandler<T>, mut reader: PoolReader, cached: Vec<Value>) {
+            let mut history: Vec<Value> = cached.into_iter().map(|event| json!(["EVENT", "", event])).collect();
+            printing_helper.print_history(&mut history);
+            history.clear();
 
             // Print history first
+            let progress = crate::ui::LoadingProgress::new(self.clone().get_name());
+            let mut loading = tokio::time::interval(LOADING_REFRESH);
             loop {
-                let json_val = match self.get_next_message(&mut reader).await {
+                let next = tokio::select! {
+                    next = self.get_next_message(&mut reader) => next,
+                    _ = loading.tick() => {
+                        progress.update(history.len());
+                        continue;
+                    },
+                    _ = SKIP_HISTORY.notified() => {
+                        printing_helper.print_history(&mut history);
+                        break;
+                    },
+                };
+                let json_val = match next {
                     Ok(val) => val,
-                    Err(_) => continue
+                    Err(Error::Disconnected) => {
+                        crate::ui::print_error(Error::Disconnected.to_string());
+                        return;
+                    },
+                    Err(why) => {
+                        crate::ui::print_error(why.to_string());
+                        continue;
+                    }
                 };
 
-                let message_kind = json_val[0].as_str().unwrap();
+                let message_kind = json_val[0].as_str().unwrap_or_default();
                 if message_kind == "EOSE" {
                    printing_helper.print_history(&mut history);
                    break;
                 } 
+                // Events from the cache were printed already
+                if message_kind == "EVENT" && !printing_helper.store.add(&json_val[2]) {
+                    continue;
+                }
 
                 history.push(json_val);
             }
 
-            // Print incoming messages second
+            drop(progress);
+
+            // Print incoming messages second, events once they are in order. Pages of older history asked for
+            // with /more are shown like the history once complete.
+            let mut ordering = OrderingBuffer::new(printing_helper.ordering_window);
+            let mut page: Vec<Value> = Vec::new();
             loop {
-                let json_val = match self.get_next_message(&mut reader).await {
-                    Ok(val) => val,
-                    Err(_) => continue
+                let next = tokio::select! {
+                    next = self.get_next_message(&mut reader) => Some(next),
+                    _ = ordering.due() => None,
+                };
+                let json_val = match next {
+                    Some(Ok(val)) => val,
+                    Some(Err(Error::Disconnected)) => {
+                        crate::ui::print_error(Error::Disconnected.to_string());
+                        return;
+                    },
+                    Some(Err(why)) => {
+                        crate::ui::print_error(why.to_string());
+                        continue;
+                    },
+                    None => {
+                        for json_val in ordering.take_due() {
+                            printing_helper.print_message(json_val);
+                        }
+                        continue;
+                    }
                 };
-                printing_helper.print_message(json_val);
+                if json_val[0] == "EOSE" && is_page(&json_val) {
+                    printing_helper.print_page(&mut page);
+                } else if json_val[0] != "EVENT" {
+                    printing_helper.print_message(json_val);
+                } else if !printing_helper.store.add(&json_val[2]) {
+                    continue;
+                } else if is_page(&json_val) {
+                    page.push(json_val);
+                } else {
+                    ordering.push(json_val);
+                }
             }
     }
 
-    fn build_request_message(&self) -> Message {
+    fn build_filter(&self, extra_kinds: &[u64]) -> Filter {
         let mut filter = Filter::default();
-        filter.kinds = Some(vec![Kind::Custom(42)]);
+        let mut kinds = vec![Kind::Custom(42)];
+        kinds.extend(extra_kinds.iter().map(|kind| Kind::Custom(*kind)));
+        filter.kinds = Some(kinds);
         filter.events = Some(vec![self.root_event.id]);
-        let req = ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json();
-        return Message::Text(req)
+        filter
     }
 
     fn get_name(self) -> String {
@@ -125,11 +375,31 @@ impl Chat for PublicChannel {
          }
     }
 
-    fn message_from(&mut self, input: String, secret_key: SecretKey) -> Message {
+    fn get_id(&self) -> String {
+        self.root_event.id.to_hex()
+    }
+
+    async fn message_from(&mut self, input: String, signer: &Signer) -> Result<Vec<Message>, String> {
 //        let event: Event = EventBuilder::new_channel_msg(nostr::ChannelId::from_hex(self.root_event.id.to_hex()).unwrap(), url::Url::parse("").unwrap(), input).to_event(&Keys::new(secret_key)).unwrap();
-        let event: Event = EventBuilder::new(Kind::Custom(42), input, &[Tag::Event(self.root_event.id, None, Some(Marker::Root))]).to_event(&Keys::new(secret_key)).unwrap();
+        let event: Event = crate::pow::sign(signer, EventBuilder::new(Kind::Custom(42), &input, &self.message_tags(&input))).await?;
         let client_msg = ClientMessage::new_event(event);
-        Message::Text(client_msg.as_json())
+        Ok(vec![Message::Text(client_msg.as_json())])
+    }
+
+    async fn reply_from(&mut self, input: String, parent: &Value, signer: &Signer) -> Result<Vec<Message>, String> {
+        let mut tags = self.message_tags(&input);
+        tags.extend(reply_tags(parent));
+        // As in NIP-10, the author of the parent and everyone it notified are notified of the reply
+        let mut notified: Vec<XOnlyPublicKey> = parent["pubkey"].as_str().and_then(|pubkey| XOnlyPublicKey::from_str(pubkey).ok()).into_iter().collect();
+        let parent_tags = parent["tags"].as_array().cloned().unwrap_or_default();
+        for public_key in parent_tags.iter().filter(|tag| tag[0] == "p").filter_map(|tag| tag[1].as_str().and_then(|pubkey| XOnlyPublicKey::from_str(pubkey).ok())) {
+            if !notified.contains(&public_key) && public_key != signer.public_key() {
+                notified.push(public_key);
+            }
+        }
+        tags.extend(notified.into_iter().map(|public_key| Tag::PubKey(public_key, None)));
+        let event: Event = crate::pow::sign(signer, EventBuilder::new(Kind::Custom(42), input, &tags)).await?;
+        Ok(vec![Message::Text(ClientMessage::new_event(event).as_json())])
     }
 
     fn get_info_table(&self, relay: &str) -> String {
@@ -140,89 +410,367 @@ impl Chat for PublicChannel {
             Some(val) => &val,
             None => "No name"
         };
-        let about = "About: ".green().to_string() + &self.metadata.about.as_ref().unwrap();
+        let about = "About: ".green().to_string() + self.metadata.about.as_deref().unwrap_or_default();
         let created_at = "Created at: ".green().to_string() + &NaiveDateTime::from_timestamp_opt(self.root_event.created_at.to_string().parse::<i64>().unwrap(), 0).unwrap().to_string();
         let creator = "Creator: ".green().to_string() + &self.root_event.pubkey.to_bech32().unwrap();
         return format!("{}\n{}\n{}\n{}\n{}\n{}\n{}", relay, channel_name, event_id_bech32, event_id_hex, about, creator, created_at)
     }
 }
 
+impl PrivateChat {
+    /// Events among `msgs` meant for the recipient, which go to their inbox relays too. Gift wraps for
+    /// ourselves stay on our relays.
+    pub fn inbox_events(&self, msgs: &[Message]) -> Vec<Value> {
+        let recipient = self.recipient_public_key.to_string();
+        msgs.iter()
+            .filter_map(|msg| serde_json::from_str::<Value>(&msg.to_string()).ok())
+            .filter(|json_val| json_val[0] == "EVENT")
+            .map(|json_val| json_val[1].clone())
+            .filter(|event| {
+                let tagged: Vec<&str> = event["tags"].as_array().into_iter().flatten().filter(|tag| tag[0] == "p").filter_map(|tag| tag[1].as_str()).collect();
+                tagged.is_empty() || tagged.contains(&recipient.as_str())
+            })
+            .collect()
+    }
+
+    /// Decrypts the content of `event`, or describes why it couldn't be decrypted.
+    pub async fn decrypt_content(&mut self, event: &Value) -> String {
+        let content = event["content"].as_str().unwrap_or_default().to_string();
+        let decrypted = match self.mode {
+            DmMode::Ratchet => {
+                let event_id = event["id"].as_str().unwrap_or_default();
+                if let Some(known) = self.ratchet_profile.known_message(event_id) {
+                    return known;
+                }
+                let author = match event["pubkey"].as_str().and_then(|pubkey| XOnlyPublicKey::from_str(pubkey).ok()) {
+                    Some(val) => val,
+                    None => return self.undecryptable(event, DecryptError::Header("invalid author".to_string())),
+                };
+                let header = match ratchet_header(event) {
+                    Ok(val) => val,
+                    Err(why) => return self.undecryptable(event, DecryptError::Header(why)),
+                };
+                // Not remembered, so it is read once nostrachat is updated
+                if header.version() > PROTOCOL_VERSION {
+                    return format!("[Sent with version {} of ratchet messages, update nostrachat to read it]", header.version());
+                }
+                let peer_version = self.ratchet_profile.peer_version();
+                // Failures aren't remembered, so the message can still be read after /resync or /import-session
+                let decrypted = match self.ratchet_profile.decrypt_message(content, author.public_key(Parity::Even), &header) {
+                    Ok(val) => {
+                        self.ratchet_profile.remember_message(event_id, &val);
+                        val
+                    },
+                    Err(why) => self.undecryptable(event, why),
+                };
+                if let Some(version) = self.ratchet_profile.peer_version().filter(|version| Some(*version) != peer_version && *version < PROTOCOL_VERSION) {
+                    crate::ui::print_error(format!("{} uses an older nostrachat that reads version {} of ratchet messages, yours are sent without padding until they update.", self.name, version));
+                }
+                return decrypted;
+            },
+            // The shared secret is the same in both directions, so this also decrypts our own messages.
+            // Both modes use kind 4, so the format of each message decides how it is decrypted.
+            DmMode::Nip04 | DmMode::Nip44 => match content.contains("?iv=") {
+                true => self.signer.nip04_decrypt(&self.recipient_public_key, &content).await,
+                false => self.signer.nip44_decrypt(&self.recipient_public_key, &content).await,
+            },
+            // Opened by open_gift_wrap already
+            DmMode::GiftWrap => Ok(content),
+        };
+        match decrypted {
+            Ok(val) => val,
+            Err(why) => self.undecryptable(event, why),
+        }
+    }
+
+    /// Placeholder shown for the message `event` that couldn't be decrypted, once the stage `why` it failed
+    /// at is logged. The first failure in the chat tells where the log is, and how to start over in ratchet mode.
+    fn undecryptable(&self, event: &Value, why: impl fmt::Display) -> String {
+        let path = crate::data_dir().join("decrypt_failures.log");
+        let line = format!("{} {} {} {}\n", Timestamp::now().as_u64(), self.recipient_public_key, event["id"].as_str().unwrap_or_default(), why);
+        if let Err(why) = fs::OpenOptions::new().create(true).append(true).open(&path).and_then(|mut file| file.write_all(line.as_bytes())) {
+            crate::ui::print_error(format!("Couldn't log the decryption failure: {}", why));
+        }
+        if UNDECRYPTABLE_NOTED.lock().unwrap().insert(self.recipient_public_key.to_string()) {
+            let resync = match self.mode {
+                DmMode::Ratchet => " /resync starts a new session with them.",
+                _ => "",
+            };
+            crate::ui::print_error(format!("A message in the chat with {} couldn't be decrypted ({}).{} Every failure is logged to {}.", self.name, why, resync, path.display()));
+        }
+        let author = match event["pubkey"].as_str() == Some(self.signer.public_key().to_string().as_str()) {
+            true => "you".to_string(),
+            false => self.name.clone(),
+        };
+        let time = chrono::DateTime::from_timestamp(event["created_at"].as_i64().unwrap_or_default(), 0)
+            .map(|time| time.with_timezone(&chrono::Local).format("%d.%m. %H:%M").to_string())
+            .unwrap_or_default();
+        format!("[undecryptable message from {} at {}]", author, time)
+    }
+
+    /// Opens and decrypts an `event` of the chat's history in place. Returns false if it isn't part of the chat.
+    async fn prepare_history_event(&mut self, event: &mut Value) -> bool {
+        if self.mode == DmMode::GiftWrap && !self.open_gift_wrap(event).await {
+            return false;
+        }
+        event["content"] = serde_json::Value::String(self.decrypt_content(event).await);
+        true
+    }
+
+    /// Replaces a gift wrapped `event` with the message inside. Returns false if it can't be opened or
+    /// belongs to a conversation with someone else.
+    pub async fn open_gift_wrap(&self, event: &mut Value) -> bool {
+        let rumor = match nip59::unwrap(&self.signer, event).await {
+            Ok(val) => val,
+            Err(_) => return false
+        };
+        let author = rumor["pubkey"].as_str().unwrap_or_default();
+        let recipient = self.recipient_public_key.to_string();
+        let to_recipient = rumor["tags"].as_array().is_some_and(|tags| tags.iter().any(|tag| tag[0] == "p" && tag[1] == recipient.as_str()));
+        if author != recipient && !(author == self.signer.public_key().to_string() && to_recipient) {
+            return false;
+        }
+        *event = rumor;
+        true
+    }
+
+    /// Events publishing `input` with `tags` added to those of the chat's DM mode.
+    async fn message_with_tags(&mut self, input: String, signer: &Signer, tags: Vec<Tag>) -> Result<Vec<Message>, String> {
+        if self.mode == DmMode::Nip04 || self.mode == DmMode::Nip44 {
+            let content = match self.mode {
+                DmMode::Nip04 => signer.nip04_encrypt(&self.recipient_public_key, &input).await?,
+                _ => signer.nip44_encrypt(&self.recipient_public_key, &input).await?,
+            };
+            let mut tags = tags;
+            tags.insert(0, Tag::PubKey(self.recipient_public_key, None));
+            let event: Event = crate::pow::sign(signer, EventBuilder::new(Kind::EncryptedDirectMessage, content, &tags)).await?;
+            return Ok(vec![Message::Text(ClientMessage::new_event(event).as_json())]);
+        }
+        if self.mode == DmMode::GiftWrap {
+            let mut tags = tags;
+            tags.insert(0, Tag::PubKey(self.recipient_public_key, None));
+            let rumor = EventBuilder::new(Kind::Custom(14), input, &tags).to_unsigned_event(signer.public_key());
+            // A copy wrapped for ourselves, so our side of the conversation can be read back from relays
+            let mut msgs = Vec::new();
+            for receiver in [self.recipient_public_key, signer.public_key()] {
+                let event = nip59::gift_wrap(signer, &receiver, &rumor).await?;
+                crate::receipts::link(&event.id.to_hex(), &rumor.id.to_hex());
+                msgs.push(Message::Text(ClientMessage::new_event(event).as_json()));
+            }
+            return Ok(msgs);
+        }
+        // The ratchet derives its keys from our private key, which a remote signer doesn't hand out
+        if signer.secret_key().is_none() {
+            return Err("Ratchet messages can't be sent with a remote signer, switch to another mode with /dmmode".to_string());
+        }
+        let random_key = SecretKey::new(&mut rand::thread_rng());
+        self.ratchet_profile.ephemeral_keys.lock().unwrap().secret_key = random_key;
+        let (enc_input, step) = self.ratchet_profile.encrypt_message(input.clone())?;
+        let rec_pub_key = self.ratchet_profile.ephemeral_keys.lock().unwrap().recipient_public_key.x_only_public_key().0;
+        // The step lets the recipient decrypt the message even if it arrives out of order
+        let version = self.ratchet_profile.send_version();
+        let mut ratchet_tags = vec![
+            Tag::PubKey(rec_pub_key, None),
+            Tag::Generic(TagKind::Custom("step".to_string()), vec![step.to_string()]),
+            Tag::Generic(TagKind::Custom("v".to_string()), vec![version.to_string()]),
+        ];
+        if version >= 2 {
+            ratchet_tags.push(Tag::Generic(TagKind::Custom("pad".to_string()), vec![crate::crypto::PADDING_VERSION.to_string()]));
+        }
+        if let Some(handshake) = self.ratchet_profile.handshake() {
+            ratchet_tags.push(Tag::Generic(TagKind::Custom("ek".to_string()), vec![handshake.ephemeral_key.to_string()]));
+            ratchet_tags.push(Tag::Generic(TagKind::Custom("spk".to_string()), vec![handshake.prekey.to_string()]));
+        }
+        ratchet_tags.extend(tags);
+        let keys = Keys::new(random_key);
+        let unsigned = crate::pow::mine(EventBuilder::new(Kind::Custom(420), enc_input, &ratchet_tags), keys.public_key()).await?;
+        let event: Event = unsigned.sign(&keys).map_err(|why| why.to_string())?;
+        // Our own message comes back from the relay, and it can't be decrypted without advancing the chain again
+        self.ratchet_profile.remember_message(&event.id.to_hex(), &input);
+        let client_msg = ClientMessage::new_event(event);
+        Ok(vec![Message::Text(client_msg.as_json())])
+    }
+}
+
 #[async_trait]
 impl Chat for PrivateChat {
-    async fn print_incoming_events<T: ExternalPrinter + std::marker::Send + std::marker::Sync>(mut self, mut printing_helper: PrintingHandler<T>, mut reader: SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>>) {
+    async fn print_incoming_events<T: ChatPrinter + std::marker::Send + std::marker::Sync>(mut self, mut printing_helper: PrintingHandler<T>, mut reader: PoolReader, cached: Vec<Value>) {
 
             let mut _current_iteration: usize = 0;
             let mut history: Vec<Value> = Vec::new();
+            for event in cached {
+                let mut json_val = json!(["EVENT", "", event]);
+                if self.prepare_history_event(&mut json_val[2]).await {
+                    crate::receipts::received(&self.get_id(), &json_val[2], printing_helper.is_own(&json_val[2]));
+                    history.push(json_val);
+                }
+            }
+            printing_helper.print_history(&mut history);
+            history.clear();
 
             // Print history first
+            let progress = crate::ui::LoadingProgress::new(self.clone().get_name());
+            let mut loading = tokio::time::interval(LOADING_REFRESH);
             loop {
-                let mut json_val = match self.get_next_message(&mut reader).await {
+                let next = tokio::select! {
+                    next = self.get_next_message(&mut reader) => next,
+                    _ = loading.tick() => {
+                        progress.update(history.len());
+                        continue;
+                    },
+                    _ = SKIP_HISTORY.notified() => {
+                        printing_helper.print_history(&mut history);
+                        break;
+                    },
+                };
+                let mut json_val = match next {
                     Ok(val) => val,
-                    Err(_) => continue
+                    Err(Error::Disconnected) => {
+                        crate::ui::print_error(Error::Disconnected.to_string());
+                        return;
+                    },
+                    Err(why) => {
+                        crate::ui::print_error(why.to_string());
+                        continue;
+                    }
                 };
-                let message_kind = json_val[0].as_str().unwrap();
+                let message_kind = json_val[0].as_str().unwrap_or_default();
                 if message_kind == "EOSE" {
                    printing_helper.print_history(&mut history);
                    break;
                 } 
 
-                let pubkey = json_val[2]["pubkey"].to_string();
-                println!("BEFORE CHANGING RECP KEY: {:?}", self.ratchet_profile.ephemeral_keys.lock().unwrap().recipient_public_key.serialize());
-                self.ratchet_profile.ephemeral_keys.lock().unwrap().recipient_public_key = XOnlyPublicKey::from_str(&pubkey[ 1 .. pubkey.len() - 1]).unwrap().public_key(Parity::Even);
-                println!("AFTER CHANGING RECP KEY: {:?}", self.ratchet_profile.ephemeral_keys.lock().unwrap().recipient_public_key.serialize());
-                json_val[2]["content"] = serde_json::Value::String(self.ratchet_profile.decrypt_message(json_val[2]["content"].to_string()));
-                history.push(json_val);
+                // Events from the cache were printed already
+                if message_kind == "EVENT" && printing_helper.store.contains(&json_val[2]) {
+                    continue;
+                }
+                // Gift wraps are only stored once they turn out to belong to this chat
+                let received = json_val[2].clone();
+                if self.prepare_history_event(&mut json_val[2]).await {
+                    printing_helper.store.add(&received);
+                    crate::receipts::received(&self.get_id(), &json_val[2], printing_helper.is_own(&json_val[2]));
+                    history.push(json_val);
+                }
             }
 
-            // Print incoming messages second
+            drop(progress);
+
+            // Print incoming messages second, once they are in order. They are decrypted as they arrive, as the
+            // ratchet needs. Pages of older history asked for with /more are shown like the history once complete.
+            let mut ordering = OrderingBuffer::new(printing_helper.ordering_window);
+            let mut page: Vec<Value> = Vec::new();
             loop {
-                let mut json_val = match self.get_next_message(&mut reader).await {
-                    Ok(val) => val,
-                    Err(_) => continue
+                let next = tokio::select! {
+                    next = self.get_next_message(&mut reader) => Some(next),
+                    _ = ordering.due() => None,
+                };
+                let mut json_val = match next {
+                    Some(Ok(val)) => val,
+                    Some(Err(Error::Disconnected)) => {
+                        crate::ui::print_error(Error::Disconnected.to_string());
+                        return;
+                    },
+                    Some(Err(why)) => {
+                        crate::ui::print_error(why.to_string());
+                        continue;
+                    },
+                    None => {
+                        for json_val in ordering.take_due() {
+                            let content = json_val[2]["content"].to_string();
+                            if !printing_helper.keep_echoed(&content, &json_val[2]) {
+                                printing_helper.print_formatted_message(&content, &json_val[2]);
+                                crate::media::show(&json_val[2]);
+                            }
+                        }
+                        continue;
+                    }
                 };
 
-                match json_val[0].as_str().unwrap() {
+                match json_val[0].as_str().unwrap_or_default() {
                     "EVENT" => {
-                        let pubkey = json_val[2]["pubkey"].to_string();
-                        self.ratchet_profile.ephemeral_keys.lock().unwrap().recipient_public_key = XOnlyPublicKey::from_str(&pubkey[ 1 .. pubkey.len() - 1]).unwrap().public_key(Parity::Even);
-                        json_val[2]["content"] = serde_json::Value::String(self.ratchet_profile.decrypt_message(json_val[2]["content"].to_string()));
-                        printing_helper.print_formatted_message(&json_val[2]["content"].to_string(), &json_val[2]["pubkey"].to_string());
+                        if printing_helper.store.contains(&json_val[2]) {
+                            continue;
+                        }
+                        let received = json_val[2].clone();
+                        if is_page(&json_val) {
+                            if self.prepare_history_event(&mut json_val[2]).await {
+                                printing_helper.store.add(&received);
+                                crate::receipts::received(&self.get_id(), &json_val[2], printing_helper.is_own(&json_val[2]));
+                                page.push(json_val);
+                            }
+                            continue;
+                        }
+                        if self.mode == DmMode::GiftWrap && !self.open_gift_wrap(&mut json_val[2]).await {
+                            continue;
+                        }
+                        printing_helper.store.add(&received);
+                        json_val[2]["content"] = serde_json::Value::String(self.decrypt_content(&json_val[2]).await);
+                        crate::receipts::received(&self.get_id(), &json_val[2], printing_helper.is_own(&json_val[2]));
+                        if printing_helper.is_moderated(&json_val[2]) || !printing_helper.passes_rules(&json_val[2], !printing_helper.is_own(&json_val[2])) {
+                            continue;
+                        }
+                        ordering.push(json_val);
                     }, 
                     "NOTICE" => {
-                        eprintln!();
+                        crate::ui::print_error(String::new());
+                    },
+                    "OK" => {
+                        if json_val[2] == true {
+                            crate::receipts::accepted(json_val[1].as_str().unwrap_or_default());
+                        }
+                    },
+                    "EOSE" => {
+                        if is_page(&json_val) {
+                            printing_helper.print_page(&mut page);
+                        }
                     },
-                    "OK" => {},
-                    "EOSE" => {},
                     &_ => {
-                        eprintln!("Unexpected event type: {}", json_val[0].as_str().unwrap()); 
+                        crate::ui::print_error(format!("Unexpected event type: {}", json_val[0])); 
                         continue;
                     }
                 }
             }
     }
 
-    fn build_request_message(&self) -> Message {
-        let mut filter = Filter::default();
-        filter.kinds = Some(vec![Kind::Custom(420)]);
-       // filter.pubkeys = Some(vec![XOnlyPublicKey::from(self.recipient_public_key)]);
-        let req = ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json();
-        return Message::Text(req)
+    fn build_filter(&self, _extra_kinds: &[u64]) -> Filter {
+        if self.mode == DmMode::GiftWrap {
+            // Gift wraps don't reveal the sender, so all of ours are fetched and sorted out once opened
+            return Filter::new().kind(Kind::Custom(1059)).pubkey(self.signer.public_key());
+        }
+        if self.mode != DmMode::Ratchet {
+            // Messages in both directions between us and the recipient
+            let own_public_key = self.signer.public_key();
+            return Filter::new()
+                .kind(Kind::EncryptedDirectMessage)
+                .authors(vec![own_public_key.to_string(), self.recipient_public_key.to_string()])
+                .pubkeys(vec![own_public_key, self.recipient_public_key]);
+        }
+        // Ratchet messages are signed with throwaway keys, but tag a key of the session
+        Filter::new().kind(Kind::Custom(420)).pubkeys(self.ratchet_profile.session_keys())
     }
 
     fn get_name(self) -> String {
         self.name
     }
 
-    fn message_from(&mut self, input: String, secret_key: SecretKey) -> Message {
-        let secp = Secp256k1::new();
-        let mut rng = rand::thread_rng();
-        let random_key = SecretKey::new(&mut rng);
-        self.ratchet_profile.ephemeral_keys.lock().unwrap().secret_key = random_key;
-        let enc_input = self.ratchet_profile.encrypt_message(input);
-        let rec_pub_key = self.ratchet_profile.ephemeral_keys.lock().unwrap().recipient_public_key.x_only_public_key().0;
-        let event: Event = EventBuilder::new(Kind::Custom(420), enc_input, &[Tag::PubKey(rec_pub_key, None)]).to_event(&Keys::new(random_key)).unwrap();
-        let client_msg = ClientMessage::new_event(event);
-        Message::Text(client_msg.as_json())
+    fn get_label(self) -> String {
+        match crate::names::verified_nip05(&self.recipient_public_key.to_string()) {
+            Some(identifier) => format!("{} ✓ {}", self.name, identifier),
+            None => self.name,
+        }
+    }
+
+    fn get_id(&self) -> String {
+        self.recipient_public_key.to_string()
+    }
+
+    async fn message_from(&mut self, input: String, signer: &Signer) -> Result<Vec<Message>, String> {
+        self.message_with_tags(input, signer, Vec::new()).await
+    }
+
+    // Only the parent is tagged, the other participant is tagged by every message anyway
+    async fn reply_from(&mut self, input: String, parent: &Value, signer: &Signer) -> Result<Vec<Message>, String> {
+        self.message_with_tags(input, signer, reply_tags(parent)).await
     }
 
     fn get_info_table(&self, relay: &str) -> String {
@@ -230,13 +778,92 @@ impl Chat for PrivateChat {
     }
 }
 
-pub struct PrintingHandler<T> where T: ExternalPrinter {
+/// Whether the relay message `json_val` is for a page of older history.
+fn is_page(json_val: &Value) -> bool {
+    crate::subscriptions::SUBSCRIPTIONS.lock().unwrap().is_page(json_val[1].as_str().unwrap_or_default())
+}
+
+/// Printer of a chat, which may be able to print a line among those it printed earlier.
+pub trait ChatPrinter: ExternalPrinter {
+    /// Prints `msg` right before `before`, a line it printed earlier. Printers that can't go back print
+    /// it last, marked as earlier.
+    fn print_before(&mut self, msg: String, _before: &str) -> rustyline::Result<()> {
+        self.print(mark_earlier(msg))
+    }
+}
+
+/// Publishes `events` of a private chat to the recipient's inbox `relays`, as NIP-65 asks, recording in the
+/// outbox how each relay answered.
+pub async fn deliver_to_inboxes(name: String, relays: Vec<String>, events: Vec<Value>) {
+    let results = futures::future::join_all(relays.iter().map(|relay| crate::relays::publish_events(relay, &events))).await;
+    let mut delivered = false;
+    for (relay, result) in relays.iter().zip(results) {
+        for event in &events {
+            let event_id = event["id"].as_str().unwrap_or_default();
+            let answer = match &result {
+                Ok((_, rejected)) => match rejected.iter().find(|(rejected_id, _)| rejected_id == event_id) {
+                    Some((_, reason)) => json!(["OK", event_id, false, reason]),
+                    None => json!(["OK", event_id, true, ""]),
+                },
+                Err(why) => json!(["OK", event_id, false, why]),
+            };
+            delivered |= answer[2] == true;
+            crate::outbox::answered(relay, &answer);
+        }
+    }
+    if !delivered {
+        crate::ui::print_error(format!("None of the relays {} reads from accepted the message, /outbox shows why.", name));
+    }
+}
+
+/// Prints `text` we just sent to the chat with `chat_id` in the "me" style, so it shows even before or without
+/// the relays sending it back. Once they do, it isn't printed a second time.
+pub fn echo_sent(chat_id: &str, text: &str) {
+    ECHOED.lock().unwrap().push((chat_id.to_string(), Value::String(text.to_string()).to_string()));
+    let time = match crate::timestamps::time(Timestamp::now().as_u64()) {
+        Some(time) => format!("{} ", time.truecolor(128, 128, 128)),
+        None => String::new(),
+    };
+    crate::ui::print(format!("{}{}: {}", time, "me".bold(), highlight_text(text)));
+}
+
+/// Whether `content` we sent to the chat with `chat_id` was echoed already, forgetting it if so.
+fn take_echoed(chat_id: &str, content: &str) -> bool {
+    let mut echoed = ECHOED.lock().unwrap();
+    match echoed.iter().position(|(echoed_chat, echoed_content)| echoed_chat == chat_id && echoed_content == content) {
+        Some(index) => {
+            echoed.remove(index);
+            true
+        },
+        None => false,
+    }
+}
+
+/// `line` marked as coming before messages printed above it.
+pub fn mark_earlier(line: String) -> String {
+    format!("{} {}", line, "(earlier)".truecolor(128, 128, 128))
+}
+
+pub struct PrintingHandler<T> where T: ChatPrinter {
     pub printer: T,
     pub pubkeys_to_colors: HashMap<String, u8>,
     pub public_key: XOnlyPublicKey,
+    /// Display templates for additional event kinds, keyed by kind
+    pub kind_templates: HashMap<u64, String>,
+    pub rules: Arc<RulesEngine>,
+    pub chat_id: String,
+    pub chat_name: String,
+    pub flood_control: Arc<Mutex<FloodControl>>,
+    /// Additional kinds toggled off in this chat
+    pub hidden_kinds: Arc<Mutex<HashSet<u64>>>,
+    pub store: ChatStore,
+    /// Messages printed so far, for the message actions menu
+    pub scrollback: Scrollback,
+    /// How long live events are held to be shown in order of creation
+    pub ordering_window: Duration,
 }
 
-impl<T: ExternalPrinter> PrintingHandler<T> {
+impl<T: ChatPrinter> PrintingHandler<T> {
     pub fn get_corresponding_color(&self, input: &str, number: u8) -> String {
         return match number {
            1 => input.green().to_string(),
@@ -253,40 +880,228 @@ impl<T: ExternalPrinter> PrintingHandler<T> {
         }
     }
 
-    fn print_formatted_message(&mut self, message: &str, author_pubkey: &str) {
+    fn print_formatted_message(&mut self, message: &str, event: &Value) {
+            let line = self.format_message(message, &event["pubkey"].to_string());
+            self.show(line, event);
+    }
+
+    /// Prints `line` and keeps it with the `event` it shows, so it can be picked later. The line starts with
+    /// the short id of the event and its time, highlighted if it mentions us, and replies to shown messages are indented. A message that came
+    /// after later ones goes before them, as far as the printer can. The first message of a day has a separator
+    /// above it.
+    fn show(&mut self, line: String, event: &Value) {
+        let event_id = event["id"].as_str().unwrap_or_default();
+        let line = match crate::receipts::glyph(event_id).or_else(|| crate::outbox::delivery_glyph(event_id)) {
+            Some(glyph) => format!("{} {}", line, glyph),
+            None => line,
+        };
+        let indent = match self.reply_depth(event) {
+            0 => String::new(),
+            depth => format!("{}↳ ", "  ".repeat(depth - 1)),
+        };
+        let created_at = event["created_at"].as_u64().unwrap_or_default();
+        let short_id = event["id"].as_str().unwrap_or_default().get(.. SHORT_ID_LENGTH).unwrap_or_default();
+        let prefix = match crate::timestamps::time(created_at) {
+            Some(time) => format!("{} {}", short_id, time),
+            None => short_id.to_string(),
+        };
+        // Messages addressed to us stand out from the rest
+        let prefix = match self.mentions_me(event) {
+            true => prefix.black().on_yellow().to_string(),
+            false => prefix.truecolor(128, 128, 128).to_string(),
+        };
+        let line = format!("{} {}{}", prefix, indent, line);
+
+        let mut scrollback = self.scrollback.lock().unwrap();
+        let parent = reply_parent(event).and_then(|parent_id| scrollback.iter().rev().find(|shown| shown.event["id"] == parent_id.as_str()));
+        // Replies stay in the thread of their parent, which other messages are ordered by
+        let (thread_created_at, later) = match parent {
+            Some(parent) => (parent.thread_created_at, 0),
+            None => (created_at, scrollback.iter().rev().take_while(|shown| shown.thread_created_at > created_at).count()),
+        };
+        let position = scrollback.len() - later;
+        let date = crate::timestamps::date(created_at);
+        let new_day = position == 0 || crate::timestamps::date(scrollback[position - 1].event["created_at"].as_u64().unwrap_or_default()) != date;
+        scrollback.insert(position, ShownMessage { line: line.clone(), event: event.clone(), thread_created_at });
+        let before = scrollback.get(position + 1).map(|shown| shown.line.clone());
+        drop(scrollback);
+        let mut lines = vec![line];
+        if new_day {
+            lines.insert(0, crate::timestamps::separator(date).truecolor(128, 128, 128).to_string());
+        }
+        for line in lines {
+            match &before {
+                Some(before) => self.printer.print_before(line, before),
+                None => self.printer.print(line),
+            }.expect("Printing failed!");
+        }
+    }
+
+    /// Keeps our own `event` without printing it if its `content` was echoed when we sent it. Returns whether
+    /// it was.
+    fn keep_echoed(&mut self, content: &str, event: &Value) -> bool {
+        if !self.is_own(event) || !take_echoed(&self.chat_id, content) {
+            return false;
+        }
+        let line = self.format_message(content, &event["pubkey"].to_string());
+        let created_at = event["created_at"].as_u64().unwrap_or_default();
+        self.scrollback.lock().unwrap().push(ShownMessage { line, event: event.clone(), thread_created_at: created_at });
+        true
+    }
+
+    /// How deep `event` is nested in replies to shown messages, 0 if it doesn't reply to one.
+    fn reply_depth(&self, event: &Value) -> usize {
+        let scrollback = self.scrollback.lock().unwrap();
+        let mut depth = 0;
+        let mut parent = reply_parent(event);
+        while let Some(parent_id) = parent.filter(|_| depth < MAX_REPLY_DEPTH) {
+            match scrollback.iter().rev().find(|shown| shown.event["id"] == parent_id.as_str()) {
+                Some(shown) => {
+                    depth += 1;
+                    parent = reply_parent(&shown.event);
+                },
+                None => break,
+            }
+        }
+        depth
+    }
+
+    fn format_message(&mut self, message: &str, author_pubkey: &str) -> String {
          if !self.pubkeys_to_colors.contains_key(author_pubkey) {
                 let mut small_rng = SmallRng::from_entropy();
                 self.pubkeys_to_colors.insert(author_pubkey.to_string(), small_rng.gen_range(1 .. 8));
             }
-            let author_key_bech32 = XOnlyPublicKey::from_str(&author_pubkey[1 .. author_pubkey.len() - 1]).unwrap().to_bech32().unwrap();
-            self.printer.print(format!("{}: {}", self.get_corresponding_color(&author_key_bech32[4 .. 10], self.pubkeys_to_colors[author_pubkey]), &message[1 .. message.len() - 1])).expect("Printing failed!");
+            let public_key = author_pubkey.trim_matches('"');
+            let badge = match crate::names::verified_nip05(public_key) {
+                Some(_) => " ✓".green().to_string(),
+                None => String::new(),
+            };
+            format!("{}{}: {}", self.get_corresponding_color(&crate::names::display_name(public_key), self.pubkeys_to_colors[author_pubkey]), badge, highlight_text(&message[1 .. message.len() - 1]))
+    }
+
+    /// Prints a message unless its author is over the flood limit, in which case it is collapsed.
+    fn print_limited_message(&mut self, message: &str, event: &Value) {
+        let author_pubkey = &event["pubkey"].to_string();
+        let created_at = event["created_at"].as_u64().unwrap_or_default();
+        let line = self.format_message(message, author_pubkey);
+        let (line, notices) = {
+            let mut flood_control = self.flood_control.lock().unwrap();
+            match flood_control.admit(author_pubkey, created_at) {
+                true => (Some(line), flood_control.take_notices()),
+                false => (None, flood_control.collapse(author_pubkey, &crate::names::display_name(author_pubkey.trim_matches('"')), line).into_iter().collect()),
+            }
+        };
+        for notice in notices {
+            self.printer.print(notice.truecolor(128, 128, 128).to_string()).expect("Printing failed!");
+        }
+        if let Some(line) = line {
+            self.show(line, event);
+        }
+    }
+
+    /// Returns the text to print for an event, quoted like its JSON content. Chat messages show their
+    /// content, other kinds are only shown if a template is configured for them.
+    fn event_text(&self, event: &Value) -> Option<String> {
+        let kind = event["kind"].as_u64()?;
+        // Channel messages, ratchet messages, kind 4 direct messages and opened NIP-17 messages
+        if [42, 420, 4, 14].contains(&kind) {
+            return Some(event["content"].to_string());
+        }
+        if self.hidden_kinds.lock().unwrap().contains(&kind) {
+            return None;
+        }
+        let template = self.kind_templates.get(&kind)?;
+        Some(Value::String(render_template(template, event)).to_string())
+    }
+
+    /// Whether `event` was published by us, so notification rules don't fire on it.
+    fn is_own(&self, event: &Value) -> bool {
+        event["pubkey"].as_str() == Some(&self.public_key.to_string())
+    }
+
+    /// Whether someone else's `event` mentions us.
+    fn mentions_me(&self, event: &Value) -> bool {
+        !self.is_own(event) && Event::from_value(event.clone()).is_ok_and(|event| is_mention(&event, &self.public_key))
+    }
+
+    /// Whether `event` is by someone we blocked, or hidden in this chat by us or the channel's creator as in
+    /// NIP-28. Our own messages are always shown.
+    fn is_moderated(&self, event: &Value) -> bool {
+        !self.is_own(event) && (crate::blocklist::is_blocked(event["pubkey"].as_str().unwrap_or_default()) || crate::moderation::is_hidden(&self.chat_id, event))
+    }
+
+    /// Checks an event against the notification rules, running their actions for live events.
+    /// Returns false if the event is ignored by a rule.
+    fn passes_rules(&self, event_json: &Value, live: bool) -> bool {
+        let event = match Event::from_value(event_json.clone()) {
+            Ok(val) => val,
+            Err(_) => return true
+        };
+        let context = RuleContext {
+            chat_id: &self.chat_id,
+            chat_name: &self.chat_name,
+            event: &event,
+            mentioned: is_mention(&event, &self.public_key),
+        };
+        self.rules.evaluate(&context, live)
     }
 
     pub fn print_history(&mut self, history: &mut Vec<Value>) {
          history.sort_by(|a, b| {
-          let a_id = a[2]["created_at"].as_i64().unwrap();
-          let b_id = b[2]["created_at"].as_i64().unwrap();
+          let a_id = a[2]["created_at"].as_i64().unwrap_or_default();
+          let b_id = b[2]["created_at"].as_i64().unwrap_or_default();
           a_id.cmp(&b_id)  
         });
+          thread_order(history);
           if history.len() != 0 {
                for i in 0 .. history.len()  {
-                   let content = history[i][2]["content"].to_string();
-                   self.print_formatted_message(&content, &history[i][2]["pubkey"].to_string());
+                   let content = match self.event_text(&history[i][2]) {
+                       Some(val) => val,
+                       None => continue
+                   };
+                   if self.is_moderated(&history[i][2]) || !self.passes_rules(&history[i][2], false) {
+                       continue;
+                   }
+                   let event = history[i][2].clone();
+                   self.print_limited_message(&content, &event);
                }
           }
+          let notices = self.flood_control.lock().unwrap().take_notices();
+          for notice in notices {
+              self.printer.print(notice.truecolor(128, 128, 128).to_string()).expect("Printing failed!");
+          }
+    }
+
+    /// Prints a page of older history, above what is shown.
+    pub fn print_page(&mut self, page: &mut Vec<Value>) {
+        let note = match page.len() {
+            0 => "No older messages".to_string(),
+            count => format!("{} older messages loaded", count),
+        };
+        crate::ui::show_pending(String::new());
+        self.printer.print(note.truecolor(128, 128, 128).to_string()).expect("Printing failed!");
+        self.print_history(page);
+        page.clear();
     }
 
     pub fn print_message(&mut self, json_val: Value) {
-           let message_kind = json_val[0].as_str().unwrap();
+           let message_kind = json_val[0].as_str().unwrap_or_default();
            match message_kind {
                  "EVENT" => {
-                     let json_pubkey = json_val[2]["pubkey"].to_string();
-                     if !(json_pubkey[1 .. json_pubkey.len() - 1] == self.public_key.to_string()) {
-                        self.print_formatted_message(&json_val[2]["content"].to_string(), &json_val[2]["pubkey"].to_string());
+                     // Our own messages are kept once the relay echoes them, so they can be acted on like any other
+                     if let Some(content) = self.event_text(&json_val[2]) {
+                        if self.is_moderated(&json_val[2]) || !self.passes_rules(&json_val[2], !self.is_own(&json_val[2])) {
+                            return;
+                        }
+                        if self.keep_echoed(&content, &json_val[2]) {
+                            return;
+                        }
+                        self.print_limited_message(&content, &json_val[2]);
+                        crate::media::show(&json_val[2]);
                      }
                  },
                  "NOTICE" => {
-                     println!("[{}] {}", "NOTICE".red(), &json_val[2]["content"]);
+                     crate::ui::print(format!("[{}] {}", "NOTICE".red(), &json_val[2]["content"]));
                  },
                  &_ => {
 
@@ -294,3 +1109,313 @@ impl<T: ExternalPrinter> PrintingHandler<T> {
            }
     }
 }
+
+fn hidden_kinds_path() -> PathBuf {
+    crate::data_dir().join("hidden_kinds.txt")
+}
+
+/// Additional kinds toggled off in a chat. The hidden kinds file has one "<chat id> <kind>" line per hidden kind.
+pub fn load_hidden_kinds(chat_id: &str) -> HashSet<u64> {
+    let content = fs::read_to_string(hidden_kinds_path()).unwrap_or_default();
+    content.lines()
+        .filter_map(|line| line.split_once(' '))
+        .filter(|(id, _)| *id == chat_id)
+        .filter_map(|(_, kind)| kind.parse().ok())
+        .collect()
+}
+
+pub fn save_hidden_kinds(chat_id: &str, kinds: &HashSet<u64>) {
+    let content = fs::read_to_string(hidden_kinds_path()).unwrap_or_default();
+    let mut lines: Vec<String> = content.lines()
+        .filter(|line| line.split_once(' ').is_some_and(|(id, _)| id != chat_id))
+        .map(|line| line.to_string())
+        .collect();
+    lines.extend(kinds.iter().map(|kind| format!("{} {}", chat_id, kind)));
+    if let Err(why) = fs::write(hidden_kinds_path(), lines.join("\n")) {
+        crate::ui::print_error(format!("Couldn't save hidden kinds: {}", why));
+    }
+}
+
+/// Tags marking a message as a reply to `parent`, as in NIP-10.
+fn reply_tags(parent: &Value) -> Vec<Tag> {
+    match parent["id"].as_str().and_then(|id| EventId::from_hex(id).ok()) {
+        Some(id) => vec![Tag::Event(id, None, Some(Marker::Reply))],
+        None => Vec::new(),
+    }
+}
+
+/// Id of the message `event` replies to, from its NIP-10 e tags: the one marked as reply, or else the last of
+/// several unmarked ones as in the deprecated positional scheme, where the first is the root.
+pub fn reply_parent(event: &Value) -> Option<String> {
+    let e_tags: Vec<&Value> = event["tags"].as_array()?.iter().filter(|tag| tag[0] == "e").collect();
+    if let Some(tag) = e_tags.iter().find(|tag| tag[3] == "reply") {
+        return tag[1].as_str().map(str::to_string);
+    }
+    let unmarked: Vec<&&Value> = e_tags.iter().filter(|tag| tag[3].as_str().unwrap_or_default().is_empty()).collect();
+    match unmarked.len() {
+        0 | 1 => None,
+        _ => unmarked.last().and_then(|tag| tag[1].as_str()).map(str::to_string),
+    }
+}
+
+/// Reorders `history`, sorted by time, so that replies follow the message they reply to, each thread
+/// still in order of time.
+fn thread_order(history: &mut Vec<Value>) {
+    let ids: HashSet<String> = history.iter().filter_map(|item| item[2]["id"].as_str()).map(str::to_string).collect();
+    let mut replies: HashMap<String, Vec<Value>> = HashMap::new();
+    let mut threads = Vec::new();
+    for item in history.drain(..) {
+        match reply_parent(&item[2]).filter(|parent| ids.contains(parent) && item[2]["id"] != parent.as_str()) {
+            Some(parent) => replies.entry(parent).or_default().push(item),
+            None => threads.push(item),
+        }
+    }
+    let mut pending: Vec<Value> = threads.into_iter().rev().collect();
+    while let Some(item) = pending.pop() {
+        if let Some(children) = replies.remove(item[2]["id"].as_str().unwrap_or_default()) {
+            pending.extend(children.into_iter().rev());
+        }
+        history.push(item);
+    }
+    // Replies in a cycle of parents have no thread to go in
+    let mut orphans: Vec<Value> = replies.into_values().flatten().collect();
+    orphans.sort_by_key(|item| item[2]["created_at"].as_i64().unwrap_or_default());
+    history.extend(orphans);
+}
+
+/// Value of the first tag of `event` named `name`.
+fn tag_value(event: &Value, name: &str) -> Option<String> {
+    event["tags"].as_array()?.iter().find(|tag| tag[0] == name).and_then(|tag| tag[1].as_str()).map(str::to_string)
+}
+
+/// Value of the first `name` tag of `event` parsed, None if there is none. Fails if it doesn't parse.
+fn parsed_tag<T: FromStr>(event: &Value, name: &str) -> Result<Option<T>, String> {
+    tag_value(event, name).map(|value| value.parse::<T>().map_err(|_| format!("invalid {} tag", name))).transpose()
+}
+
+/// Header of the ratchet message `event`. Fails if a tag of it is malformed, instead of decrypting the message
+/// as if the tag was missing.
+fn ratchet_header(event: &Value) -> Result<MessageHeader, String> {
+    let handshake = match (parsed_tag(event, "ek")?, parsed_tag(event, "spk")?) {
+        (Some(ephemeral_key), Some(prekey)) => Some(Handshake { ephemeral_key, prekey }),
+        (None, None) => None,
+        _ => return Err("handshake lacks its ek or spk tag".to_string()),
+    };
+    Ok(MessageHeader {
+        step: parsed_tag(event, "step")?,
+        sent_to: parsed_tag(event, "p")?,
+        handshake,
+        padding: parsed_tag(event, "pad")?,
+        version: parsed_tag(event, "v")?,
+    })
+}
+
+/// Length of the hashtag `token` starts with, like 5 for "#rust," but none for "#1" or "#".
+fn hashtag_length(token: &str) -> Option<usize> {
+    let name_length: usize = token.strip_prefix('#')?
+        .chars()
+        .take_while(|character| character.is_alphanumeric() || *character == '_' || *character == '-')
+        .map(char::len_utf8)
+        .sum();
+    let name = &token[1 .. 1 + name_length];
+    match name.chars().any(char::is_alphabetic) {
+        true => Some(1 + name_length),
+        false => None,
+    }
+}
+
+/// Hashtags of `content`, lowercased and without the #, for NIP-12 t tags.
+pub fn parse_hashtags(content: &str) -> Vec<String> {
+    let mut hashtags: Vec<String> = Vec::new();
+    for token in content.split_whitespace() {
+        if let Some(length) = hashtag_length(token) {
+            let hashtag = token[1 .. length].to_lowercase();
+            if !hashtags.contains(&hashtag) {
+                hashtags.push(hashtag);
+            }
+        }
+    }
+    hashtags
+}
+
+/// Whether links are printed as OSC-8 hyperlinks the terminal makes clickable, rather than underlined. Only
+/// --no-tui prints them, the chat screen can't show such escapes, so there links are underlined and opened with /open.
+static HYPERLINKS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_hyperlinks(enabled: bool) {
+    HYPERLINKS.store(enabled, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// The http or https link in `token`, without surrounding punctuation, along with what comes before and after it.
+fn split_link(token: &str) -> Option<(&str, &str, &str)> {
+    let start = token.len() - token.trim_start_matches(['(', '[', '<', '\'', '"']).len();
+    let rest = &token[start ..];
+    if !rest.starts_with("http://") && !rest.starts_with("https://") {
+        return None;
+    }
+    let link = rest.trim_end_matches(|c: char| c.is_whitespace() || matches!(c, '.' | ',' | ';' | ':' | '!' | '?' | ')' | ']' | '>' | '\'' | '"'));
+    Url::parse(link).ok()?;
+    Some((&token[.. start], link, &rest[link.len() ..]))
+}
+
+/// Links of `text`, in the order they appear.
+pub fn links(text: &str) -> Vec<String> {
+    text.split_whitespace().filter_map(split_link).map(|(_, link, _)| link.to_string()).collect()
+}
+
+/// `link` as a hyperlink to the url it parses to if the terminal supports them, underlined otherwise. Control
+/// characters are left out, as the sender could end the escape sequence with them and start one of their own.
+fn show_link(link: &str) -> String {
+    let shown: String = link.chars().filter(|character| !character.is_control()).collect();
+    match (HYPERLINKS.load(std::sync::atomic::Ordering::Relaxed), Url::parse(&shown)) {
+        (true, Ok(url)) => format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\", url.as_str(), shown.underline()),
+        _ => shown.underline().to_string(),
+    }
+}
+
+/// `text` with its hashtags highlighted and its links made clickable.
+fn highlight_text(text: &str) -> String {
+    text.split_inclusive(char::is_whitespace)
+        .map(|token| match (split_link(token), hashtag_length(token)) {
+            (Some((before, link, after)), _) => format!("{}{}{}", before, show_link(link), after),
+            (None, Some(length)) => format!("{}{}", token[.. length].cyan(), &token[length ..]),
+            (None, None) => token.to_string(),
+        })
+        .collect()
+}
+
+/// Name an author is shown with, from their public key quoted like in the event JSON.
+pub fn short_name(author_pubkey: &str) -> String {
+    match XOnlyPublicKey::from_str(author_pubkey.trim_matches('"')).ok().and_then(|public_key| public_key.to_bech32().ok()) {
+        Some(author_key_bech32) => author_key_bech32[4 .. 10].to_string(),
+        None => author_pubkey.trim_matches('"').chars().take(6).collect(),
+    }
+}
+
+/// Fills `{content}`, `{kind}`, `{id}`, `{created_at}` and `{tag:<name>}` placeholders in a kind template.
+/// The template is read once from left to right, so placeholders in the filled in values stay as they are.
+fn render_template(template: &str, event: &Value) -> String {
+    let mut output = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[.. start]);
+        let end = match rest[start ..].find('}') {
+            Some(val) => start + val,
+            None => {
+                rest = &rest[start ..];
+                break;
+            }
+        };
+        let value = match &rest[start + 1 .. end] {
+            "content" => event["content"].as_str().unwrap_or_default().to_string(),
+            "kind" => event["kind"].to_string(),
+            "id" => event["id"].as_str().unwrap_or_default().to_string(),
+            "created_at" => event["created_at"].to_string(),
+            placeholder => match placeholder.strip_prefix("tag:") {
+                Some(tag_name) => event["tags"].as_array()
+                    .and_then(|tags| tags.iter().find(|tag| tag[0].as_str() == Some(tag_name)))
+                    .and_then(|tag| tag[1].as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                // Not a placeholder, but one may start further on
+                None => {
+                    output.push('{');
+                    rest = &rest[start + 1 ..];
+                    continue;
+                },
+            },
+        };
+        output.push_str(&value);
+        rest = &rest[end + 1 ..];
+    }
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Relay message carrying an event with `id`, created at `created_at`, replying to `parent` if given.
+    fn message(id: &str, created_at: i64, parent: Option<&str>) -> Value {
+        let tags = match parent {
+            Some(parent) => json!([["e", "root", "", "root"], ["e", parent, "", "reply"]]),
+            None => json!([]),
+        };
+        json!(["EVENT", "sub", { "id": id, "created_at": created_at, "tags": tags }])
+    }
+
+    fn ids(history: &[Value]) -> Vec<&str> {
+        history.iter().map(|item| item[2]["id"].as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn render_template_fills_placeholders() {
+        let event = json!({ "content": "hello", "kind": 30023, "id": "abc", "created_at": 1700000000, "tags": [["title", "Post"]] });
+        assert_eq!(render_template("{tag:title} ({kind}): {content}", &event), "Post (30023): hello");
+        assert_eq!(render_template("{id} at {created_at}{tag:missing}", &event), "abc at 1700000000");
+    }
+
+    #[test]
+    fn render_template_doesnt_fill_filled_in_values() {
+        let event = json!({ "content": "{id} {tag:title}", "id": "abc", "tags": [["title", "{content}"]] });
+        assert_eq!(render_template("{content} / {tag:title}", &event), "{id} {tag:title} / {content}");
+    }
+
+    #[test]
+    fn render_template_keeps_other_braces() {
+        let event = json!({ "content": "hello" });
+        assert_eq!(render_template("{not {content}} {", &event), "{not hello} {");
+    }
+
+    #[test]
+    fn hashtags_are_parsed_lowercased_and_once() {
+        assert_eq!(parse_hashtags("#Rust and #nostr, again #rust"), vec!["rust", "nostr"]);
+        assert_eq!(parse_hashtags("#1 # #_- a#b #über-cool"), vec!["über-cool"]);
+    }
+
+    #[test]
+    fn links_are_found_without_surrounding_punctuation() {
+        assert_eq!(links("see (https://example.com/a?b=c). and http://nostr.com, but not ftp://x.y"), vec!["https://example.com/a?b=c", "http://nostr.com"]);
+        assert!(links("https:// nor example.com").is_empty());
+    }
+
+    #[test]
+    fn hyperlinks_carry_no_escapes_of_the_sender() {
+        set_hyperlinks(true);
+        let shown = show_link("https://example.com/\x1b]8;;https://evil.example\x07");
+        set_hyperlinks(false);
+        assert!(!shown.contains('\x07'));
+        assert!(!shown.contains("\x1b]8;;https://evil"));
+    }
+
+    #[test]
+    fn reply_parent_is_the_marked_or_last_positional_e_tag() {
+        assert_eq!(reply_parent(&message("b", 1, Some("a"))[2]), Some("a".to_string()));
+        let positional = json!({ "tags": [["e", "root"], ["e", "parent"]] });
+        assert_eq!(reply_parent(&positional), Some("parent".to_string()));
+        let root_only = json!({ "tags": [["e", "root"]] });
+        assert_eq!(reply_parent(&root_only), None);
+    }
+
+    #[test]
+    fn thread_order_puts_replies_after_their_parents() {
+        let mut history = vec![
+            message("a", 1, None),
+            message("b", 2, None),
+            message("c", 3, Some("a")),
+            message("d", 4, Some("c")),
+            message("e", 5, Some("a")),
+            message("f", 6, Some("unknown")),
+        ];
+        thread_order(&mut history);
+        assert_eq!(ids(&history), vec!["a", "c", "d", "e", "b", "f"]);
+    }
+
+    #[test]
+    fn thread_order_keeps_replies_in_a_cycle() {
+        let mut history = vec![message("a", 1, Some("b")), message("b", 2, Some("a")), message("c", 3, None)];
+        thread_order(&mut history);
+        assert_eq!(ids(&history), vec!["c", "a", "b"]);
+    }
+}
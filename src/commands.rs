@@ -0,0 +1,2631 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::process::exit;
+
+use colored::Colorize;
+use futures::future::{ join_all, FutureExt, LocalBoxFuture };
+use nostr::prelude::*;
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::app::App;
+use crate::chats::{ self, Chat, ChatType, DmMode, PublicChannel };
+use crate::monitor::ActivityEntry;
+use crate::receipts::{ self, ReceiptState };
+use crate::storage::{ self, ChatStore };
+use crate::subscriptions::{ self, SUBSCRIPTIONS };
+use crate::tabs::Tab;
+use crate::{ blocklist, cache, directory, export, filters, moderation, names, nip05, nip11, nip49, outbox, profiles, relays, tail, ui, watch };
+
+/// How a command takes the rest of the input line, checked before its handler runs.
+pub enum Arguments {
+    /// Nothing may follow the command
+    None,
+    /// Anything may follow the command, or nothing
+    Optional,
+    /// Something has to follow the command
+    Required,
+}
+
+/// Who may run a command that doesn't come from the prompt.
+pub enum Permission {
+    /// Anyone allowed to send commands, for commands that only show things that aren't secret
+    Anyone,
+    /// Only the admins in config.toml, for commands that quit, open or close chats and connections, connect to
+    /// other relays, change files, sign with our key or show decrypted or hidden messages
+    Admin,
+}
+
+/// Where a command comes from.
+pub enum Origin {
+    /// Typed at the prompt, which may run every command
+    Local,
+    /// Sent by the holder of a public key, who may run the commands their permission allows
+    Remote(XOnlyPublicKey),
+}
+
+/// Runs a command with the rest of the input line.
+pub type Handler = for<'a> fn(&'a mut App, &'a str) -> LocalBoxFuture<'a, ()>;
+
+/// A command of the chat prompt: how it is typed, what runs it, and what help and hints say about it.
+pub struct CommandInfo {
+    pub name: &'static str,
+    /// Other names the command can be typed as
+    pub aliases: &'static [&'static str],
+    pub usage: &'static str,
+    pub summary: &'static str,
+    pub examples: &'static [&'static str],
+    /// Names of commands used together with this one
+    pub related: &'static [&'static str],
+    pub arguments: Arguments,
+    pub permission: Permission,
+    pub handler: Handler,
+}
+
+impl CommandInfo {
+    /// Whether the command is typed as `name`, by its name or one of its aliases.
+    fn is_named(&self, name: &str) -> bool {
+        self.name == name || self.aliases.contains(&name)
+    }
+}
+
+/// Every command of the chat prompt, in the order they are listed by /help.
+pub const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "/help",
+        aliases: &["/?"],
+        usage: "/help [command]",
+        summary: "Lists all commands, or explains one of them",
+        examples: &["/help", "/help dmmode"],
+        related: &[],
+        arguments: Arguments::Optional,
+        permission: Permission::Anyone,
+        handler: help,
+    },
+    CommandInfo {
+        name: "/editor",
+        aliases: &[],
+        usage: "/editor",
+        summary: "Opens a text editor to type your message out",
+        examples: &[],
+        related: &["/preview"],
+        arguments: Arguments::None,
+        permission: Permission::Admin,
+        handler: editor,
+    },
+    CommandInfo {
+        name: "/channelinfo",
+        aliases: &[],
+        usage: "/channelinfo",
+        summary: "Shows metadata about the current channel",
+        examples: &[],
+        related: &["/whois"],
+        arguments: Arguments::None,
+        permission: Permission::Anyone,
+        handler: channel_info,
+    },
+    CommandInfo {
+        name: "/preview",
+        aliases: &[],
+        usage: "/preview",
+        summary: "Toggles showing outgoing events for confirmation before they are sent",
+        examples: &[],
+        related: &["/editor"],
+        arguments: Arguments::None,
+        permission: Permission::Admin,
+        handler: preview,
+    },
+    CommandInfo {
+        name: "/presence",
+        aliases: &[],
+        usage: "/presence [on|off]",
+        summary: "Shows how many are in the channel now, or turns announcing that you are here on or off",
+        examples: &["/presence", "/presence on"],
+        related: &["/preview"],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: presence,
+    },
+    CommandInfo {
+        name: "/switch",
+        aliases: &[],
+        usage: "/switch [n|next|prev]",
+        summary: "Opens another chat in a new tab (also Ctrl-K), or switches to tab n, the next or the previous one (also Ctrl-Right and Ctrl-Left)",
+        examples: &["/switch", "/switch 2", "/switch next"],
+        related: &["/close", "/join", "/split"],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: switch,
+    },
+    CommandInfo {
+        name: "/close",
+        aliases: &[],
+        usage: "/close",
+        summary: "Closes the current tab",
+        examples: &[],
+        related: &["/switch"],
+        arguments: Arguments::None,
+        permission: Permission::Admin,
+        handler: close,
+    },
+    CommandInfo {
+        name: "/more",
+        aliases: &[],
+        usage: "/more [count]",
+        summary: "Loads the page of messages before the oldest one shown, history_page of them unless a count is given. PageUp does too",
+        examples: &["/more", "/more 200"],
+        related: &["/export"],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: more,
+    },
+    CommandInfo {
+        name: "/open",
+        aliases: &[],
+        usage: "/open [n]",
+        summary: "Opens the nth link, the first unless given, of the last message with links in the browser",
+        examples: &["/open", "/open 2"],
+        related: &["/more"],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: open_link,
+    },
+    CommandInfo {
+        name: "/join",
+        aliases: &[],
+        usage: "/join <id|name>",
+        summary: "Joins a channel by its note1, nevent1 or hex id, or by its name on the relay completed with Tab, in a new tab, and adds it to your joined channels shared with other clients",
+        examples: &["/join note1...", "/join nevent1...", "/join Nostrachat"],
+        related: &["/switch", "/dm", "/create-channel", "/channelinfo"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: join,
+    },
+    CommandInfo {
+        name: "/pin",
+        aliases: &[],
+        usage: "/pin",
+        summary: "Adds the shown chat to your joined channels or pinned chats, which other clients share and the chat list starts with",
+        examples: &[],
+        related: &["/unpin", "/join", "/dm"],
+        arguments: Arguments::None,
+        permission: Permission::Admin,
+        handler: pin,
+    },
+    CommandInfo {
+        name: "/unpin",
+        aliases: &[],
+        usage: "/unpin",
+        summary: "Removes the shown chat from your joined channels or pinned chats",
+        examples: &[],
+        related: &["/pin", "/close"],
+        arguments: Arguments::None,
+        permission: Permission::Admin,
+        handler: unpin,
+    },
+    CommandInfo {
+        name: "/create-channel",
+        aliases: &[],
+        usage: "/create-channel [name]",
+        summary: "Creates a channel, asking for its name, description and picture, and opens it in a new tab",
+        examples: &["/create-channel", "/create-channel rust"],
+        related: &["/join", "/edit-channel", "/channelinfo"],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: create_channel,
+    },
+    CommandInfo {
+        name: "/edit-channel",
+        aliases: &[],
+        usage: "/edit-channel",
+        summary: "Changes the name, description or picture of a channel you created",
+        examples: &[],
+        related: &["/create-channel", "/channelinfo"],
+        arguments: Arguments::None,
+        permission: Permission::Admin,
+        handler: edit_channel,
+    },
+    CommandInfo {
+        name: "/dm",
+        aliases: &["/msg"],
+        usage: "/dm <npub|n|name> [--save]",
+        summary: "Opens a private chat in a new tab with anyone, or with the author of the nth newest message or of the newest by a name shown, --save also pins it",
+        examples: &["/dm npub1...", "/dm 1", "/dm 1qx3fz --save"],
+        related: &["/switch", "/dmmode", "/follow", "/pin", "/whois"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: dm,
+    },
+    CommandInfo {
+        name: "/follow",
+        aliases: &[],
+        usage: "/follow <npub|n|name>",
+        summary: "Adds someone to your contact list, shared with other clients, and to the chats of the quick switcher",
+        examples: &["/follow npub1...", "/follow 1"],
+        related: &["/unfollow", "/dm"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: follow,
+    },
+    CommandInfo {
+        name: "/unfollow",
+        aliases: &[],
+        usage: "/unfollow <npub|n|name>",
+        summary: "Removes someone from your contact list",
+        examples: &["/unfollow npub1..."],
+        related: &["/follow"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: unfollow,
+    },
+    CommandInfo {
+        name: "/split",
+        aliases: &[],
+        usage: "/split",
+        summary: "Opens the current and another chat side by side, Tab moves between them and Esc closes the view",
+        examples: &[],
+        related: &["/switch"],
+        arguments: Arguments::None,
+        permission: Permission::Admin,
+        handler: split,
+    },
+    CommandInfo {
+        name: "/whois",
+        aliases: &[],
+        usage: "/whois [npub]",
+        summary: "Shows the profile of a user, or of the contact in a private chat",
+        examples: &["/whois", "/whois npub1..."],
+        related: &["/channelinfo"],
+        arguments: Arguments::Optional,
+        permission: Permission::Anyone,
+        handler: whois,
+    },
+    CommandInfo {
+        name: "/profile",
+        aliases: &[],
+        usage: "/profile [edit]",
+        summary: "Shows your profile, or edits it in your editor and publishes it",
+        examples: &["/profile", "/profile edit"],
+        related: &["/whois"],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: profile,
+    },
+    CommandInfo {
+        name: "/relays",
+        aliases: &[],
+        usage: "/relays [probe]",
+        summary: "Compares the history of the current chat across all configured relays, or ranks relays by latency",
+        examples: &["/relays", "/relays probe"],
+        related: &["/stats", "/compare", "/rebroadcast"],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: compare_relays,
+    },
+    CommandInfo {
+        name: "/relayinfo",
+        aliases: &[],
+        usage: "/relayinfo [relay] [pay]",
+        summary: "Shows the NIP-11 information of a relay, by default the lookup relay: what it is, the NIPs it supports, its limits and whether it wants payment before it accepts your events. pay opens its payment page",
+        examples: &["/relayinfo", "/relayinfo wss://relay.example.com", "/relayinfo wss://relay.example.com pay"],
+        related: &["/relays", "/outbox"],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: relay_info,
+    },
+    CommandInfo {
+        name: "/compare",
+        aliases: &[],
+        usage: "/compare <relay>",
+        summary: "Fetches the history of the current chat from another relay and shows the events each of it and the current relay is missing",
+        examples: &["/compare wss://nos.lol"],
+        related: &["/relays", "/rebroadcast"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: compare,
+    },
+    CommandInfo {
+        name: "/stats",
+        aliases: &[],
+        usage: "/stats",
+        summary: "Shows the bandwidth used per relay this session",
+        examples: &[],
+        related: &["/relays"],
+        arguments: Arguments::None,
+        permission: Permission::Anyone,
+        handler: stats,
+    },
+    CommandInfo {
+        name: "/activity",
+        aliases: &[],
+        usage: "/activity [list]",
+        summary: "Shows mentions of you and replies, reactions and zaps to your messages across all chats, to jump to them",
+        examples: &["/activity", "/activity list"],
+        related: &["/switch"],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: activity,
+    },
+    CommandInfo {
+        name: "/chats",
+        aliases: &[],
+        usage: "/chats",
+        summary: "Lists your chats with how many messages came in since you last read them, unread ones first",
+        examples: &[],
+        related: &["/switch", "/activity"],
+        arguments: Arguments::None,
+        permission: Permission::Admin,
+        handler: chats_list,
+    },
+    CommandInfo {
+        name: "/watching",
+        aliases: &[],
+        usage: "/watching",
+        summary: "Shows the Watching feed, the latest notes and channel messages of the npubs in watch in config.toml",
+        examples: &[],
+        related: &["/activity"],
+        arguments: Arguments::None,
+        permission: Permission::Admin,
+        handler: watching,
+    },
+    CommandInfo {
+        name: "/receipts",
+        aliases: &[],
+        usage: "/receipts [n]",
+        summary: "Shows when your last n messages in this private chat were queued, sent, accepted by relays and seen, which is once the recipient wrote back",
+        examples: &["/receipts", "/receipts 10"],
+        related: &["/dmmode"],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: receipts,
+    },
+    CommandInfo {
+        name: "/outbox",
+        aliases: &[],
+        usage: "/outbox [retry|edit|discard <n>]",
+        summary: "Lists what you sent that not every relay has accepted yet, with the relays still waited for and why others rejected it. Sends entry n again, edits and sends it anew, or drops it",
+        examples: &["/outbox", "/outbox retry 1", "/outbox edit 2", "/outbox discard 1"],
+        related: &["/receipts", "/rebroadcast", "/relays"],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: outbox,
+    },
+    CommandInfo {
+        name: "/tag",
+        aliases: &[],
+        usage: "/tag <hashtag>",
+        summary: "Shows the recent events with a hashtag on the connected relays",
+        examples: &["/tag nostr", "/tag #rust"],
+        related: &["/firehose", "/filter"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: tag,
+    },
+    CommandInfo {
+        name: "/tail",
+        aliases: &[],
+        usage: "/tail <filter> <duration>",
+        summary: "Shows the events matching a REQ filter in a pane below the messages for a while, then closes the subscription. Only new events unless the filter has a since or limit",
+        examples: &["/tail {\"kinds\":[1]} 5m", "/tail {\"#t\":[\"nostr\"],\"limit\":10} 30s"],
+        related: &["/filter"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: tail,
+    },
+    CommandInfo {
+        name: "/filter",
+        aliases: &[],
+        usage: "/filter",
+        summary: "Builds a REQ filter in a form that shows its JSON as you type, then lists the matching events on the connected relays",
+        examples: &[],
+        related: &["/tag", "/tail"],
+        arguments: Arguments::None,
+        permission: Permission::Admin,
+        handler: filter_builder,
+    },
+    CommandInfo {
+        name: "/dmmode",
+        aliases: &[],
+        usage: "/dmmode <mode>",
+        summary: "Switches the private chat between nip44 (default), nip17 (gift wrapped), nip04 (older clients) and ratchet (nostrachat only) encryption, or detects the contact's with auto",
+        examples: &["/dmmode nip17", "/dmmode auto"],
+        related: &["/whois"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: dm_mode,
+    },
+    CommandInfo {
+        name: "/resync",
+        aliases: &[],
+        usage: "/resync",
+        summary: "Starts a new ratchet session with the contact from their prekey, when their messages can't be decrypted anymore",
+        examples: &[],
+        related: &["/dmmode", "/import-session"],
+        arguments: Arguments::None,
+        permission: Permission::Admin,
+        handler: resync,
+    },
+    CommandInfo {
+        name: "/kinds",
+        aliases: &[],
+        usage: "/kinds [kind|on|off]",
+        summary: "Lists the additional kinds shown in this chat, or toggles one or all of them",
+        examples: &["/kinds", "/kinds 7", "/kinds off"],
+        related: &[],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: kinds,
+    },
+    CommandInfo {
+        name: "/expand",
+        aliases: &[],
+        usage: "/expand [name]",
+        summary: "Shows the messages collapsed by flood control, of one author or of everyone",
+        examples: &["/expand", "/expand a1b2c3"],
+        related: &[],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: expand,
+    },
+    CommandInfo {
+        name: "/export",
+        aliases: &[],
+        usage: "/export [file]",
+        summary: "Exports the chat's history as signed events with a verification report",
+        examples: &["/export", "/export chat.json"],
+        related: &["/rebroadcast"],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: export_history,
+    },
+    CommandInfo {
+        name: "/export-key",
+        aliases: &[],
+        usage: "/export-key",
+        summary: "Encrypts your private key with a passphrase (NIP-49), for backups or the privkey in config.toml",
+        examples: &[],
+        related: &["/export"],
+        arguments: Arguments::None,
+        permission: Permission::Admin,
+        handler: export_key,
+    },
+    CommandInfo {
+        name: "/export-session",
+        aliases: &[],
+        usage: "/export-session [file]",
+        summary: "Encrypts the private chat's session with a passphrase, to continue it on another device without a reset",
+        examples: &["/export-session", "/export-session alice.session"],
+        related: &["/import-session", "/export-key"],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: export_session,
+    },
+    CommandInfo {
+        name: "/import-session",
+        aliases: &[],
+        usage: "/import-session <file>",
+        summary: "Continues the private chat's session exported on another device with /export-session",
+        examples: &["/import-session alice.session"],
+        related: &["/export-session"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: import_session,
+    },
+    CommandInfo {
+        name: "/firehose",
+        aliases: &[],
+        usage: "/firehose [relay]",
+        summary: "Samples everything a relay carries for a while, to see what kinds of events it has",
+        examples: &["/firehose", "/firehose wss://relay.damus.io"],
+        related: &["/kinds"],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: firehose,
+    },
+    CommandInfo {
+        name: "/cache",
+        aliases: &[],
+        usage: "/cache stats|prune",
+        summary: "Shows the size of the local event cache, or prunes it by the retention policy now",
+        examples: &["/cache stats", "/cache prune"],
+        related: &["/rebroadcast"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: manage_cache,
+    },
+    CommandInfo {
+        name: "/rebroadcast",
+        aliases: &[],
+        usage: "/rebroadcast [--chat|--mine] <relay>",
+        summary: "Republishes your own cached events, or the current chat's cached history, to a relay",
+        examples: &["/rebroadcast wss://nos.lol", "/rebroadcast --chat wss://nos.lol"],
+        related: &["/cache", "/relays"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: rebroadcast,
+    },
+    CommandInfo {
+        name: "/reply",
+        aliases: &[],
+        usage: "/reply <id> <text>",
+        summary: "Replies to a message by the id shown before it, also from the actions menu Esc opens. Replies are shown indented under the message",
+        examples: &["/reply 4f2a hi there"],
+        related: &["/react", "/raw"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: reply,
+    },
+    CommandInfo {
+        name: "/react",
+        aliases: &[],
+        usage: "/react <id> [reaction]",
+        summary: "Reacts to a channel message, with a like by default",
+        examples: &["/react 4f2a", "/react 4f2a 🤙"],
+        related: &["/reply", "/zap"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: react,
+    },
+    CommandInfo {
+        name: "/raw",
+        aliases: &[],
+        usage: "/raw <id>",
+        summary: "Shows the event of a message",
+        examples: &["/raw 4f2a"],
+        related: &["/reply"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: raw,
+    },
+    CommandInfo {
+        name: "/zap",
+        aliases: &[],
+        usage: "/zap <id>",
+        summary: "Opens your lightning wallet to zap the author of a message",
+        examples: &["/zap 4f2a"],
+        related: &["/react", "/whois"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: zap,
+    },
+    CommandInfo {
+        name: "/delete",
+        aliases: &[],
+        usage: "/delete <id>",
+        summary: "Asks relays to delete one of your messages",
+        examples: &["/delete 4f2a"],
+        related: &["/raw"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: delete,
+    },
+    CommandInfo {
+        name: "/hide",
+        aliases: &[],
+        usage: "/hide <id> [reason]",
+        summary: "Hides a channel message for you and publishes that you did, as in NIP-28. Messages the channel's creator hid are hidden too",
+        examples: &["/hide 4f2a", "/hide 4f2a spam"],
+        related: &["/mute", "/delete"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: hide,
+    },
+    CommandInfo {
+        name: "/mute",
+        aliases: &[],
+        usage: "/mute <user> [reason]",
+        summary: "Hides the messages of a user in channels and publishes that you muted them, as in NIP-28. Users the channel's creator muted are hidden too",
+        examples: &["/mute npub1...", "/mute 3 spam"],
+        related: &["/hide", "/block", "/whois"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: mute,
+    },
+    CommandInfo {
+        name: "/block",
+        aliases: &[],
+        usage: "/block [user]",
+        summary: "Drops the messages of a user in all chats from now on, without telling anyone. Lists who is blocked without a user",
+        examples: &["/block npub1...", "/block 3", "/block"],
+        related: &["/unblock", "/mute"],
+        arguments: Arguments::Optional,
+        permission: Permission::Admin,
+        handler: block,
+    },
+    CommandInfo {
+        name: "/unblock",
+        aliases: &[],
+        usage: "/unblock <user>",
+        summary: "Shows the messages of a blocked user again",
+        examples: &["/unblock npub1..."],
+        related: &["/block"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: unblock,
+    },
+    CommandInfo {
+        name: "/status",
+        aliases: &[],
+        usage: "/status",
+        summary: "Shows how long Nostrachat has been running, with which key, chats and relays",
+        examples: &[],
+        related: &["/stats", "/broadcast"],
+        arguments: Arguments::None,
+        permission: Permission::Anyone,
+        handler: status,
+    },
+    CommandInfo {
+        name: "/broadcast",
+        aliases: &[],
+        usage: "/broadcast <text>",
+        summary: "Sends a message to every open chat",
+        examples: &["/broadcast back in five minutes"],
+        related: &["/status"],
+        arguments: Arguments::Required,
+        permission: Permission::Admin,
+        handler: broadcast,
+    },
+    CommandInfo {
+        name: "/exit",
+        aliases: &["/quit"],
+        usage: "/exit",
+        summary: "Quits Nostrachat",
+        examples: &[],
+        related: &[],
+        arguments: Arguments::None,
+        permission: Permission::Admin,
+        handler: quit,
+    },
+];
+
+/// Channel names the hint line lists at most while /join is typed.
+pub const MAX_HINTED_CHANNELS: usize = 8;
+/// Relays from relay lists /relays probe measures at most, besides the configured ones.
+pub const MAX_DISCOVERED_RELAYS: usize = 10;
+/// Events /tag shows at most, newest first.
+pub const MAX_TAGGED_EVENTS: usize = 50;
+/// Events /filter shows at most, newest first.
+pub const MAX_FILTERED_EVENTS: usize = 200;
+/// Messages /receipts shows without an argument.
+pub const DEFAULT_RECEIPTS: usize = 5;
+/// Characters of a message /receipts shows.
+pub const MAX_RECEIPT_PREVIEW: usize = 40;
+
+/// Keys of the chat screen and what they do.
+pub const KEYS: &[(&str, &str)] = &[
+    ("Esc", "Picks a message in the scrollback to reply, react, copy, zap, message its author or delete"),
+    ("Ctrl-K", "Opens the quick switcher, like /switch"),
+    ("PageUp", "Loads the page of messages before the oldest one shown, like /more"),
+    ("Ctrl-G", "Stops loading history, the chat starts with what has been received so far"),
+    ("Ctrl-Z", "Cancels the message counting down to be sent, or mining proof of work"),
+    ("Ctrl-Right, Ctrl-Left", "Switches to the next or the previous tab"),
+    ("Up, Down", "Browses the input history"),
+    ("Tab", "Completes the channel name after /join"),
+];
+
+/// Command typed as `name`, with or without its slash.
+pub fn find(name: &str) -> Option<&'static CommandInfo> {
+    let name = format!("/{}", name.trim_start_matches('/'));
+    COMMANDS.iter().find(|command| command.is_named(&name))
+}
+
+/// Usage and summary of every command, followed by the keys.
+pub fn help_overview() -> String {
+    let mut lines: Vec<String> = COMMANDS.iter().map(|command| format!("{:<40} - {}", command.usage, command.summary)).collect();
+    lines.push(String::new());
+    lines.extend(KEYS.iter().map(|(keys, action)| format!("{:<40} - {}", keys, action)));
+    lines.push("Get the details of a command with /help <command>".to_string());
+    lines.join("\n")
+}
+
+/// Usage, examples, aliases and related commands of the command `name`, with or without its slash.
+pub fn help_for(name: &str) -> Option<String> {
+    let command = find(name)?;
+    let mut lines = vec![format!("Usage: {}", command.usage), command.summary.to_string()];
+    if !command.examples.is_empty() {
+        lines.push("Examples:".to_string());
+        lines.extend(command.examples.iter().map(|example| format!("  {}", example)));
+    }
+    if !command.aliases.is_empty() {
+        lines.push(format!("Also typed as: {}", command.aliases.join(", ")));
+    }
+    if !command.related.is_empty() {
+        lines.push(format!("See also: {}", command.related.join(", ")));
+    }
+    Some(lines.join("\n"))
+}
+
+/// One line hint for a command being typed: the usage of the commands starting with `input`, or the full
+/// usage and summary once a single one matches.
+pub fn hint(input: &str) -> Option<String> {
+    let typed = input.split_whitespace().next()?;
+    if !typed.starts_with('/') {
+        return None;
+    }
+    if let Some(prefix) = input.strip_prefix("/join ").map(str::trim_start).filter(|prefix| !prefix.is_empty()) {
+        let names: Vec<String> = directory::matching(prefix).into_iter().take(MAX_HINTED_CHANNELS).map(|entry| entry.name).collect();
+        if !names.is_empty() {
+            return Some(format!("Tab completes: {}", names.join(", ")));
+        }
+    }
+    // Once arguments are typed, only the command that is typed out in full counts
+    let matching: Vec<&CommandInfo> = match input.contains(' ') {
+        true => COMMANDS.iter().filter(|command| command.is_named(typed)).collect(),
+        false => COMMANDS.iter().filter(|command| command.name.starts_with(typed) || command.aliases.contains(&typed)).collect(),
+    };
+    match matching.as_slice() {
+        [] => None,
+        [command] => Some(format!("{} - {}", command.usage, command.summary)),
+        commands => Some(commands.iter().map(|command| command.usage).collect::<Vec<&str>>().join("  ")),
+    }
+}
+
+/// Whether `origin` may run a command that needs `permission`, given the `admins` of config.toml. The prompt
+/// may run everything.
+fn is_permitted(admins: &[String], origin: &Origin, permission: &Permission) -> bool {
+    match (origin, permission) {
+        (Origin::Local, _) | (_, Permission::Anyone) => true,
+        (Origin::Remote(public_key), Permission::Admin) => admins.iter()
+            .any(|admin| profiles::parse_public_key(admin) == Some(*public_key)),
+    }
+}
+
+/// `input` with the channel name after /join completed as far as the channels the relay knows agree.
+pub fn complete(input: &str) -> Option<String> {
+    let prefix = input.strip_prefix("/join ")?.trim_start();
+    directory::complete(prefix).map(|completed| format!("/join {}", completed))
+}
+
+/// Runs the command `input` starts with, or sends `input` to the shown chat if it isn't a command, as far
+/// as `origin` is permitted to.
+pub async fn dispatch(app: &mut App, input: String, origin: Origin) {
+    if !input.starts_with('/') {
+        // Sending signs with our key
+        if !is_permitted(&app.config.admins, &origin, &Permission::Admin) {
+            ui::print_error("Only admins can send messages.".to_string());
+            return;
+        }
+        send_text(app, input).await;
+        return;
+    }
+    let (name, argument) = match input.split_once(' ') {
+        Some((name, argument)) => (name, argument.trim()),
+        None => (input.as_str(), ""),
+    };
+    let command = match find(name) {
+        Some(val) => val,
+        None => {
+            ui::print_error("Command not found! Get all commands with /help".to_string());
+            return;
+        }
+    };
+    if !is_permitted(&app.config.admins, &origin, &command.permission) {
+        ui::print_error(format!("Only admins can run {}.", command.name));
+        return;
+    }
+    let accepted = match command.arguments {
+        Arguments::None => argument.is_empty(),
+        Arguments::Optional => true,
+        Arguments::Required => !argument.is_empty(),
+    };
+    if !accepted {
+        ui::print_error(format!("Usage: {}", command.usage));
+        return;
+    }
+    (command.handler)(app, argument).await;
+}
+
+async fn send_text(app: &mut App, text: String) {
+    // Sign on a draft, so a discarded preview doesn't advance the ratchet of the real chat
+    let mut draft = app.chat.draft();
+    let msgs = match draft.message_from(text.clone(), &app.signer).await {
+        Ok(val) => val,
+        Err(why) => {
+            ui::print_error(format!("Couldn't send the message: {}", why));
+            return;
+        }
+    };
+    if !app.confirm(&msgs).await || !app.hold_send().await {
+        return;
+    }
+    app.chat.keep_draft(draft);
+    if let Err(why) = app.send(msgs.clone()).await {
+        ui::print_error(format!("Couldn't send the message: {}", why));
+        return;
+    }
+    outbox::attach_text(&msgs, &text);
+    chats::echo_sent(&app.chat.get_id(), &text);
+}
+
+fn help<'a>(_: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        match argument {
+            "" => ui::print(help_overview().truecolor(128, 128, 128).to_string()),
+            name => match help_for(name) {
+                Some(help) => ui::print(help.truecolor(128, 128, 128).to_string()),
+                None => ui::print_error(format!("There's no command {}, get all commands with /help", name)),
+            },
+        }
+    }.boxed_local()
+}
+
+fn editor<'a>(app: &'a mut App, _: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let mut draft = app.chat.draft();
+        app.screen.suspend();
+        let text = crate::editor("*Type out your message here*").expect("Couldn't open editor!");
+        app.screen.resume();
+        let msgs = match draft.message_from(text.clone(), &app.signer).await {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(format!("Couldn't send the message: {}", why));
+                return;
+            }
+        };
+        if !app.confirm(&msgs).await || !app.hold_send().await {
+            return;
+        }
+        app.chat.keep_draft(draft);
+        if let Err(why) = app.send(msgs.clone()).await {
+            ui::print_error(format!("Couldn't send the message: {}", why));
+            return;
+        }
+        outbox::attach_text(&msgs, &text);
+        chats::echo_sent(&app.chat.get_id(), &text);
+    }.boxed_local()
+}
+
+fn channel_info<'a>(app: &'a mut App, _: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        ui::print(app.chat.get_info_table(&app.relay).to_string());
+    }.boxed_local()
+}
+
+fn preview<'a>(app: &'a mut App, _: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        app.preview_mode = !app.preview_mode;
+        ui::print(format!("Preview mode {}.", if app.preview_mode { "enabled" } else { "disabled" }));
+        app.update_status();
+    }.boxed_local()
+}
+
+fn presence<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        match argument {
+            "" => {
+                let sharing = if app.presence.is_sharing() { "Others see you here." } else { "You aren't announced, /presence on lets others see you here." };
+                match app.presence.here_now() {
+                    Some(here_now) => ui::print(format!("~{} here now. {}", here_now, sharing)),
+                    None => ui::print(format!("Presence is only counted in channels. {}", sharing)),
+                }
+            },
+            "on" | "off" => {
+                app.presence.set_sharing(argument == "on");
+                ui::print(format!("Announcing your presence {}.", if argument == "on" { "enabled, from the next announcement" } else { "disabled, others stop counting you within minutes" }));
+            },
+            _ => ui::print_error("Usage: /presence [on|off]".to_string()),
+        }
+    }.boxed_local()
+}
+
+fn switch<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let mut opened = false;
+        let index = match argument {
+            "" => {
+                app.screen.suspend();
+                let switched_to = ui::quick_switcher(app.config.clone(), app.known_chats.clone(), app.monitor.snapshot());
+                app.screen.resume();
+                let new_chat = match switched_to {
+                    Some(val) => val,
+                    None => return
+                };
+                match app.tabs.find(&new_chat.get_id()) {
+                    Some(index) => index,
+                    None => match app.open_tab(new_chat).await {
+                        Ok(index) => {
+                            opened = true;
+                            index
+                        },
+                        Err(why) => {
+                            ui::print_error(format!("Couldn't open the chat: {}", why));
+                            return;
+                        }
+                    }
+                }
+            },
+            "next" => (app.tabs.active + 1) % app.tabs.tabs.len(),
+            "prev" => (app.tabs.active + app.tabs.tabs.len() - 1) % app.tabs.tabs.len(),
+            number => match number.parse::<usize>() {
+                Ok(number) if (1 ..= app.tabs.tabs.len()).contains(&number) => number - 1,
+                _ => {
+                    ui::print_error("Usage: /switch [tab number|next|prev]".to_string());
+                    return;
+                }
+            },
+        };
+        app.show_tab(index);
+        if opened {
+            crate::print_chat_header(&app.config, &app.relay, &app.chat).await;
+        }
+    }.boxed_local()
+}
+
+fn close<'a>(app: &'a mut App, _: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        if app.tabs.tabs.len() == 1 {
+            ui::print_error("This is the only open chat, quit with /exit instead.".to_string());
+            return;
+        }
+        let closed = app.tabs.remove(app.tabs.active);
+        app.leave(&closed.get_id()).await;
+        app.tabs.activate(app.tabs.active, &app.screen);
+        app.chat = app.tabs.active().chat.clone();
+        app.monitor.set_active(app.chat.get_id());
+        app.presence.set_active(&app.chat);
+        app.update_status();
+    }.boxed_local()
+}
+
+fn more<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let count = match argument {
+            "" => subscriptions::history_page().max(1),
+            _ => match argument.parse::<usize>() {
+                Ok(count) if count > 0 => count,
+                _ => {
+                    ui::print_error("Usage: /more [number of messages]".to_string());
+                    return;
+                }
+            },
+        };
+        let oldest = app.tabs.active().scrollback.lock().unwrap().iter()
+            .filter_map(|shown| shown.event["created_at"].as_u64())
+            .min();
+        let chat_id = app.chat.get_id();
+        let mut connections = app.connections.lock().await;
+        let connection = match connections.get_mut(&chat_id) {
+            Some(val) => val,
+            None => {
+                ui::print_error("This chat isn't connected.".to_string());
+                return;
+            }
+        };
+        let mut filter = connection.filter.clone().limit(count);
+        if let Some(oldest) = oldest {
+            filter = filter.until(Timestamp::from(oldest));
+        }
+        let (subscription_id, closes) = SUBSCRIPTIONS.lock().unwrap().open_page(&chat_id, &connection.writer.relays());
+        let req = Message::Text(ClientMessage::new_req(subscription_id, vec![filter]).as_json());
+        for msg in closes.into_iter().chain([req]) {
+            if let Err(why) = connection.writer.send(msg).await {
+                ui::print_error(format!("Couldn't ask the relays for older messages: {}", why));
+                return;
+            }
+        }
+        ui::show_pending(format!("Loading {} older messages...", count));
+    }.boxed_local()
+}
+
+fn open_link<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let number = match argument {
+            "" => 1,
+            _ => match argument.parse::<usize>() {
+                Ok(number) if number > 0 => number,
+                _ => {
+                    ui::print_error("Usage: /open [number of the link]".to_string());
+                    return;
+                }
+            },
+        };
+        let links = app.tabs.active().scrollback.lock().unwrap().iter().rev()
+            .map(|shown| chats::links(shown.event["content"].as_str().unwrap_or_default()))
+            .find(|links| !links.is_empty());
+        let links = match links {
+            Some(val) => val,
+            None => {
+                ui::print_error("No message with links is shown.".to_string());
+                return;
+            }
+        };
+        let link = match links.get(number - 1) {
+            Some(val) => val,
+            None => {
+                ui::print_error(format!("The last message with links has only {} of them.", links.len()));
+                return;
+            }
+        };
+        match open::that(link) {
+            Ok(()) => ui::print(format!("Opened {}", link)),
+            Err(why) => ui::print_error(format!("Couldn't open {}: {}", link, why)),
+        }
+    }.boxed_local()
+}
+
+fn join<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let by_name = || directory::find(argument).and_then(|entry| EventId::from_hex(entry.id).ok()).map(|event_id| (event_id, Vec::new()));
+        let (event_id, relay_hints) = match crate::parse_event_pointer(argument).or_else(by_name) {
+            Some(val) => val,
+            None => {
+                ui::print_error("Usage: /join <note1..., nevent1..., hex id or name of the channel>".to_string());
+                return;
+            }
+        };
+        let index = match app.tabs.find(&event_id.to_hex()) {
+            Some(index) => index,
+            None => {
+                // Relays named by the nevent first, as they are likely to have it
+                let mut lookup_relays = relay_hints;
+                lookup_relays.extend(app.config.relays.iter().filter(|relay| !lookup_relays.contains(relay)).cloned().collect::<Vec<String>>());
+                ui::print(format!("Looking for the channel on {} relays...", lookup_relays.len()));
+                match crate::fetch_channel(&lookup_relays, event_id).await.map(ChatType::PublicChannel) {
+                    Ok(channel) => match app.open_tab(channel).await {
+                        Ok(index) => index,
+                        Err(why) => {
+                            ui::print_error(format!("Couldn't join the channel: {}", why));
+                            return;
+                        }
+                    },
+                    Err(why) => {
+                        ui::print_error(format!("Couldn't join the channel: {}", why));
+                        return;
+                    }
+                }
+            }
+        };
+        app.show_tab(index);
+        ui::print(format!("Joined {}", app.chat.clone().get_name().green()));
+        let chat = app.chat.clone();
+        update_lists(app, &chat, true).await;
+    }.boxed_local()
+}
+
+fn pin<'a>(app: &'a mut App, _: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let chat = app.chat.clone();
+        if !update_lists(app, &chat, true).await {
+            ui::print(format!("{} is pinned already.", chat.get_name()));
+        }
+    }.boxed_local()
+}
+
+fn unpin<'a>(app: &'a mut App, _: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let chat = app.chat.clone();
+        if !update_lists(app, &chat, false).await {
+            ui::print(format!("{} isn't pinned.", chat.get_name()));
+        }
+    }.boxed_local()
+}
+
+/// Adds `chat` to the NIP-51 list of joined channels or the set of pinned chats, or removes it, and publishes
+/// the list. Returns false if it was in the list already or wasn't in it, so nothing is published.
+async fn update_lists(app: &mut App, chat: &ChatType, add: bool) -> bool {
+    let (list, tag_name, what) = match chat {
+        ChatType::PublicChannel(_) => (app.channels_list.clone(), "e", "joined channels"),
+        ChatType::PrivateChat(_) => (app.pinned_chats.clone(), "p", "pinned chats"),
+    };
+    let mut list = match list {
+        Some(val) => val,
+        None => {
+            ui::print_error(format!("Your {} couldn't be fetched at startup, so they aren't changed to not lose any.", what));
+            return true;
+        }
+    };
+    let changed = match add {
+        true => list.add(tag_name, &chat.get_id()),
+        false => list.remove(tag_name, &chat.get_id()),
+    };
+    if !changed {
+        return false;
+    }
+    let event = match list.to_event(&app.signer).await {
+        Ok(val) => val,
+        Err(why) => {
+            ui::print_error(format!("Couldn't sign your {}: {}", what, why));
+            return true;
+        }
+    };
+    let msgs = vec![Message::Text(ClientMessage::new_event(event).as_json())];
+    if !app.confirm(&msgs).await {
+        return true;
+    }
+    if let Err(why) = app.send(msgs).await {
+        ui::print_error(format!("Couldn't publish your {}: {}", what, why));
+        return true;
+    }
+    match chat {
+        ChatType::PublicChannel(_) => app.channels_list = Some(list),
+        ChatType::PrivateChat(_) => app.pinned_chats = Some(list),
+    }
+    let name = chat.clone().get_name().green();
+    match add {
+        true => ui::print(format!("Added {} to your {}.", name, what)),
+        false => ui::print(format!("Removed {} from your {}.", name, what)),
+    }
+    true
+}
+
+fn create_channel<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let name = match argument {
+            "" => app.ask("Name of the channel:").await,
+            name => name.to_string(),
+        };
+        if name.is_empty() {
+            ui::print_error("A channel needs a name.".to_string());
+            return;
+        }
+        let mut metadata = Metadata::new().name(&name);
+        let about = app.ask("What is it about? Leave empty to skip.").await;
+        if !about.is_empty() {
+            metadata = metadata.about(about);
+        }
+        let picture = app.ask("Address of its picture? Leave empty to skip.").await;
+        if !picture.is_empty() {
+            match Url::parse(&picture) {
+                Ok(url) => metadata = metadata.picture(url),
+                Err(why) => {
+                    ui::print_error(format!("The picture's address isn't valid: {}", why));
+                    return;
+                }
+            }
+        }
+        let root_event = match app.signer.sign(EventBuilder::new_channel(metadata.clone())).await {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(format!("Couldn't sign the channel: {}", why));
+                return;
+            }
+        };
+        let note = root_event.id.to_bech32().unwrap();
+        let msgs = vec![Message::Text(ClientMessage::new_event(root_event.clone()).as_json())];
+        if !app.confirm(&msgs).await {
+            return;
+        }
+        if let Err(why) = app.send(msgs).await {
+            ui::print_error(format!("Couldn't publish the event: {}", why));
+            return;
+        }
+        let index = match app.open_tab(ChatType::PublicChannel(PublicChannel::new(root_event, metadata))).await {
+            Ok(index) => index,
+            Err(why) => {
+                ui::print_error(format!("Created {}, but couldn't open it: {}", name, why));
+                return;
+            }
+        };
+        app.show_tab(index);
+        ui::print(format!("Created {}, others can join it with /join {}", name.green(), note));
+    }.boxed_local()
+}
+
+fn edit_channel<'a>(app: &'a mut App, _: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let mut channel = match &app.chat {
+            ChatType::PublicChannel(val) => val.clone(),
+            ChatType::PrivateChat(_) => {
+                ui::print_error("Only channels can be edited.".to_string());
+                return;
+            }
+        };
+        if channel.root_event.pubkey != app.signer.public_key() {
+            ui::print_error("Only the creator of a channel can edit it.".to_string());
+            return;
+        }
+        let mut metadata = channel.metadata.clone();
+        let name = app.ask(&format!("Name? Leave empty to keep {}.", metadata.name.clone().unwrap_or_default())).await;
+        if !name.is_empty() {
+            metadata = metadata.name(name);
+        }
+        let about = app.ask("What is it about? Leave empty to keep the description.").await;
+        if !about.is_empty() {
+            metadata = metadata.about(about);
+        }
+        let picture = app.ask("Address of its picture? Leave empty to keep the picture.").await;
+        if !picture.is_empty() {
+            match Url::parse(&picture) {
+                Ok(url) => metadata = metadata.picture(url),
+                Err(why) => {
+                    ui::print_error(format!("The picture's address isn't valid: {}", why));
+                    return;
+                }
+            }
+        }
+        if metadata == channel.metadata {
+            ui::print("Nothing changed.".to_string());
+            return;
+        }
+        let event = match app.signer.sign(EventBuilder::set_channel_metadata(ChannelId::from(channel.root_event.id), None, metadata.clone())).await {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(format!("Couldn't sign the channel metadata: {}", why));
+                return;
+            }
+        };
+        let msgs = vec![Message::Text(ClientMessage::new_event(event).as_json())];
+        if !app.confirm(&msgs).await {
+            return;
+        }
+        if let Err(why) = app.send(msgs).await {
+            ui::print_error(format!("Couldn't publish the event: {}", why));
+            return;
+        }
+        channel.metadata = metadata;
+        app.chat = ChatType::PublicChannel(channel);
+        let chat = app.chat.clone();
+        if let Some(known) = app.known_chats.iter_mut().find(|known| known.get_id() == chat.get_id()) {
+            *known = chat.clone();
+        }
+        let index = app.tabs.active;
+        app.tabs.tabs[index].chat = chat;
+        app.tabs.rename(index);
+        app.update_status();
+        ui::print("Updated the channel.".to_string());
+    }.boxed_local()
+}
+
+fn dm<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let (target, save) = match argument.strip_suffix("--save") {
+            Some(rest) => (rest.trim(), true),
+            None => (argument, false),
+        };
+        let contact = match pick_user(app, target) {
+            Some(val) => val,
+            None => {
+                ui::print_error("Usage: /dm <npub, hex public key, number of a message counted from the newest, or name shown> [--save]".to_string());
+                return;
+            }
+        };
+        if contact == app.signer.public_key() {
+            ui::print_error("That's you.".to_string());
+            return;
+        }
+        let npub = contact.to_bech32().unwrap();
+        let mut opened = false;
+        let index = match app.tabs.find(&contact.to_string()) {
+            Some(index) => index,
+            None => {
+                let new_chat = match app.known_chats.iter().find(|known| known.get_id() == contact.to_string()) {
+                    Some(known) => known.clone(),
+                    None => ChatType::PrivateChat(crate::new_private_chat(&app.config, &app.signer, contact)),
+                };
+                match app.open_tab(new_chat).await {
+                    Ok(index) => {
+                        opened = true;
+                        index
+                    },
+                    Err(why) => {
+                        ui::print_error(format!("Couldn't open the chat with {}: {}", npub, why));
+                        return;
+                    }
+                }
+            }
+        };
+        app.show_tab(index);
+        if opened {
+            crate::print_chat_header(&app.config, &app.relay, &app.chat).await;
+        }
+        if save && !update_lists(app, &app.chat.clone(), true).await {
+            ui::print(format!("{} is pinned already.", npub));
+        }
+    }.boxed_local()
+}
+
+/// User `who` names: the author of a shown message, picked as ChatScreen::find_author does, or a public key.
+fn pick_user(app: &App, who: &str) -> Option<XOnlyPublicKey> {
+    // Shown messages first, as the id of one would pass for a hex public key
+    app.screen.find_author(who).or_else(|| profiles::parse_public_key(who))
+}
+
+fn follow<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    update_contacts(app, argument, true).boxed_local()
+}
+
+fn unfollow<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    update_contacts(app, argument, false).boxed_local()
+}
+
+/// Publishes the contact list with the user `who` names followed or unfollowed.
+async fn update_contacts(app: &mut App, who: &str, follow: bool) {
+    let public_key = match pick_user(app, who) {
+        Some(val) => val,
+        None => {
+            ui::print_error("Name someone by npub, hex public key, number of a message counted from the newest, or name shown.".to_string());
+            return;
+        }
+    };
+    let mut contacts = match app.contacts.clone() {
+        Some(val) => val,
+        None => {
+            ui::print_error("Your contact list couldn't be fetched at startup, so it isn't changed to not lose any contacts.".to_string());
+            return;
+        }
+    };
+    let npub = public_key.to_bech32().unwrap();
+    let changed = match follow {
+        true => contacts.follow(public_key),
+        false => contacts.unfollow(public_key),
+    };
+    if !changed {
+        ui::print(format!("You {} {} already.", if follow { "follow" } else { "don't follow" }, npub));
+        return;
+    }
+    let event = match contacts.to_event(&app.signer).await {
+        Ok(val) => val,
+        Err(why) => {
+            ui::print_error(format!("Couldn't sign the contact list: {}", why));
+            return;
+        }
+    };
+    let msgs = vec![Message::Text(ClientMessage::new_event(event).as_json())];
+    if !app.confirm(&msgs).await {
+        return;
+    }
+    if let Err(why) = app.send(msgs).await {
+        ui::print_error(format!("Couldn't publish the event: {}", why));
+        return;
+    }
+    app.contacts = Some(contacts);
+    if follow && !app.known_chats.iter().any(|known| known.get_id() == public_key.to_string()) {
+        app.known_chats.push(ChatType::PrivateChat(crate::new_private_chat(&app.config, &app.signer, public_key)));
+    }
+    ui::print(format!("{} {}", if follow { "Following" } else { "Unfollowed" }, npub.green()));
+}
+
+fn split<'a>(app: &'a mut App, _: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        app.screen.suspend();
+        let second_chat = match ui::quick_switcher(app.config.clone(), app.known_chats.clone(), app.monitor.snapshot()) {
+            Some(val) => val,
+            None => {
+                app.screen.resume();
+                return;
+            }
+        };
+
+        let (outgoing_tx, mut outgoing_rx) = tokio::sync::mpsc::unbounded_channel::<(usize, String)>();
+        let (mut siv, printers) = ui::split_view(&app.config, [app.chat.clone().get_name(), second_chat.clone().get_name()], outgoing_tx);
+        let mut split_chats = [app.chat.clone(), second_chat];
+        let mut split_writers = Vec::new();
+        let mut split_tasks: Vec<tokio::task::JoinHandle<()>> = Vec::new();
+        for (split_chat, printer) in split_chats.iter().zip(printers) {
+            let split_tab = Tab::new(split_chat.clone(), app.config.flood_limit.clone());
+            let printing_handler = crate::new_printing_handler(&app.config, printer, app.signer.public_key(), &split_tab, app.rules.clone());
+            let (split_connection, split_task) = match crate::connect_chat(&app.config.relays, split_chat, printing_handler, &app.extra_kinds).await {
+                Ok(val) => val,
+                Err(why) => {
+                    for split_task in split_tasks {
+                        split_task.abort();
+                    }
+                    app.screen.resume();
+                    app.tabs.activate(app.tabs.active, &app.screen);
+                    ui::print_error(format!("Couldn't connect the split view: {}", why));
+                    return;
+                }
+            };
+            split_writers.push(split_connection.writer);
+            split_tasks.push(split_task);
+        }
+
+        let signer = app.signer.clone();
+        let sending_task = tokio::spawn(async move {
+            while let Some((index, text)) = outgoing_rx.recv().await {
+                let msgs = match split_chats[index].message_from(text, &signer).await {
+                    Ok(val) => val,
+                    Err(why) => {
+                        ui::print_error(format!("Couldn't send the message: {}", why));
+                        continue;
+                    }
+                };
+                for msg in msgs {
+                    if let Err(why) = split_writers[index].send(msg).await {
+                        ui::print_error(format!("Couldn't send the message: {}", why));
+                        break;
+                    }
+                }
+            }
+        });
+
+        siv.run();
+        app.screen.resume();
+
+        sending_task.abort();
+        for split_task in split_tasks {
+            split_task.abort();
+        }
+        // The open tabs kept receiving meanwhile
+        app.tabs.activate(app.tabs.active, &app.screen);
+        ui::print(format!("Back to {}", app.chat.clone().get_name().green()));
+    }.boxed_local()
+}
+
+fn whois<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let public_key = match (argument, &app.chat) {
+            ("", ChatType::PrivateChat(private_chat)) => Some(private_chat.recipient_public_key),
+            _ => profiles::parse_public_key(argument),
+        };
+        let public_key = match public_key {
+            Some(val) => val,
+            None => {
+                ui::print_error("Usage: /whois <npub or hex public key>".to_string());
+                return;
+            }
+        };
+        let metadata = profiles::fetch_metadata(&app.relay, public_key).await;
+        ui::print(crate::get_avatar(&app.config, &public_key, metadata.as_ref()).await.to_string());
+        ui::print(profiles::get_profile_table(&public_key, metadata.as_ref()).to_string());
+        if let Some(identifier) = metadata.and_then(|metadata| metadata.nip05) {
+            match nip05::verify(&identifier, &public_key.to_string()).await {
+                Ok(true) => ui::print(format!("{} {}", "✓".green(), nip05::display(&identifier))),
+                Ok(false) => ui::print_error(format!("{} doesn't list this public key", nip05::display(&identifier))),
+                Err(why) => ui::print_error(format!("Couldn't verify {}: {}", identifier, why)),
+            }
+        }
+    }.boxed_local()
+}
+
+fn profile<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        if !argument.is_empty() && argument != "edit" {
+            ui::print_error("Usage: /profile [edit]".to_string());
+            return;
+        }
+        let public_key = app.signer.public_key();
+        // Edits start from the published content, so fields other clients set aren't lost
+        let event = match profiles::fetch_metadata_event(&app.config.relays, public_key).await {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(format!("Couldn't fetch your profile: {}", why));
+                return;
+            }
+        };
+        let content: Value = event.as_ref()
+            .and_then(|event| serde_json::from_str(event["content"].as_str()?).ok())
+            .unwrap_or(Value::Object(Default::default()));
+        if argument.is_empty() {
+            let metadata = Metadata::from_json(content.to_string()).ok().filter(|_| event.is_some());
+            ui::print(profiles::get_profile_table(&public_key, metadata.as_ref()).to_string());
+            return;
+        }
+
+        app.screen.suspend();
+        let edited = crate::editor(&serde_json::to_string_pretty(&content).unwrap());
+        app.screen.resume();
+        let edited: Value = match edited.map(|text| serde_json::from_str::<Value>(&text)) {
+            Ok(Ok(val)) if val.is_object() => val,
+            Ok(_) => {
+                ui::print_error("The profile has to stay a JSON object, nothing was published.".to_string());
+                return;
+            },
+            Err(why) => {
+                ui::print_error(format!("Couldn't open the editor: {}", why));
+                return;
+            }
+        };
+        if edited == content {
+            ui::print("Nothing changed.".to_string());
+            return;
+        }
+        let metadata = match Metadata::from_json(edited.to_string()) {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(format!("The profile has invalid fields, nothing was published: {}", why));
+                return;
+            }
+        };
+        let event = match app.signer.sign(EventBuilder::new(Kind::Metadata, edited.to_string(), &[])).await {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(format!("Couldn't sign the profile: {}", why));
+                return;
+            }
+        };
+        let msgs = vec![Message::Text(ClientMessage::new_event(event).as_json())];
+        if !app.confirm(&msgs).await {
+            return;
+        }
+        if let Err(why) = app.send(msgs).await {
+            ui::print_error(format!("Couldn't publish the event: {}", why));
+            return;
+        }
+        ui::print("Published your profile.".to_string());
+        ui::print(profiles::get_profile_table(&public_key, Some(&metadata)).to_string());
+    }.boxed_local()
+}
+
+fn compare_relays<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        match argument {
+            "" => {},
+            "probe" => {
+                // Relays we and our contacts use are candidates too
+                let mut authors = vec![app.signer.public_key().to_string()];
+                authors.extend(app.contacts.iter().flat_map(|contacts| contacts.public_keys()).map(|public_key| public_key.to_string()));
+                let discovered = relays::discover(&app.relay, authors, &app.config.relays, MAX_DISCOVERED_RELAYS).await;
+                ui::print(format!("Probing {} configured and {} discovered relays...", app.config.relays.len(), discovered.len()));
+                ui::print(relays::latency_report(&app.config.relays, &discovered).await.to_string());
+                return;
+            },
+            _ => {
+                ui::print_error("Usage: /relays [probe]".to_string());
+                return;
+            }
+        }
+        ui::print(format!("Fetching history from {} relays...", app.config.relays.len()));
+        ui::print(relays::history_report(&app.config.relays, app.chat.build_request_message(&app.extra_kinds)).await.to_string());
+        ui::print(relays::traffic_report().to_string());
+    }.boxed_local()
+}
+
+fn compare<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        if !argument.starts_with("wss://") && !argument.starts_with("ws://") {
+            ui::print_error("Usage: /compare <relay>, with a relay url starting with wss:// or ws://".to_string());
+            return;
+        }
+        if argument == app.relay {
+            ui::print_error(format!("{} is the current relay already.", argument));
+            return;
+        }
+        ui::print(format!("Fetching history from {} and {}...", app.relay, argument));
+        ui::print(relays::compare_report(&app.relay, argument, app.chat.build_request_message(&app.extra_kinds)).await);
+    }.boxed_local()
+}
+
+fn stats<'a>(_: &'a mut App, _: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        ui::print(relays::traffic_report().to_string());
+    }.boxed_local()
+}
+
+fn receipts<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        if let ChatType::PublicChannel(_) = app.chat {
+            ui::print_error("Receipts are only kept in private chats.".to_string());
+            return;
+        }
+        let count = match argument {
+            "" => DEFAULT_RECEIPTS,
+            _ => match argument.parse::<usize>() {
+                Ok(val) if val > 0 => val,
+                _ => {
+                    ui::print_error("Usage: /receipts [n]".to_string());
+                    return;
+                }
+            },
+        };
+        let latest = receipts::latest(&app.chat.get_id(), count);
+        if latest.is_empty() {
+            ui::print("You haven't sent anything in this chat yet.".to_string());
+            return;
+        }
+        for (id, receipt) in latest.iter().rev() {
+            let message = app.screen.find_message(id)
+                .and_then(|event| event["content"].as_str().map(|content| content.chars().take(MAX_RECEIPT_PREVIEW).collect::<String>()))
+                .unwrap_or(format!("Message {}", &id[.. 8]));
+            ui::print(format!("{} {}", message, receipts::glyph(id).unwrap_or_default()));
+            let steps: Vec<String> = receipt.timeline.iter().map(|(state, at)| {
+                let time = chrono::DateTime::from_timestamp(*at as i64, 0).map(|time| time.with_timezone(&chrono::Local).format("%H:%M:%S").to_string()).unwrap_or_default();
+                match state {
+                    ReceiptState::Accepted => format!("accepted {} ({} relays)", time, receipt.accepted_by),
+                    state => format!("{} {}", format!("{:?}", state).to_lowercase(), time),
+                }
+            }).collect();
+            ui::print(format!("  {}", steps.join(" → ")).truecolor(128, 128, 128).to_string());
+        }
+    }.boxed_local()
+}
+
+fn outbox<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let entries = outbox::entries();
+        if argument.is_empty() {
+            if entries.is_empty() {
+                ui::print("Every relay accepted what you sent.".to_string());
+            }
+            let names: HashMap<String, String> = app.known_chats.iter().map(|known| (known.get_id(), known.clone().get_name())).collect();
+            for (number, entry) in entries.iter().enumerate() {
+                let preview = match &entry.text {
+                    Some(text) => text.chars().take(MAX_RECEIPT_PREVIEW).collect(),
+                    None => format!("Kind {} event {}", entry.event["kind"], &entry.event["id"].as_str().unwrap_or_default()[.. 8]),
+                };
+                let chat = names.get(&entry.chat_id).cloned().unwrap_or(entry.chat_id.chars().take(8).collect());
+                ui::print(format!("{}. {} {}", number + 1, chat.green(), preview));
+                let mut states: Vec<String> = Vec::new();
+                if !entry.pending.is_empty() {
+                    states.push(format!("waiting for {}", entry.pending.iter().cloned().collect::<Vec<String>>().join(", ")));
+                }
+                for (relay, reason) in &entry.rejected {
+                    states.push(format!("rejected by {}: {}", relay, outbox::explain_rejection(reason)).red().to_string());
+                }
+                if !entry.accepted.is_empty() {
+                    states.push(format!("accepted by {}", entry.accepted.len()));
+                }
+                ui::print(format!("  {}", states.join("; ")).truecolor(128, 128, 128).to_string());
+            }
+            return;
+        }
+        let (action, number) = argument.split_once(' ').unwrap_or((argument, ""));
+        let entry = match number.trim().parse::<usize>().ok().and_then(|number| entries.get(number.checked_sub(1)?)) {
+            Some(val) if matches!(action, "retry" | "edit" | "discard") => val.clone(),
+            _ => {
+                ui::print_error("Usage: /outbox [retry|edit|discard <n>], n as listed by /outbox".to_string());
+                return;
+            }
+        };
+        // Any connection will do when the chat isn't open, they all go out to the relays of the config
+        let chat_id = match app.connections.lock().await.contains_key(&entry.chat_id) {
+            true => entry.chat_id.clone(),
+            false => app.chat.get_id(),
+        };
+        match action {
+            "retry" => {
+                match app.send_to(&chat_id, vec![entry.message()]).await {
+                    Ok(_) => ui::print("Sent it again.".to_string()),
+                    Err(why) => ui::print_error(format!("Couldn't send it again: {}", why)),
+                }
+            },
+            "discard" => {
+                outbox::discard(entry.event["id"].as_str().unwrap_or_default());
+                match entry.accepted.is_empty() {
+                    true => ui::print("Dropped it.".to_string()),
+                    false => ui::print(format!("Dropped it. The {} relays that accepted it keep it, /delete asks them to remove it.", entry.accepted.len())),
+                }
+            },
+            _ => {
+                let text = match &entry.text {
+                    Some(val) => val.clone(),
+                    None => {
+                        ui::print_error("Only typed messages can be edited, retry or discard other events.".to_string());
+                        return;
+                    }
+                };
+                let mut draft = match app.tabs.find(&entry.chat_id) {
+                    Some(index) => app.tabs.tabs[index].chat.draft(),
+                    None => {
+                        ui::print_error("Open the chat it was sent in first.".to_string());
+                        return;
+                    }
+                };
+                app.screen.suspend();
+                let edited = crate::editor(&text);
+                app.screen.resume();
+                let edited = match edited {
+                    Ok(val) if !val.trim().is_empty() => val,
+                    Ok(_) => return,
+                    Err(why) => {
+                        ui::print_error(format!("Couldn't open the editor: {}", why));
+                        return;
+                    }
+                };
+                let msgs = match draft.message_from(edited.clone(), &app.signer).await {
+                    Ok(val) => val,
+                    Err(why) => {
+                        ui::print_error(format!("Couldn't send the message: {}", why));
+                        return;
+                    }
+                };
+                if !app.confirm(&msgs).await || !app.hold_send().await {
+                    return;
+                }
+                outbox::discard(entry.event["id"].as_str().unwrap_or_default());
+                if let Some(index) = app.tabs.find(&entry.chat_id) {
+                    app.tabs.tabs[index].chat.keep_draft(draft);
+                    if index == app.tabs.active {
+                        app.chat = app.tabs.tabs[index].chat.clone();
+                    }
+                }
+                if let Err(why) = app.send_to(&entry.chat_id, msgs.clone()).await {
+                    ui::print_error(format!("Couldn't send the edited message: {}", why));
+                    return;
+                }
+                outbox::attach_text(&msgs, &edited);
+                ui::print("Sent the edited message.".to_string());
+            },
+        }
+    }.boxed_local()
+}
+
+fn relay_info<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let words: Vec<&str> = argument.split_whitespace().collect();
+        let (relay, pay) = match words.as_slice() {
+            [] => (app.relay.clone(), false),
+            ["pay"] => (app.relay.clone(), true),
+            [relay] => (relay.to_string(), false),
+            [relay, "pay"] => (relay.to_string(), true),
+            _ => (String::new(), false),
+        };
+        if !relay.starts_with("wss://") && !relay.starts_with("ws://") {
+            ui::print_error("Usage: /relayinfo [wss://relay] [pay]".to_string());
+            return;
+        }
+        let info = match nip11::fetch(&relay).await {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(format!("Couldn't get the information of {}: {}", relay, why));
+                return;
+            }
+        };
+        if !pay {
+            ui::print(nip11::info_report(&relay, &info).join("\n"));
+            return;
+        }
+        match info["payments_url"].as_str() {
+            Some(url) => match open::that(url) {
+                Ok(()) => ui::print(format!("Opened {} to pay {}", url, relay)),
+                Err(why) => ui::print_error(format!("Couldn't open {}: {}", url, why)),
+            },
+            None => ui::print_error(format!("{} doesn't say where to pay it.", relay)),
+        }
+    }.boxed_local()
+}
+
+fn activity<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        if !argument.is_empty() && argument != "list" {
+            ui::print_error("Usage: /activity [list]".to_string());
+            return;
+        }
+        let mut feed = app.monitor.activity_feed();
+        if feed.is_empty() {
+            ui::print("No mentions, replies, reactions or zaps yet.".to_string());
+            return;
+        }
+        feed.sort_by_key(|entry| std::cmp::Reverse(entry.created_at));
+        let lines: Vec<String> = feed.iter().map(|entry| {
+            let time = chrono::DateTime::from_timestamp(entry.created_at.as_i64(), 0).map(|time| time.with_timezone(&chrono::Local).format("%H:%M").to_string()).unwrap_or_default();
+            format!("{} [{}] {} {}", time.truecolor(128, 128, 128), entry.label.yellow(), names::display_name(&entry.author.to_string()).green(), entry.summary)
+        }).collect();
+        if argument == "list" {
+            for line in lines.into_iter().rev() {
+                ui::print(line);
+            }
+            return;
+        }
+
+        app.screen.suspend();
+        let picked = ui::activity_pane(app.config.clone(), lines.clone());
+        app.screen.resume();
+        if let Some(index) = picked {
+            jump_to_activity(app, &feed[index], &lines[index]).await;
+        }
+    }.boxed_local()
+}
+
+fn chats_list<'a>(app: &'a mut App, _: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let activity = app.monitor.snapshot();
+        let public_key = app.signer.public_key().to_string();
+        let active_id = app.chat.get_id();
+        let mut chats: Vec<(String, usize, usize)> = app.known_chats.iter().map(|chat| {
+            let chat_id = chat.get_id();
+            let label = match chat {
+                ChatType::PublicChannel(channel) => format!("# {}", channel.clone().get_name()),
+                ChatType::PrivateChat(private_chat) => format!("@ {}", private_chat.clone().get_label()),
+            };
+            // Open chats store what they receive, the others are only watched for new messages this session
+            let (unread, mentions) = match (chat_id == active_id, app.tabs.find(&chat_id)) {
+                (true, _) => (0, 0),
+                (false, Some(_)) => (storage::unread(&chat_id, &public_key), 0),
+                (false, None) => {
+                    let watched = activity.get(&chat_id).cloned().unwrap_or_default();
+                    (storage::unread(&chat_id, &public_key) + watched.unread, watched.mentions)
+                },
+            };
+            let label = match app.tabs.find(&chat_id) {
+                Some(_) => format!("{} {}", label, "(open)".truecolor(128, 128, 128)),
+                None => label,
+            };
+            (label, unread, mentions)
+        }).collect();
+        chats.sort_by_key(|(_, unread, _)| std::cmp::Reverse(*unread));
+        for (label, unread, mentions) in chats {
+            let badge = match (unread, mentions) {
+                (0, _) => String::new(),
+                (unread, 0) => format!(" {} unread", unread).yellow().to_string(),
+                (unread, mentions) => format!(" {} unread, {} mentions", unread, mentions).yellow().to_string(),
+            };
+            ui::print(format!("{}{}", label, badge));
+        }
+    }.boxed_local()
+}
+
+fn watching<'a>(app: &'a mut App, _: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        if app.config.watch.is_empty() {
+            ui::print_error("Nobody is watched, add their npubs to watch in config.toml.".to_string());
+            return;
+        }
+        let lines = watch::feed();
+        if lines.is_empty() {
+            ui::print("Nothing from the watched accounts yet.".to_string());
+            return;
+        }
+        app.screen.suspend();
+        ui::text_overlay(app.config.clone(), "Watching", lines);
+        app.screen.resume();
+    }.boxed_local()
+}
+
+fn tag<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let hashtag = match chats::parse_hashtags(&format!("#{}", argument.trim_start_matches('#'))).into_iter().next() {
+            Some(val) => val,
+            None => {
+                ui::print_error(format!("{} isn't a hashtag.", argument));
+                return;
+            }
+        };
+        let filter = Filter::new().hashtag(&hashtag).limit(MAX_TAGGED_EVENTS);
+        let mut events = fetch_events(&app.config.relays, filter).await;
+        if events.is_empty() {
+            ui::print(format!("No events with #{} on the connected relays.", hashtag));
+            return;
+        }
+        events.truncate(MAX_TAGGED_EVENTS);
+        let lines: Vec<String> = events.iter().map(event_line).collect();
+        app.screen.suspend();
+        ui::text_overlay(app.config.clone(), &format!("#{}, {} recent events", hashtag, lines.len()), lines);
+        app.screen.resume();
+    }.boxed_local()
+}
+
+fn filter_builder<'a>(app: &'a mut App, _: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        app.screen.suspend();
+        let filter = ui::filter_builder(app.config.clone());
+        app.screen.resume();
+        let filter = match filter {
+            Some(val) => val,
+            None => return,
+        };
+        let json = filter.as_json();
+        ui::print(format!("[\"REQ\", \"...\", {}]", json).truecolor(128, 128, 128).to_string());
+        let mut events = fetch_events(&app.config.relays, filter).await;
+        if events.is_empty() {
+            ui::print(format!("No events match {} on the connected relays.", json));
+            return;
+        }
+        events.truncate(MAX_FILTERED_EVENTS);
+        let lines: Vec<String> = events.iter().map(event_line).collect();
+        app.screen.suspend();
+        ui::text_overlay(app.config.clone(), &format!("{} events matching {}", lines.len(), json), lines);
+        app.screen.resume();
+    }.boxed_local()
+}
+
+fn tail<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let (filter, duration) = match argument.rsplit_once(char::is_whitespace) {
+            Some(val) => val,
+            None => {
+                ui::print_error("Usage: /tail <filter> <duration>".to_string());
+                return;
+            }
+        };
+        let mut filter = match Filter::from_json(filter.trim()) {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(format!("{} isn't a REQ filter: {}", filter.trim(), why));
+                return;
+            }
+        };
+        let duration = match filters::parse_duration(duration) {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(why);
+                return;
+            }
+        };
+        // Without a since or limit relays would send everything they have first
+        if filter.since.is_none() && filter.limit.is_none() {
+            filter = filter.since(Timestamp::now());
+        }
+        tokio::spawn(tail::run(app.config.relays.clone(), filter, duration));
+    }.boxed_local()
+}
+
+/// Authentic events matching `filter` stored on `relays`, without duplicates and newest first.
+async fn fetch_events(relays: &[String], filter: Filter) -> Vec<Value> {
+    let req = Message::Text(ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json());
+    let results = join_all(relays.iter().map(|relay| relays::fetch_stored_events(relay, req.clone()))).await;
+    let mut events: Vec<Value> = Vec::new();
+    for event in results.into_iter().filter_map(|result| result.ok()).flatten() {
+        if !events.iter().any(|known| known["id"] == event["id"]) && export::verify_event(&event).is_ok() {
+            events.push(event);
+        }
+    }
+    events.sort_by_key(|event| std::cmp::Reverse(event["created_at"].as_u64().unwrap_or_default()));
+    events
+}
+
+/// One line of a list of events: when, of which kind, by whom and the first line of the content.
+pub fn event_line(event: &Value) -> String {
+    let time = chrono::DateTime::from_timestamp(event["created_at"].as_i64().unwrap_or_default(), 0).map(|time| time.with_timezone(&chrono::Local).format("%d.%m. %H:%M").to_string()).unwrap_or_default();
+    let content = event["content"].as_str().unwrap_or_default().lines().next().unwrap_or_default().to_string();
+    format!("{} {} {}: {}", time.truecolor(128, 128, 128), format!("[{}]", relays::kind_name(event["kind"].as_u64().unwrap_or_default())).truecolor(128, 128, 128), names::display_name(event["pubkey"].as_str().unwrap_or_default()).green(), content)
+}
+
+/// Shows the chat `entry` happened in, opening it if needed, with `line` and the message it responds to.
+async fn jump_to_activity(app: &mut App, entry: &ActivityEntry, line: &str) {
+    // Reactions and zaps don't name the chat, the message they respond to does
+    let target = match entry.target {
+        Some(target) => {
+            let filter = Filter::new().id(target.to_hex());
+            let req = Message::Text(ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json());
+            relays::fetch_stored_events(&app.relay, req).await.ok().and_then(|events| events.into_iter().next())
+        },
+        None => None,
+    };
+    let chat_id = entry.chat_id.clone().or_else(|| {
+        let target = target.as_ref()?;
+        match target["kind"].as_u64()? {
+            42 => target["tags"].as_array()?.iter().find(|tag| tag[0] == "e").and_then(|tag| tag[1].as_str()).map(str::to_string),
+            _ => target["tags"].as_array()?.iter().find(|tag| tag[0] == "p").and_then(|tag| tag[1].as_str()).map(str::to_string),
+        }
+    });
+
+    let index = match chat_id {
+        Some(chat_id) => match app.tabs.find(&chat_id) {
+            Some(index) => Some(index),
+            None => {
+                let chat = match app.known_chats.iter().find(|known| known.get_id() == chat_id) {
+                    Some(known) => Some(known.clone()),
+                    None => match (EventId::from_hex(&chat_id), profiles::parse_public_key(&chat_id)) {
+                        (Ok(event_id), _) if target.as_ref().is_none_or(|target| target["kind"] == 42) => {
+                            crate::fetch_channel(&app.config.relays, event_id).await.ok().map(ChatType::PublicChannel)
+                        },
+                        (_, Some(contact)) => Some(ChatType::PrivateChat(crate::new_private_chat(&app.config, &app.signer, contact))),
+                        _ => None,
+                    },
+                };
+                match chat {
+                    Some(chat) => match app.open_tab(chat).await {
+                        Ok(index) => Some(index),
+                        Err(why) => {
+                            ui::print_error(format!("Couldn't open the chat: {}", why));
+                            return;
+                        }
+                    },
+                    None => None,
+                }
+            }
+        },
+        None => None,
+    };
+    match index {
+        Some(index) => app.show_tab(index),
+        None => ui::print_error("Couldn't find the chat this happened in.".to_string()),
+    }
+    ui::print(line.to_string());
+    // Direct messages would only show their ciphertext
+    if let Some(content) = target.as_ref().filter(|target| target["kind"] == 42).and_then(|target| target["content"].as_str()) {
+        ui::print(format!("  {} {}", "your message:".truecolor(128, 128, 128), content));
+    }
+}
+
+fn dm_mode<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let mut private_chat = match &app.chat {
+            ChatType::PrivateChat(val) => val.clone(),
+            ChatType::PublicChannel(_) => {
+                ui::print_error("Only private chats have a DM mode.".to_string());
+                return;
+            }
+        };
+        if argument == "auto" {
+            // Detect again, even if the mode is configured
+            app.detected_dm_modes.remove(&private_chat.recipient_public_key.to_bech32().unwrap());
+            app.chat = ChatType::PrivateChat(private_chat);
+            crate::apply_detected_dm_mode(&app.config, &app.relay, &mut app.chat, &mut app.detected_dm_modes, true).await;
+        } else {
+            private_chat.mode = match argument.parse::<DmMode>() {
+                Ok(val) => val,
+                Err(why) => {
+                    ui::print_error(why.to_string());
+                    return;
+                }
+            };
+            app.chat = ChatType::PrivateChat(private_chat);
+        }
+        crate::start_ratchet_session(&app.relay, &mut app.chat).await;
+        let chat = app.chat.clone();
+        if let Some(known) = app.known_chats.iter_mut().find(|known| known.get_id() == chat.get_id()) {
+            *known = chat.clone();
+        }
+        // The mode decides which events make up the chat, so it is subscribed to again
+        let index = app.tabs.active;
+        app.tabs.tabs[index].chat = chat.clone();
+        app.tabs.reset(index, app.config.flood_limit.clone());
+        if let Err(why) = app.subscribe(index).await {
+            ui::print_error(format!("Couldn't subscribe to the chat again: {}", why));
+        }
+        app.tabs.activate(index, &app.screen);
+        app.update_status();
+        if let ChatType::PrivateChat(private_chat) = &chat {
+            ui::print(format!("Private chat now uses {:?} mode.", private_chat.mode));
+        }
+    }.boxed_local()
+}
+
+fn kinds<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let mut hidden = app.tabs.active().hidden_kinds.lock().unwrap();
+        match argument {
+            "" => {
+                if app.config.kind_handlers.is_empty() {
+                    ui::print("No additional kinds are configured, see kind_handlers in config.toml.".to_string());
+                }
+                for handler in app.config.kind_handlers.iter() {
+                    let state = if hidden.contains(&handler.kind) { "hidden".red() } else { "shown".green() };
+                    ui::print(format!("{} {} {}", handler.kind, state, handler.template.truecolor(128, 128, 128)));
+                }
+                return;
+            },
+            "on" => {
+                hidden.clear();
+                ui::print("All additional kinds shown in this chat.".to_string());
+            },
+            "off" => {
+                hidden.extend(app.config.kind_handlers.iter().map(|handler| handler.kind));
+                ui::print("All additional kinds hidden in this chat.".to_string());
+            },
+            _ => {
+                let kind = match argument.parse::<u64>() {
+                    Ok(val) => val,
+                    Err(_) => {
+                        ui::print_error("Usage: /kinds [kind|on|off]".to_string());
+                        return;
+                    }
+                };
+                if !hidden.remove(&kind) {
+                    hidden.insert(kind);
+                }
+                ui::print(format!("Kind {} {} in this chat.", kind, if hidden.contains(&kind) { "hidden" } else { "shown" }));
+            }
+        }
+        chats::save_hidden_kinds(&app.chat.get_id(), &hidden);
+    }.boxed_local()
+}
+
+fn expand<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let lines = app.tabs.active().flood_control.lock().unwrap().expand(argument);
+        if lines.is_empty() {
+            ui::print("No collapsed messages.".to_string());
+        }
+        for line in lines {
+            ui::print(line.to_string());
+        }
+    }.boxed_local()
+}
+
+fn export_history<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let path = match argument {
+            "" => PathBuf::from(format!("nostrachat-export-{}-{}.json", &app.chat.get_id()[.. 8], Timestamp::now().as_u64())),
+            _ => PathBuf::from(argument),
+        };
+        ui::print(format!("Fetching history from {} relays...", app.config.relays.len()));
+        match export::export_chat(&app.config.relays, &app.chat, &app.extra_kinds, &path).await {
+            Ok(report) => ui::print(report.to_string()),
+            Err(why) => ui::print_error(format!("Couldn't export chat: {}", why)),
+        }
+    }.boxed_local()
+}
+
+fn export_key<'a>(app: &'a mut App, _: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let secret_key = match app.signer.secret_key() {
+            Some(val) => val,
+            None => {
+                ui::print_error("Your key is kept by your remote signer, export it from there.".to_string());
+                return;
+            }
+        };
+        app.screen.suspend();
+        let passphrase = ui::read_secret("Passphrase to encrypt your key with:");
+        let repeated = match passphrase.is_empty() {
+            true => String::new(),
+            false => ui::read_secret("Repeat it:"),
+        };
+        app.screen.resume();
+        if passphrase.is_empty() {
+            ui::print_error("Encrypting your key needs a passphrase.".to_string());
+            return;
+        }
+        if passphrase != repeated {
+            ui::print_error("The passphrases don't match.".to_string());
+            return;
+        }
+        // A key kept in plain text may have leaked before it was encrypted
+        let security = match app.config.privkey.starts_with(nip49::HRP) {
+            true => nip49::KeySecurity::Secure,
+            false => nip49::KeySecurity::Insecure,
+        };
+        let encrypted = tokio::task::spawn_blocking(move || nip49::encrypt(&secret_key, &passphrase, nip49::DEFAULT_LOG_N, security)).await.unwrap();
+        match encrypted {
+            Ok(encrypted) => {
+                ui::print(format!("Your encrypted key: {}", encrypted.yellow()));
+                ui::print("Use it as privkey in config.toml to be asked for the passphrase on start instead of keeping the nsec there.".to_string());
+            },
+            Err(why) => ui::print_error(format!("Couldn't encrypt your key: {}", why)),
+        }
+    }.boxed_local()
+}
+
+fn resync<'a>(app: &'a mut App, _: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let private_chat = match &app.chat {
+            ChatType::PrivateChat(val) if val.mode == DmMode::Ratchet => val.clone(),
+            _ => {
+                ui::print_error("Only private chats in ratchet mode have a session to start again.".to_string());
+                return;
+            }
+        };
+        match profiles::fetch_prekey(&app.relay, private_chat.recipient_public_key).await {
+            // Every copy of the chat shares the ratchet, so they all continue in the new session
+            Some(prekey) => {
+                private_chat.ratchet_profile.clone().initiate_session(prekey);
+                ui::print(format!("Started a new session with {}, your next message tells them about it.", private_chat.name));
+            },
+            None => ui::print_error(format!("{} hasn't published a prekey, so a new session can't be started until they open nostrachat again.", private_chat.name)),
+        }
+    }.boxed_local()
+}
+
+fn export_session<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let ratchet_profile = match &app.chat {
+            ChatType::PrivateChat(private_chat) => private_chat.ratchet_profile.clone(),
+            ChatType::PublicChannel(_) => {
+                ui::print_error("Only private chats have a session to export.".to_string());
+                return;
+            }
+        };
+        let path = match argument {
+            "" => PathBuf::from(format!("nostrachat-session-{}.session", &app.chat.get_id()[.. 8])),
+            _ => PathBuf::from(argument),
+        };
+        app.screen.suspend();
+        let passphrase = ui::read_secret("Passphrase to encrypt the session with:");
+        let repeated = match passphrase.is_empty() {
+            true => String::new(),
+            false => ui::read_secret("Repeat it:"),
+        };
+        app.screen.resume();
+        if passphrase.is_empty() {
+            ui::print_error("Exporting a session needs a passphrase.".to_string());
+            return;
+        }
+        if passphrase != repeated {
+            ui::print_error("The passphrases don't match.".to_string());
+            return;
+        }
+        let exported = tokio::task::spawn_blocking(move || ratchet_profile.export_session(&passphrase)).await.unwrap();
+        match exported.and_then(|exported| fs::write(&path, exported).map_err(|why| why.to_string())) {
+            Ok(()) => {
+                ui::print(format!("Session exported to {}", path.display().to_string().yellow()));
+                ui::print("Run /import-session with it in this chat on the other device, and don't send here until then, or the two will drift apart.".to_string());
+            },
+            Err(why) => ui::print_error(format!("Couldn't export the session: {}", why)),
+        }
+    }.boxed_local()
+}
+
+fn import_session<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let ratchet_profile = match &app.chat {
+            ChatType::PrivateChat(private_chat) => private_chat.ratchet_profile.clone(),
+            ChatType::PublicChannel(_) => {
+                ui::print_error("Sessions can only be imported into private chats.".to_string());
+                return;
+            }
+        };
+        let exported = match fs::read(argument) {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(format!("Couldn't read {}: {}", argument, why));
+                return;
+            }
+        };
+        app.screen.suspend();
+        let passphrase = ui::read_secret("Passphrase the session was encrypted with:");
+        app.screen.resume();
+        match tokio::task::spawn_blocking(move || ratchet_profile.import_session(&exported, &passphrase)).await.unwrap() {
+            Ok(()) => ui::print(format!("Continuing the session with {} from the other device.", app.chat.clone().get_name())),
+            Err(why) => ui::print_error(format!("Couldn't import the session: {}", why)),
+        }
+    }.boxed_local()
+}
+
+fn firehose<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let firehose_relay = if argument.is_empty() { app.relay.as_str() } else { argument };
+        ui::print(format!("Listening to everything on {}...", firehose_relay.green()));
+        match relays::firehose(firehose_relay).await {
+            Ok(summary) => ui::print(summary.to_string()),
+            Err(why) => ui::print_error(format!("Couldn't connect to {}: {}", firehose_relay, why)),
+        }
+    }.boxed_local()
+}
+
+fn manage_cache<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        match argument {
+            "stats" => {
+                let names: HashMap<String, String> = app.known_chats.iter().map(|known| (known.get_id(), known.clone().get_name())).collect();
+                ui::print(cache::stats(&names).to_string());
+            },
+            "prune" => {
+                let (events, bytes) = cache::prune(&app.config.retention.clone().unwrap_or_default());
+                ui::print(format!("Pruned {} events ({})", events, relays::format_bytes(bytes)));
+            },
+            _ => ui::print_error("Usage: /cache stats|prune".to_string()),
+        }
+    }.boxed_local()
+}
+
+fn rebroadcast<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let (scope, target) = match argument.split_once(' ') {
+            Some((scope, target)) if scope.starts_with("--") => (scope, target.trim()),
+            _ => ("--mine", argument),
+        };
+        if target.is_empty() || !matches!(scope, "--mine" | "--chat") {
+            ui::print_error("Usage: /rebroadcast [--chat|--mine] <relay>".to_string());
+            return;
+        }
+        let events: Vec<Value> = match scope {
+            "--chat" => ChatStore::open(&app.chat.get_id()).events(&Filter::new()),
+            _ => storage::all_events().into_iter().filter(|event| event["pubkey"] == app.signer.public_key().to_string().as_str()).collect(),
+        };
+        if events.is_empty() {
+            ui::print("No cached events to rebroadcast.".to_string());
+            return;
+        }
+        ui::print(format!("Publishing {} events to {}...", events.len(), target.green()));
+        match relays::publish_events(target, &events).await {
+            Ok((accepted, rejected)) => {
+                ui::print(format!("{} accepted, {} rejected", accepted, rejected.len()));
+                for (id, why) in rejected {
+                    ui::print(format!("{} {}", id.red(), why));
+                }
+            },
+            Err(why) => ui::print_error(format!("Couldn't rebroadcast to {}: {}", target, why)),
+        }
+    }.boxed_local()
+}
+
+fn reply<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let (parent, text) = match crate::picked_message(&app.screen, argument) {
+            Some((parent, text)) if !text.is_empty() => (parent, text),
+            _ => {
+                ui::print_error("Usage: /reply <message id> <text>, or pick a message with Esc".to_string());
+                return;
+            }
+        };
+        let mut draft = app.chat.draft();
+        let msgs = match draft.reply_from(text.to_string(), &parent, &app.signer).await {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(format!("Couldn't send the reply: {}", why));
+                return;
+            }
+        };
+        if !app.confirm(&msgs).await {
+            return;
+        }
+        app.chat.keep_draft(draft);
+        if let Err(why) = app.send(msgs).await {
+            ui::print_error(format!("Couldn't send the reply: {}", why));
+        }
+    }.boxed_local()
+}
+
+fn react<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let (parent, reaction) = match crate::picked_message(&app.screen, argument) {
+            Some(val) => val,
+            None => {
+                ui::print_error("Usage: /react <message id> [reaction], or pick a message with Esc".to_string());
+                return;
+            }
+        };
+        // A reaction is a plain public event, so it would reveal who a private chat is with
+        if let ChatType::PrivateChat(_) = app.chat {
+            ui::print_error("Reactions can only be sent in channels.".to_string());
+            return;
+        }
+        let event_id = EventId::from_hex(parent["id"].as_str().unwrap_or_default()).unwrap();
+        let author = profiles::parse_public_key(parent["pubkey"].as_str().unwrap_or_default()).unwrap();
+        let reaction = if reaction.is_empty() { "+" } else { reaction };
+        let event = match app.signer.sign(EventBuilder::new_reaction(event_id, author, reaction)).await {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(format!("Couldn't sign the reaction: {}", why));
+                return;
+            }
+        };
+        let msgs = vec![Message::Text(ClientMessage::new_event(event).as_json())];
+        if !app.confirm(&msgs).await {
+            return;
+        }
+        if let Err(why) = app.send(msgs).await {
+            ui::print_error(format!("Couldn't publish the event: {}", why));
+            return;
+        }
+        ui::print(format!("Reacted with {}", reaction));
+    }.boxed_local()
+}
+
+fn raw<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        match crate::picked_message(&app.screen, argument) {
+            Some((event, _)) => ui::print(serde_json::to_string_pretty(&event).unwrap()),
+            None => ui::print_error("Usage: /raw <message id>, or pick a message with Esc".to_string()),
+        }
+    }.boxed_local()
+}
+
+fn zap<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let author = match crate::picked_message(&app.screen, argument).and_then(|(event, _)| profiles::parse_public_key(event["pubkey"].as_str().unwrap_or_default())) {
+            Some(val) => val,
+            None => {
+                ui::print_error("Usage: /zap <message id>, or pick a message with Esc".to_string());
+                return;
+            }
+        };
+        let metadata = profiles::fetch_metadata(&app.relay, author).await;
+        let name = metadata.as_ref().and_then(|metadata| metadata.display_name.clone().or(metadata.name.clone())).unwrap_or(author.to_bech32().unwrap()[.. 12].to_string());
+        // Paying is left to the wallet that handles lightning: links
+        match metadata.and_then(|metadata| metadata.lud16.or(metadata.lud06)) {
+            Some(address) => {
+                ui::print(format!("Opening your wallet to zap {} at {}", name.green(), address));
+                if let Err(why) = open::that(format!("lightning:{}", address)) {
+                    ui::print_error(format!("Couldn't open a lightning wallet: {}", why));
+                }
+            },
+            None => ui::print_error(format!("{} hasn't set a lightning address.", name)),
+        }
+    }.boxed_local()
+}
+
+fn delete<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let event = match crate::picked_message(&app.screen, argument) {
+            Some((event, _)) => event,
+            None => {
+                ui::print_error("Usage: /delete <message id>, or pick a message with Esc".to_string());
+                return;
+            }
+        };
+        if event["pubkey"].as_str() != Some(&app.signer.public_key().to_string()) {
+            ui::print_error("Only messages published with your own key can be deleted.".to_string());
+            return;
+        }
+        let event_id = EventId::from_hex(event["id"].as_str().unwrap_or_default()).unwrap();
+        let deletion = match app.signer.sign(EventBuilder::delete(vec![event_id], None::<String>)).await {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(format!("Couldn't sign the deletion: {}", why));
+                return;
+            }
+        };
+        let msgs = vec![Message::Text(ClientMessage::new_event(deletion).as_json())];
+        if !app.confirm(&msgs).await {
+            return;
+        }
+        if let Err(why) = app.send(msgs).await {
+            ui::print_error(format!("Couldn't publish the event: {}", why));
+            return;
+        }
+        ui::print("Asked the relays to delete the message.".to_string());
+    }.boxed_local()
+}
+
+fn hide<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let (event, reason) = match crate::picked_message(&app.screen, argument) {
+            Some(val) => val,
+            None => {
+                ui::print_error("Usage: /hide <message id> [reason], or pick a message with Esc".to_string());
+                return;
+            }
+        };
+        let event_id = match EventId::from_hex(event["id"].as_str().unwrap_or_default()) {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(format!("The message has an invalid id: {}", why));
+                return;
+            }
+        };
+        if publish_moderation(app, moderation::HIDE_MESSAGE_KIND, Tag::Event(event_id, None, None), reason).await {
+            ui::print("The message is hidden from now on.".to_string());
+        }
+    }.boxed_local()
+}
+
+fn mute<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let (who, reason) = argument.split_once(' ').unwrap_or((argument, ""));
+        let public_key = match pick_user(app, who) {
+            Some(val) => val,
+            None => {
+                ui::print_error("Name someone by npub, hex public key, number of a message counted from the newest, or name shown.".to_string());
+                return;
+            }
+        };
+        if public_key == app.signer.public_key() {
+            ui::print_error("You can't mute yourself.".to_string());
+            return;
+        }
+        if publish_moderation(app, moderation::MUTE_USER_KIND, Tag::PubKey(public_key, None), reason.trim()).await {
+            ui::print(format!("The messages of {} are hidden from now on.", names::display_name(&public_key.to_string())));
+        }
+    }.boxed_local()
+}
+
+/// Publishes the NIP-28 moderation event of `kind` for `target` with `reason`, and applies it right away.
+/// Returns whether it was published.
+async fn publish_moderation(app: &mut App, kind: u64, target: Tag, reason: &str) -> bool {
+    if let ChatType::PrivateChat(_) = app.chat {
+        ui::print_error("Messages and users can only be hidden in channels.".to_string());
+        return false;
+    }
+    let content = serde_json::json!({ "reason": reason }).to_string();
+    let event = match app.signer.sign(EventBuilder::new(Kind::Custom(kind), content, &[target])).await {
+        Ok(val) => val,
+        Err(why) => {
+            ui::print_error(format!("Couldn't sign the event: {}", why));
+            return false;
+        }
+    };
+    let msgs = vec![Message::Text(ClientMessage::new_event(event.clone()).as_json())];
+    if !app.confirm(&msgs).await {
+        return false;
+    }
+    if let Err(why) = app.send(msgs).await {
+        ui::print_error(format!("Couldn't publish the event: {}", why));
+        return false;
+    }
+    moderation::record(&serde_json::from_str(&event.as_json()).unwrap_or_default());
+    true
+}
+
+fn block<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        if argument.is_empty() {
+            let blocked = blocklist::blocked();
+            if blocked.is_empty() {
+                ui::print("Nobody is blocked.".to_string());
+            }
+            for public_key in blocked {
+                let npub = profiles::parse_public_key(&public_key).and_then(|public_key| public_key.to_bech32().ok()).unwrap_or(public_key.clone());
+                ui::print(format!("{} {}", names::display_name(&public_key).green(), npub));
+            }
+            return;
+        }
+        let public_key = match pick_user(app, argument) {
+            Some(val) => val,
+            None => {
+                ui::print_error("Name someone by npub, hex public key, number of a message counted from the newest, or name shown.".to_string());
+                return;
+            }
+        };
+        if public_key == app.signer.public_key() {
+            ui::print_error("You can't block yourself.".to_string());
+            return;
+        }
+        let name = names::display_name(&public_key.to_string());
+        match blocklist::block(&public_key.to_string()) {
+            true => ui::print(format!("Messages of {} are dropped from now on.", name)),
+            false => ui::print(format!("{} is blocked already.", name)),
+        }
+    }.boxed_local()
+}
+
+fn unblock<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let public_key = match pick_user(app, argument) {
+            Some(val) => val,
+            None => {
+                ui::print_error("Name someone by npub, hex public key, number of a message counted from the newest, or name shown.".to_string());
+                return;
+            }
+        };
+        let name = names::display_name(&public_key.to_string());
+        match blocklist::unblock(&public_key.to_string()) {
+            true => ui::print(format!("Messages of {} are shown again.", name)),
+            false => ui::print(format!("{} isn't blocked.", name)),
+        }
+    }.boxed_local()
+}
+
+fn status<'a>(app: &'a mut App, _: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        let uptime = app.started.elapsed().as_secs();
+        ui::print(format!("Running for {}h {}m as {}", uptime / 3600, uptime % 3600 / 60, app.signer.public_key().to_bech32().unwrap()));
+        for (index, tab) in app.tabs.tabs.iter().enumerate() {
+            let shown = if index == app.tabs.active { " (shown)" } else { "" };
+            ui::print(format!("{} {}{}", index + 1, tab.chat.clone().get_name(), shown));
+        }
+        ui::print(format!("{} relays, lookups on {}", app.config.relays.len(), app.relay));
+    }.boxed_local()
+}
+
+fn broadcast<'a>(app: &'a mut App, argument: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        // Every tab's chat signs its own message, the shown one's as kept in `app.chat`
+        let active = app.tabs.active;
+        app.tabs.tabs[active].chat = app.chat.clone();
+        // Signed on drafts, like a message typed at the prompt, so discarding them leaves the ratchets as they were
+        let mut signed = Vec::new();
+        for index in 0 .. app.tabs.tabs.len() {
+            let mut draft = app.tabs.tabs[index].chat.draft();
+            match draft.message_from(argument.to_string(), &app.signer).await {
+                Ok(msgs) => signed.push((index, draft, msgs)),
+                Err(why) => ui::print_error(format!("Couldn't send to {}: {}", app.tabs.tabs[index].chat.clone().get_name(), why)),
+            }
+        }
+        let all_msgs: Vec<Message> = signed.iter().flat_map(|(_, _, msgs)| msgs.clone()).collect();
+        if signed.is_empty() || !app.confirm(&all_msgs).await || !app.hold_send().await {
+            return;
+        }
+        let mut sent = 0;
+        for (index, draft, msgs) in signed {
+            let chat_id = app.tabs.tabs[index].chat.get_id();
+            app.tabs.tabs[index].chat.keep_draft(draft);
+            if let Err(why) = app.send_to(&chat_id, msgs.clone()).await {
+                ui::print_error(format!("Couldn't send to {}: {}", app.tabs.tabs[index].chat.clone().get_name(), why));
+                continue;
+            }
+            outbox::attach_text(&msgs, argument);
+            // The other tabs show it once the relays send it back
+            if index == active {
+                chats::echo_sent(&chat_id, argument);
+            }
+            sent += 1;
+        }
+        app.chat = app.tabs.tabs[active].chat.clone();
+        ui::print(format!("Sent to {} chats.", sent));
+    }.boxed_local()
+}
+
+fn quit<'a>(app: &'a mut App, _: &'a str) -> LocalBoxFuture<'a, ()> {
+    async move {
+        storage::mark_read(&app.chat.get_id());
+        app.screen.close();
+        ui::print("Goodbye!".to_string());
+        exit(0);
+    }.boxed_local()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_public_key() -> XOnlyPublicKey {
+        nostr::Keys::generate().public_key()
+    }
+
+    #[test]
+    fn commands_showing_private_messages_are_for_admins() {
+        let admin = random_public_key();
+        let admins = vec![admin.to_bech32().unwrap()];
+        for name in ["/raw", "/receipts", "/expand"] {
+            let command = find(name).unwrap();
+            assert!(!is_permitted(&admins, &Origin::Remote(random_public_key()), &command.permission), "{} is open to anyone", name);
+            assert!(is_permitted(&admins, &Origin::Remote(admin), &command.permission));
+            assert!(is_permitted(&[], &Origin::Local, &command.permission));
+        }
+    }
+}
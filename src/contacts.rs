@@ -0,0 +1,78 @@
+use futures::future::join_all;
+use nostr::prelude::*;
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::export::verify_event;
+use crate::signer::Signer;
+use crate::profiles;
+use crate::relays;
+
+/// The NIP-02 contact list of the user. Updates keep the tags and content of the latest published list,
+/// so what other clients put in it, like petnames or relays, isn't lost.
+#[derive(Clone, Default)]
+pub struct ContactList {
+    tags: Vec<Vec<String>>,
+    content: String,
+}
+
+impl ContactList {
+    pub fn from_event(event: &Value) -> ContactList {
+        let tags = event["tags"].as_array().map_or(Vec::new(), |tags| tags.iter()
+            .filter_map(|tag| tag.as_array().map(|values| values.iter().filter_map(|value| value.as_str().map(str::to_string)).collect()))
+            .collect());
+        ContactList { tags, content: event["content"].as_str().unwrap_or_default().to_string() }
+    }
+
+    /// Followed public keys, in the order they were followed.
+    pub fn public_keys(&self) -> Vec<XOnlyPublicKey> {
+        self.tags.iter()
+            .filter(|tag| tag.first().is_some_and(|name| name == "p"))
+            .filter_map(|tag| profiles::parse_public_key(tag.get(1)?))
+            .collect()
+    }
+
+    pub fn contains(&self, public_key: &XOnlyPublicKey) -> bool {
+        self.public_keys().contains(public_key)
+    }
+
+    /// Adds `public_key`. Returns false if it is followed already.
+    pub fn follow(&mut self, public_key: XOnlyPublicKey) -> bool {
+        if self.contains(&public_key) {
+            return false;
+        }
+        self.tags.push(vec!["p".to_string(), public_key.to_string()]);
+        true
+    }
+
+    /// Removes `public_key`. Returns false if it isn't followed.
+    pub fn unfollow(&mut self, public_key: XOnlyPublicKey) -> bool {
+        let before = self.tags.len();
+        self.tags.retain(|tag| !(tag.first().is_some_and(|name| name == "p") && tag.get(1) == Some(&public_key.to_string())));
+        self.tags.len() != before
+    }
+
+    /// Kind 3 event publishing the list.
+    pub async fn to_event(&self, signer: &Signer) -> Result<Event, String> {
+        let tags: Vec<Tag> = self.tags.iter().filter_map(|tag| Tag::parse(tag.clone()).ok()).collect();
+        signer.sign(EventBuilder::new(Kind::ContactList, &self.content, &tags)).await
+    }
+}
+
+/// Newest contact list of `public_key` on `relays`, empty if there is none. Fails if no relay could be asked,
+/// as publishing an update then would replace the list with one missing the earlier contacts.
+pub async fn fetch(relays: &[String], public_key: XOnlyPublicKey) -> Result<ContactList, String> {
+    let filter = Filter::new().kind(Kind::ContactList).author(public_key.to_string());
+    let req = Message::Text(ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json());
+    let results = join_all(relays.iter().map(|relay| relays::fetch_stored_events(relay, req.clone()))).await;
+    if results.iter().all(|result| result.is_err()) {
+        return Err("no relay answered".to_string());
+    }
+    let newest = results.into_iter()
+        .filter_map(|result| result.ok())
+        .flatten()
+        .filter(|event| event["kind"].as_u64() == Some(3) && event["pubkey"] == public_key.to_string().as_str())
+        .filter(|event| verify_event(event).is_ok())
+        .max_by_key(|event| event["created_at"].as_u64().unwrap_or_default());
+    Ok(newest.map(|event| ContactList::from_event(&event)).unwrap_or_default())
+}
@@ -1,69 +1,810 @@
+use std::collections::{ BTreeMap, BTreeSet, HashMap, VecDeque };
+use std::fmt;
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
 use std::sync::{ Arc, Mutex };
+use chacha20poly1305::{ ChaCha20Poly1305, Key, Nonce };
+use chacha20poly1305::aead::{ Aead, AeadCore, KeyInit, OsRng, Payload };
 use hkdf::Hkdf;
 //use rand::rngs::SmallRng;
 use sha2::Sha256;
 
 //use secp256k1::{ KeyPair, ecdh::SharedSecret, Secp256k1, rand::rngs::OsRng, PublicKey };
-use nostr::prelude::secp256k1::SecretKey;
-use nostr::prelude::secp256k1::ecdh::SharedSecret;
+use nostr::prelude::secp256k1::{ Secp256k1, SecretKey };
+use nostr::prelude::secp256k1::ecdh::{ shared_secret_point, SharedSecret };
 use nostr::prelude::secp256k1::PublicKey;
 use nostr::key::Keys;
 use nostr::prelude::XOnlyPublicKey;
 use nostr::prelude::Parity;
 
 use hex::encode;
+use serde::{ Deserialize, Serialize };
+
+/// Length of the nonce prepended to every ciphertext.
+const NONCE_LENGTH: usize = 12;
+
+/// Info of the HKDF deriving the key ratchet state files are encrypted with from the identity key.
+const STATE_KEY_INFO: &[u8] = b"nostrachat ratchet state";
+/// Most chain outputs kept for messages that haven't arrived yet, which is also how far a message may
+/// be ahead of the chain.
+const MAX_SKIPPED: usize = 1000;
+const MAX_OWN_KEYS: usize = 100;
+/// Most plaintexts of read messages kept in a ratchet's state, the oldest are forgotten first.
+const MAX_MESSAGES: usize = 1000;
+/// Salt of the HKDF deriving a session's first chain key from the handshake's shared secrets.
+const SESSION_SALT: &[u8] = b"nostrachat session";
+/// First byte of session exports, changed if their format ever changes.
+const SESSION_EXPORT_VERSION: u8 = 0x01;
+/// Length of the scrypt salt in session exports.
+const SESSION_EXPORT_SALT_LENGTH: usize = 16;
+/// Version of the ratchet message format we send, told in the "v" tag. 1 is the format from before the tag,
+/// 2 added padding. Messages of newer versions aren't run through the chain, as that could break the session.
+pub const PROTOCOL_VERSION: u8 = 2;
+/// Version of the padding plaintexts get before encryption, told in the "pad" tag. 1 is NIP-44's padding to
+/// standard lengths, so relays only learn roughly how long a message is. Messages without the tag aren't padded.
+pub const PADDING_VERSION: u8 = 1;
+/// Kind of the replaceable event a user's signed prekey is published with.
+pub const PREKEY_KIND: u64 = 10420;
+
+/// Stage decrypting a ratchet message failed at.
+#[derive(Debug)]
+pub enum DecryptError {
+    /// The event's tags don't make a valid message header
+    Header(String),
+    /// The message is in a newer format version than ours
+    Version(u8),
+    /// The content isn't a nonce followed by a ciphertext
+    Format(String),
+    /// The chain has no message key for the step the message was sent at
+    Chain(String),
+    /// The ciphertext doesn't authenticate under the message key, because the chains went apart or it was tampered with
+    Mac,
+    /// The decrypted plaintext isn't a padded UTF-8 text
+    Plaintext(String),
+}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecryptError::Header(why) => write!(f, "header parse: {}", why),
+            DecryptError::Version(version) => write!(f, "version: sent with version {} of ratchet messages, update nostrachat to read it", version),
+            DecryptError::Format(why) => write!(f, "content: {}", why),
+            DecryptError::Chain(why) => write!(f, "chain mismatch: {}", why),
+            DecryptError::Mac => write!(f, "MAC failure: the message doesn't authenticate under the chain's key"),
+            DecryptError::Plaintext(why) => write!(f, "plaintext: {}", why),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct RatchetProfile {
-    chain_key: [u8; 32],
-    pub ephemeral_keys: Arc::<Mutex::<EphemeralKeyPair>>
+    /// Shared by all copies of a chat, so sending and receiving advance the same chain. Drafts have their own.
+    chain: Arc::<Mutex::<Chain>>,
+    pub ephemeral_keys: Arc::<Mutex::<EphemeralKeyPair>>,
+    identity_key: SecretKey,
+    contact: PublicKey,
+    /// Whether this is a copy made by `draft`, whose state is only saved once `adopt` takes it
+    is_draft: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Chain {
+    key: [u8; 32],
+    /// Times the chain was rotated, one per message
+    counter: u64,
+    /// Plaintexts of the messages already run through the chain by event id, so history fetched
+    /// again after a restart doesn't advance it a second time
+    messages: HashMap<String, String>,
+    /// Event ids of `messages` in the order they were read, so the oldest are forgotten first
+    #[serde(default)]
+    message_order: VecDeque<String>,
+    /// Outputs of steps no received message has used yet, for messages that arrive late or out of order
+    #[serde(default)]
+    skipped: BTreeMap<u64, [u8; 32]>,
+    /// Ephemeral secret keys we sent messages with by public key, as answers may be encrypted to any of them
+    #[serde(default)]
+    own_keys: BTreeMap<String, String>,
+    /// Ephemeral public keys of the contact we sent messages to, which those messages are tagged with
+    #[serde(default)]
+    peer_keys: BTreeSet<String>,
+    /// Ephemeral public key of the handshake the session was started with, if it was
+    #[serde(default)]
+    session: Option<String>,
+    /// Handshake we started the session with, sent along with our messages until the contact answers
+    #[serde(default)]
+    handshake: Option<Handshake>,
+    /// Newest message format version the contact sent, which our messages fall back to if it is older than ours
+    #[serde(default)]
+    peer_version: Option<u8>,
+}
+
+/// What the initiator of a session tells the other side, so both can agree on the same fresh chain key.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Handshake {
+    /// Public key generated for this handshake only
+    pub ephemeral_key: XOnlyPublicKey,
+    /// The other side's signed prekey the handshake was made with
+    pub prekey: XOnlyPublicKey,
+}
+
+/// What the sender of a ratchet message tells about how it was encrypted, taken from the event's tags.
+#[derive(Default)]
+pub struct MessageHeader {
+    /// Chain step the message key came from
+    pub step: Option<u64>,
+    /// Which of our ephemeral keys the sender used
+    pub sent_to: Option<XOnlyPublicKey>,
+    pub handshake: Option<Handshake>,
+    /// Version of the padding the plaintext got, None if it wasn't padded
+    pub padding: Option<u8>,
+    /// Version of the message format, None if the sender didn't tell
+    pub version: Option<u8>,
+}
+
+impl MessageHeader {
+    /// Version of the message format, guessed by the padding for messages that don't tell it.
+    pub fn version(&self) -> u8 {
+        self.version.unwrap_or(if self.padding.is_some() { 2 } else { 1 })
+    }
+}
+
+/// What is saved of a ratchet between sessions.
+#[derive(Deserialize, Serialize)]
+struct SavedRatchet {
+    chain: Chain,
+    ephemeral_secret_key: String,
+    recipient_public_key: String,
 }
 
 impl RatchetProfile {
 
+    /// Ratchet between `secret_key` and `recipient_public_key`, continuing from the saved state if
+    /// there is one.
     pub fn new(secret_key: SecretKey, recipient_public_key: PublicKey) -> Self {
+        let path = state_path(&recipient_public_key);
+        if path.exists() {
+            match load_state(&path, &secret_key) {
+                Ok((chain, ephemeral_keys)) => return RatchetProfile {
+                    chain: Arc::new(Mutex::new(chain)),
+                    ephemeral_keys: Arc::new(Mutex::new(ephemeral_keys)),
+                    identity_key: secret_key,
+                    contact: recipient_public_key,
+                    is_draft: false,
+                },
+                Err(why) => crate::ui::print_error(format!("Couldn't load ratchet state, starting over: {}", why)),
+            }
+        }
+        RatchetProfile::fresh(secret_key, recipient_public_key)
+    }
 
+    /// Ratchet between `secret_key` and `recipient_public_key` with no messages sent or received yet.
+    fn fresh(secret_key: SecretKey, recipient_public_key: PublicKey) -> Self {
         let shared_secret = SharedSecret::new(&recipient_public_key, &secret_key);
         let (chain_key, _) = Hkdf::<Sha256>::extract(None, &shared_secret.secret_bytes());
         RatchetProfile {
-            chain_key: chain_key.into(),
+            chain: Arc::new(Mutex::new(Chain { key: chain_key.into(), counter: 0, messages: HashMap::new(), message_order: VecDeque::new(), skipped: BTreeMap::new(), own_keys: BTreeMap::new(), peer_keys: BTreeSet::new(), session: None, handshake: None, peer_version: None })),
             ephemeral_keys: Arc::new(Mutex::new(EphemeralKeyPair { secret_key: secret_key, recipient_public_key: recipient_public_key})),
+            identity_key: secret_key,
+            contact: recipient_public_key,
+            is_draft: false,
         }
     }
 
-    pub fn rotate(&mut self) -> [u8; 256] {
-        let (chain_key, ratchet) = Hkdf::<Sha256>::extract(None, &self.chain_key);
-        self.chain_key = chain_key.into();
-        let mut okm = [0u8; 256];
-        let recipient_public_key = self.ephemeral_keys.lock().unwrap().recipient_public_key;
-        let secret_key = self.ephemeral_keys.lock().unwrap().secret_key;
-        let shared_secret = SharedSecret::new(&recipient_public_key, &secret_key);
-        // Debugging
-/*        println!("RECP PUBKEY (ROTATE): {:?}", recipient_public_key.serialize_uncompressed());
-        println!("SEC KEY (ROTATE): {:?}", secret_key.secret_bytes());
-        println!("PUBKEY OUT OF SEC_KEY EVEN (ROTATE): {:?}", Keys::new(secret_key).public_key().public_key(Parity::Even).serialize_uncompressed());
-        println!("PUBKEY OUT OF SEC_KEY ODD (ROTATE): {:?}", Keys::new(secret_key).public_key().public_key(Parity::Odd).serialize_uncompressed());
-        println!("PUBKEY OUT OF SEC_KEY NORMALIZED (ROTATE): {:?}", Keys::new(secret_key).normalized_public_key().unwrap().serialize_uncompressed()); 
-        println!("SHARED SECRET (ROTATE): {:?}", shared_secret.secret_bytes());
-        println!("SHARED SECRET DISPLAY SECRET (ROTATE): {:?}", shared_secret.display_secret());
-*/
+    /// Copy of the ratchet with a state of its own, for signing a message that may still be discarded.
+    pub fn draft(&self) -> RatchetProfile {
+        let chain = self.chain.lock().unwrap().clone();
+        let ephemeral_keys = self.ephemeral_keys.lock().unwrap();
+        RatchetProfile {
+            chain: Arc::new(Mutex::new(chain)),
+            ephemeral_keys: Arc::new(Mutex::new(EphemeralKeyPair { secret_key: ephemeral_keys.secret_key, recipient_public_key: ephemeral_keys.recipient_public_key })),
+            identity_key: self.identity_key,
+            contact: self.contact,
+            is_draft: true,
+        }
+    }
+
+    /// Takes what sending the message of `draft`, made by `draft`, changed in its state, keeping what was
+    /// received here meanwhile, and saves it.
+    pub fn adopt(&self, draft: &RatchetProfile) {
+        let sent = draft.chain.lock().unwrap().clone();
+        self.chain.lock().unwrap().merge_draft(sent);
+        // Answers to the message go to the key it was signed with
+        self.ephemeral_keys.lock().unwrap().secret_key = draft.ephemeral_keys.lock().unwrap().secret_key;
+        if let Err(why) = self.save() {
+            crate::ui::print_error(format!("Couldn't save ratchet state: {}", why));
+        }
+    }
+
+    /// Whether nothing was sent or received yet, so the session can still start with a handshake
+    /// instead of the chain key derived from the identity keys alone.
+    pub fn needs_session(&self) -> bool {
+        let chain = self.chain.lock().unwrap();
+        chain.session.is_none() && chain.counter == 0
+    }
+
+    /// Starts a session with the contact's signed `prekey`: the chain key comes from the shared secrets
+    /// of our identity key and a fresh ephemeral key with their identity key and prekey, as in X3DH.
+    pub fn initiate_session(&mut self, prekey: XOnlyPublicKey) {
+        let ephemeral_key = SecretKey::new(&mut rand::thread_rng());
+        let contact = self.contact.x_only_public_key().0;
+        let key = session_key(&[dh(&self.identity_key, &prekey), dh(&ephemeral_key, &contact), dh(&ephemeral_key, &prekey)]);
+        let handshake = Handshake { ephemeral_key: ephemeral_key.x_only_public_key(&Secp256k1::new()).0, prekey };
+        self.chain.lock().unwrap().start_session(key, &handshake.ephemeral_key, Some(handshake.clone()));
+        if let Err(why) = self.save() {
+            crate::ui::print_error(format!("Couldn't save ratchet state: {}", why));
+        }
+    }
+
+    /// Version of the message format our messages are sent in: ours, or the contact's if they only understand
+    /// an older one.
+    pub fn send_version(&self) -> u8 {
+        self.chain.lock().unwrap().peer_version.map_or(PROTOCOL_VERSION, |version| version.min(PROTOCOL_VERSION))
+    }
+
+    /// Newest message format version the contact sent, None if they didn't send anything yet.
+    pub fn peer_version(&self) -> Option<u8> {
+        self.chain.lock().unwrap().peer_version
+    }
+
+    /// Handshake to send along with our messages, until the contact answered in the session.
+    pub fn handshake(&self) -> Option<Handshake> {
+        self.chain.lock().unwrap().handshake.clone()
+    }
+
+    /// Chain key of the session started by the contact with `handshake`, unless we are in it already,
+    /// it was made with a prekey we don't have anymore, or our own handshake takes precedence.
+    fn accept_handshake(&self, handshake: &Handshake) -> Option<[u8; 32]> {
+        let chain = self.chain.lock().unwrap();
+        if chain.session.as_deref() == Some(handshake.ephemeral_key.to_string().as_str()) {
+            return None;
+        }
+        // Both sides sent messages in sessions they started, the one with the lower ephemeral key wins
+        if chain.counter > 0 && chain.handshake.as_ref().is_some_and(|ours| ours.ephemeral_key.serialize() < handshake.ephemeral_key.serialize()) {
+            return None;
+        }
+        drop(chain);
+        let prekey = load_or_create_prekey(&self.identity_key);
+        if prekey.x_only_public_key(&Secp256k1::new()).0 != handshake.prekey {
+            return None;
+        }
+        let contact = self.contact.x_only_public_key().0;
+        Some(session_key(&[dh(&prekey, &contact), dh(&self.identity_key, &handshake.ephemeral_key), dh(&prekey, &handshake.ephemeral_key)]))
+    }
+
+    /// Plaintext of the message with `event_id` if it already went through the ratchet. Those the state
+    /// forgot beyond MAX_MESSAGES are read from the event cache, as their message keys are used up.
+    pub fn known_message(&self, event_id: &str) -> Option<String> {
+        if let Some(plaintext) = self.chain.lock().unwrap().messages.get(event_id).cloned() {
+            return Some(plaintext);
+        }
+        let sealed = crate::storage::sealed_plaintext(&self.chat_id(), event_id)?;
+        self.unseal(event_id, &sealed)
+    }
+
+    /// Records the plaintext of the message with `event_id` and saves the state, so it survives a restart.
+    /// It is kept sealed in the event cache as well, where it outlasts the state's MAX_MESSAGES.
+    pub fn remember_message(&self, event_id: &str, plaintext: &str) {
+        let forgotten = self.chain.lock().unwrap().remember(event_id, plaintext);
+        // The forgotten ones may be from before plaintexts were kept in the cache
+        for (event_id, plaintext) in forgotten.iter().chain([(event_id.to_string(), plaintext.to_string())].iter()) {
+            crate::storage::keep_sealed_plaintext(&self.chat_id(), event_id, &self.seal(event_id, plaintext));
+        }
+        if let Err(why) = self.save() {
+            crate::ui::print_error(format!("Couldn't save ratchet state: {}", why));
+        }
+    }
+
+    /// Id of the chat with the contact in the event cache.
+    fn chat_id(&self) -> String {
+        self.contact.x_only_public_key().0.to_string()
+    }
+
+    /// `plaintext` of the message with `event_id` encrypted like the state file, bound to the event.
+    fn seal(&self, event_id: &str, plaintext: &str) -> Vec<u8> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = state_cipher(&self.identity_key)
+            .encrypt(&nonce, Payload { msg: plaintext.as_bytes(), aad: event_id.as_bytes() })
+            .expect("Couldn't seal message!");
+        [nonce.as_slice(), &ciphertext].concat()
+    }
+
+    /// Plaintext sealed by `seal` for the message with `event_id`, None if it doesn't open.
+    fn unseal(&self, event_id: &str, sealed: &[u8]) -> Option<String> {
+        if sealed.len() < NONCE_LENGTH {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+        let plaintext = state_cipher(&self.identity_key).decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: event_id.as_bytes() }).ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+
+    /// Writes the chain and ephemeral keys to the contact's state file, encrypted with a key derived
+    /// from the identity key.
+    fn save(&self) -> Result<(), String> {
+        if self.is_draft {
+            return Ok(());
+        }
+        let chain = self.chain.lock().unwrap().clone();
+        let ephemeral_keys = self.ephemeral_keys.lock().unwrap();
+        let saved = SavedRatchet {
+            chain,
+            ephemeral_secret_key: ephemeral_keys.secret_key.display_secret().to_string(),
+            recipient_public_key: ephemeral_keys.recipient_public_key.to_string(),
+        };
+        drop(ephemeral_keys);
+        let plaintext = serde_json::to_vec(&saved).map_err(|why| why.to_string())?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = state_cipher(&self.identity_key).encrypt(&nonce, plaintext.as_slice()).map_err(|why| why.to_string())?;
+        fs::write(state_path(&self.contact), [nonce.as_slice(), &ciphertext].concat()).map_err(|why| why.to_string())
+    }
+
+    /// The ratchet state encrypted with `passphrase`, so the session can be continued on another device with
+    /// `import_session` instead of being reset. The file is bound to the contact, and made of a version
+    /// byte, the scrypt work factor and salt, a nonce and the ciphertext.
+    pub fn export_session(&self, passphrase: &str) -> Result<Vec<u8>, String> {
+        let chain = self.chain.lock().unwrap().clone();
+        let ephemeral_keys = self.ephemeral_keys.lock().unwrap();
+        let saved = SavedRatchet {
+            chain,
+            ephemeral_secret_key: ephemeral_keys.secret_key.display_secret().to_string(),
+            recipient_public_key: ephemeral_keys.recipient_public_key.to_string(),
+        };
+        drop(ephemeral_keys);
+        let plaintext = serde_json::to_vec(&saved).map_err(|why| why.to_string())?;
+        let mut salt = [0u8; SESSION_EXPORT_SALT_LENGTH];
+        rand::RngCore::fill_bytes(&mut OsRng, &mut salt);
+        let key = crate::nip49::symmetric_key(passphrase, &salt, crate::nip49::DEFAULT_LOG_N)?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let contact = self.contact.x_only_public_key().0.serialize();
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .encrypt(&nonce, Payload { msg: &plaintext, aad: &contact })
+            .map_err(|why| why.to_string())?;
+        Ok([&[SESSION_EXPORT_VERSION, crate::nip49::DEFAULT_LOG_N], salt.as_slice(), nonce.as_slice(), &ciphertext].concat())
+    }
+
+    /// Continues the session exported by `export_session` on another device, replacing the state of this
+    /// one. Fails if the passphrase is wrong or the export is of a session with someone else.
+    pub fn import_session(&self, exported: &[u8], passphrase: &str) -> Result<(), String> {
+        let header_length = 2 + SESSION_EXPORT_SALT_LENGTH;
+        if exported.len() < header_length + NONCE_LENGTH {
+            return Err("not a session export".to_string());
+        }
+        if exported[0] != SESSION_EXPORT_VERSION {
+            return Err(format!("unsupported session export version {}", exported[0]));
+        }
+        if exported[1] > crate::nip49::MAX_LOG_N {
+            return Err(format!("scrypt work factor {} is above the {} supported", exported[1], crate::nip49::MAX_LOG_N));
+        }
+        let key = crate::nip49::symmetric_key(passphrase, &exported[2 .. header_length], exported[1])?;
+        let (nonce, ciphertext) = exported[header_length ..].split_at(NONCE_LENGTH);
+        let contact = self.contact.x_only_public_key().0.serialize();
+        let plaintext = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &contact })
+            .map_err(|_| "wrong passphrase, or the session is with someone else".to_string())?;
+        let saved: SavedRatchet = serde_json::from_slice(&plaintext).map_err(|why| why.to_string())?;
+        let ephemeral_keys = EphemeralKeyPair {
+            secret_key: SecretKey::from_str(&saved.ephemeral_secret_key).map_err(|why| why.to_string())?,
+            recipient_public_key: PublicKey::from_str(&saved.recipient_public_key).map_err(|why| why.to_string())?,
+        };
+        // Every copy of the chat shares the state, so they all continue the imported session
+        *self.chain.lock().unwrap() = saved.chain;
+        *self.ephemeral_keys.lock().unwrap() = ephemeral_keys;
+        self.save()
+    }
+
+    /// Pads `input` as PADDING_VERSION says, unless the contact's format version is too old for that, and
+    /// encrypts it with ChaCha20-Poly1305 under the next message key. Returns the hex of a random nonce
+    /// followed by the ciphertext, and the number of the chain step the key came from. Fails if `input` is
+    /// empty or too long to pad.
+    pub fn encrypt_message(&mut self, input: String) -> Result<(String, u64), String> {
+        let padded = match self.send_version() {
+            1 => input.as_bytes().to_vec(),
+            _ => crate::nip44::pad(&input)?,
+        };
+        let mut chain = self.chain.lock().unwrap();
+        let (output, step) = chain.step();
+        let keys = self.ephemeral_keys.lock().unwrap();
+        let cipher = message_cipher(&message_key(&output, &keys.secret_key, &keys.recipient_public_key));
+        // The contact may send a message of the same step at the same time, and may answer to this key later
+        chain.skipped.insert(step, output);
+        chain.own_keys.insert(keys.secret_key.x_only_public_key(&Secp256k1::new()).0.to_string(), keys.secret_key.display_secret().to_string());
+        chain.peer_keys.insert(keys.recipient_public_key.x_only_public_key().0.to_string());
+        chain.prune();
+        drop(keys);
+        drop(chain);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, padded.as_slice()).expect("Couldn't encrypt message!");
+        Ok((hex::encode([nonce.as_slice(), &ciphertext].concat()), step))
+    }
+
+    /// Decrypts content made by `encrypt_message` by `author`. A message starting a new session switches
+    /// to it if it decrypts there. Fails with the stage that went wrong, e.g. if the content is malformed or
+    /// doesn't authenticate because it was tampered with or the ratchets are out of step. The chain is only
+    /// moved on by messages that authenticate, so anyone else's can't use up or skip its keys.
+    pub fn decrypt_message(&mut self, input: String, author: PublicKey, header: &MessageHeader) -> Result<String, DecryptError> {
+        if header.version() > PROTOCOL_VERSION {
+            return Err(DecryptError::Version(header.version()));
+        }
+        let session_key = header.handshake.as_ref().and_then(|handshake| self.accept_handshake(handshake));
+        // Held throughout, so a message sent meanwhile can't take a step this one is decrypted with
+        let mut shared = self.chain.lock().unwrap();
+        if let Some(key) = session_key {
+            let mut session = shared.clone();
+            session.start_session(key, &header.handshake.as_ref().unwrap().ephemeral_key, None);
+            // Otherwise not by the contact, or not to us
+            if let Ok(plaintext) = self.decrypt_in_chain(&mut session, &input, author, header) {
+                *shared = session;
+                drop(shared);
+                return unpad(plaintext, header);
+            }
+        }
+        let mut chain = shared.clone();
+        let plaintext = self.decrypt_in_chain(&mut chain, &input, author, header)?;
+        if header.handshake.is_none() {
+            // The contact answered in the session, so it doesn't need to be announced anymore
+            chain.handshake = None;
+        }
+        *shared = chain;
+        drop(shared);
+        unpad(plaintext, header)
+    }
+
+    /// Decrypts a message in `chain`, a copy of the ratchet's that is only kept if the message authenticates.
+    /// With the `step` the sender encrypted it at, messages that arrive late or out of order are decrypted with
+    /// the chain output kept for that step, and `sent_to` picks which of our ephemeral keys the sender used.
+    /// Without it the next message key is used. Returns the plaintext, still padded.
+    fn decrypt_in_chain(&self, chain: &mut Chain, input: &str, author: PublicKey, header: &MessageHeader) -> Result<Vec<u8>, DecryptError> {
+        let (step, sent_to) = (header.step, header.sent_to);
+        if let Some(padding) = header.padding.filter(|padding| *padding != PADDING_VERSION) {
+            return Err(DecryptError::Header(format!("unsupported padding version {}", padding)));
+        }
+        let content = hex::decode(input).map_err(|_| DecryptError::Format("content isn't hex encoded".to_string()))?;
+        if content.len() < NONCE_LENGTH {
+            return Err(DecryptError::Format("content is too short".to_string()));
+        }
+        let output = match step {
+            Some(step) => chain.output_for(step).map_err(DecryptError::Chain)?,
+            None => chain.step().0,
+        };
+        let is_newest = step.is_none_or(|step| step >= chain.counter);
+        let secret_key = sent_to
+            .and_then(|public_key| chain.own_keys.get(&public_key.to_string()).cloned())
+            .and_then(|secret_key| SecretKey::from_str(&secret_key).ok())
+            .unwrap_or(self.ephemeral_keys.lock().unwrap().secret_key);
+        let cipher = message_cipher(&message_key(&output, &secret_key, &author));
+
+        let (nonce, ciphertext) = content.split_at(NONCE_LENGTH);
+        let plaintext = cipher.decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|_| DecryptError::Mac)?;
+        // Answers go to the key of the newest message, not to one that arrived late
+        if is_newest {
+            self.ephemeral_keys.lock().unwrap().recipient_public_key = author;
+        }
+        chain.peer_version = chain.peer_version.max(Some(header.version()));
+        Ok(plaintext)
+    }
+
+    /// Public keys messages of the session are tagged with: the identity keys, our ephemeral keys the contact
+    /// may answer to and the contact's ones our messages went to.
+    pub fn session_keys(&self) -> Vec<XOnlyPublicKey> {
+        let chain = self.chain.lock().unwrap();
+        let ephemeral_keys = self.ephemeral_keys.lock().unwrap();
+        let secp = Secp256k1::new();
+        let mut keys = vec![
+            self.identity_key.x_only_public_key(&secp).0,
+            self.contact.x_only_public_key().0,
+            ephemeral_keys.secret_key.x_only_public_key(&secp).0,
+            ephemeral_keys.recipient_public_key.x_only_public_key().0,
+        ];
+        keys.extend(chain.own_keys.keys().chain(chain.peer_keys.iter()).filter_map(|public_key| XOnlyPublicKey::from_str(public_key).ok()));
+        keys.sort_by_key(|key| key.serialize());
+        keys.dedup();
+        keys
+    }
+}
+
+impl Chain {
+    /// Restarts the chain from the first chain key of a session, keeping the messages read so far.
+    fn start_session(&mut self, key: [u8; 32], ephemeral_key: &XOnlyPublicKey, handshake: Option<Handshake>) {
+        self.key = key;
+        self.counter = 0;
+        self.skipped.clear();
+        self.session = Some(ephemeral_key.to_string());
+        self.handshake = handshake;
+    }
+
+    /// Keeps the `plaintext` of the message with `event_id`, forgetting the oldest beyond MAX_MESSAGES.
+    /// Returns the event ids and plaintexts forgotten.
+    fn remember(&mut self, event_id: &str, plaintext: &str) -> Vec<(String, String)> {
+        if self.messages.insert(event_id.to_string(), plaintext.to_string()).is_none() {
+            self.message_order.push_back(event_id.to_string());
+        }
+        let mut forgotten = Vec::new();
+        while self.messages.len() > MAX_MESSAGES {
+            // States saved before the order was kept forget theirs in no particular order
+            let oldest = match self.message_order.pop_front() {
+                Some(val) => val,
+                None => self.messages.keys().next().cloned().unwrap(),
+            };
+            if let Some(plaintext) = self.messages.remove(&oldest) {
+                forgotten.push((oldest, plaintext));
+            }
+        }
+        forgotten
+    }
+
+    /// Takes what sending a message changed in `draft`, a copy of this chain made before it was signed. Steps
+    /// the chain took meanwhile for received messages are kept, and a session started meanwhile isn't left.
+    fn merge_draft(&mut self, draft: Chain) {
+        if draft.session == self.session && draft.counter > self.counter {
+            let counter = self.counter;
+            self.skipped.extend(draft.skipped.range(counter + 1 ..));
+            self.key = draft.key;
+            self.counter = draft.counter;
+        }
+        self.own_keys.extend(draft.own_keys);
+        self.peer_keys.extend(draft.peer_keys);
+        for event_id in draft.message_order {
+            if let Some(plaintext) = draft.messages.get(&event_id).filter(|_| !self.messages.contains_key(&event_id)) {
+                self.remember(&event_id, plaintext);
+            }
+        }
+        self.prune();
+    }
+
+    /// Advances the chain, returning the output of the new step and its number.
+    fn step(&mut self) -> ([u8; 32], u64) {
+        let (chain_key, _) = Hkdf::<Sha256>::extract(None, &self.key);
+        self.key = chain_key.into();
+        self.counter += 1;
+        (self.key, self.counter)
+    }
+
+    /// Chain output of `step`. Steps ahead of the chain are reached by advancing it, keeping the outputs
+    /// of the steps in between for messages still on their way. Earlier steps use the kept output once.
+    fn output_for(&mut self, step: u64) -> Result<[u8; 32], String> {
+        if step <= self.counter {
+            return self.skipped.remove(&step).ok_or_else(|| "message key was already used".to_string());
+        }
+        if step - self.counter > MAX_SKIPPED as u64 {
+            return Err(format!("message is {} steps ahead of the chain", step - self.counter));
+        }
+        loop {
+            let (output, current) = self.step();
+            if current == step {
+                self.prune();
+                return Ok(output);
+            }
+            self.skipped.insert(current, output);
+        }
+    }
 
-        ratchet.expand(&shared_secret.secret_bytes(), &mut okm);
-        okm
+    /// Drops the oldest kept outputs and keys beyond the limits.
+    fn prune(&mut self) {
+        while self.skipped.len() > MAX_SKIPPED {
+            self.skipped.pop_first();
+        }
+        while self.own_keys.len() > MAX_OWN_KEYS {
+            let oldest = self.own_keys.keys().next().cloned().unwrap();
+            self.own_keys.remove(&oldest);
+        }
+        while self.peer_keys.len() > MAX_OWN_KEYS {
+            self.peer_keys.pop_first();
+        }
     }
+}
 
-    pub fn encrypt_message(&mut self, input: String) -> String {
-        let message_key = self.rotate();
-        hex::encode(message_key)
+/// Plaintext of a decrypted message, unpadded as its `header` says.
+fn unpad(plaintext: Vec<u8>, header: &MessageHeader) -> Result<String, DecryptError> {
+    match header.padding {
+        Some(_) => crate::nip44::unpad(&plaintext).map_err(DecryptError::Plaintext),
+        None => String::from_utf8(plaintext).map_err(|_| DecryptError::Plaintext("message isn't valid UTF-8".to_string())),
     }
+}
+
+/// Shared secret of `secret_key` and `public_key`. Only the x coordinate is used, so it doesn't depend
+/// on the parity the public key is assumed to have.
+fn dh(secret_key: &SecretKey, public_key: &XOnlyPublicKey) -> [u8; 32] {
+    let point = shared_secret_point(&public_key.public_key(Parity::Even), secret_key);
+    point[.. 32].try_into().unwrap()
+}
+
+/// First chain key of a session from the `shared_secrets` of its handshake.
+fn session_key(shared_secrets: &[[u8; 32]]) -> [u8; 32] {
+    let (key, _) = Hkdf::<Sha256>::extract(Some(SESSION_SALT), &shared_secrets.concat());
+    key.into()
+}
+
+/// Secret of our signed prekey, created and saved on first use. Its public key is published with
+/// PREKEY_KIND, so contacts can start sessions with us.
+pub fn load_or_create_prekey(identity_key: &SecretKey) -> SecretKey {
+    let path = crate::data_dir().join("ratchets").join("prekey");
+    if let Ok(content) = fs::read(&path) {
+        if content.len() > NONCE_LENGTH {
+            let (nonce, ciphertext) = content.split_at(NONCE_LENGTH);
+            if let Ok(secret) = state_cipher(identity_key).decrypt(Nonce::from_slice(nonce), ciphertext) {
+                if let Ok(prekey) = SecretKey::from_slice(&secret) {
+                    return prekey;
+                }
+            }
+        }
+        crate::ui::print_error("Couldn't read the saved prekey, creating a new one".to_string());
+    }
+    let prekey = SecretKey::new(&mut rand::thread_rng());
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = state_cipher(identity_key).encrypt(&nonce, prekey.secret_bytes().as_slice()).expect("Couldn't encrypt prekey!");
+    fs::create_dir_all(path.parent().unwrap()).expect("Couldn't create ratchet state directory!");
+    fs::write(&path, [nonce.as_slice(), &ciphertext].concat()).expect("Couldn't save prekey!");
+    prekey
+}
 
-    pub fn decrypt_message(&mut self, input: String) -> String {
-        let message_key = self.rotate();
-        input + &hex::encode(message_key)
+/// Message key of a chain `output`, mixed with the shared secret of the ephemeral keys.
+fn message_key(output: &[u8; 32], secret_key: &SecretKey, public_key: &PublicKey) -> [u8; 256] {
+    let ratchet = Hkdf::<Sha256>::from_prk(output).expect("Chain output has the length of a SHA-256 output");
+    // Public keys are only known by their x coordinate in events, so their parity is guessed
+    let shared_secret = dh(secret_key, &public_key.x_only_public_key().0);
+    let mut okm = [0u8; 256];
+    ratchet.expand(&shared_secret, &mut okm).expect("256 bytes is a valid HKDF-SHA256 output length");
+    okm
+}
+
+/// File the ratchet state with `contact` is saved to.
+fn state_path(contact: &PublicKey) -> PathBuf {
+    let dir = crate::data_dir().join("ratchets");
+    fs::create_dir_all(&dir).expect("Couldn't create ratchet state directory!");
+    dir.join(hex::encode(contact.x_only_public_key().0.serialize()))
+}
+
+fn load_state(path: &Path, identity_key: &SecretKey) -> Result<(Chain, EphemeralKeyPair), String> {
+    let content = fs::read(path).map_err(|why| why.to_string())?;
+    if content.len() < NONCE_LENGTH {
+        return Err("state file is too short".to_string());
     }
+    let (nonce, ciphertext) = content.split_at(NONCE_LENGTH);
+    let plaintext = state_cipher(identity_key).decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|_| "state file is corrupted or belongs to another key".to_string())?;
+    let saved: SavedRatchet = serde_json::from_slice(&plaintext).map_err(|why| why.to_string())?;
+    let ephemeral_keys = EphemeralKeyPair {
+        secret_key: SecretKey::from_str(&saved.ephemeral_secret_key).map_err(|why| why.to_string())?,
+        recipient_public_key: PublicKey::from_str(&saved.recipient_public_key).map_err(|why| why.to_string())?,
+    };
+    Ok((saved.chain, ephemeral_keys))
+}
+
+/// Cipher for state files, keyed with a key derived from `identity_key`.
+fn state_cipher(identity_key: &SecretKey) -> ChaCha20Poly1305 {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, &identity_key.secret_bytes()).expand(STATE_KEY_INFO, &mut key).expect("32 bytes is a valid HKDF-SHA256 output length");
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+/// Cipher keyed with the first 32 bytes of a ratchet output.
+fn message_cipher(message_key: &[u8; 256]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(Key::from_slice(&message_key[.. 32]))
 }
 
 pub struct EphemeralKeyPair {
     pub recipient_public_key: PublicKey,
     pub secret_key: SecretKey,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ratchets of two contacts with each other.
+    fn pair() -> (RatchetProfile, RatchetProfile, PublicKey, PublicKey) {
+        let secp = Secp256k1::new();
+        let (alice_key, bob_key) = (SecretKey::new(&mut rand::thread_rng()), SecretKey::new(&mut rand::thread_rng()));
+        let (alice, bob) = (PublicKey::from_secret_key(&secp, &alice_key), PublicKey::from_secret_key(&secp, &bob_key));
+        (RatchetProfile::fresh(alice_key, bob), RatchetProfile::fresh(bob_key, alice), alice, bob)
+    }
+
+    fn header(step: u64) -> MessageHeader {
+        MessageHeader { step: Some(step), padding: Some(PADDING_VERSION), version: Some(PROTOCOL_VERSION), ..Default::default() }
+    }
+
+    #[test]
+    fn session_imports_with_huge_work_factors_are_rejected() {
+        let (alice, _, _, _) = pair();
+        let mut exported = vec![SESSION_EXPORT_VERSION, crate::nip49::MAX_LOG_N + 1];
+        exported.resize(2 + SESSION_EXPORT_SALT_LENGTH + NONCE_LENGTH + 16, 0);
+        assert!(alice.import_session(&exported, "passphrase").unwrap_err().contains("work factor"));
+    }
+
+    #[test]
+    fn messages_decrypt_in_order() {
+        let (mut alice, mut bob, alice_key, _) = pair();
+        for text in ["hi", "how are you?"] {
+            let (content, step) = alice.encrypt_message(text.to_string()).unwrap();
+            assert_eq!(bob.decrypt_message(content, alice_key, &header(step)).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn messages_decrypt_out_of_order() {
+        let (mut alice, mut bob, alice_key, _) = pair();
+        let first = alice.encrypt_message("first".to_string()).unwrap();
+        let second = alice.encrypt_message("second".to_string()).unwrap();
+        let third = alice.encrypt_message("third".to_string()).unwrap();
+        assert_eq!(bob.decrypt_message(third.0, alice_key, &header(third.1)).unwrap(), "third");
+        assert_eq!(bob.decrypt_message(first.0, alice_key, &header(first.1)).unwrap(), "first");
+        assert_eq!(bob.decrypt_message(second.0, alice_key, &header(second.1)).unwrap(), "second");
+    }
+
+    #[test]
+    fn message_keys_are_used_once() {
+        let (mut alice, mut bob, alice_key, _) = pair();
+        let (content, step) = alice.encrypt_message("hi".to_string()).unwrap();
+        bob.decrypt_message(content.clone(), alice_key, &header(step)).unwrap();
+        assert!(matches!(bob.decrypt_message(content, alice_key, &header(step)), Err(DecryptError::Chain(_))));
+    }
+
+    #[test]
+    fn forged_messages_leave_the_chain_as_it_was() {
+        let (mut alice, mut bob, alice_key, _) = pair();
+        let (content, step) = alice.encrypt_message("hi".to_string()).unwrap();
+        let mut tampered = hex::decode(&content).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(bob.decrypt_message(hex::encode(tampered), alice_key, &header(step)), Err(DecryptError::Mac)));
+        // Far ahead, so it would skip many steps if it was taken
+        let forged = hex::encode([0u8; 64]);
+        assert!(matches!(bob.decrypt_message(forged, alice_key, &header(500)), Err(DecryptError::Mac)));
+        assert_eq!(bob.chain.lock().unwrap().counter, 0);
+        assert!(bob.chain.lock().unwrap().skipped.is_empty());
+        assert_eq!(bob.decrypt_message(content, alice_key, &header(step)).unwrap(), "hi");
+    }
+
+    #[test]
+    fn messages_of_others_dont_decrypt() {
+        let (mut alice, _, alice_key, _) = pair();
+        let (_, mut mallory, _, _) = pair();
+        let (content, step) = alice.encrypt_message("hi".to_string()).unwrap();
+        assert!(matches!(mallory.decrypt_message(content, alice_key, &header(step)), Err(DecryptError::Mac)));
+    }
+
+    #[test]
+    fn sealed_plaintexts_only_open_for_their_message() {
+        let (alice, _, _, _) = pair();
+        let sealed = alice.seal("a0", "hi");
+        assert_eq!(alice.unseal("a0", &sealed), Some("hi".to_string()));
+        assert_eq!(alice.unseal("a1", &sealed), None);
+    }
+
+    #[test]
+    fn plaintexts_beyond_the_limit_are_handed_back() {
+        let (alice, _, _, _) = pair();
+        let mut chain = alice.chain.lock().unwrap();
+        for index in 0 .. MAX_MESSAGES {
+            assert!(chain.remember(&index.to_string(), "old").is_empty());
+        }
+        assert_eq!(chain.remember("new", "new"), vec![("0".to_string(), "old".to_string())]);
+        assert_eq!(chain.messages.len(), MAX_MESSAGES);
+    }
+
+    #[test]
+    fn padding_hides_the_length_of_short_messages() {
+        let (mut alice, mut bob, alice_key, _) = pair();
+        let (short, short_step) = alice.encrypt_message("a".to_string()).unwrap();
+        let (longer, step) = alice.encrypt_message("a message of 30 characters....".to_string()).unwrap();
+        assert_eq!(short.len(), longer.len());
+        assert_eq!(bob.decrypt_message(short, alice_key, &header(short_step)).unwrap(), "a");
+        assert_eq!(bob.decrypt_message(longer, alice_key, &header(step)).unwrap(), "a message of 30 characters....");
+    }
+
+    #[test]
+    fn unpadded_messages_of_version_1_decrypt() {
+        let (mut alice, mut bob, alice_key, _) = pair();
+        alice.chain.lock().unwrap().peer_version = Some(1);
+        let (content, step) = alice.encrypt_message("hi".to_string()).unwrap();
+        let header = MessageHeader { step: Some(step), ..Default::default() };
+        assert_eq!(bob.decrypt_message(content, alice_key, &header).unwrap(), "hi");
+    }
+
+    #[test]
+    fn unknown_padding_versions_are_rejected() {
+        let (mut alice, mut bob, alice_key, _) = pair();
+        let (content, step) = alice.encrypt_message("hi".to_string()).unwrap();
+        let header = MessageHeader { padding: Some(PADDING_VERSION + 1), ..header(step) };
+        assert!(matches!(bob.decrypt_message(content, alice_key, &header), Err(DecryptError::Header(_))));
+    }
+}
@@ -0,0 +1,99 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use nostr::prelude::*;
+use serde::{ Deserialize, Serialize };
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::relays;
+
+/// Channels fetched from the lookup relay at most, newest first.
+pub const MAX_CHANNELS: usize = 1000;
+
+/// Channels the lookup relay knows, to complete their names in /join.
+static CHANNELS: Mutex<Vec<ChannelEntry>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChannelEntry {
+    pub name: String,
+    /// Id of the channel's kind 40 event
+    pub id: String,
+}
+
+fn cache_path() -> PathBuf {
+    crate::data_dir().join("channels.json")
+}
+
+/// Fills the directory from the cache of the last session, so names complete before the relay answers.
+pub fn load() {
+    let entries: Vec<ChannelEntry> = fs::read_to_string(cache_path()).ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    *CHANNELS.lock().unwrap() = entries;
+}
+
+/// Replaces the directory with the channels `relay` has now, and caches them for the next session.
+pub async fn refresh(relay: String) {
+    let filter = Filter::new().kind(Kind::ChannelCreation).limit(MAX_CHANNELS);
+    let req = Message::Text(ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json());
+    let events = match relays::fetch_stored_events(&relay, req).await {
+        Ok(val) => val,
+        // The cached directory stays in use
+        Err(_) => return,
+    };
+    let entries: Vec<ChannelEntry> = events.iter()
+        .filter_map(|event| {
+            let metadata: Value = serde_json::from_str(event["content"].as_str()?).ok()?;
+            let name = metadata["name"].as_str()?.trim();
+            match name.is_empty() {
+                true => None,
+                false => Some(ChannelEntry { name: name.to_string(), id: event["id"].as_str()?.to_string() }),
+            }
+        })
+        .collect();
+    if let Err(why) = fs::create_dir_all(crate::data_dir()).and_then(|_| fs::write(cache_path(), serde_json::to_string(&entries).unwrap())) {
+        crate::ui::print_error(format!("Couldn't cache the channel list: {}", why));
+    }
+    *CHANNELS.lock().unwrap() = entries;
+}
+
+/// Channels whose name starts with `prefix`, ignoring case, by name.
+pub fn matching(prefix: &str) -> Vec<ChannelEntry> {
+    let prefix = prefix.to_lowercase();
+    let mut entries: Vec<ChannelEntry> = CHANNELS.lock().unwrap().iter()
+        .filter(|entry| entry.name.to_lowercase().starts_with(&prefix))
+        .cloned()
+        .collect();
+    entries.sort_by_key(|entry| entry.name.to_lowercase());
+    entries
+}
+
+/// Channel named `name`, ignoring case. The newest is taken if several share the name.
+pub fn find(name: &str) -> Option<ChannelEntry> {
+    let name = name.to_lowercase();
+    CHANNELS.lock().unwrap().iter().find(|entry| entry.name.to_lowercase() == name).cloned()
+}
+
+/// Name of the channel with the hex `id`, if the directory has it.
+pub fn name_of(id: &str) -> Option<String> {
+    CHANNELS.lock().unwrap().iter().find(|entry| entry.id == id).map(|entry| entry.name.clone())
+}
+
+/// `prefix` completed as far as the names of the channels starting with it agree.
+pub fn complete(prefix: &str) -> Option<String> {
+    let entries = matching(prefix);
+    let first = entries.first()?;
+    let common = entries.iter().fold(first.name.chars().count(), |common, entry| {
+        first.name.chars().zip(entry.name.chars())
+            .take(common)
+            .take_while(|(a, b)| a.to_lowercase().eq(b.to_lowercase()))
+            .count()
+    });
+    let completed: String = first.name.chars().take(common).collect();
+    match completed.chars().count() > prefix.chars().count() {
+        true => Some(completed),
+        false => None,
+    }
+}
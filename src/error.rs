@@ -0,0 +1,64 @@
+use std::fmt;
+
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::export::verify_event;
+
+/// What can go wrong with the connection to the relays and with what they send over it.
+#[derive(Debug)]
+pub enum Error {
+    /// Every relay of the pool has given up
+    Disconnected,
+    /// Sending to the relays failed
+    Send(String),
+    /// A relay sent something that isn't a NIP-01 message
+    Malformed(String),
+    /// A relay sent an event that is incomplete or isn't authentic
+    InvalidEvent(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Disconnected => write!(f, "Lost connection to all relays."),
+            Error::Send(why) => write!(f, "Couldn't send to the relays: {}", why),
+            Error::Malformed(why) => write!(f, "A relay sent a malformed message: {}", why),
+            Error::InvalidEvent(why) => write!(f, "A relay sent an invalid event: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(why: serde_json::Error) -> Self {
+        Error::Malformed(why.to_string())
+    }
+}
+
+/// Parses `message` from a relay. The event of an EVENT message is checked to have the fields chats read
+/// and to be authentic, so it can be used without checking it again.
+pub fn parse_relay_message(message: &Message) -> Result<Value> {
+    let text = match message {
+        Message::Text(text) => text,
+        _ => return Err(Error::Malformed("it isn't a text frame".to_string())),
+    };
+    let json_val: Value = serde_json::from_str(text)?;
+    let message_kind = json_val[0].as_str().ok_or_else(|| Error::Malformed("it doesn't start with a message type".to_string()))?;
+    if message_kind == "EVENT" {
+        check_event(&json_val[2])?;
+    }
+    Ok(json_val)
+}
+
+fn check_event(event: &Value) -> Result<()> {
+    let well_formed = event["id"].is_string() && event["pubkey"].is_string() && event["created_at"].is_u64()
+        && event["kind"].is_u64() && event["tags"].is_array() && event["content"].is_string();
+    if !well_formed {
+        return Err(Error::InvalidEvent("fields are missing or have the wrong type".to_string()));
+    }
+    verify_event(event).map_err(Error::InvalidEvent)
+}
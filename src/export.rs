@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+
+use colored::Colorize;
+use futures::future::join_all;
+use nostr::prelude::secp256k1::{ self, schnorr::Signature, Secp256k1 };
+use nostr::prelude::{ Filter, Timestamp, XOnlyPublicKey };
+use serde_json::{ json, Value };
+use sha2::{ Digest, Sha256 };
+
+use crate::chats::{ Chat, ChatType, DmMode };
+use crate::relays;
+use crate::storage::{ self, ChatStore };
+
+/// Checks that `event` is authentic: its id is the hash of its contents as defined by NIP-01, and its
+/// signature of that id by its author is valid.
+pub fn verify_event(event: &Value) -> Result<(), String> {
+    let serialized = json!([0, event["pubkey"], event["created_at"], event["kind"], event["tags"], event["content"]]).to_string();
+    let id = Sha256::digest(serialized.as_bytes());
+    if hex::encode(id) != event["id"].as_str().unwrap_or_default() {
+        return Err("id doesn't match the event's contents".to_string());
+    }
+    let public_key = XOnlyPublicKey::from_str(event["pubkey"].as_str().unwrap_or_default()).map_err(|_| "invalid public key".to_string())?;
+    let signature = Signature::from_str(event["sig"].as_str().unwrap_or_default()).map_err(|_| "invalid signature encoding".to_string())?;
+    let message = secp256k1::Message::from_slice(&id).map_err(|why| why.to_string())?;
+    Secp256k1::verification_only().verify_schnorr(&signature, &message, &public_key).map_err(|_| "signature is invalid".to_string())
+}
+
+/// Verifies every event of an export, returning the number of authentic events and the ids of the others with why.
+pub fn verify_events(events: &[Value]) -> (usize, Vec<(String, String)>) {
+    let mut valid = 0;
+    let mut invalid = Vec::new();
+    for event in events {
+        match verify_event(event) {
+            Ok(_) => valid += 1,
+            Err(why) => invalid.push((event["id"].as_str().unwrap_or_default().to_string(), why)),
+        }
+    }
+    (valid, invalid)
+}
+
+/// Writes the full history of `chat` on all `relays` to `path`, as the original signed events together
+/// with a verification report, so the transcript can be checked by anyone with a Nostr library.
+/// Returns the report.
+pub async fn export_chat(relays: &[String], chat: &ChatType, extra_kinds: &[u64], path: &Path) -> Result<String, String> {
+    let results = join_all(relays.iter().map(|relay| relays::fetch_stored_events(relay, chat.build_request_message(extra_kinds)))).await;
+
+    let mut events: HashMap<String, Value> = HashMap::new();
+    let mut lines = Vec::new();
+    for (relay, result) in relays.iter().zip(results) {
+        match result {
+            Ok(relay_events) => {
+                for event in relay_events {
+                    if let Some(id) = event["id"].as_str() {
+                        events.insert(id.to_string(), event);
+                    }
+                }
+            },
+            Err(why) => lines.push(format!("{} {}", relay.red(), why)),
+        }
+    }
+    let mut events: Vec<Value> = events.into_values().collect();
+    events.sort_by_key(|event| event["created_at"].as_u64().unwrap_or_default());
+
+    let (valid, invalid) = verify_events(&events);
+    let mut export = json!({
+        "chat": {
+            "id": chat.get_id(),
+            "name": chat.clone().get_name(),
+            "type": match chat { ChatType::PublicChannel(_) => "channel", ChatType::PrivateChat(_) => "private" },
+        },
+        "exported_at": Timestamp::now().as_u64(),
+        "relays": relays,
+        "events": events,
+        "verification": {
+            "events": events.len(),
+            "valid": valid,
+            "invalid": invalid.iter().map(|(id, why)| json!({ "id": id, "reason": why })).collect::<Vec<Value>>(),
+        },
+    });
+    // Kind 4 messages can be decrypted without any state, the ratchet's would only come out garbled
+    if let ChatType::PrivateChat(private_chat) = chat {
+        if private_chat.mode != DmMode::Ratchet {
+            let mut private_chat = private_chat.clone();
+            let mut decrypted: HashMap<String, String> = HashMap::new();
+            for event in &events {
+                let id = event["id"].as_str().unwrap_or_default().to_string();
+                let mut event = event.clone();
+                if private_chat.mode == DmMode::GiftWrap && !private_chat.open_gift_wrap(&mut event).await {
+                    continue;
+                }
+                decrypted.insert(id, private_chat.decrypt_content(&event).await);
+            }
+            export["decrypted"] = json!(decrypted);
+        }
+    }
+    fs::write(path, serde_json::to_string_pretty(&export).unwrap()).map_err(|why| why.to_string())?;
+
+    lines.push(format!("Exported {} events to {}", events.len(), path.display().to_string().green()));
+    lines.push(format!("{} have a valid id and signature", valid));
+    for (id, why) in invalid {
+        lines.push(format!("{} {}", id.red(), why));
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Re-verifies every event stored at `path`: an export, a JSON array of events, a file with one event
+/// per line, or a directory of such files. Returns the report and whether all events are authentic.
+pub fn verify_path(path: &Path) -> Result<(String, bool), String> {
+    let files = match path.is_dir() {
+        true => {
+            let mut files: Vec<PathBuf> = fs::read_dir(path).map_err(|why| why.to_string())?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_file())
+                .collect();
+            files.sort();
+            files
+        },
+        false => vec![path.to_path_buf()],
+    };
+
+    let mut lines = Vec::new();
+    let mut total = 0;
+    let mut all_valid = true;
+    for file in files {
+        let events = match read_events(&file) {
+            Ok(val) => val,
+            Err(why) => {
+                lines.push(format!("{} {}", file.display().to_string().red(), why));
+                all_valid = false;
+                continue;
+            }
+        };
+        total += events.len();
+        all_valid &= report_events(&file.display().to_string(), &events, &mut lines);
+    }
+    lines.push(format!("Checked {} events, {}", total, if all_valid { "all authentic".green() } else { "some tampered or corrupted".red() }));
+    Ok((lines.join("\n"), all_valid))
+}
+
+/// Verifies the events of every chat in the local event cache, like `verify_path`.
+pub fn verify_cache() -> (String, bool) {
+    let mut lines = Vec::new();
+    let mut total = 0;
+    let mut all_valid = true;
+    for (chat_id, _, _, _) in storage::chat_sizes() {
+        let events = ChatStore::open(&chat_id).events(&Filter::new());
+        total += events.len();
+        all_valid &= report_events(&chat_id, &events, &mut lines);
+    }
+    lines.push(format!("Checked {} events, {}", total, if all_valid { "all authentic".green() } else { "some tampered or corrupted".red() }));
+    (lines.join("\n"), all_valid)
+}
+
+/// Adds the result of verifying the `events` of `source` to `lines`. Returns whether all are authentic.
+fn report_events(source: &str, events: &[Value], lines: &mut Vec<String>) -> bool {
+    let (valid, invalid) = verify_events(events);
+    lines.push(format!("{}: {} of {} events have a valid id and signature", source.green(), valid, events.len()));
+    for (id, why) in &invalid {
+        lines.push(format!("{} {}", id.red(), why));
+    }
+    invalid.is_empty()
+}
+
+fn read_events(path: &Path) -> Result<Vec<Value>, String> {
+    let contents = fs::read_to_string(path).map_err(|why| why.to_string())?;
+    match serde_json::from_str::<Value>(&contents) {
+        Ok(Value::Array(events)) => Ok(events),
+        Ok(export) if export["events"].is_array() => Ok(export["events"].as_array().unwrap().clone()),
+        Ok(event) if event["id"].is_string() => Ok(vec![event]),
+        Ok(_) => Err("doesn't contain any events".to_string()),
+        // Not a single JSON document, so one event per line
+        Err(_) => contents.lines()
+            .filter(|line| !line.trim().is_empty())
+            .enumerate()
+            .map(|(index, line)| serde_json::from_str(line).map_err(|_| format!("line {} isn't a valid event", index + 1)))
+            .collect(),
+    }
+}
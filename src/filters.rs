@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use nostr::prelude::*;
+use serde_json::{ json, Map, Value };
+
+/// Fields of the /filter form, as typed.
+#[derive(Clone, Debug, Default)]
+pub struct FilterForm {
+    /// Kind numbers, like "1, 42"
+    pub kinds: String,
+    /// npubs or hex public keys
+    pub authors: String,
+    /// Unix timestamp, or how long ago like 30m, 2h or 7d
+    pub since: String,
+    pub until: String,
+    /// Tag filters separated by semicolons, like "t=nostr,rust; p=npub1..."
+    pub tags: String,
+    pub limit: String,
+}
+
+impl FilterForm {
+    /// The NIP-01 filter the fields describe, as the JSON sent in a REQ.
+    pub fn to_json(&self) -> Result<Value, String> {
+        let mut filter = Map::new();
+        let kinds = split(&self.kinds).iter()
+            .map(|kind| kind.parse::<u64>().map_err(|_| format!("kind {} isn't a number", kind)))
+            .collect::<Result<Vec<u64>, String>>()?;
+        if !kinds.is_empty() {
+            filter.insert("kinds".to_string(), json!(kinds));
+        }
+        let authors = split(&self.authors).iter()
+            .map(|author| crate::profiles::parse_public_key(author).map(|public_key| public_key.to_string()).ok_or_else(|| format!("author {} isn't an npub or hex public key", author)))
+            .collect::<Result<Vec<String>, String>>()?;
+        if !authors.is_empty() {
+            filter.insert("authors".to_string(), json!(authors));
+        }
+        for tag in self.tags.split(';').map(str::trim).filter(|tag| !tag.is_empty()) {
+            let (name, values) = tag.split_once('=').ok_or_else(|| format!("tag filter {} isn't like t=value", tag))?;
+            let name = name.trim().trim_start_matches('#');
+            if name.chars().count() != 1 || !name.chars().all(|c| c.is_ascii_alphabetic()) {
+                return Err(format!("tag filters are for single letter tags, {} isn't one", name));
+            }
+            // Public keys may be given as npubs, relays expect them in hex
+            let values: Vec<String> = split(values).into_iter()
+                .map(|value| match name {
+                    "p" => crate::profiles::parse_public_key(&value).map(|public_key| public_key.to_string()).unwrap_or(value),
+                    _ => value,
+                })
+                .collect();
+            filter.insert(format!("#{}", name), json!(values));
+        }
+        if let Some(since) = parse_time(&self.since)? {
+            filter.insert("since".to_string(), json!(since));
+        }
+        if let Some(until) = parse_time(&self.until)? {
+            filter.insert("until".to_string(), json!(until));
+        }
+        if !self.limit.trim().is_empty() {
+            let limit = self.limit.trim().parse::<usize>().map_err(|_| format!("limit {} isn't a number", self.limit.trim()))?;
+            filter.insert("limit".to_string(), json!(limit));
+        }
+        Ok(Value::Object(filter))
+    }
+
+    /// The filter the fields describe, checked to be one relays understand.
+    pub fn to_filter(&self) -> Result<Filter, String> {
+        Filter::from_json(self.to_json()?.to_string()).map_err(|why| why.to_string())
+    }
+}
+
+/// Items of a comma or space separated list.
+fn split(list: &str) -> Vec<String> {
+    list.split(|c: char| c == ',' || c.is_whitespace()).filter(|item| !item.is_empty()).map(str::to_string).collect()
+}
+
+/// Unix timestamp `input` stands for, given as one or as how long ago with an s, m, h or d suffix.
+fn parse_time(input: &str) -> Result<Option<u64>, String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Ok(None);
+    }
+    if let Ok(timestamp) = input.parse::<u64>() {
+        return Ok(Some(timestamp));
+    }
+    let ago = parse_duration(input).map_err(|_| format!("{} isn't a unix timestamp or like 30m, 2h or 7d", input))?;
+    Ok(Some(Timestamp::now().as_u64().saturating_sub(ago.as_secs())))
+}
+
+/// How long `input` stands for, given with an s, m, h or d suffix like 30s or 5m.
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    let unit = match input.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        _ => return Err(format!("{} isn't like 30s, 5m or 2h", input)),
+    };
+    let amount = input[.. input.len() - 1].parse::<u64>().map_err(|_| format!("{} isn't like 30s, 5m or 2h", input))?;
+    Ok(Duration::from_secs(amount * unit))
+}
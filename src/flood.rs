@@ -0,0 +1,85 @@
+use std::collections::{ HashMap, VecDeque };
+
+use serde::{ Deserialize, Serialize };
+
+/// Limit on how many messages one author may post within `window` seconds before the rest is collapsed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FloodLimit {
+    pub max_messages: usize,
+    pub window: u64,
+}
+
+/// Messages collapsed for one author, kept until they are expanded.
+#[derive(Default)]
+struct Collapsed {
+    name: String,
+    lines: Vec<String>,
+    /// Collapsed messages that haven't been announced yet
+    unannounced: usize,
+}
+
+/// Per-author flood control of a chat. It is shared with the input loop, so /expand can show what
+/// was collapsed.
+#[derive(Default)]
+pub struct FloodControl {
+    limit: Option<FloodLimit>,
+    /// Creation times of each author's messages within the window, keyed by public key
+    recent: HashMap<String, VecDeque<u64>>,
+    collapsed: HashMap<String, Collapsed>,
+}
+
+impl FloodControl {
+    pub fn new(limit: Option<FloodLimit>) -> FloodControl {
+        FloodControl { limit, ..Default::default() }
+    }
+
+    /// Records a message of `author` created at `created_at`. Returns false if it exceeds the limit
+    /// and should be collapsed.
+    pub fn admit(&mut self, author: &str, created_at: u64) -> bool {
+        let limit = match &self.limit {
+            Some(val) => val,
+            None => return true
+        };
+        let recent = self.recent.entry(author.to_string()).or_default();
+        while recent.front().is_some_and(|time| *time + limit.window < created_at) {
+            recent.pop_front();
+        }
+        recent.push_back(created_at);
+        recent.len() <= limit.max_messages
+    }
+
+    /// Keeps the formatted `line` of a message of `author`, shown as `name`, until it is expanded.
+    /// Returns a notice once enough messages piled up, so a storm is announced even while nobody else talks.
+    pub fn collapse(&mut self, author: &str, name: &str, line: String) -> Option<String> {
+        let max_messages = self.limit.as_ref().map_or(1, |limit| limit.max_messages.max(1));
+        let collapsed = self.collapsed.entry(author.to_string()).or_default();
+        collapsed.name = name.to_string();
+        collapsed.lines.push(line);
+        collapsed.unannounced += 1;
+        match collapsed.unannounced >= max_messages {
+            true => Some(announce(collapsed)),
+            false => None,
+        }
+    }
+
+    /// Notices for all collapsed messages that haven't been announced yet.
+    pub fn take_notices(&mut self) -> Vec<String> {
+        self.collapsed.values_mut().filter(|collapsed| collapsed.unannounced > 0).map(announce).collect()
+    }
+
+    /// Removes and returns the collapsed messages of authors whose shown name starts with `name`, or
+    /// of all authors if `name` is empty.
+    pub fn expand(&mut self, name: &str) -> Vec<String> {
+        let authors: Vec<String> = self.collapsed.iter()
+            .filter(|(_, collapsed)| collapsed.name.starts_with(name))
+            .map(|(author, _)| author.clone())
+            .collect();
+        authors.iter().filter_map(|author| self.collapsed.remove(author)).flat_map(|collapsed| collapsed.lines).collect()
+    }
+}
+
+fn announce(collapsed: &mut Collapsed) -> String {
+    let notice = format!("+{} more from {} (/expand {})", collapsed.unannounced, collapsed.name, collapsed.name);
+    collapsed.unannounced = 0;
+    notice
+}
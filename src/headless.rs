@@ -0,0 +1,133 @@
+use std::collections::HashSet;
+use std::io::IsTerminal;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustyline::ExternalPrinter;
+use serde_json::Value;
+use tokio::io::{ AsyncBufReadExt, BufReader };
+use tokio::time::timeout;
+
+use crate::chats::{ self, Chat, ChatPrinter, ChatType };
+use crate::rules::RulesEngine;
+use crate::signer::Signer;
+use crate::tabs::Tab;
+use crate::{ pool, ui, Config };
+
+/// How long relays get to accept a message sent with --send.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Prints the messages of a chat as plain lines on stdout, for --no-tui.
+pub struct StdoutPrinter;
+
+impl ExternalPrinter for StdoutPrinter {
+    fn print(&mut self, msg: String) -> rustyline::Result<()> {
+        println!("{}", msg);
+        Ok(())
+    }
+}
+
+impl ChatPrinter for StdoutPrinter {}
+
+/// Publishes `text` in `chat` and waits for the relays to accept it. Returns whether at least one relay
+/// accepted every event of the message.
+pub async fn send(config: &Config, signer: &Signer, mut chat: ChatType, text: String) -> bool {
+    let msgs = match chat.message_from(text, signer).await {
+        Ok(val) => val,
+        Err(why) => {
+            ui::print_error(format!("Couldn't send the message: {}", why));
+            return false;
+        }
+    };
+    let (mut writer, mut reader) = match pool::connect(&config.relays).await {
+        Ok(val) => val,
+        Err(why) => {
+            ui::print_error(why);
+            return false;
+        }
+    };
+    if let ChatType::PrivateChat(private_chat) = &chat {
+        let inboxes: Vec<String> = private_chat.inbox_relays.iter().filter(|relay| !config.relays.iter().any(|known| known.trim_end_matches('/') == relay.as_str())).cloned().collect();
+        let events = private_chat.inbox_events(&msgs);
+        if !inboxes.is_empty() && !events.is_empty() {
+            chats::deliver_to_inboxes(private_chat.name.clone(), inboxes, events).await;
+        }
+    }
+    let mut pending: HashSet<String> = HashSet::new();
+    for msg in msgs {
+        let event: Value = serde_json::from_str(&msg.to_string()).unwrap_or_default();
+        pending.insert(event[1]["id"].as_str().unwrap_or_default().to_string());
+        if let Err(why) = writer.send(msg).await {
+            ui::print_error(why);
+            return false;
+        }
+    }
+
+    let accepted = timeout(SEND_TIMEOUT, async {
+        while let Some(message) = reader.next().await {
+            let json_val: Value = serde_json::from_str(&message.to_string()).unwrap_or_default();
+            if json_val[0] != "OK" {
+                continue;
+            }
+            let event_id = json_val[1].as_str().unwrap_or_default();
+            match json_val[2].as_bool() {
+                Some(true) => {
+                    pending.remove(event_id);
+                },
+                _ => ui::print_error(format!("A relay rejected the message: {}", json_val[3].as_str().unwrap_or_default())),
+            }
+            if pending.is_empty() {
+                return true;
+            }
+        }
+        false
+    }).await;
+    match accepted {
+        Ok(true) => true,
+        Ok(false) => {
+            ui::print_error("Lost connection to the relays before they accepted the message.".to_string());
+            false
+        },
+        Err(_) => {
+            ui::print_error("No relay accepted the message in time.".to_string());
+            false
+        },
+    }
+}
+
+/// Follows `chat` without the chat screen: its messages are printed on stdout and every line read from
+/// stdin is sent. Keeps printing once stdin is closed, until interrupted.
+pub async fn follow(config: &Config, signer: &Signer, mut chat: ChatType) {
+    let (writer, reader) = match pool::connect(&config.relays).await {
+        Ok(val) => val,
+        Err(why) => {
+            ui::print_error(why);
+            return;
+        }
+    };
+    // Piped output and dumb terminals would show the escape sequences as they are
+    chats::set_hyperlinks(std::io::stdout().is_terminal() && std::env::var("TERM").map_or(true, |term| term != "dumb"));
+    let rules = Arc::new(RulesEngine::new(config.rules.clone(), config.tts.clone(), config.mention_alerts.clone()));
+    let extra_kinds: Vec<u64> = config.kind_handlers.iter().map(|handler| handler.kind).collect();
+    let tab = Tab::new(chat.clone(), config.flood_limit.clone());
+    let printing_handler = crate::new_printing_handler(config, StdoutPrinter, signer.public_key(), &tab, rules);
+    crate::moderation::watch(&config.relays, &chat, signer.public_key()).await;
+    let (mut connection, task) = crate::start_chat(&chat, writer, reader, printing_handler, &extra_kinds).await;
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match chat.message_from(line, signer).await {
+            Ok(msgs) => for msg in msgs {
+                if let Err(why) = connection.writer.send(msg).await {
+                    ui::print_error(why);
+                    return;
+                }
+            },
+            Err(why) => ui::print_error(format!("Couldn't send the message: {}", why)),
+        }
+    }
+    task.await.ok();
+}
@@ -0,0 +1,91 @@
+use futures::future::join_all;
+use nostr::prelude::*;
+use serde_json::{ json, Value };
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::export::verify_event;
+use crate::signer::Signer;
+use crate::relays;
+
+/// Kind of the NIP-51 list of joined public chats, whose e tags are the ids of our channels.
+pub const CHANNELS_KIND: u64 = 10005;
+/// Kind of the NIP-51 follow sets. The one identified by PINNED_CHATS_IDENTIFIER lists our private chats.
+pub const PEOPLE_SET_KIND: u64 = 30000;
+pub const PINNED_CHATS_IDENTIFIER: &str = "nostrachat-chats";
+
+/// A NIP-51 list of the user. Updates keep the tags and content of the latest published list, so what other
+/// clients put in it, like bookmarks of other kinds or encrypted private items, isn't lost.
+#[derive(Clone)]
+pub struct List {
+    kind: u64,
+    tags: Vec<Vec<String>>,
+    content: String,
+}
+
+impl List {
+    /// Empty list of `kind`, identified by `identifier` if it is a set.
+    pub fn new(kind: u64, identifier: Option<&str>) -> List {
+        let tags = identifier.map(|identifier| vec!["d".to_string(), identifier.to_string()]).into_iter().collect();
+        List { kind, tags, content: String::new() }
+    }
+
+    pub fn from_event(event: &Value) -> List {
+        let tags = event["tags"].as_array().map_or(Vec::new(), |tags| tags.iter()
+            .filter_map(|tag| tag.as_array().map(|values| values.iter().filter_map(|value| value.as_str().map(str::to_string)).collect()))
+            .collect());
+        List { kind: event["kind"].as_u64().unwrap_or_default(), tags, content: event["content"].as_str().unwrap_or_default().to_string() }
+    }
+
+    /// Values of the public `tag_name` items, in the order they were added.
+    pub fn values(&self, tag_name: &str) -> Vec<String> {
+        self.tags.iter()
+            .filter(|tag| tag.first().is_some_and(|name| name == tag_name))
+            .filter_map(|tag| tag.get(1).cloned())
+            .collect()
+    }
+
+    /// Adds a `tag_name` item with `value`. Returns false if it is in the list already.
+    pub fn add(&mut self, tag_name: &str, value: &str) -> bool {
+        if self.values(tag_name).iter().any(|listed| listed == value) {
+            return false;
+        }
+        self.tags.push(vec![tag_name.to_string(), value.to_string()]);
+        true
+    }
+
+    /// Removes the `tag_name` items with `value`. Returns false if there is none.
+    pub fn remove(&mut self, tag_name: &str, value: &str) -> bool {
+        let before = self.tags.len();
+        self.tags.retain(|tag| !(tag.first().is_some_and(|name| name == tag_name) && tag.get(1).is_some_and(|listed| listed == value)));
+        self.tags.len() != before
+    }
+
+    /// Event publishing the list.
+    pub async fn to_event(&self, signer: &Signer) -> Result<Event, String> {
+        let tags: Vec<Tag> = self.tags.iter().filter_map(|tag| Tag::parse(tag.clone()).ok()).collect();
+        signer.sign(EventBuilder::new(Kind::Custom(self.kind), &self.content, &tags)).await
+    }
+}
+
+/// Newest list of `kind` by `public_key` on `relays`, identified by `identifier` if it is a set. None if it
+/// was never published. Fails if no relay could be asked, as publishing an update then would replace the
+/// list with one missing the earlier items.
+pub async fn fetch(relays: &[String], public_key: XOnlyPublicKey, kind: u64, identifier: Option<&str>) -> Result<Option<List>, String> {
+    let mut filter = Filter::new().kind(Kind::Custom(kind)).author(public_key.to_string());
+    if let Some(identifier) = identifier {
+        filter.custom.insert("#d".to_string(), json!([identifier]));
+    }
+    let req = Message::Text(ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json());
+    let results = join_all(relays.iter().map(|relay| relays::fetch_stored_events(relay, req.clone()))).await;
+    if results.iter().all(|result| result.is_err()) {
+        return Err("no relay answered".to_string());
+    }
+    let newest = results.into_iter()
+        .filter_map(|result| result.ok())
+        .flatten()
+        .filter(|event| event["kind"].as_u64() == Some(kind) && event["pubkey"] == public_key.to_string().as_str())
+        .filter(|event| identifier.is_none_or(|identifier| List::from_event(event).values("d").first().is_some_and(|d| d == identifier)))
+        .filter(|event| verify_event(event).is_ok())
+        .max_by_key(|event| event["created_at"].as_u64().unwrap_or_default());
+    Ok(newest.map(|event| List::from_event(&event)))
+}
@@ -4,30 +4,125 @@ use std::fs::File;
 use std::process::exit;
 use std::env::temp_dir;
 use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, OnceLock };
+use std::time::{ Duration, Instant };
 
-use rustyline::error;
-use rustyline::validate::{ ValidationResult::Valid, ValidationResult::Invalid, ValidationContext, ValidationResult, Validator};
-use rustyline::{ Editor, Completer, Helper, Highlighter, Hinter };
-use rustyline::history::FileHistory;
-
+use clap::Parser;
+use futures::future::join_all;
 use colored::Colorize;
+use directories::ProjectDirs;
 use serde::{ Deserialize, Serialize };
 use serde_json::Value;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message, WebSocketStream};
+use tokio_tungstenite::tungstenite::protocol::Message;
 use nostr::prelude::*;
 use nostr::prelude::secp256k1::PublicKey;
 
-use futures_util::{StreamExt, SinkExt};
-use futures::stream::SplitStream;
-use futures::stream::SplitSink;
+use tokio::task::JoinHandle;
 
-use chats::{ Chat, ChatType, PrivateChat, PublicChannel };
+use app::App;
+use cache::Retention;
+use commands::Origin;
+use chats::{ Chat, ChatPrinter, ChatType, DmMode, OutgoingConfig, PrintingHandler, PrivateChat, PublicChannel };
 use crypto::{ RatchetProfile };
+use error::Error;
+use flood::FloodLimit;
+use monitor::Monitor;
+use profiles::DmScheme;
+use notifications::NotificationConfig;
+use quiet_hours::QuietHours;
+use pool::{ PoolReader, PoolWriter };
+use presence::Presence;
+use relays::{ ChatConnection, Connections };
+use rules::{ Action, Rule, RulesEngine, TtsConfig };
+use signer::{ RemoteSigner, Signer };
+use storage::ChatStore;
+use subscriptions::SUBSCRIPTIONS;
+use tabs::{ Tab, TabPrinter, Tabs };
 
 mod ascii_art;
+mod error;
+mod app;
+mod commands;
+mod contacts;
+mod directory;
+mod names;
+mod nip05;
+mod nip11;
 mod ui;
 mod crypto;
 mod chats;
+mod moderation;
+mod monitor;
+mod notifications;
+mod rules;
+mod avatar;
+mod blocklist;
+mod profiles;
+mod relays;
+mod pool;
+mod presence;
+mod quiet_hours;
+mod receipts;
+mod remote;
+mod setup;
+mod flood;
+mod lists;
+mod headless;
+mod nip44;
+mod ordering;
+mod outbox;
+mod nip49;
+mod nip59;
+mod pow;
+mod media;
+mod signer;
+mod export;
+mod filters;
+mod cache;
+mod storage;
+mod subscriptions;
+mod tabs;
+mod tail;
+mod timestamps;
+mod watch;
+
+#[derive(Parser, Debug)]
+#[clap(name = "nostrachat", about = "A terminal chat client for the Nostr protocol")]
+struct Args {
+    /// Show every outgoing event and ask for confirmation before publishing it
+    #[clap(long)]
+    dry_run: bool,
+    /// Config file to use instead of config.toml in the config directory
+    #[clap(long)]
+    config: Option<PathBuf>,
+    /// Relay to connect to instead of the configured ones, can be given several times. The first is used for lookups.
+    #[clap(long = "relay")]
+    relays: Vec<String>,
+    /// Channel to open right away, as a note1, nevent1 or hex id, instead of picking one
+    #[clap(long, conflicts_with = "dm")]
+    channel: Option<String>,
+    /// Private chat to open right away, with this npub, instead of picking one
+    #[clap(long)]
+    dm: Option<String>,
+    /// Send this message to the chat of --channel or --dm and exit once a relay accepted it
+    #[clap(long)]
+    send: Option<String>,
+    /// Print the messages of the chat of --channel or --dm as plain lines and send the lines read from stdin,
+    /// instead of opening the chat screen. Only here are pictures previewed with kitty or sixel graphics
+    #[clap(long, conflicts_with = "send")]
+    no_tui: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Re-validate ids and signatures of an exported transcript, or of the local event cache with `cache`
+    Verify {
+        target: String,
+    },
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
@@ -37,6 +132,94 @@ pub struct Config {
     chats: Vec<String>,
     privkey: String,
     pubkey: String,
+    /// bunker:// URI of a NIP-46 remote signer, which signs for us instead of privkey
+    bunker: Option<String>,
+    #[serde(default)]
+    kind_handlers: Vec<KindHandler>,
+    #[serde(default)]
+    rules: Vec<Rule>,
+    tts: Option<TtsConfig>,
+    /// Alerts for live messages mentioning us, "notify" and/or "bell"
+    #[serde(default)]
+    mention_alerts: Vec<Action>,
+    /// Render profile pictures as avatars in /whois and private chat headers
+    #[serde(default)]
+    avatars: bool,
+    /// Preview pictures linked in live messages below them: off, blocks, kitty or sixel
+    #[serde(default)]
+    image_previews: media::ImagePreviews,
+    /// Public keys allowed to run admin commands that don't come from the prompt
+    #[serde(default)]
+    admins: Vec<String>,
+    /// Public keys allowed to send commands in encrypted DMs, besides the admins
+    #[serde(default)]
+    remote_control: Vec<String>,
+    /// Show desktop notifications for replies and reactions to our events in chats that aren't open
+    #[serde(default)]
+    notify_activity: bool,
+    /// Announce that we are in the shown channel, so others see us in their presence count
+    #[serde(default)]
+    share_presence: bool,
+    /// Milliseconds live events are held to be shown in order of creation, 0 shows them as they arrive
+    #[serde(default)]
+    ordering_window: u64,
+    /// Events a relay may send for one subscription within 10 seconds before it is asked for less, 0 for no limit
+    #[serde(default = "default_overload_threshold")]
+    overload_threshold: usize,
+    /// How the time of each message is shown: 24h, 12h, relative or off
+    #[serde(default)]
+    timestamps: timestamps::TimestampFormat,
+    /// Older messages a chat fetches from the relays when it opens and on each /more, 0 fetches all of them
+    #[serde(default = "default_history_page")]
+    history_page: usize,
+    /// Try publishing to each relay when the first chat opens, to warn about those not accepting our events
+    #[serde(default)]
+    probe_writes: bool,
+    /// Relays that may have us authenticate with NIP-42 when they ask, which tells them who we are
+    #[serde(default)]
+    auth_relays: Vec<String>,
+    /// Seconds a composed message waits before it is sent, so it can be cancelled with Ctrl-Z, 0 sends right away
+    #[serde(default)]
+    undo_send: u64,
+    /// NIP-13 difficulty our messages are mined to, the highest the relays ask for in their NIP-11 documents if unset
+    pow_difficulty: Option<u8>,
+    quiet_hours: Option<QuietHours>,
+    notifications: Option<NotificationConfig>,
+    /// npubs whose notes and channel messages are followed in the Watching feed, whichever chat is open
+    #[serde(default)]
+    watch: Vec<String>,
+    #[serde(default)]
+    startup: StartupConfig,
+    flood_limit: Option<FloodLimit>,
+    retention: Option<Retention>,
+    #[serde(default)]
+    outgoing: OutgoingConfig,
+    /// Encryption used for each private chat, keyed by the contact's npub
+    #[serde(default)]
+    dm_modes: HashMap<String, DmMode>,
+}
+
+fn default_overload_threshold() -> usize {
+    1000
+}
+
+fn default_history_page() -> usize {
+    50
+}
+
+/// The [startup] config: what runs once the chat screen is up, as if typed at the prompt.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct StartupConfig {
+    /// Commands, or messages to send to the first chat, in order
+    #[serde(default)]
+    commands: Vec<String>,
+}
+
+/// Maps an event kind that isn't a chat message to a line of text shown in the chat.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct KindHandler {
+    kind: u64,
+    template: String,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -60,149 +243,608 @@ struct ThemeColors {
 
 impl Config {
     fn new() -> Config {
-         let content: String = fs::read_to_string("config.toml").unwrap();
+         let path = config_path();
+         if !path.exists() {
+             if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                 fs::create_dir_all(parent).expect("Couldn't create config directory!");
+             }
+             if let Err(why) = setup::run(&path.display().to_string()) {
+                 ui::print_error(why);
+                 exit(1);
+             }
+         }
+         let content: String = fs::read_to_string(&path).unwrap();
          let config_contents: Config = toml::from_str(&content).unwrap();
          return config_contents;
     }
 }
 
-#[derive(Completer, Helper, Highlighter, Hinter)]
-struct InputValidator {}
+/// Config file given with --config, set once at startup.
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
 
-impl Validator for InputValidator {
-    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> { 
-        let input = ctx.input();
-        let result = if input.is_empty() {
-            Invalid(Some("".to_string()))
-        } else {
-            Valid(None)
-        };
-        Ok(result) 
+/// Path of the config file: the one given with --config, else config.toml in the config directory, like
+/// $XDG_CONFIG_HOME/nostrachat. A config.toml in the working directory is still used if there is none there.
+pub fn config_path() -> PathBuf {
+    if let Some(path) = CONFIG_PATH.get() {
+        return path.clone();
+    }
+    let path = match ProjectDirs::from("", "", "nostrachat") {
+        Some(dirs) => dirs.config_dir().join("config.toml"),
+        None => PathBuf::from("config.toml"),
+    };
+    match !path.exists() && Path::new("config.toml").exists() {
+        true => PathBuf::from("config.toml"),
+        false => path,
+    }
+}
+
+/// Times the passphrase of an encrypted private key can be mistyped at startup.
+const MAX_PASSPHRASE_ATTEMPTS: usize = 3;
+
+/// Keys of the privkey in the config. An ncryptsec is decrypted with a passphrase asked for on the terminal,
+/// so the plain key is only ever held in memory.
+fn load_keys(config: &Config) -> Keys {
+    if !config.privkey.starts_with(nip49::HRP) {
+        return Keys::new(SecretKey::from_bech32(&config.privkey).unwrap());
     }
+    for _ in 0 .. MAX_PASSPHRASE_ATTEMPTS {
+        let passphrase = ui::read_secret("Passphrase of your private key:");
+        match nip49::decrypt(&config.privkey, &passphrase) {
+            Ok(secret_key) => return Keys::new(secret_key),
+            Err(why) => ui::print_error(format!("Couldn't decrypt your private key: {}", why)),
+        }
+    }
+    exit(1);
+}
+
+/// Signer of the session: the remote signer of the bunker URI in the config if there is one, else the privkey.
+async fn load_signer(config: &Config) -> Signer {
+    let uri = match &config.bunker {
+        Some(val) if !val.is_empty() => val,
+        _ => return Signer::Local(load_keys(config)),
+    };
+    ui::print("Connecting to your remote signer, it may ask you to approve nostrachat...".to_string());
+    match RemoteSigner::connect(uri).await {
+        Ok(remote) => Signer::Remote(Arc::new(remote)),
+        Err(why) => {
+            ui::print_error(format!("Couldn't connect to your remote signer: {}", why));
+            exit(1);
+        }
+    }
+}
+
+/// Directory for state persisted between sessions, created on first use.
+pub fn data_dir() -> PathBuf {
+    let dir = match ProjectDirs::from("", "", "nostrachat") {
+        Some(dirs) => dirs.data_dir().to_path_buf(),
+        None => PathBuf::from("."),
+    };
+    fs::create_dir_all(&dir).expect("Couldn't create data directory!");
+    dir
 }
 
 #[tokio::main]
 async fn main() {
 
-    let config: Config = Config::new();
-    let key_pair = Keys::new(SecretKey::from_bech32(&config.privkey).unwrap());
-    let relay = ui::select_relay(config.clone());
-    print!("{esc}[2J{esc}[1;1H", esc = 27 as char);
-    println!("Public key bech32: {}", key_pair.public_key().to_bech32().unwrap());
-    println!("Connecting to {}", relay.green());
+    let args = Args::parse();
+    if let Some(Command::Verify { target }) = &args.command {
+        let verified = match target.as_str() {
+            "cache" => Ok(export::verify_cache()),
+            _ => {
+                let path = PathBuf::from(target);
+                if !path.exists() {
+                    ui::print_error(format!("Nothing to verify at {}", path.display()));
+                    exit(2);
+                }
+                export::verify_path(&path)
+            }
+        };
+        match verified {
+            Ok((report, true)) => ui::print(report.to_string()),
+            Ok((report, false)) => {
+                ui::print(report.to_string());
+                exit(1);
+            },
+            Err(why) => {
+                ui::print_error(format!("Couldn't verify {}: {}", target, why));
+                exit(2);
+            }
+        }
+        exit(0);
+    }
+    if let Some(path) = args.config.clone() {
+        CONFIG_PATH.set(path).ok();
+    }
+    let headless = args.send.is_some() || args.no_tui;
+    if headless && args.channel.is_none() && args.dm.is_none() {
+        ui::print_error("--send and --no-tui need a chat, given with --channel or --dm".to_string());
+        exit(2);
+    }
+    let mut config: Config = Config::new();
+    pool::set_overload_threshold(config.overload_threshold);
+    subscriptions::set_history_page(config.history_page);
+    timestamps::set_format(config.timestamps);
+    media::set_previews(config.image_previews);
+    notifications::configure(config.notifications.clone());
+    let signer = load_signer(&config).await;
+    pool::set_auth(signer.clone(), &config.auth_relays);
+    let relay = match args.relays.first() {
+        Some(relay) => {
+            config.relays = args.relays.clone();
+            relay.clone()
+        },
+        None => ui::select_relay(config.clone()),
+    };
+    let requested_chat = match open_requested_chat(&config, &signer, &args).await {
+        Ok(val) => val,
+        Err(why) => {
+            ui::print_error(why);
+            exit(2);
+        }
+    };
+    if let Some(difficulty) = config.pow_difficulty {
+        pow::set_difficulty(difficulty);
+    }
+    // Headless mode sends right away, so it waits for the relays to tell the difficulty they want
+    match headless {
+        true => apply_relay_information(config.relays.clone(), config.pow_difficulty.is_none()).await,
+        false => {
+            tokio::spawn(apply_relay_information(config.relays.clone(), config.pow_difficulty.is_none()));
+        },
+    }
+    if let Some(mut chat) = requested_chat.clone().filter(|_| headless) {
+        apply_detected_dm_mode(&config, &relay, &mut chat, &mut HashMap::new(), false).await;
+        apply_outgoing_tags(&config, &mut chat);
+        start_ratchet_session(&relay, &mut chat).await;
+        apply_inbox_relays(&relay, &mut chat).await;
+        match args.send.clone() {
+            Some(text) => exit(if headless::send(&config, &signer, chat, text).await { 0 } else { 1 }),
+            None => {
+                headless::follow(&config, &signer, chat).await;
+                exit(0);
+            }
+        }
+    }
+    ui::clear();
+    ui::print(format!("Public key bech32: {}", signer.public_key().to_bech32().unwrap()));
+    ui::print(format!("Connecting to {} relays, using {} for lookups", config.relays.len(), relay.green()));
+    if matches!(config.image_previews, media::ImagePreviews::Kitty | media::ImagePreviews::Sixel) {
+        ui::print(format!("The chat screen only shows text, so pictures are previewed with colored blocks instead of {:?} graphics, which need --no-tui.", config.image_previews).truecolor(128, 128, 128).to_string());
+    }
 
-    let (socket, _response) = connect_async(&relay).await.expect("Failed to connect");
-    let (mut writer, mut reader) = socket.split();
+    let (mut writer, mut reader) = match pool::connect(&config.relays).await {
+        Ok(val) => val,
+        Err(why) => {
+            ui::print_error(format!("Couldn't connect to the relays: {}", why));
+            exit(1);
+        }
+    };
 
-    let mut rl = Editor::new().unwrap();
+    // Joined channels and pinned chats come from the NIP-51 lists shared with other clients, config.toml is
+    // only used when they can't be fetched
+    let config_chats: Vec<String> = config.chats.iter()
+        .filter(|contact_pubkey| !contact_pubkey.is_empty())
+        .map(|contact_pubkey| XOnlyPublicKey::from_bech32(contact_pubkey).unwrap().to_string())
+        .collect();
+    let channels_list = fetch_list(&config.relays, signer.public_key(), lists::CHANNELS_KIND, None, ("e", &config.channels), "joined channels").await;
+    let pinned_chats = fetch_list(&config.relays, signer.public_key(), lists::PEOPLE_SET_KIND, Some(lists::PINNED_CHATS_IDENTIFIER), ("p", &config_chats), "pinned chats").await;
+    let channel_ids = channels_list.as_ref().map_or(config.channels.clone(), |list| list.values("e"));
+    let chat_ids = pinned_chats.as_ref().map_or(config_chats, |list| list.values("p"));
 
-    let channel_list: Vec<PublicChannel> = match get_channel_list(&mut writer, &mut reader, Some(config.channels.clone())).await {
+    let channel_list: Vec<PublicChannel> = match get_channel_list(&mut writer, &mut reader, Some(channel_ids)).await {
         Ok(val) => val,
-        Err(why) => panic!("{}", why),
+        Err(why) => {
+            ui::print_error(why.to_string());
+            exit(1);
+        },
     }; 
     
-    let private_chats: Vec<PrivateChat> = config.chats.iter().map(|contact_pubkey| PrivateChat {
-        name: contact_pubkey.to_string(), // TODO: Fetch name from server somehow, like with get_channel_list
-        recipient_public_key: XOnlyPublicKey::from_bech32(contact_pubkey).unwrap(),
-        secret_key: key_pair.secret_key().unwrap(),
-        ratchet_profile: RatchetProfile::new(key_pair.secret_key().unwrap(), XOnlyPublicKey::from_bech32(contact_pubkey).unwrap().public_key(Parity::Even)),
-    }).collect();
+    let contact_list = match contacts::fetch(&config.relays, signer.public_key()).await {
+        Ok(val) => Some(val),
+        Err(why) => {
+            ui::print_error(format!("Couldn't fetch your contact list: {}", why));
+            None
+        }
+    };
+    // Pinned chats first, then the contacts followed in other clients too
+    let mut contact_keys: Vec<XOnlyPublicKey> = chat_ids.iter()
+        .filter_map(|contact| profiles::parse_public_key(contact))
+        .collect();
+    for followed in contact_list.iter().flat_map(|list| list.public_keys()) {
+        if !contact_keys.contains(&followed) {
+            contact_keys.push(followed);
+        }
+    }
+    // Resolved before the chats are listed, so they are shown with their names
+    names::load();
+    receipts::load();
+    blocklist::load();
+    outbox::load();
+    storage::load_last_read();
+    names::resolve(&relay, contact_keys.iter().map(|contact| contact.to_string()).collect()).await;
+    let private_chats: Vec<PrivateChat> = contact_keys.into_iter()
+        .map(|contact| new_private_chat(&config, &signer, contact))
+        .collect();
     
     // Clears terminal and sets cursor to the start
-    print!("{esc}[2J{esc}[1;1H", esc = 27 as char);
-
-    let mut chat = match ui::select_chat(config.clone(), channel_list.clone(), private_chats.clone()) {
-        Some(val) => {
-            val
-        }, 
-        None => {
-            ChatType::PublicChannel(ui::select_unknown_channel(config.clone(), get_channel_list(&mut writer, &mut reader, None).await.unwrap()))
-        }
+    ui::clear();
+
+    let mut chat = match requested_chat {
+        Some(val) => val,
+        None => match ui::select_chat(config.clone(), channel_list.clone(), private_chats.clone(), unread_counts(&channel_list, &private_chats, &signer.public_key().to_string())) {
+            Some(val) => {
+                val
+            }, 
+            None => {
+                let all_channels = match get_channel_list(&mut writer, &mut reader, None).await {
+                    Ok(val) => val,
+                    Err(why) => {
+                        ui::print_error(why.to_string());
+                        exit(1);
+                    },
+                };
+                ChatType::PublicChannel(ui::select_unknown_channel(config.clone(), all_channels))
+            }
+        },
     };
 
     //print_channel_info(&relay, &channel); TODO: Print channel/chat info.
 
-    let pubkeys_to_colors: HashMap<String, u8> = HashMap::new();
-    let printing_handler = {
-        chats::PrintingHandler {
-            printer: rl.create_external_printer().unwrap(),
-            pubkeys_to_colors: pubkeys_to_colors,
-            public_key: key_pair.public_key(),
+    let mut known_chats: Vec<ChatType> = channel_list.iter().map(|channel| ChatType::PublicChannel(channel.clone())).collect();
+    known_chats.extend(private_chats.iter().map(|private_chat| ChatType::PrivateChat(private_chat.clone())));
+    for known in known_chats.iter_mut() {
+        apply_outgoing_tags(&config, known);
+    }
+    if !known_chats.iter().any(|known| known.get_id() == chat.get_id()) {
+        known_chats.push(chat.clone());
+    }
+
+    let screen = ui::ChatScreen::open(&config);
+    let rules = Arc::new(RulesEngine::new(config.rules.clone(), config.tts.clone(), config.mention_alerts.clone()));
+    directory::load();
+    tokio::spawn(directory::refresh(relay.clone()));
+    tokio::spawn(names::resolve_pending(relay.clone()));
+    let monitor = Monitor::new(chat.get_id(), config.notify_activity);
+    let presence = Presence::new(&chat, config.share_presence);
+    tokio::spawn(presence.clone().run(config.relays.clone(), signer.clone()));
+    tokio::spawn(monitor.clone().run(relay.clone(), known_chats.clone(), signer.public_key(), rules.clone(), config.quiet_hours.clone(), screen.printer()));
+    let watched: Vec<XOnlyPublicKey> = config.watch.iter().filter_map(|npub| {
+        let public_key = profiles::parse_public_key(npub);
+        if public_key.is_none() {
+            ui::print_error(format!("{} in watch isn't an npub or hex public key.", npub));
         }
-    };
+        public_key
+    }).collect();
+    if !watched.is_empty() {
+        tokio::spawn(watch::run(config.relays.clone(), watched, screen.printer()));
+    }
 
-    writer.send(chat.build_request_message()).await.expect("Couldn't write message to websocket!");
-    let ws_to_stdout = chat.clone().print_incoming_events(printing_handler, reader);
+    let extra_kinds: Vec<u64> = config.kind_handlers.iter().map(|handler| handler.kind).collect();
+    let mut detected_dm_modes: HashMap<String, DmMode> = HashMap::new();
+    apply_detected_dm_mode(&config, &relay, &mut chat, &mut detected_dm_modes, false).await;
+    apply_outgoing_tags(&config, &mut chat);
+    start_ratchet_session(&relay, &mut chat).await;
+    apply_inbox_relays(&relay, &mut chat).await;
+    let mut tabs = Tabs::new();
+    let index = tabs.add(Tab::new(chat.clone(), config.flood_limit.clone()));
+    let printing_handler = tab_printing_handler(&config, signer.public_key(), rules.clone(), &tabs, index);
+    moderation::watch(&config.relays, &chat, signer.public_key()).await;
+    let (chat_connection, chat_task) = start_chat(&chat, writer, reader, printing_handler, &extra_kinds).await;
+    if config.probe_writes {
+        tokio::spawn(relays::probe_writes(config.relays.clone(), signer.clone()));
+    }
+    tabs.tabs[index].task = Some(chat_task);
+    let connections: Connections = Arc::new(tokio::sync::Mutex::new(HashMap::from([(chat.get_id(), chat_connection)])));
+    tabs.activate(index, &screen);
+    if let Some(hours) = config.quiet_hours.clone() {
+        tokio::spawn(quiet_hours::enforce(hours, connections.clone(), screen.printer()));
+    }
+    // Published on every start, so contacts can begin ratchet sessions with us. The ratchet needs our private key.
+    if let Some(secret_key) = signer.secret_key() {
+        let prekey = crypto::load_or_create_prekey(&secret_key);
+        let prekey_event = EventBuilder::new(Kind::Custom(crypto::PREKEY_KIND), prekey.x_only_public_key(&Secp256k1::new()).0.to_string(), &[]).to_event(&Keys::new(secret_key)).unwrap();
+        if let Err(why) = connections.lock().await.get_mut(&chat.get_id()).unwrap().writer.send(Message::Text(ClientMessage::new_event(prekey_event).as_json())).await {
+            ui::print_error(format!("Couldn't publish prekey: {}", why));
+        }
+    }
+    if let Some(retention) = config.retention.clone() {
+        tokio::spawn(cache::prune_periodically(retention, screen.printer()));
+    }
 
-    tokio::spawn(ws_to_stdout);
+    let preview_mode = args.dry_run;
+    screen.set_status(chat_status(&chat, &relay, preview_mode, &presence));
+    print_chat_header(&config, &relay, &chat).await;
     
-    loop {
-        let input = prompt(key_pair.public_key().to_bech32().unwrap()[4 .. 10].to_string(), &mut rl);
+    let (remote_tx, mut remote_rx) = tokio::sync::mpsc::unbounded_channel();
+    let controllers: Vec<XOnlyPublicKey> = config.admins.iter().chain(config.remote_control.iter())
+        .filter_map(|controller| profiles::parse_public_key(controller))
+        .collect();
+    if !controllers.is_empty() {
+        ui::print(format!("Taking commands in DMs from {} keys", controllers.len()));
+        tokio::spawn(remote::listen(config.relays.clone(), signer.clone(), controllers, remote_tx));
+    }
 
-        match input.as_str() {
-            "/help" => {
-                let help_text = "/help		- Prints this help message\n/editor		- Opens a text editor to type your message out\n/channelinfo       - Shows metadata about the current channel\n/exit		- Quits Nostrachat\n";
-                println!("{}", help_text.truecolor(128, 128, 128));
-            },
-            "/exit" => {
-                println!("Goodbye!");
-                exit(0);
-            },
-            "/editor" => {
-                let msg = chat.message_from(editor().expect("Couldn't open editor!"), key_pair.secret_key().unwrap());
-                writer.send(msg).await.expect("Couldn't sent message over websocket!");
-//              writer.send(channel_event(editor().expect("Couldn't open the editor."), channel)).await.expect("Impossible to send message");
-            },
-            "/channelinfo" => {
-                println!("{}", chat.get_info_table(&relay));
-            },
-            &_ => {
-                if &input[0 .. 1] == "/" {
-                    eprintln!("Command not found! Get all commands with /help");
-                    continue;
-                }
-                //writer.send(private_event(input, KeyPair::from_secret_key(&chat.secret_key), chat.recipient_public_key)).await.expect("Impossible to send message");
-                let msg = chat.message_from(input, key_pair.secret_key().unwrap());
-                writer.send(msg).await.expect("Couldn't sent message over websocket!");
-            }
+    let mut app = App {
+        config,
+        signer,
+        relay,
+        screen,
+        tabs,
+        connections,
+        chat,
+        known_chats,
+        detected_dm_modes,
+        monitor,
+        presence,
+        rules,
+        extra_kinds,
+        preview_mode,
+        started: Instant::now(),
+        contacts: contact_list,
+        channels_list,
+        pinned_chats,
+    };
+    for line in app.config.startup.commands.clone() {
+        ui::print(format!("Startup: {}", line).truecolor(128, 128, 128).to_string());
+        commands::dispatch(&mut app, line, Origin::Local).await;
+    }
+    let mut status_refresh = tokio::time::interval(presence::STATUS_REFRESH);
+    loop {
+        tokio::select! {
+            input = app.screen.next_line() => commands::dispatch(&mut app, input, Origin::Local).await,
+            Some(command) = remote_rx.recv() => remote::run(&mut app, command).await,
+            // Keeps the presence count up to date
+            _ = status_refresh.tick() => app.update_status(),
         }
     }
 }
 
-fn prompt(name: String, rl: &mut Editor<InputValidator, FileHistory>) -> String {
-    if rl.load_history("history.txt").is_err() {
-        println!("No previous history.");
-    } 
-    let validator_for_empty_input = InputValidator { };
-    rl.set_helper(Some(validator_for_empty_input));
-    let readline = rl.readline(&format!("[{}] ", name.green()));
-      return match readline {
-        Ok(line) => { 
-            rl.add_history_entry(line.as_str()).unwrap();
-                rl.save_history("history.txt").unwrap();
-                line
+fn new_printing_handler<T: ChatPrinter>(config: &Config, printer: T, public_key: XOnlyPublicKey, tab: &Tab, rules: Arc<RulesEngine>) -> PrintingHandler<T> {
+    PrintingHandler {
+        printer,
+        pubkeys_to_colors: HashMap::new(),
+        public_key,
+        kind_templates: config.kind_handlers.iter().map(|handler| (handler.kind, handler.template.clone())).collect(),
+        rules,
+        chat_id: tab.chat.get_id(),
+        chat_name: tab.chat.clone().get_name(),
+        flood_control: tab.flood_control.clone(),
+        hidden_kinds: tab.hidden_kinds.clone(),
+        store: ChatStore::open(&tab.chat.get_id()),
+        scrollback: tab.scrollback.clone(),
+        ordering_window: Duration::from_millis(config.ordering_window),
+    }
+}
+
+/// Printing handler of the tab at `index`, printing into the tab's buffer.
+fn tab_printing_handler(config: &Config, public_key: XOnlyPublicKey, rules: Arc<RulesEngine>, tabs: &Tabs, index: usize) -> PrintingHandler<TabPrinter> {
+    new_printing_handler(config, tabs.printer(index), public_key, &tabs.tabs[index], rules)
+}
+
+/// Takes what the relays ask for from their NIP-11 documents: the subscription limits, and the proof of work
+/// difficulty if `pow` as it isn't configured.
+async fn apply_relay_information(relays: Vec<String>, pow: bool) {
+    let infos = nip11::fetch_all(&relays).await;
+    SUBSCRIPTIONS.lock().unwrap().set_limits(&infos);
+    if pow {
+        pow::discover(&infos);
+    }
+}
+
+/// Subscribes to `chat` over `writer` and spawns the task printing its events from `reader`.
+async fn start_chat<T: ChatPrinter + Send + Sync + 'static>(chat: &ChatType, mut writer: PoolWriter, reader: PoolReader, printing_handler: PrintingHandler<T>, extra_kinds: &[u64]) -> (ChatConnection, JoinHandle<()>) {
+    let (subscription_id, closes) = SUBSCRIPTIONS.lock().unwrap().open(&chat.get_id(), &writer.relays());
+    for close in closes {
+        writer.send(close).await.ok();
+    }
+    let filter = chat.build_filter(extra_kinds);
+    // Only what is newer than the cache is fetched. Gift wraps are backdated, so they are looked for further back.
+    let cached = printing_handler.store.events(&filter);
+    let backdate = match chat {
+        ChatType::PrivateChat(private_chat) if private_chat.mode == DmMode::GiftWrap => nip59::MAX_BACKDATE,
+        _ => 0,
+    };
+    let mut request_filter = match cached.last().and_then(|event| event["created_at"].as_u64()) {
+        Some(latest) => filter.clone().since(Timestamp::from(latest.saturating_sub(backdate))),
+        None => filter.clone(),
+    };
+    // Busy channels have a long history, older pages are fetched with /more
+    if subscriptions::history_page() > 0 {
+        request_filter = request_filter.limit(subscriptions::history_page());
+    }
+    let req = ClientMessage::new_req(subscription_id.clone(), vec![request_filter]).as_json();
+    writer.send(Message::Text(req)).await.expect("Couldn't write message to websocket!");
+    let task = tokio::spawn(chat.clone().print_incoming_events(printing_handler, reader, cached));
+    let connection = ChatConnection {
+        writer,
+        subscription_id,
+        filter,
+        is_channel: matches!(chat, ChatType::PublicChannel(_)),
+        suspended_since: None,
+    };
+    (connection, task)
+}
+
+/// Unread messages of `channels` and `private_chats` in the local store, by chat id.
+fn unread_counts(channels: &[PublicChannel], private_chats: &[PrivateChat], public_key: &str) -> HashMap<String, usize> {
+    channels.iter().map(|channel| channel.get_id())
+        .chain(private_chats.iter().map(|private_chat| private_chat.get_id()))
+        .map(|chat_id| {
+            let count = storage::unread(&chat_id, public_key);
+            (chat_id, count)
+        })
+        .collect()
+}
+
+/// Private chat with `contact`, in the DM mode configured for them.
+fn new_private_chat(config: &Config, signer: &Signer, contact: XOnlyPublicKey) -> PrivateChat {
+    let npub = contact.to_bech32().unwrap();
+    // Without our private key the ratchet can't be used, a throwaway key keeps the chat working in other modes
+    let ratchet_key = signer.secret_key().unwrap_or_else(|| SecretKey::new(&mut rand::thread_rng()));
+    PrivateChat {
+        name: names::cached(&contact.to_string()).unwrap_or(npub.clone()),
+        recipient_public_key: contact,
+        signer: signer.clone(),
+        ratchet_profile: RatchetProfile::new(ratchet_key, contact.public_key(Parity::Even)),
+        mode: config.dm_modes.get(&npub).copied().unwrap_or_default(),
+        inbox_relays: Vec::new(),
+    }
+}
+
+/// Sets the tags configured for the messages sent to a channel.
+fn apply_outgoing_tags(config: &Config, chat: &mut ChatType) {
+    if let ChatType::PublicChannel(channel) = chat {
+        channel.outgoing = config.outgoing.for_channel(channel);
+    }
+}
+
+/// Sets the DM mode of a private chat that has none configured, or any chat if `force` is set, to the
+/// scheme the contact is seen using. Detected modes are kept in `detected` for the rest of the session.
+async fn apply_detected_dm_mode(config: &Config, relay: &str, chat: &mut ChatType, detected: &mut HashMap<String, DmMode>, force: bool) {
+    let private_chat = match chat {
+        ChatType::PrivateChat(val) => val,
+        ChatType::PublicChannel(_) => return,
+    };
+    let npub = private_chat.recipient_public_key.to_bech32().unwrap();
+    if config.dm_modes.contains_key(&npub) && !force {
+        return;
+    }
+    if let Some(mode) = detected.get(&npub) {
+        private_chat.mode = *mode;
+        return;
+    }
+    let mode = match profiles::detect_dm_scheme(relay, private_chat.recipient_public_key).await {
+        Some(DmScheme::Ratchet) => DmMode::Ratchet,
+        Some(DmScheme::Nip04) => DmMode::Nip04,
+        Some(DmScheme::Nip44) => DmMode::Nip44,
+        Some(DmScheme::GiftWrap) => DmMode::GiftWrap,
+        None => DmMode::default(),
+    };
+    ui::print(format!("Using {:?} mode for this chat, override it with /dmmode or dm_modes in config.toml.", mode));
+    private_chat.mode = mode;
+    detected.insert(npub, mode);
+}
+
+/// Starts a fresh ratchet session with the contact of a private chat in ratchet mode that hasn't
+/// exchanged any messages yet, using the prekey they published.
+async fn start_ratchet_session(relay: &str, chat: &mut ChatType) {
+    let private_chat = match chat {
+        ChatType::PrivateChat(val) if val.mode == DmMode::Ratchet => val,
+        _ => return,
+    };
+    if !private_chat.ratchet_profile.needs_session() {
+        return;
+    }
+    match profiles::fetch_prekey(relay, private_chat.recipient_public_key).await {
+        Some(prekey) => private_chat.ratchet_profile.initiate_session(prekey),
+        None => ui::print("This contact hasn't published a prekey, so the session starts from your identity keys only.".yellow().to_string()),
+    }
+}
+
+/// Looks up the relays the contact of a private chat reads from, so messages are published to them too.
+async fn apply_inbox_relays(relay: &str, chat: &mut ChatType) {
+    if let ChatType::PrivateChat(private_chat) = chat {
+        private_chat.inbox_relays = profiles::fetch_inbox_relays(relay, private_chat.recipient_public_key).await;
+    }
+}
+
+/// Status bar line of the chat screen: the chat, its DM mode, the lookup relay and whether previews are on.
+fn chat_status(chat: &ChatType, relay: &str, preview_mode: bool, presence: &Presence) -> String {
+    let mut status = format!(" {}", chat.clone().get_name());
+    if let ChatType::PrivateChat(private_chat) = chat {
+        status += &format!(" | {:?}", private_chat.mode);
+    }
+    // Ephemeral events only reach who is listening, so the count is a guess
+    if let Some(here_now) = presence.here_now().filter(|here_now| *here_now > 0) {
+        status += &format!(" | ~{} here now", here_now);
+    }
+    status += &format!(" | {}", relay);
+    if preview_mode {
+        status += " | preview";
+    }
+    status
+}
+
+/// Avatar of a user: their rendered profile picture if avatars are enabled, their identicon otherwise.
+async fn get_avatar(config: &Config, public_key: &XOnlyPublicKey, metadata: Option<&Metadata>) -> String {
+    let picture = match config.avatars {
+        true => metadata.and_then(|metadata| metadata.picture.as_deref()),
+        false => None,
+    };
+    avatar::render_or_identicon(public_key, picture).await
+}
+
+/// Shows who a private chat is with, including their avatar if enabled. Public channels have no header.
+async fn print_chat_header(config: &Config, relay: &str, chat: &ChatType) {
+    let private_chat = match chat {
+        ChatType::PrivateChat(val) => val,
+        ChatType::PublicChannel(_) => return,
+    };
+    let metadata = profiles::fetch_metadata(relay, private_chat.recipient_public_key).await;
+    ui::print(get_avatar(config, &private_chat.recipient_public_key, metadata.as_ref()).await.to_string());
+    let name = metadata.and_then(|metadata| metadata.display_name.or(metadata.name)).unwrap_or(private_chat.name.clone());
+    ui::print(format!("Private chat with {}", name.green()));
+}
+
+/// Opens fresh connections to all `relays` and starts `chat` on them.
+async fn connect_chat<T: ChatPrinter + Send + Sync + 'static>(relays: &[String], chat: &ChatType, printing_handler: PrintingHandler<T>, extra_kinds: &[u64]) -> Result<(ChatConnection, JoinHandle<()>), String> {
+    let (writer, reader) = pool::connect(relays).await?;
+    moderation::watch(relays, chat, printing_handler.public_key).await;
+    Ok(start_chat(chat, writer, reader, printing_handler, extra_kinds).await)
+}
+
+/// Message shown in the chat screen whose id starts with the first word of `argument`, and the rest of `argument`.
+fn picked_message<'a>(screen: &ui::ChatScreen, argument: &'a str) -> Option<(Value, &'a str)> {
+    let (id, rest) = argument.split_once(' ').unwrap_or((argument, ""));
+    screen.find_message(id).map(|event| (event, rest.trim()))
+}
+
+/// Prints the signed events carried by `msgs` and asks whether they should be published.
+async fn confirm_events(msgs: &[Message], screen: &mut ui::ChatScreen) -> bool {
+    for msg in msgs {
+        let client_msg: Value = match serde_json::from_str(&msg.to_string()) {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(format!("Couldn't read outgoing message: {}", why));
+                return false;
+            }
+        };
+        let event = match Event::from_value(client_msg[1].clone()) {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(format!("Outgoing message doesn't carry a valid event: {}", why));
+                return false;
             }
+        };
 
-        Err(err) => match err {
-            error::ReadlineError::Interrupted => {
-                println!("Goodbye!");
-                exit(2);
-            },
-            error::ReadlineError::Eof => {
-                println!("Goodbye!");
-                exit(0);
+        ui::print(serde_json::to_string_pretty(&client_msg[1]).unwrap().to_string());
+        ui::print(format!("{} {}", "Kind:".green(), event.kind.as_u64()));
+        for tag in event.tags.iter() {
+            ui::print(format!("{} {:?}", "Tag:".green(), tag.as_vec()));
+        }
+        ui::print(format!("{} {} bytes", "Size:".green(), msg.len()));
+        ui::print(format!("{} {} leading zero bits", "PoW:".green(), get_leading_zero_bits(event.id.as_bytes())));
+    }
+
+    let question = if msgs.len() == 1 { "Publish this event? [y/n]".to_string() } else { format!("Publish these {} events? [y/n]", msgs.len()) };
+    loop {
+        ui::print(question.clone());
+        match screen.next_line().await.trim() {
+            "y" | "Y" | "yes" => return true,
+            "n" | "N" | "no" => {
+                ui::print("Discarded.".to_string());
+                return false;
             },
-            _ => {
-                panic!("Error {:?}", err);
-            }
+            _ => continue,
         }
-    };
+    }
 }
 
-fn editor() -> Result<String> {
+/// Opens `initial` in the default editor and returns the text it was edited to.
+fn editor(initial: &str) -> Result<String> {
    let mut temporary_file = temp_dir();
    temporary_file.push("nostrachat-buffer.txt");
-   fs::write(&temporary_file, b"*Type out your message here*")?;
+   fs::write(&temporary_file, initial.as_bytes())?;
 
    open::that(&temporary_file)?;
    let mut file = File::open(temporary_file.as_path())?;
@@ -211,49 +853,159 @@ fn editor() -> Result<String> {
    return Ok(content);
 }
 
-async fn get_channel_list(writer: &mut SplitSink<WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>, tokio_tungstenite::tungstenite::Message>, reader: &mut SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>>, ids: Option<Vec<String>>) -> Result<Vec<PublicChannel>> {
+/// Id of the event `input` points to as a note1, nevent1 or hex id, and the relays an nevent names.
+fn parse_event_pointer(input: &str) -> Option<(EventId, Vec<String>)> {
+    if let Ok(pointer) = Nip19Event::from_bech32(input) {
+        // The fields of a parsed nevent are only reachable through its serialization
+        let pointer = serde_json::to_value(pointer).ok()?;
+        let event_id = EventId::from_hex(pointer["event_id"].as_str()?).ok()?;
+        let relays = pointer["relays"].as_array()?.iter().filter_map(|relay| relay.as_str().map(str::to_string)).collect();
+        return Some((event_id, relays));
+    }
+    EventId::from_bech32(input).or_else(|_| EventId::from_hex(input)).ok().map(|event_id| (event_id, Vec::new()))
+}
+
+/// Chat given with --channel or --dm, None if neither was given.
+async fn open_requested_chat(config: &Config, signer: &Signer, args: &Args) -> std::result::Result<Option<ChatType>, String> {
+    let chat = match (&args.channel, &args.dm) {
+        (Some(channel), _) => {
+            let (event_id, mut lookup_relays) = parse_event_pointer(channel).ok_or(format!("{} isn't a note1, nevent1 or hex id", channel))?;
+            lookup_relays.extend(config.relays.iter().filter(|relay| !lookup_relays.contains(relay)).cloned().collect::<Vec<String>>());
+            ChatType::PublicChannel(fetch_channel(&lookup_relays, event_id).await.map_err(|why| format!("Couldn't open the channel: {}", why))?)
+        },
+        (None, Some(npub)) => {
+            let contact = profiles::parse_public_key(npub).ok_or(format!("{} isn't a valid npub", npub))?;
+            ChatType::PrivateChat(new_private_chat(config, signer, contact))
+        },
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(chat))
+}
+
+/// Fetches the kind 40 event creating the channel `event_id` from the first of `relays` that has it.
+async fn fetch_channel(relays: &[String], event_id: EventId) -> std::result::Result<PublicChannel, String> {
+    let filter = Filter::new().id(event_id.to_hex()).kind(Kind::ChannelCreation);
+    let req = Message::Text(ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json());
+    let results = join_all(relays.iter().map(|relay| relays::fetch_stored_events(relay, req.clone()))).await;
+    let root_event = results.into_iter()
+        .filter_map(|result| result.ok())
+        .flatten()
+        .find(|event| event["id"].as_str() == Some(&event_id.to_hex()) && export::verify_event(event).is_ok())
+        .ok_or("no relay has a channel with this id".to_string())?;
+    let metadata = Metadata::from_json(root_event["content"].as_str().unwrap_or_default()).map_err(|why| format!("the channel's metadata is invalid: {}", why))?;
+    let root_event = Event::from_value(root_event).map_err(|why| why.to_string())?;
+    let mut channel = PublicChannel::new(root_event, metadata);
+
+    let filter = PublicChannel::metadata_updates_filter(&[channel.clone()]);
+    let req = Message::Text(ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json());
+    let updates: Vec<Value> = join_all(relays.iter().map(|relay| relays::fetch_stored_events(relay, req.clone()))).await
+        .into_iter()
+        .filter_map(|result| result.ok())
+        .flatten()
+        .collect();
+    channel.apply_metadata_updates(&updates);
+    Ok(channel)
+}
+
+/// The user's NIP-51 list of `kind`, identified by `identifier` if it is a set. One that was never published
+/// starts with the `fallback` values of its tag from config.toml, so they are kept once it is. None if it
+/// couldn't be fetched, so it isn't replaced by updates missing what it has.
+async fn fetch_list(relays: &[String], public_key: XOnlyPublicKey, kind: u64, identifier: Option<&str>, fallback: (&str, &[String]), what: &str) -> Option<lists::List> {
+    match lists::fetch(relays, public_key, kind, identifier).await {
+        Ok(Some(list)) => Some(list),
+        Ok(None) => {
+            let (tag_name, values) = fallback;
+            let mut list = lists::List::new(kind, identifier);
+            for value in values {
+                list.add(tag_name, value);
+            }
+            Some(list)
+        },
+        Err(why) => {
+            ui::print_error(format!("Couldn't fetch your {}, using those in config.toml: {}", what, why));
+            None
+        }
+    }
+}
+
+/// Channels found on the relays, all of them or those of `ids`. Events that don't make a channel are skipped.
+async fn get_channel_list(writer: &mut PoolWriter, reader: &mut PoolReader, ids: Option<Vec<String>>) -> error::Result<Vec<PublicChannel>> {
    let mut list: Vec<PublicChannel> = Vec::new();
    let mut filter = Filter::default();
    filter.kinds = Some(vec![Kind::Custom(40)]);
-   filter.ids = match ids {
-        Some(val) => Some(val),
-        None => None,
-   };
+   filter.ids = ids;
    let req = ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json();
-   writer.send(Message::Text(req.clone())).await.expect("Error");
+   writer.send(Message::Text(req.clone())).await.map_err(Error::Send)?;
 
     loop {
-        let event_text = reader.next().await.unwrap().unwrap().to_string();
-
-        let json_val: Value = match serde_json::from_str(&event_text) {
+        let message = reader.next().await.ok_or(Error::Disconnected)?;
+        let json_val = match error::parse_relay_message(&message) {
             Ok(val) => val,
             Err(why) => {
-                eprintln!("Faulty JSON: {}", why);
+                ui::print_error(why.to_string());
                 continue;
             }
         };
 
-        match json_val[0].as_str().unwrap() {
+        match json_val[0].as_str().unwrap_or_default() {
             "EOSE" => {
                 break;
             },
+            "EVENT" => { },
             "NOTICE" => {
-                println!("NOTICE: {:?}", &json_val);
-                break;
+                // Another relay may still deliver the list
+                ui::print(format!("NOTICE: {:?}", &json_val));
+                continue;
             }
-            &_ => { }
+            &_ => continue,
         }
 
-        let event = Event::from_json(&json_val[2].to_string()).unwrap();
-        let metadata = match Metadata::from_json(json_val[2]["content"].as_str().unwrap()) {
+        let event = match Event::from_value(json_val[2].clone()) {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(Error::InvalidEvent(why.to_string()).to_string());
+                continue;
+            }
+        };
+        let metadata = match Metadata::from_json(&event.content) {
             Ok(val) => val, 
             Err(error) => {
-                eprintln!("Poorly formatted event. {}", error);
+                ui::print_error(format!("Poorly formatted event. {}", error));
                 continue;
             }
         };
         
-        list.push(PublicChannel { root_event: event, metadata: metadata });
+        list.push(PublicChannel::new(event, metadata));
+   }
+
+   // Renamed channels show their current name
+   if !list.is_empty() {
+       let req = ClientMessage::new_req(SubscriptionId::generate(), vec![PublicChannel::metadata_updates_filter(&list)]).as_json();
+       writer.send(Message::Text(req)).await.map_err(Error::Send)?;
+       let updates = read_stored_events(reader).await?;
+       for channel in list.iter_mut() {
+           channel.apply_metadata_updates(&updates);
+       }
    }
    return Ok(list);
 }
+
+/// Events `reader` delivers until the end of stored events. Malformed messages and invalid events are skipped.
+async fn read_stored_events(reader: &mut PoolReader) -> error::Result<Vec<Value>> {
+    let mut events = Vec::new();
+    loop {
+        let message = reader.next().await.ok_or(Error::Disconnected)?;
+        let json_val = match error::parse_relay_message(&message) {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(why.to_string());
+                continue;
+            }
+        };
+        match json_val[0].as_str() {
+            Some("EOSE") => return Ok(events),
+            Some("EVENT") => events.push(json_val[2].clone()),
+            _ => continue,
+        }
+    }
+}
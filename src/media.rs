@@ -0,0 +1,150 @@
+use std::io::Cursor;
+use std::sync::Mutex;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use colored::Colorize;
+use image::{ DynamicImage, ImageFormat };
+use image::imageops::FilterType;
+use nostr::prelude::Url;
+use serde::{ Deserialize, Serialize };
+use serde_json::Value;
+
+use crate::{ avatar, chats, ui };
+
+/// Whether and how pictures linked in live messages are shown below them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImagePreviews {
+    #[default]
+    Off,
+    /// Colored half blocks, which every terminal with true color shows
+    Blocks,
+    /// The kitty graphics protocol, used by kitty, WezTerm and Konsole. Only with --no-tui, the chat screen uses blocks
+    Kitty,
+    /// Sixel graphics, used by xterm -ti vt340, foot, mlterm and others. Only with --no-tui, the chat screen uses blocks
+    Sixel,
+}
+
+static PREVIEWS: Mutex<ImagePreviews> = Mutex::new(ImagePreviews::Off);
+
+pub fn set_previews(previews: ImagePreviews) {
+    *PREVIEWS.lock().unwrap() = previews;
+}
+
+/// File extensions of the links previewed.
+const IMAGE_EXTENSIONS: [&str; 5] = [".png", ".jpg", ".jpeg", ".gif", ".webp"];
+/// Pictures previewed of a single message at most.
+const MAX_PREVIEWS: usize = 3;
+/// Columns and text rows half block previews fit in.
+const BLOCKS_WIDTH: u32 = 48;
+const BLOCKS_ROWS: u32 = 16;
+/// Pixels kitty and sixel previews fit in.
+const GRAPHICS_WIDTH: u32 = 320;
+const GRAPHICS_HEIGHT: u32 = 240;
+/// Base64 characters sent in one kitty graphics escape, the most the protocol allows.
+const KITTY_CHUNK: usize = 4096;
+
+/// Links of `content` to pictures, by the extension of their path.
+pub fn image_links(content: &str) -> Vec<String> {
+    chats::links(content).into_iter()
+        .filter(|link| Url::parse(link).is_ok_and(|url| {
+            let path = url.path().to_lowercase();
+            IMAGE_EXTENSIONS.iter().any(|extension| path.ends_with(extension))
+        }))
+        .collect()
+}
+
+/// Downloads the pictures linked in the content of `event` and prints a preview of each, if previews are on.
+pub fn show(event: &Value) {
+    let previews = *PREVIEWS.lock().unwrap();
+    if previews == ImagePreviews::Off {
+        return;
+    }
+    for link in image_links(event["content"].as_str().unwrap_or_default()).into_iter().take(MAX_PREVIEWS) {
+        tokio::spawn(async move {
+            match avatar::download(&link).await {
+                Some(picture) => ui::print(render(&picture, previews)),
+                None => ui::print(format!("Couldn't preview {}", link).truecolor(128, 128, 128).to_string()),
+            }
+        });
+    }
+}
+
+/// `picture` drawn the way `previews` asks for. The panes of the chat screen only show text, so there
+/// pictures are always drawn with half blocks.
+fn render(picture: &DynamicImage, previews: ImagePreviews) -> String {
+    match (previews, ui::screen_open()) {
+        (ImagePreviews::Kitty, false) => kitty(picture),
+        (ImagePreviews::Sixel, false) => sixel(picture),
+        _ => avatar::half_blocks(&shrink(picture, BLOCKS_WIDTH, BLOCKS_ROWS * 2)),
+    }
+}
+
+/// `picture` scaled down to fit `width` and `height`, keeping its aspect ratio. Smaller pictures stay as they are.
+fn shrink(picture: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    match picture.width() > width || picture.height() > height {
+        true => picture.resize(width, height, FilterType::Triangle),
+        false => picture.clone(),
+    }
+}
+
+/// `picture` as PNG sent in kitty graphics escapes, which carry at most KITTY_CHUNK characters each.
+fn kitty(picture: &DynamicImage) -> String {
+    let mut png = Vec::new();
+    if shrink(picture, GRAPHICS_WIDTH, GRAPHICS_HEIGHT).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).is_err() {
+        return avatar::half_blocks(&shrink(picture, BLOCKS_WIDTH, BLOCKS_ROWS * 2));
+    }
+    let encoded = STANDARD.encode(png);
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(KITTY_CHUNK).collect();
+    let mut escapes = String::new();
+    for (index, chunk) in chunks.iter().enumerate() {
+        // Only the first escape says what to do, the others tell whether more follow
+        let keys = match index {
+            0 => "a=T,f=100,",
+            _ => "",
+        };
+        let more = (index + 1 < chunks.len()) as u8;
+        escapes += &format!("\x1b_G{}m={};{}\x1b\\", keys, more, String::from_utf8_lossy(chunk));
+    }
+    escapes
+}
+
+/// `picture` as sixels, in a palette of 216 colors that quantizes each channel to 6 levels.
+fn sixel(picture: &DynamicImage) -> String {
+    let pixels = shrink(picture, GRAPHICS_WIDTH, GRAPHICS_HEIGHT).to_rgb8();
+    let (width, height) = pixels.dimensions();
+    let color = |x: u32, y: u32| pixels.get_pixel(x, y).0.iter().fold(0, |color, channel| color * 6 + (*channel as usize * 5 + 127) / 255);
+    let mut sixels = format!("\x1bPq\"1;1;{};{}", width, height);
+    for color in 0 .. 216 {
+        sixels += &format!("#{};2;{};{};{}", color, color / 36 * 20, color / 6 % 6 * 20, color % 6 * 20);
+    }
+    // Each band of 6 pixel rows is drawn once per color in it, going back to its start in between
+    for band in (0 .. height).step_by(6) {
+        let rows = 6.min(height - band);
+        let mut colors: Vec<usize> = (0 .. width).flat_map(|x| (0 .. rows).map(move |row| (x, band + row))).map(|(x, y)| color(x, y)).collect();
+        colors.sort_unstable();
+        colors.dedup();
+        for (index, current) in colors.iter().enumerate() {
+            if index > 0 {
+                sixels.push('$');
+            }
+            sixels += &format!("#{}", current);
+            let columns: Vec<char> = (0 .. width)
+                .map(|x| (0 .. rows).filter(|row| color(x, band + row) == *current).fold(0u8, |bits, row| bits | 1 << row))
+                .map(|bits| (63 + bits) as char)
+                .collect();
+            let mut x = 0;
+            while x < columns.len() {
+                let run = columns[x ..].iter().take_while(|column| **column == columns[x]).count();
+                match run {
+                    1 ..= 3 => sixels.extend(std::iter::repeat_n(columns[x], run)),
+                    _ => sixels += &format!("!{}{}", run, columns[x]),
+                }
+                x += run;
+            }
+        }
+        sixels.push('-');
+    }
+    sixels + "\x1b\\"
+}
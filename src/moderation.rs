@@ -0,0 +1,93 @@
+use std::collections::{ HashMap, HashSet };
+use std::sync::{ LazyLock, Mutex };
+use std::time::Duration;
+
+use futures::future::join_all;
+use nostr::prelude::*;
+use serde_json::Value;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::chats::{ ChatType, PublicChannel };
+use crate::export::verify_event;
+use crate::relays;
+
+/// Kind of the NIP-28 event hiding a channel message.
+pub const HIDE_MESSAGE_KIND: u64 = 43;
+/// Kind of the NIP-28 event muting a user in channels.
+pub const MUTE_USER_KIND: u64 = 44;
+/// How long a relay gets to deliver the lists before the channel opens without its part of them.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Messages hidden and users muted by one public key, from its kind 43 and 44 events.
+#[derive(Default)]
+struct Lists {
+    hidden: HashSet<String>,
+    muted: HashSet<String>,
+}
+
+/// Lists of our own public key and the creators of the open channels, keyed by hex public key.
+static LISTS: LazyLock<Mutex<HashMap<String, Lists>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+/// Public keys whose lists apply in a channel besides our own, keyed by channel id: its creator's.
+static MODERATORS: LazyLock<Mutex<HashMap<String, String>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+/// Our own public key, whose lists apply in every channel.
+static OWN: Mutex<String> = Mutex::new(String::new());
+
+/// Adds what the kind 43 or 44 `event` hides or mutes to the lists of its author.
+pub fn record(event: &Value) {
+    let author = event["pubkey"].as_str().unwrap_or_default().to_string();
+    let (tag_name, kind) = match event["kind"].as_u64() {
+        Some(HIDE_MESSAGE_KIND) => ("e", HIDE_MESSAGE_KIND),
+        Some(MUTE_USER_KIND) => ("p", MUTE_USER_KIND),
+        _ => return,
+    };
+    let targets: Vec<String> = event["tags"].as_array().into_iter().flatten()
+        .filter(|tag| tag[0] == tag_name)
+        .filter_map(|tag| tag[1].as_str().map(str::to_string))
+        .collect();
+    let mut lists = LISTS.lock().unwrap();
+    let lists = lists.entry(author).or_default();
+    match kind {
+        HIDE_MESSAGE_KIND => lists.hidden.extend(targets),
+        _ => lists.muted.extend(targets),
+    }
+}
+
+/// Fetches the kind 43 and 44 events by us and the creator of `chat`, if it is a channel, so the messages
+/// they hid and the users they muted aren't shown in it. Later ones by the creator apply once it is opened again.
+pub async fn watch(relays: &[String], chat: &ChatType, own: XOnlyPublicKey) {
+    let channel: &PublicChannel = match chat {
+        ChatType::PublicChannel(channel) => channel,
+        ChatType::PrivateChat(_) => return,
+    };
+    *OWN.lock().unwrap() = own.to_string();
+    let creator = channel.root_event.pubkey.to_string();
+    MODERATORS.lock().unwrap().insert(channel.root_event.id.to_hex(), creator.clone());
+
+    let moderators = vec![own.to_string(), creator];
+    let filter = Filter::new()
+        .kinds(vec![Kind::Custom(HIDE_MESSAGE_KIND), Kind::Custom(MUTE_USER_KIND)])
+        .authors(moderators.clone());
+    let req = Message::Text(ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json());
+    let results = join_all(relays.iter().map(|relay| timeout(FETCH_TIMEOUT, relays::fetch_stored_events(relay, req.clone())))).await;
+    for event in results.into_iter().filter_map(|result| result.ok()?.ok()).flatten() {
+        // Relays don't have to honour the filter, and anyone else's events don't count
+        if moderators.iter().any(|moderator| event["pubkey"] == moderator.as_str()) && verify_event(&event).is_ok() {
+            record(&event);
+        }
+    }
+}
+
+/// Whether `event` in the channel `chat_id` is a message we or the channel's creator hid, or by a user one
+/// of us muted. Always false outside channels.
+pub fn is_hidden(chat_id: &str, event: &Value) -> bool {
+    let creator = match MODERATORS.lock().unwrap().get(chat_id) {
+        Some(val) => val.clone(),
+        None => return false,
+    };
+    let own = OWN.lock().unwrap().clone();
+    let lists = LISTS.lock().unwrap();
+    [own, creator].iter().filter_map(|moderator| lists.get(moderator)).any(|lists| {
+        lists.hidden.contains(event["id"].as_str().unwrap_or_default()) || lists.muted.contains(event["pubkey"].as_str().unwrap_or_default())
+    })
+}
@@ -0,0 +1,344 @@
+use std::collections::{ HashMap, VecDeque };
+use std::sync::{ Arc, Mutex };
+
+use colored::Colorize;
+use nostr::prelude::*;
+use rustyline::ExternalPrinter;
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::chats::{ Chat, ChatType, PrivateChat };
+use crate::{ names, relays };
+use crate::quiet_hours::{ QuietHours, QUIET_HOURS_CHECK_INTERVAL };
+use crate::rules::{ is_mention, RuleContext, RulesEngine };
+
+/// Activity the background monitor has seen on a chat since it was last opened.
+#[derive(Clone, Default, Debug)]
+pub struct ChatActivity {
+    pub unread: usize,
+    pub mentions: usize,
+}
+
+/// Entries the activity feed keeps at most, the oldest are dropped first.
+pub const MAX_ACTIVITY: usize = 100;
+
+/// A mention of us, or a reply, reaction or zap to one of our events.
+#[derive(Clone, Debug)]
+pub struct ActivityEntry {
+    /// MENTION, REPLY, REACTION or ZAP
+    pub label: &'static str,
+    pub author: XOnlyPublicKey,
+    pub created_at: Timestamp,
+    /// What happened, like "replied in #nostr: gm" or "reacted + to 1a2b3c4d"
+    pub summary: String,
+    /// Id of the event that brought the activity
+    pub event_id: EventId,
+    /// Chat it happened in, if the event tells
+    pub chat_id: Option<String>,
+    /// Our event it responds to, if the event tells
+    pub target: Option<EventId>,
+}
+
+/// Keeps live-only subscriptions to every known chat, so activity is tracked for chats that aren't open.
+#[derive(Clone)]
+pub struct Monitor {
+    pub activity: Arc<Mutex<HashMap<String, ChatActivity>>>,
+    pub active_chat: Arc<Mutex<String>>,
+    /// Mentions, replies, reactions and zaps, in the order they came in
+    pub feed: Arc<Mutex<VecDeque<ActivityEntry>>>,
+    /// Show desktop notifications for new activity
+    pub notify: bool,
+}
+
+impl Monitor {
+    pub fn new(active_chat_id: String, notify: bool) -> Self {
+        Monitor {
+            activity: Arc::new(Mutex::new(HashMap::new())),
+            active_chat: Arc::new(Mutex::new(active_chat_id)),
+            feed: Arc::new(Mutex::new(VecDeque::new())),
+            notify,
+        }
+    }
+
+    /// Marks `chat_id` as the open chat and clears its counters.
+    pub fn set_active(&self, chat_id: String) {
+        self.activity.lock().unwrap().remove(&chat_id);
+        *self.active_chat.lock().unwrap() = chat_id;
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, ChatActivity> {
+        self.activity.lock().unwrap().clone()
+    }
+
+    pub fn activity_feed(&self) -> Vec<ActivityEntry> {
+        self.feed.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Subscribes to `chats` on `relay` with `limit: 0`, so only new events arrive, and records them
+    /// until the connection ends. Mentions in chats other than the open one are announced on `printer`.
+    /// Mentions, replies, reactions and zaps go to the activity feed.
+    /// During `quiet_hours` the channel subscription is closed; direct messages keep arriving.
+    pub async fn run<T: ExternalPrinter + Send + Sync>(self, relay: String, chats: Vec<ChatType>, public_key: XOnlyPublicKey, rules: Arc<RulesEngine>, quiet_hours: Option<QuietHours>, mut printer: T) {
+        let channel_ids: Vec<EventId> = chats.iter().filter_map(|chat| match chat {
+            ChatType::PublicChannel(channel) => Some(channel.root_event.id),
+            ChatType::PrivateChat(_) => None,
+        }).collect();
+        let has_private_chats = chats.iter().any(|chat| matches!(chat, ChatType::PrivateChat(_)));
+        let names: HashMap<String, String> = chats.iter().map(|chat| (chat.get_id(), chat.clone().get_name())).collect();
+        let chats: HashMap<String, ChatType> = chats.into_iter().map(|chat| (chat.get_id(), chat)).collect();
+
+        let (mut writer, mut reader) = match relays::connect(&relay).await {
+            Ok(val) => val,
+            Err(why) => {
+                printer.print(format!("[{}] Background monitoring unavailable: {}", "MONITOR".red(), why)).ok();
+                return;
+            }
+        };
+
+        let channel_subscription = SubscriptionId::generate();
+        let channel_filter = Filter::new().kind(Kind::Custom(42)).events(channel_ids.clone());
+        let mut channels_suspended_since: Option<Timestamp> = None;
+        if !channel_ids.is_empty() {
+            let req = ClientMessage::new_req(channel_subscription.clone(), vec![channel_filter.clone().limit(0)]).as_json();
+            if writer.send(Message::Text(req)).await.is_err() {
+                return;
+            }
+        }
+        if has_private_chats {
+            let dm_filter = Filter::new().kinds(vec![Kind::Custom(420), Kind::EncryptedDirectMessage]).pubkey(public_key).limit(0);
+            let req = ClientMessage::new_req(SubscriptionId::generate(), vec![dm_filter]).as_json();
+            if writer.send(Message::Text(req)).await.is_err() {
+                return;
+            }
+        }
+
+        // Replies tag the author of the message they answer, as do reactions and zap receipts
+        let activity_subscription = SubscriptionId::generate();
+        let activity_filter = Filter::new().kinds(vec![Kind::Reaction, Kind::Custom(42), Kind::Zap]).pubkey(public_key).limit(0);
+        let req = ClientMessage::new_req(activity_subscription.clone(), vec![activity_filter]).as_json();
+        if writer.send(Message::Text(req)).await.is_err() {
+            return;
+        }
+
+        let mut quiet_check = tokio::time::interval(QUIET_HOURS_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                message = reader.next() => {
+                    match message {
+                        Some(Ok(message)) if is_subscription(&message, &activity_subscription) => self.record_activity(&message, &names, &public_key, &mut printer),
+                        Some(Ok(message)) => self.record(&message, &channel_ids, &chats, &public_key, &rules, &mut printer).await,
+                        _ => break
+                    }
+                },
+                _ = quiet_check.tick() => {
+                    let quiet = quiet_hours.as_ref().is_some_and(|hours| hours.is_quiet_now());
+                    if channel_ids.is_empty() {
+                        continue;
+                    }
+                    match (quiet, channels_suspended_since) {
+                        (true, None) => {
+                            let close = ClientMessage::close(channel_subscription.clone()).as_json();
+                            if writer.send(Message::Text(close)).await.is_ok() {
+                                channels_suspended_since = Some(Timestamp::now());
+                            }
+                        },
+                        (false, Some(since)) => {
+                            // Catch up on what was posted while quiet, so it still counts as unread
+                            let req = ClientMessage::new_req(channel_subscription.clone(), vec![channel_filter.clone().since(since)]).as_json();
+                            if writer.send(Message::Text(req)).await.is_ok() {
+                                channels_suspended_since = None;
+                            }
+                        },
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Counts a new message of one of `chats`, keyed by chat id, and announces it if it mentions us.
+    async fn record<T: ExternalPrinter>(&self, message: &Message, channel_ids: &[EventId], chats: &HashMap<String, ChatType>, public_key: &XOnlyPublicKey, rules: &RulesEngine, printer: &mut T) {
+        let json_val: Value = match serde_json::from_str(&message.to_string()) {
+            Ok(val) => val,
+            Err(_) => return
+        };
+        if json_val[0].as_str() != Some("EVENT") {
+            return;
+        }
+        let mut event = match Event::from_value(json_val[2].clone()) {
+            Ok(val) => val,
+            Err(_) => return
+        };
+        if event.pubkey == *public_key || crate::blocklist::is_blocked(&event.pubkey.to_string()) {
+            return;
+        }
+
+        let chat_id = match event.kind {
+            Kind::Custom(42) => event.tags.iter().find_map(|tag| match tag {
+                Tag::Event(id, _, _) if channel_ids.contains(id) => Some(id.to_hex()),
+                _ => None,
+            }),
+            _ => Some(event.pubkey.to_string()).filter(|id| chats.contains_key(id)),
+        };
+        let (chat_id, chat) = match chat_id.and_then(|chat_id| chats.get(&chat_id).map(|chat| (chat_id, chat))) {
+            Some(val) => val,
+            None => return
+        };
+        let chat_name = chat.clone().get_name();
+        // Rules, speech and notifications are given the text of direct messages, not their ciphertext
+        if let ChatType::PrivateChat(private_chat) = chat {
+            event.content = readable_content(&event, private_chat).await;
+        }
+        let mentioned = event.kind == Kind::Custom(42) && is_mention(&event, public_key);
+        // Replies to us come in on the activity subscription as well
+        if mentioned && !is_reply(&event) {
+            self.push_activity(ActivityEntry {
+                label: "MENTION",
+                author: event.pubkey,
+                created_at: event.created_at,
+                summary: format!("mentioned you in #{}: {}", chat_name, event.content),
+                event_id: event.id,
+                chat_id: Some(chat_id.clone()),
+                target: None,
+            });
+        }
+        if *self.active_chat.lock().unwrap() == chat_id {
+            return;
+        }
+
+        let rule_context = RuleContext { chat_id: &chat_id, chat_name: &chat_name, event: &event, mentioned };
+        if !rules.evaluate(&rule_context, true) {
+            return;
+        }
+        {
+            let mut activity = self.activity.lock().unwrap();
+            let entry = activity.entry(chat_id.clone()).or_default();
+            entry.unread += 1;
+            if mentioned {
+                entry.mentions += 1;
+            }
+        }
+        if mentioned {
+            printer.print(format!("[{}] {} mentioned you in {}", "MENTION".yellow(), names::display_name(&event.pubkey.to_string()), chat_name.green())).ok();
+        }
+    }
+
+    /// Adds a reply, reaction or zap to our events to the feed, and announces it unless its chat is open.
+    fn record_activity<T: ExternalPrinter>(&self, message: &Message, names: &HashMap<String, String>, public_key: &XOnlyPublicKey, printer: &mut T) {
+        let json_val: Value = match serde_json::from_str(&message.to_string()) {
+            Ok(val) => val,
+            Err(_) => return
+        };
+        let event = match Event::from_value(json_val[2].clone()) {
+            Ok(val) => val,
+            Err(_) => return
+        };
+        if event.pubkey == *public_key || crate::blocklist::is_blocked(&event.pubkey.to_string()) || event.verify().is_err() {
+            return;
+        }
+        let event_ids: Vec<EventId> = event.tags.iter().filter_map(|tag| match tag {
+            Tag::Event(id, _, _) => Some(*id),
+            _ => None,
+        }).collect();
+
+        let entry = match event.kind {
+            Kind::Custom(42) => {
+                // The root tag, which comes first, is the channel
+                let channel_id = match event_ids.first() {
+                    Some(val) => val.to_hex(),
+                    None => return,
+                };
+                let channel = names.get(&channel_id).cloned().unwrap_or(channel_id[.. 8].to_string());
+                let (label, summary) = match is_reply(&event) {
+                    true => ("REPLY", format!("replied in #{}: {}", channel, event.content)),
+                    // Mentions in known channels are recorded from their subscription
+                    false if names.contains_key(&channel_id) => return,
+                    false => ("MENTION", format!("mentioned you in #{}: {}", channel, event.content)),
+                };
+                let target = event.tags.iter().find_map(|tag| match tag {
+                    Tag::Event(id, _, Some(Marker::Reply)) => Some(*id),
+                    _ => None,
+                });
+                ActivityEntry { label, author: event.pubkey, created_at: event.created_at, summary, event_id: event.id, chat_id: Some(channel_id), target }
+            },
+            Kind::Reaction => {
+                // The reacted to event is the last e tag
+                let target = match event_ids.last() {
+                    Some(val) => *val,
+                    None => return,
+                };
+                let summary = format!("reacted {} to {}", event.content, &target.to_hex()[.. 8]);
+                ActivityEntry { label: "REACTION", author: event.pubkey, created_at: event.created_at, summary, event_id: event.id, chat_id: None, target: Some(target) }
+            },
+            Kind::Zap => {
+                // Receipts are signed by the lightning service, the zapper signed the request in the description
+                let request: Option<Value> = event.tags.iter().find_map(|tag| match tag.as_vec().as_slice() {
+                    [name, description, ..] if name == "description" => serde_json::from_str(description).ok(),
+                    _ => None,
+                });
+                let author = request.as_ref()
+                    .and_then(|request| request["pubkey"].as_str().and_then(crate::profiles::parse_public_key))
+                    .unwrap_or(event.pubkey);
+                let sats = request.as_ref().and_then(|request| request["tags"].as_array()?.iter()
+                    .find(|tag| tag[0] == "amount")
+                    .and_then(|tag| tag[1].as_str()?.parse::<u64>().ok()))
+                    .map(|millisats| millisats / 1000);
+                let target = event_ids.last().copied();
+                let summary = match (sats, target) {
+                    (Some(sats), Some(target)) => format!("zapped {} sats to {}", sats, &target.to_hex()[.. 8]),
+                    (Some(sats), None) => format!("zapped you {} sats", sats),
+                    (None, Some(target)) => format!("zapped {}", &target.to_hex()[.. 8]),
+                    (None, None) => "zapped you".to_string(),
+                };
+                ActivityEntry { label: "ZAP", author, created_at: event.created_at, summary, event_id: event.id, chat_id: None, target }
+            },
+            _ => return,
+        };
+
+        let open = entry.chat_id.as_ref() == Some(&*self.active_chat.lock().unwrap());
+        if !open {
+            let name = names::display_name(&entry.author.to_string());
+            printer.print(format!("[{}] {} {}", entry.label.yellow(), name, entry.summary)).ok();
+            if self.notify {
+                let chat = entry.chat_id.as_deref().map(|chat_id| (chat_id, names.get(chat_id).map_or(chat_id, String::as_str)));
+                if let Err(why) = crate::notifications::show(chat, &format!("nostrachat: {}", name), &entry.summary) {
+                    printer.print(format!("[{}] Couldn't show desktop notification: {}", "MONITOR".red(), why)).ok();
+                }
+            }
+        }
+        self.push_activity(entry);
+    }
+
+    /// Adds `entry` to the feed, unless its event is in there already.
+    fn push_activity(&self, entry: ActivityEntry) {
+        let mut feed = self.feed.lock().unwrap();
+        if feed.iter().any(|known| known.event_id == entry.event_id) {
+            return;
+        }
+        feed.push_back(entry);
+        if feed.len() > MAX_ACTIVITY {
+            feed.pop_front();
+        }
+    }
+}
+
+/// Text of the direct message `event` in `private_chat`. Ratchet messages can't be decrypted here without
+/// putting the chat's ratchet out of step, so they are only told of, as are messages that don't decrypt.
+async fn readable_content(event: &Event, private_chat: &PrivateChat) -> String {
+    let decrypted = match event.kind {
+        Kind::EncryptedDirectMessage if event.content.contains("?iv=") => private_chat.signer.nip04_decrypt(&event.pubkey, &event.content).await,
+        Kind::EncryptedDirectMessage => private_chat.signer.nip44_decrypt(&event.pubkey, &event.content).await,
+        _ => Err(String::new()),
+    };
+    decrypted.unwrap_or_else(|_| "[encrypted message]".to_string())
+}
+
+/// Whether `event` answers another message, as marked by NIP-10.
+fn is_reply(event: &Event) -> bool {
+    event.tags.iter().any(|tag| matches!(tag, Tag::Event(_, _, Some(Marker::Reply))))
+}
+
+/// Whether `message` is an event of the subscription `id`.
+fn is_subscription(message: &Message, id: &SubscriptionId) -> bool {
+    serde_json::from_str::<Value>(&message.to_string()).is_ok_and(|json_val| json_val[0] == "EVENT" && json_val[1] == id.to_string().as_str())
+}
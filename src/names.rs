@@ -0,0 +1,153 @@
+use std::collections::{ HashMap, HashSet };
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{ LazyLock, Mutex };
+use std::time::Duration;
+
+use futures::future::join_all;
+use nostr::prelude::*;
+use serde::{ Deserialize, Serialize };
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::export::verify_event;
+use crate::{ nip05, profiles, relays };
+
+/// How often the public keys seen since the last lookup are resolved.
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(2);
+/// Characters of a name shown at most, so a long one doesn't push the messages off the screen.
+pub const MAX_NAME_LENGTH: usize = 32;
+
+/// Names and NIP-05 identifiers from kind 0 metadata, keyed by hex public key.
+static PROFILES: LazyLock<Mutex<HashMap<String, CachedProfile>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+/// Public keys looked up this session, or waiting to be, so each is only asked for once.
+static REQUESTED: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+/// Public keys shown without a name since the last lookup.
+static PENDING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CachedProfile {
+    pub name: String,
+    /// created_at of the metadata event the name is from, so older ones don't replace it
+    pub created_at: u64,
+    #[serde(default)]
+    pub nip05: Option<String>,
+    /// Whether the domain of `nip05` confirmed it the last time it was asked
+    #[serde(default)]
+    pub verified: bool,
+}
+
+fn cache_path() -> PathBuf {
+    crate::data_dir().join("profiles.json")
+}
+
+/// Fills the cache with the names resolved in earlier sessions.
+pub fn load() {
+    let profiles: HashMap<String, CachedProfile> = fs::read_to_string(cache_path()).ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    *PROFILES.lock().unwrap() = profiles;
+}
+
+fn save() {
+    let content = serde_json::to_string(&*PROFILES.lock().unwrap()).unwrap();
+    if let Err(why) = fs::write(cache_path(), content) {
+        crate::ui::print_error(format!("Couldn't cache profile names: {}", why));
+    }
+}
+
+/// Cached name of the hex `public_key`, None if it has none yet.
+pub fn cached(public_key: &str) -> Option<String> {
+    PROFILES.lock().unwrap().get(public_key).map(|profile| profile.name.clone())
+}
+
+/// Verified NIP-05 identifier of the hex `public_key`, as it is shown.
+pub fn verified_nip05(public_key: &str) -> Option<String> {
+    let profiles = PROFILES.lock().unwrap();
+    let profile = profiles.get(public_key).filter(|profile| profile.verified)?;
+    profile.nip05.as_deref().map(nip05::display)
+}
+
+/// Name the hex `public_key` is shown with: their profile name, or a piece of their npub until it is resolved.
+/// Unknown keys are queued for the resolver.
+pub fn display_name(public_key: &str) -> String {
+    if let Some(name) = cached(public_key) {
+        return name;
+    }
+    if REQUESTED.lock().unwrap().insert(public_key.to_string()) {
+        PENDING.lock().unwrap().push(public_key.to_string());
+    }
+    match profiles::parse_public_key(public_key) {
+        Some(val) => val.to_bech32().unwrap()[4 .. 10].to_string(),
+        None => public_key.to_string(),
+    }
+}
+
+/// Name to show from kind 0 `metadata`: the display name, else the name, without control characters.
+fn name_from_metadata(metadata: &Metadata) -> Option<String> {
+    [&metadata.display_name, &metadata.name].into_iter()
+        .flatten()
+        .map(|name| name.chars().filter(|character| !character.is_control()).take(MAX_NAME_LENGTH).collect::<String>().trim().to_string())
+        .find(|name| !name.is_empty())
+}
+
+/// Fetches the metadata of the hex `public_keys` from `relay` and caches their names, verifying their NIP-05
+/// identifiers again.
+pub async fn resolve(relay: &str, public_keys: Vec<String>) {
+    if public_keys.is_empty() {
+        return;
+    }
+    REQUESTED.lock().unwrap().extend(public_keys.iter().cloned());
+    let filter = Filter::new().kind(Kind::Metadata).authors(public_keys.clone());
+    let req = Message::Text(ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json());
+    let events = match relays::fetch_stored_events(relay, req).await {
+        Ok(val) => val,
+        // Names are shown as npubs until the next session
+        Err(_) => return,
+    };
+    {
+        let mut profiles = PROFILES.lock().unwrap();
+        for event in events.iter().filter(|event| event["kind"].as_u64() == Some(0) && verify_event(event).is_ok()) {
+            let (public_key, created_at) = match (event["pubkey"].as_str(), event["created_at"].as_u64()) {
+                (Some(public_key), Some(created_at)) => (public_key, created_at),
+                _ => continue,
+            };
+            if profiles.get(public_key).is_some_and(|profile| profile.created_at >= created_at) {
+                continue;
+            }
+            let metadata = match Metadata::from_json(event["content"].as_str().unwrap_or_default()) {
+                Ok(val) => val,
+                Err(_) => continue,
+            };
+            if let Some(name) = name_from_metadata(&metadata) {
+                profiles.insert(public_key.to_string(), CachedProfile { name, created_at, nip05: metadata.nip05, verified: false });
+            }
+        }
+    }
+
+    let identifiers: Vec<(String, String)> = {
+        let profiles = PROFILES.lock().unwrap();
+        public_keys.into_iter()
+            .filter_map(|public_key| Some((profiles.get(&public_key)?.nip05.clone()?, public_key)))
+            .collect()
+    };
+    let results = join_all(identifiers.iter().map(|(identifier, public_key)| nip05::verify(identifier, public_key))).await;
+    {
+        let mut profiles = PROFILES.lock().unwrap();
+        for ((identifier, public_key), result) in identifiers.iter().zip(results) {
+            // Another update may have come in while the domains were asked
+            if let Some(profile) = profiles.get_mut(public_key).filter(|profile| profile.nip05.as_ref() == Some(identifier)) {
+                profile.verified = result.unwrap_or(false);
+            }
+        }
+    }
+    save();
+}
+
+/// Resolves the public keys shown without a name, in batches, for as long as the session runs.
+pub async fn resolve_pending(relay: String) {
+    loop {
+        tokio::time::sleep(RESOLVE_INTERVAL).await;
+        let public_keys: Vec<String> = PENDING.lock().unwrap().drain(..).collect();
+        resolve(&relay, public_keys).await;
+    }
+}
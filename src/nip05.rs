@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+use reqwest::redirect::Policy;
+use serde_json::Value;
+
+/// Splits a NIP-05 identifier into its local part and domain, lowercased.
+pub fn parse(identifier: &str) -> Option<(String, String)> {
+    let (name, domain) = identifier.trim().to_lowercase().split_once('@').map(|(name, domain)| (name.to_string(), domain.to_string()))?;
+    let valid_name = !name.is_empty() && name.chars().all(|character| character.is_ascii_alphanumeric() || "-_.".contains(character));
+    let valid_domain = domain.contains('.') && domain.chars().all(|character| character.is_ascii_alphanumeric() || "-.".contains(character));
+    match valid_name && valid_domain {
+        true => Some((name, domain)),
+        false => None,
+    }
+}
+
+/// `identifier` as it is shown, the domain alone for the root identifier `_@domain`.
+pub fn display(identifier: &str) -> String {
+    match parse(identifier) {
+        Some((name, domain)) if name == "_" => domain,
+        Some((name, domain)) => format!("{}@{}", name, domain),
+        None => identifier.to_string(),
+    }
+}
+
+/// Checks that the domain of `identifier` maps it to the hex `public_key` in its /.well-known/nostr.json.
+pub async fn verify(identifier: &str, public_key: &str) -> Result<bool, String> {
+    let (name, domain) = parse(identifier).ok_or("not a valid NIP-05 identifier")?;
+    // NIP-05 forbids following redirects, they would let another host vouch for the domain
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .redirect(Policy::none())
+        .build()
+        .map_err(|why| why.to_string())?;
+    let response = client.get(format!("https://{}/.well-known/nostr.json", domain))
+        .query(&[("name", &name)])
+        .send()
+        .await
+        .map_err(|why| why.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("{} answered {}", domain, response.status()));
+    }
+    let body: Value = serde_json::from_str(&response.text().await.map_err(|why| why.to_string())?).map_err(|why| why.to_string())?;
+    Ok(body["names"][&name].as_str().is_some_and(|listed| listed.eq_ignore_ascii_case(public_key)))
+}
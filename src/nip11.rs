@@ -0,0 +1,129 @@
+use std::collections::HashSet;
+use std::sync::{ LazyLock, Mutex };
+use std::time::Duration;
+
+use colored::Colorize;
+use serde_json::Value;
+
+/// How long a relay gets to serve its information document.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Relays the user was told already that they require payment, so it is only said once.
+static PAYMENT_NOTICED: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Fetches the NIP-11 information document of `relay`, served over HTTP at the relay's own address.
+pub async fn fetch(relay: &str) -> Result<Value, String> {
+    let url = match relay.split_once("://") {
+        Some(("wss", rest)) => format!("https://{}", rest),
+        Some(("ws", rest)) => format!("http://{}", rest),
+        _ => return Err(format!("{} isn't a relay address", relay)),
+    };
+    let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build().map_err(|why| why.to_string())?;
+    let response = client.get(url)
+        .header("Accept", "application/nostr+json")
+        .send()
+        .await
+        .map_err(|why| why.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("{} answered {}", relay, response.status()));
+    }
+    serde_json::from_str(&response.text().await.map_err(|why| why.to_string())?).map_err(|_| format!("{} has no NIP-11 information document", relay))
+}
+
+/// Information documents of those of `relays` that serve one, fetched at once.
+pub async fn fetch_all(relays: &[String]) -> Vec<(String, Value)> {
+    let infos = futures::future::join_all(relays.iter().map(|relay| fetch(relay))).await;
+    relays.iter().cloned().zip(infos).filter_map(|(relay, info)| info.ok().map(|info| (relay, info))).collect()
+}
+
+/// Limits of the NIP-11 limitation object shown by /relayinfo, with their labels.
+const LIMITS: [(&str, &str); 8] = [
+    ("max_message_length", "Max message length"),
+    ("max_subscriptions", "Max subscriptions"),
+    ("max_filters", "Max filters"),
+    ("max_limit", "Max limit"),
+    ("max_event_tags", "Max event tags"),
+    ("max_content_length", "Max content length"),
+    ("min_pow_difficulty", "Min PoW difficulty"),
+    ("created_at_lower_limit", "Oldest created_at accepted (seconds ago)"),
+];
+
+/// Everything /relayinfo shows of the information document `info` of `relay`: what it is, what it supports,
+/// its limits and what it charges.
+pub fn info_report(relay: &str, info: &Value) -> Vec<String> {
+    let mut lines = vec!["Relay: ".green().to_string() + relay];
+    for (field, label) in [("name", "Name"), ("description", "Description"), ("contact", "Contact"), ("software", "Software"), ("version", "Version")] {
+        if let Some(value) = info[field].as_str().filter(|value| !value.is_empty()) {
+            lines.push(format!("{}: ", label).green().to_string() + value);
+        }
+    }
+    if let Some(nips) = info["supported_nips"].as_array() {
+        lines.push("Supported NIPs: ".green().to_string() + &nips.iter().map(Value::to_string).collect::<Vec<String>>().join(", "));
+    }
+    let limitation = &info["limitation"];
+    for (field, label) in LIMITS {
+        if let Some(value) = limitation[field].as_u64() {
+            lines.push(format!("{}: ", label).green().to_string() + &value.to_string());
+        }
+    }
+    for (field, label) in [("auth_required", "Authentication required (auth_relays)"), ("restricted_writes", "Restricted writes")] {
+        if limitation[field].as_bool() == Some(true) {
+            lines.push(format!("{}: ", label).green().to_string() + &"yes".yellow().to_string());
+        }
+    }
+    lines.extend(payment_report(info));
+    lines
+}
+
+/// Whether the relay of `info` wants to be paid before it accepts events.
+pub fn payment_required(info: &Value) -> bool {
+    info["limitation"]["payment_required"].as_bool() == Some(true)
+}
+
+/// The amount of `fee` in its unit as shown, whole sats of millisats in sats.
+fn format_amount(fee: &Value) -> String {
+    let amount = fee["amount"].as_u64().unwrap_or_default();
+    match fee["unit"].as_str().unwrap_or("msats") {
+        "msats" if amount.is_multiple_of(1000) => format!("{} sats", amount / 1000),
+        "sats" => format!("{} sats", amount),
+        unit => format!("{} {}", amount, unit),
+    }
+}
+
+/// Lines on what the relay of `info` charges and where to pay it.
+pub fn payment_report(info: &Value) -> Vec<String> {
+    let mut lines = Vec::new();
+    lines.push(match payment_required(info) {
+        true => "Payment required: ".green().to_string() + &"yes, events are rejected until you pay".yellow().to_string(),
+        false => "Payment required: ".green().to_string() + "no",
+    });
+    if let Some(url) = info["payments_url"].as_str() {
+        lines.push("Pay at: ".green().to_string() + url + &" (/relayinfo <relay> pay opens it)".truecolor(128, 128, 128).to_string());
+    }
+    let fees = &info["fees"];
+    for fee in fees["admission"].as_array().into_iter().flatten() {
+        lines.push("Admission: ".green().to_string() + &format_amount(fee));
+    }
+    for fee in fees["subscription"].as_array().into_iter().flatten() {
+        let period = match fee["period"].as_u64() {
+            Some(seconds) => format!(" per {} days", seconds / (24 * 60 * 60)),
+            None => String::new(),
+        };
+        lines.push("Subscription: ".green().to_string() + &format_amount(fee) + &period);
+    }
+    for fee in fees["publication"].as_array().into_iter().flatten() {
+        let kinds = match fee["kinds"].as_array() {
+            Some(kinds) => format!(" for kinds {}", kinds.iter().map(Value::to_string).collect::<Vec<String>>().join(", ")),
+            None => String::new(),
+        };
+        lines.push("Publication: ".green().to_string() + &format_amount(fee) + " per event" + &kinds);
+    }
+    lines
+}
+
+/// Tells the user once per relay why `relay` rejected an event, if the NIP-01 `reason` says it wants payment.
+pub fn note_rejection(relay: &str, reason: &str) {
+    if reason.starts_with("payment-required:") && PAYMENT_NOTICED.lock().unwrap().insert(relay.to_string()) {
+        crate::ui::print_error(format!("{} only accepts events once you pay, /relayinfo {} shows how.", relay, relay));
+    }
+}
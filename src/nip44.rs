@@ -0,0 +1,240 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20::ChaCha20;
+use chacha20::cipher::{ KeyIvInit, StreamCipher };
+use hkdf::Hkdf;
+use hkdf::hmac::{ Hmac, KeyInit, Mac };
+use nostr::prelude::secp256k1::ecdh::shared_secret_point;
+use nostr::prelude::{ Parity, SecretKey, XOnlyPublicKey };
+use rand::RngCore;
+use sha2::Sha256;
+
+const VERSION: u8 = 2;
+const SALT: &[u8] = b"nip44-v2";
+const MAX_PLAINTEXT_LENGTH: usize = 65535;
+/// Shortest and longest decoded payloads: version, nonce and MAC around 32 to 65536 padded bytes
+const MIN_PAYLOAD_LENGTH: usize = 99;
+const MAX_PAYLOAD_LENGTH: usize = 65603;
+
+/// NIP-44 v2 conversation key of `secret_key` and `public_key`, the same from either side.
+pub fn conversation_key(secret_key: &SecretKey, public_key: &XOnlyPublicKey) -> [u8; 32] {
+    let point = shared_secret_point(&public_key.public_key(Parity::Even), secret_key);
+    let (prk, _) = Hkdf::<Sha256>::extract(Some(SALT), &point[.. 32]);
+    prk.into()
+}
+
+/// Encrypts `plaintext` from `secret_key` to `public_key` as a base64 NIP-44 v2 payload.
+pub fn encrypt(secret_key: &SecretKey, public_key: &XOnlyPublicKey, plaintext: &str) -> Result<String, String> {
+    let mut nonce = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    encrypt_with_nonce(&conversation_key(secret_key, public_key), &nonce, plaintext)
+}
+
+/// Encrypts `plaintext` under `conversation_key` with `nonce`, which must never be used twice.
+fn encrypt_with_nonce(conversation_key: &[u8; 32], nonce: &[u8; 32], plaintext: &str) -> Result<String, String> {
+    let mut ciphertext = pad(plaintext)?;
+    let (chacha_key, chacha_nonce, hmac_key) = message_keys(conversation_key, nonce);
+
+    ChaCha20::new(&chacha_key.into(), &chacha_nonce.into()).apply_keystream(&mut ciphertext);
+    let mac = mac(&hmac_key, nonce, &ciphertext).finalize().into_bytes();
+
+    Ok(STANDARD.encode([&[VERSION], nonce.as_slice(), &ciphertext, &mac].concat()))
+}
+
+/// Decrypts a NIP-44 v2 payload sent between `secret_key` and `public_key`, checking its MAC and padding.
+pub fn decrypt(secret_key: &SecretKey, public_key: &XOnlyPublicKey, payload: &str) -> Result<String, String> {
+    if payload.starts_with('#') {
+        return Err("unsupported encryption version".to_string());
+    }
+    let payload = STANDARD.decode(payload).map_err(|_| "payload isn't base64 encoded".to_string())?;
+    if !(MIN_PAYLOAD_LENGTH ..= MAX_PAYLOAD_LENGTH).contains(&payload.len()) {
+        return Err("payload has an invalid length".to_string());
+    }
+    if payload[0] != VERSION {
+        return Err(format!("unsupported encryption version {}", payload[0]));
+    }
+    let nonce: [u8; 32] = payload[1 .. 33].try_into().unwrap();
+    let (ciphertext, received_mac) = payload[33 ..].split_at(payload.len() - 33 - 32);
+    let (chacha_key, chacha_nonce, hmac_key) = message_keys(&conversation_key(secret_key, public_key), &nonce);
+    mac(&hmac_key, &nonce, ciphertext).verify_slice(received_mac).map_err(|_| "authentication failed".to_string())?;
+
+    let mut padded = ciphertext.to_vec();
+    ChaCha20::new(&chacha_key.into(), &chacha_nonce.into()).apply_keystream(&mut padded);
+    unpad(&padded)
+}
+
+/// `plaintext` prefixed with its length and padded with zeros to one of the standard lengths, as NIP-44
+/// does before encrypting.
+pub fn pad(plaintext: &str) -> Result<Vec<u8>, String> {
+    if plaintext.is_empty() || plaintext.len() > MAX_PLAINTEXT_LENGTH {
+        return Err(format!("message must be 1 to {} bytes long", MAX_PLAINTEXT_LENGTH));
+    }
+    let mut padded = vec![0u8; 2 + padded_length(plaintext.len())];
+    padded[.. 2].copy_from_slice(&(plaintext.len() as u16).to_be_bytes());
+    padded[2 .. 2 + plaintext.len()].copy_from_slice(plaintext.as_bytes());
+    Ok(padded)
+}
+
+/// The plaintext `pad` padded, checking the padding has the standard length.
+pub fn unpad(padded: &[u8]) -> Result<String, String> {
+    if padded.len() < 2 {
+        return Err("invalid padding".to_string());
+    }
+    let length = u16::from_be_bytes([padded[0], padded[1]]) as usize;
+    if length == 0 || padded.len() != 2 + padded_length(length) {
+        return Err("invalid padding".to_string());
+    }
+    String::from_utf8(padded[2 .. 2 + length].to_vec()).map_err(|_| "message isn't valid UTF-8".to_string())
+}
+
+/// ChaCha20 key, ChaCha20 nonce and HMAC key of the message with `nonce`.
+fn message_keys(conversation_key: &[u8; 32], nonce: &[u8; 32]) -> ([u8; 32], [u8; 12], [u8; 32]) {
+    let hkdf = Hkdf::<Sha256>::from_prk(conversation_key).expect("Conversation key has the length of a SHA-256 output");
+    let mut keys = [0u8; 76];
+    hkdf.expand(nonce, &mut keys).expect("76 bytes is a valid HKDF-SHA256 output length");
+    (keys[.. 32].try_into().unwrap(), keys[32 .. 44].try_into().unwrap(), keys[44 ..].try_into().unwrap())
+}
+
+fn mac(hmac_key: &[u8; 32], nonce: &[u8; 32], ciphertext: &[u8]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as KeyInit>::new_from_slice(hmac_key).expect("HMAC takes keys of any length");
+    mac.update(nonce);
+    mac.update(ciphertext);
+    mac
+}
+
+/// Length plaintexts are padded to, so ciphertexts only reveal roughly how long a message is.
+fn padded_length(length: usize) -> usize {
+    if length <= 32 {
+        return 32;
+    }
+    let next_power = 1 << (usize::BITS - (length - 1).leading_zeros());
+    let chunk = if next_power <= 256 { 32 } else { next_power / 8 };
+    chunk * ((length - 1) / chunk + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    // From the official NIP-44 test vectors, https://github.com/paulmillr/nip44/blob/main/nip44.vectors.json
+
+    fn secret_key(hex: &str) -> SecretKey {
+        SecretKey::from_str(hex).unwrap()
+    }
+
+    fn public_key(hex: &str) -> XOnlyPublicKey {
+        XOnlyPublicKey::from_str(hex).unwrap()
+    }
+
+    fn public_key_of(secret_key: &SecretKey) -> XOnlyPublicKey {
+        secret_key.x_only_public_key(&nostr::prelude::secp256k1::Secp256k1::new()).0
+    }
+
+    #[test]
+    fn conversation_key_vectors() {
+        let vectors = [
+            ("315e59ff51cb9209768cf7da80791ddcaae56ac9775eb25b6dee1234bc5d2268", "c2f9d9948dc8c7c38321e4b85c8558872eafa0641cd269db76848a6073e69133", "3dfef0ce2a4d80a25e7a328accf73448ef67096f65f79588e358d9a0eb9013f1"),
+            ("a1e37752c9fdc1273be53f68c5f74be7c8905728e8de75800b94262f9497c86e", "03bb7947065dde12ba991ea045132581d0954f042c84e06d8c00066e23c1a800", "4d14f36e81b8452128da64fe6f1eae873baae2f444b02c950b90e43553f2178b"),
+        ];
+        for (sec1, pub2, expected) in vectors {
+            assert_eq!(hex::encode(conversation_key(&secret_key(sec1), &public_key(pub2))), expected);
+        }
+    }
+
+    #[test]
+    fn conversation_key_is_the_same_from_either_side() {
+        let (sec1, sec2) = (secret_key(&format!("{:064x}", 1)), secret_key(&format!("{:064x}", 2)));
+        let expected = "c41c775356fd92eadc63ff5a0dc1da211b268cbea22316767095b2871ea1412d";
+        assert_eq!(hex::encode(conversation_key(&sec1, &public_key_of(&sec2))), expected);
+        assert_eq!(hex::encode(conversation_key(&sec2, &public_key_of(&sec1))), expected);
+    }
+
+    #[test]
+    fn invalid_conversation_key_inputs_are_rejected() {
+        // Zero and the curve order aren't valid secret keys, and not every x coordinate is on the curve
+        assert!(SecretKey::from_str(&format!("{:064x}", 0)).is_err());
+        assert!(SecretKey::from_str("fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141").is_err());
+        assert!(XOnlyPublicKey::from_str(&format!("{:064x}", 0)).is_err());
+    }
+
+    #[test]
+    fn calc_padded_len_vectors() {
+        let vectors = [
+            (16, 32), (32, 32), (33, 64), (37, 64), (45, 64), (49, 64), (64, 64), (65, 96), (100, 128), (111, 128),
+            (200, 224), (250, 256), (320, 320), (383, 384), (384, 384), (400, 448), (500, 512), (512, 512),
+            (515, 640), (700, 768), (800, 896), (900, 1024), (1020, 1024), (65536, 65536),
+        ];
+        for (length, expected) in vectors {
+            assert_eq!(padded_length(length), expected, "padded length of {}", length);
+        }
+    }
+
+    #[test]
+    fn encrypt_decrypt_vectors() {
+        let vectors = [
+            (1, 2, format!("{:064x}", 1), "a", "AgAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAABee0G5VSK0/9YypIObAtDKfYEAjD35uVkHyB0F4DwrcNaCXlCWZKaArsGrY6M9wnuTMxWfp1RTN9Xga8no+kF5Vsb"),
+            (2, 1, "f00000000000000000000000000000f00000000000000000000000000000000f".to_string(), "🍕🫃", "AvAAAAAAAAAAAAAAAAAAAPAAAAAAAAAAAAAAAAAAAAAPSKSK6is9ngkX2+cSq85Th16oRTISAOfhStnixqZziKMDvB0QQzgFZdjLTPicCJaV8nDITO+QfaQ61+KbWQIOO2Yj"),
+        ];
+        for (sec1, sec2, nonce, plaintext, payload) in vectors {
+            let (sec1, sec2) = (secret_key(&format!("{:064x}", sec1)), secret_key(&format!("{:064x}", sec2)));
+            let nonce: [u8; 32] = hex::decode(nonce).unwrap().try_into().unwrap();
+            let key = conversation_key(&sec1, &public_key_of(&sec2));
+            assert_eq!(encrypt_with_nonce(&key, &nonce, plaintext).unwrap(), payload);
+            assert_eq!(decrypt(&sec2, &public_key_of(&sec1), payload).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn long_messages_round_trip() {
+        let (sec1, sec2) = (secret_key(&format!("{:064x}", 1)), secret_key(&format!("{:064x}", 2)));
+        for length in [1, 32, 33, 1000, MAX_PLAINTEXT_LENGTH] {
+            let plaintext = "x".repeat(length);
+            let payload = encrypt(&sec1, &public_key_of(&sec2), &plaintext).unwrap();
+            assert_eq!(decrypt(&sec2, &public_key_of(&sec1), &payload).unwrap(), plaintext);
+        }
+        assert!(encrypt(&sec1, &public_key_of(&sec2), "").is_err());
+        assert!(encrypt(&sec1, &public_key_of(&sec2), &"x".repeat(MAX_PLAINTEXT_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn invalid_payloads_are_rejected() {
+        let (sec1, sec2) = (secret_key(&format!("{:064x}", 1)), secret_key(&format!("{:064x}", 2)));
+        let (key, public_key) = (conversation_key(&sec1, &public_key_of(&sec2)), public_key_of(&sec1));
+        let payload = STANDARD.decode(encrypt_with_nonce(&key, &[1; 32], "hello").unwrap()).unwrap();
+        let encode = |bytes: &[u8]| STANDARD.encode(bytes);
+        let with = |index: usize, byte: u8| {
+            let mut changed = payload.clone();
+            changed[index] = byte;
+            encode(&changed)
+        };
+        let last = payload.len() - 1;
+
+        // Unknown version, both as a prefix and in the payload
+        assert!(decrypt(&sec2, &public_key, &format!("#{}", encode(&payload))).is_err());
+        assert!(decrypt(&sec2, &public_key, &with(0, 1)).is_err());
+        // Invalid base64
+        assert!(decrypt(&sec2, &public_key, "Ag%%invalid%%").is_err());
+        // Too short and too long
+        assert!(decrypt(&sec2, &public_key, &encode(&payload[.. MIN_PAYLOAD_LENGTH - 1])).is_err());
+        assert!(decrypt(&sec2, &public_key, &encode(&vec![VERSION; MAX_PAYLOAD_LENGTH + 1])).is_err());
+        // Tampered nonce, ciphertext or MAC
+        assert_eq!(decrypt(&sec2, &public_key, &with(1, payload[1] ^ 1)), Err("authentication failed".to_string()));
+        assert_eq!(decrypt(&sec2, &public_key, &with(40, payload[40] ^ 1)), Err("authentication failed".to_string()));
+        assert_eq!(decrypt(&sec2, &public_key, &with(last, payload[last] ^ 1)), Err("authentication failed".to_string()));
+        // Sent to someone else
+        assert!(decrypt(&secret_key(&format!("{:064x}", 3)), &public_key, &encode(&payload)).is_err());
+    }
+
+    #[test]
+    fn invalid_padding_is_rejected() {
+        let padded = pad("hello").unwrap();
+        assert_eq!(unpad(&padded).unwrap(), "hello");
+        // Zero length, a length the padding doesn't fit, and padding of the wrong length
+        assert!(unpad(&[0; 34]).is_err());
+        assert!(unpad(&[[0, 40].as_slice(), &[b'x'; 32]].concat()).is_err());
+        assert!(unpad(&padded[.. 20]).is_err());
+        assert!(unpad(&[padded.as_slice(), &[0; 32]].concat()).is_err());
+    }
+}
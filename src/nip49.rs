@@ -0,0 +1,71 @@
+use chacha20poly1305::aead::{ Aead, AeadCore, KeyInit, OsRng, Payload };
+use chacha20poly1305::XChaCha20Poly1305;
+use nostr::bech32::{ self, FromBase32, ToBase32, Variant };
+use nostr::prelude::*;
+use rand::RngCore;
+use unicode_normalization::UnicodeNormalization;
+
+/// Prefix of encrypted private keys.
+pub const HRP: &str = "ncryptsec";
+/// scrypt work factor keys are encrypted with, as 2^n rounds. Takes about a second and 64 MiB.
+pub const DEFAULT_LOG_N: u8 = 16;
+/// Highest scrypt work factor accepted when decrypting, as one beyond would take minutes and gigabytes.
+pub const MAX_LOG_N: u8 = 22;
+const VERSION: u8 = 0x02;
+
+/// Whether the key is known to have been handled insecurely, like stored unencrypted, before it was encrypted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeySecurity {
+    Insecure = 0x00,
+    Secure = 0x01,
+    Unknown = 0x02,
+}
+
+/// Key derived from `passphrase` with scrypt, salted with `salt` and 2^`log_n` rounds.
+pub fn symmetric_key(passphrase: &str, salt: &[u8], log_n: u8) -> Result<[u8; 32], String> {
+    // Passphrases are normalized, so they can be typed the same on every system
+    let passphrase: String = passphrase.nfkc().collect();
+    let params = scrypt::Params::new(log_n, 8, 1, 32).map_err(|why| why.to_string())?;
+    let mut key = [0u8; 32];
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key).map_err(|why| why.to_string())?;
+    Ok(key)
+}
+
+/// Encrypts `secret_key` with `passphrase` into an ncryptsec, as in NIP-49.
+pub fn encrypt(secret_key: &SecretKey, passphrase: &str, log_n: u8, security: KeySecurity) -> Result<String, String> {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let key = symmetric_key(passphrase, &salt, log_n)?;
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let security_byte = [security as u8];
+    let ciphertext = XChaCha20Poly1305::new(&key.into())
+        .encrypt(&nonce, Payload { msg: &secret_key.secret_bytes(), aad: &security_byte })
+        .map_err(|why| why.to_string())?;
+
+    let mut data = vec![VERSION, log_n];
+    data.extend(salt);
+    data.extend(nonce);
+    data.extend(security_byte);
+    data.extend(ciphertext);
+    bech32::encode(HRP, data.to_base32(), Variant::Bech32).map_err(|why| why.to_string())
+}
+
+/// Decrypts the ncryptsec `encrypted` with `passphrase`.
+pub fn decrypt(encrypted: &str, passphrase: &str) -> Result<SecretKey, String> {
+    let (hrp, data, _) = bech32::decode(encrypted).map_err(|why| why.to_string())?;
+    let data = Vec::<u8>::from_base32(&data).map_err(|why| why.to_string())?;
+    if hrp != HRP || data.len() != 91 {
+        return Err("not an ncryptsec".to_string());
+    }
+    if data[0] != VERSION {
+        return Err(format!("unsupported ncryptsec version {}", data[0]));
+    }
+    if data[1] > MAX_LOG_N {
+        return Err(format!("scrypt work factor {} is above the {} supported", data[1], MAX_LOG_N));
+    }
+    let key = symmetric_key(passphrase, &data[2 .. 18], data[1])?;
+    let plaintext = XChaCha20Poly1305::new(&key.into())
+        .decrypt(data[18 .. 42].into(), Payload { msg: &data[43 ..], aad: &data[42 .. 43] })
+        .map_err(|_| "wrong passphrase".to_string())?;
+    SecretKey::from_slice(&plaintext).map_err(|why| why.to_string())
+}
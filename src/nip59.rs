@@ -0,0 +1,58 @@
+use std::str::FromStr;
+
+use nostr::prelude::*;
+use rand::Rng;
+use serde_json::Value;
+
+use crate::export::verify_event;
+use crate::nip44;
+use crate::signer::Signer;
+
+/// How far into the past seals and gift wraps are backdated at most, so their timestamps don't reveal
+/// when a message was sent.
+pub const MAX_BACKDATE: u64 = 2 * 24 * 60 * 60;
+
+/// Seals `rumor`, an unsigned event by the user of `signer`, and wraps the seal for `recipient` with a
+/// throwaway key, so relays only see a kind 1059 event from a random key to `recipient`.
+pub async fn gift_wrap(signer: &Signer, recipient: &XOnlyPublicKey, rumor: &UnsignedEvent) -> Result<Event, String> {
+    let seal_content = signer.nip44_encrypt(recipient, &rumor.as_json()).await?;
+    let seal = signer.sign_unsigned(backdated(signer.public_key(), Kind::Custom(13), seal_content, Vec::new())).await?;
+
+    let wrap_keys = Keys::generate();
+    let wrap_content = nip44::encrypt(&wrap_keys.secret_key().map_err(|why| why.to_string())?, recipient, &seal.as_json())?;
+    backdated(wrap_keys.public_key(), Kind::Custom(1059), wrap_content, vec![Tag::PubKey(*recipient, None)])
+        .sign(&wrap_keys)
+        .map_err(|why| why.to_string())
+}
+
+/// Opens a gift wrap addressed to the user of `signer` and returns the rumor inside. Fails unless the seal
+/// is signed by the rumor's author, so nobody can wrap messages in someone else's name.
+pub async fn unwrap(signer: &Signer, wrap: &Value) -> Result<Value, String> {
+    let wrap_author = XOnlyPublicKey::from_str(wrap["pubkey"].as_str().unwrap_or_default()).map_err(|_| "invalid gift wrap author".to_string())?;
+    let seal: Value = serde_json::from_str(&signer.nip44_decrypt(&wrap_author, wrap["content"].as_str().unwrap_or_default()).await?)
+        .map_err(|_| "gift wrap doesn't contain a seal".to_string())?;
+    if seal["kind"].as_u64() != Some(13) {
+        return Err("gift wrap doesn't contain a seal".to_string());
+    }
+    verify_event(&seal)?;
+
+    let seal_author = XOnlyPublicKey::from_str(seal["pubkey"].as_str().unwrap_or_default()).map_err(|_| "invalid seal author".to_string())?;
+    let rumor: Value = serde_json::from_str(&signer.nip44_decrypt(&seal_author, seal["content"].as_str().unwrap_or_default()).await?)
+        .map_err(|_| "seal doesn't contain an event".to_string())?;
+    if rumor["pubkey"] != seal["pubkey"] {
+        return Err("message author doesn't match the seal".to_string());
+    }
+    Ok(rumor)
+}
+
+fn backdated(public_key: XOnlyPublicKey, kind: Kind, content: String, tags: Vec<Tag>) -> UnsignedEvent {
+    let created_at = Timestamp::from(Timestamp::now().as_u64() - rand::thread_rng().gen_range(0 .. MAX_BACKDATE));
+    UnsignedEvent {
+        id: EventId::new(&public_key, created_at, &kind, &tags, &content),
+        pubkey: public_key,
+        created_at,
+        kind,
+        tags,
+        content,
+    }
+}
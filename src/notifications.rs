@@ -0,0 +1,77 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use nostr::prelude::*;
+use notify_rust::Notification;
+use serde::{ Deserialize, Serialize };
+
+use crate::quiet_hours::QuietHours;
+use crate::rules::RuleContext;
+
+/// The [notifications] config: desktop notifications for messages arriving in the open chats.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct NotificationConfig {
+    /// Notify of every incoming private message
+    #[serde(default)]
+    pub dms: bool,
+    /// Notify of every channel message mentioning me
+    #[serde(default)]
+    pub mentions: bool,
+    /// Chats never notified of, by channel id, contact npub or hex public key, or name
+    #[serde(default)]
+    pub muted: Vec<String>,
+    /// Daily window, in local "HH:MM" times, in which no desktop notification is shown at all
+    pub do_not_disturb: Option<QuietHours>,
+}
+
+static CONFIG: Mutex<Option<NotificationConfig>> = Mutex::new(None);
+
+pub fn configure(config: Option<NotificationConfig>) {
+    *CONFIG.lock().unwrap() = config;
+}
+
+/// Whether notifications of the chat with `chat_id` and `chat_name` may be shown now. Without a chat only
+/// do-not-disturb applies.
+fn allowed(chat: Option<(&str, &str)>) -> bool {
+    let config = CONFIG.lock().unwrap();
+    let config = match config.as_ref() {
+        Some(val) => val,
+        None => return true,
+    };
+    if config.do_not_disturb.as_ref().is_some_and(|hours| hours.is_quiet_now()) {
+        return false;
+    }
+    let (chat_id, chat_name) = match chat {
+        Some(val) => val,
+        None => return true,
+    };
+    let npub = XOnlyPublicKey::from_str(chat_id).ok().and_then(|public_key| public_key.to_bech32().ok());
+    !config.muted.iter().any(|muted| muted == chat_id || muted == chat_name || Some(muted) == npub.as_ref())
+}
+
+/// Shows a desktop notification, unless do-not-disturb is on or `chat` is muted.
+pub fn show(chat: Option<(&str, &str)>, summary: &str, body: &str) -> Result<(), String> {
+    if !allowed(chat) {
+        return Ok(());
+    }
+    Notification::new().summary(summary).body(body).show().map(|_| ()).map_err(|why| why.to_string())
+}
+
+/// Notifies of the live message of `context` if it is a private message or mentions me and the config asks
+/// for those.
+pub fn message(context: &RuleContext) {
+    let wanted = match CONFIG.lock().unwrap().as_ref() {
+        Some(config) => {
+            let is_dm = matches!(context.event.kind.as_u64(), 4 | 14 | 420);
+            (config.dms && is_dm) || (config.mentions && context.mentioned && !is_dm)
+        },
+        None => false,
+    };
+    if !wanted {
+        return;
+    }
+    let author = crate::names::display_name(&context.event.pubkey.to_string());
+    if let Err(why) = show(Some((context.chat_id, context.chat_name)), &format!("nostrachat: {}", context.chat_name), &format!("{}: {}", author, context.event.content)) {
+        crate::ui::print_error(format!("Couldn't show desktop notification: {}", why));
+    }
+}
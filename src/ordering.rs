@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::time::Instant;
+
+/// Holds live events for a short window after they arrive and releases them in order of created_at, so
+/// messages written at about the same time but delivered by different relays don't show up interleaved.
+pub struct OrderingBuffer {
+    window: Duration,
+    /// Held EVENT messages with when they arrived, in order of arrival
+    held: Vec<(Instant, Value)>,
+}
+
+fn created_at(json_val: &Value) -> u64 {
+    json_val[2]["created_at"].as_u64().unwrap_or_default()
+}
+
+impl OrderingBuffer {
+    pub fn new(window: Duration) -> Self {
+        OrderingBuffer { window, held: Vec::new() }
+    }
+
+    pub fn push(&mut self, json_val: Value) {
+        self.held.push((Instant::now(), json_val));
+    }
+
+    /// Waits until the event that arrived first has been held for the whole window. Never returns while
+    /// nothing is held.
+    pub async fn due(&self) {
+        match self.held.first() {
+            Some((arrived, _)) => tokio::time::sleep_until(*arrived + self.window).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Releases the events held for the whole window, together with those that arrived later but were
+    /// created no later than one of them, all in order of created_at.
+    pub fn take_due(&mut self) -> Vec<Value> {
+        let now = Instant::now();
+        let latest_due = match self.held.iter().filter(|(arrived, _)| *arrived + self.window <= now).map(|(_, json_val)| created_at(json_val)).max() {
+            Some(val) => val,
+            None => return Vec::new(),
+        };
+        let (mut released, held): (Vec<_>, Vec<_>) = self.held.drain(..)
+            .partition(|(arrived, json_val)| *arrived + self.window <= now || created_at(json_val) <= latest_due);
+        self.held = held;
+        // Stable, so events created in the same second keep the order they arrived in
+        released.sort_by_key(|(_, json_val)| created_at(json_val));
+        released.into_iter().map(|(_, json_val)| json_val).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn message(created_at: u64) -> Value {
+        json!(["EVENT", "sub", { "created_at": created_at }])
+    }
+
+    #[tokio::test]
+    async fn due_events_are_released_in_order_of_creation() {
+        let mut buffer = OrderingBuffer::new(Duration::ZERO);
+        for created_at in [30, 10, 20] {
+            buffer.push(message(created_at));
+        }
+        let released: Vec<u64> = buffer.take_due().iter().map(created_at).collect();
+        assert_eq!(released, vec![10, 20, 30]);
+        assert!(buffer.take_due().is_empty());
+    }
+
+    #[tokio::test]
+    async fn events_are_held_for_the_window() {
+        let mut buffer = OrderingBuffer::new(Duration::from_secs(60));
+        buffer.push(message(10));
+        assert!(buffer.take_due().is_empty());
+    }
+}
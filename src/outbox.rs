@@ -0,0 +1,190 @@
+use std::collections::{ BTreeSet, HashMap };
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{ LazyLock, Mutex };
+
+use colored::Colorize;
+use nostr::prelude::Timestamp;
+use serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+/// Entries kept at most, the oldest are dropped first.
+const MAX_ENTRIES: usize = 200;
+
+/// An event we published that not every relay has accepted yet.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OutboxEntry {
+    pub chat_id: String,
+    pub event: Value,
+    /// What was typed for it, if it is a message, so it can be edited and sent again
+    pub text: Option<String>,
+    pub queued_at: u64,
+    /// Relays it was sent to that haven't answered with an OK yet
+    pub pending: BTreeSet<String>,
+    /// Relays that rejected it, with the reason they gave
+    pub rejected: Vec<(String, String)>,
+    /// Relays that accepted it
+    pub accepted: BTreeSet<String>,
+}
+
+impl OutboxEntry {
+    /// The EVENT message publishing it again.
+    pub fn message(&self) -> Message {
+        Message::Text(json!(["EVENT", self.event]).to_string())
+    }
+}
+
+/// Our events waiting for relays to accept them, in the order they were sent.
+static OUTBOX: LazyLock<Mutex<Vec<OutboxEntry>>> = LazyLock::new(|| Mutex::new(Vec::new()));
+/// Whether a relay accepted each event answered this session, false while only rejections came in.
+static DELIVERED: LazyLock<Mutex<HashMap<String, bool>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Explanations of the machine-readable prefixes NIP-01 gives the reasons of rejections.
+const REJECTION_PREFIXES: [(&str, &str); 8] = [
+    ("rate-limited:", "you are sending too fast"),
+    ("pow:", "it wants more proof of work"),
+    ("blocked:", "you are blocked there"),
+    ("invalid:", "it considers the event invalid"),
+    ("restricted:", "only allowed users may write there"),
+    ("auth-required:", "it wants you to authenticate, see auth_relays in config.toml"),
+    ("payment-required:", "it wants payment, see /relayinfo"),
+    ("error:", "the relay failed"),
+];
+
+fn outbox_path() -> PathBuf {
+    crate::data_dir().join("outbox.json")
+}
+
+/// Fills the outbox with the events left unconfirmed in earlier sessions.
+pub fn load() {
+    let entries: Vec<OutboxEntry> = fs::read_to_string(outbox_path()).ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    *OUTBOX.lock().unwrap() = entries;
+}
+
+fn save(entries: &mut Vec<OutboxEntry>) {
+    if entries.len() > MAX_ENTRIES {
+        let excess = entries.len() - MAX_ENTRIES;
+        entries.drain(.. excess);
+    }
+    if let Err(why) = fs::write(outbox_path(), serde_json::to_string(entries).unwrap()) {
+        crate::ui::print_error(format!("Couldn't save the outbox: {}", why));
+    }
+}
+
+/// Puts the events among `msgs`, about to be sent to `relays` for the chat `chat_id`, in the outbox. Events
+/// in it already wait for the relays again, as when they are retried.
+pub fn queued(chat_id: &str, relays: &[String], msgs: &[Message]) {
+    let events: Vec<Value> = msgs.iter()
+        .filter_map(|msg| serde_json::from_str::<Value>(&msg.to_string()).ok())
+        .filter(|json_val| json_val[0] == "EVENT")
+        .map(|json_val| json_val[1].clone())
+        .collect();
+    if events.is_empty() {
+        return;
+    }
+    let mut entries = OUTBOX.lock().unwrap();
+    for event in events {
+        entries.retain(|entry| entry.event["id"] != event["id"]);
+        entries.push(OutboxEntry {
+            chat_id: chat_id.to_string(),
+            event,
+            text: None,
+            queued_at: Timestamp::now().as_u64(),
+            pending: relays.iter().cloned().collect(),
+            rejected: Vec::new(),
+            accepted: BTreeSet::new(),
+        });
+    }
+    save(&mut entries);
+}
+
+/// Makes the outbox wait for `relays` too to accept `events`, which were queued already.
+pub fn expect(events: &[Value], relays: &[String]) {
+    let mut entries = OUTBOX.lock().unwrap();
+    for entry in entries.iter_mut().filter(|entry| events.iter().any(|event| event["id"] == entry.event["id"])) {
+        entry.pending.extend(relays.iter().cloned());
+    }
+    save(&mut entries);
+}
+
+/// Keeps `text` with the events among `msgs` still in the outbox, as what was typed for them.
+pub fn attach_text(msgs: &[Message], text: &str) {
+    let ids: Vec<String> = msgs.iter()
+        .filter_map(|msg| serde_json::from_str::<Value>(&msg.to_string()).ok())
+        .filter_map(|json_val| json_val[1]["id"].as_str().map(str::to_string))
+        .collect();
+    let mut entries = OUTBOX.lock().unwrap();
+    for entry in entries.iter_mut().filter(|entry| ids.iter().any(|id| entry.event["id"] == id.as_str())) {
+        entry.text = Some(text.to_string());
+    }
+    save(&mut entries);
+}
+
+/// Records the NIP-20 OK message `json_val` of `relay`. Events every relay accepted leave the outbox.
+pub fn answered(relay: &str, json_val: &Value) {
+    let event_id = json_val[1].as_str().unwrap_or_default();
+    let mut entries = OUTBOX.lock().unwrap();
+    let entry = match entries.iter_mut().find(|entry| entry.event["id"] == event_id) {
+        Some(val) => val,
+        None => return,
+    };
+    entry.pending.remove(relay);
+    let mut delivered = DELIVERED.lock().unwrap();
+    match json_val[2].as_bool() {
+        Some(true) => {
+            entry.accepted.insert(relay.to_string());
+            delivered.insert(event_id.to_string(), true);
+        },
+        _ => {
+            let reason = json_val[3].as_str().unwrap_or_default().to_string();
+            let preview = match &entry.text {
+                Some(text) => format!("\"{}\"", text.chars().take(crate::commands::MAX_RECEIPT_PREVIEW).collect::<String>()),
+                None => format!("kind {} event", entry.event["kind"]),
+            };
+            crate::ui::print(format!("✗ {} rejected your {}: {}", relay, preview, explain_rejection(&reason)).red().to_string());
+            entry.rejected.push((relay.to_string(), reason));
+            delivered.entry(event_id.to_string()).or_insert(false);
+        },
+    }
+    drop(delivered);
+    entries.retain(|entry| !entry.pending.is_empty() || !entry.rejected.is_empty());
+    save(&mut entries);
+}
+
+/// `reason` of a rejection with what its NIP-01 prefix means, if it has one.
+pub fn explain_rejection(reason: &str) -> String {
+    match REJECTION_PREFIXES.iter().find(|(prefix, _)| reason.starts_with(prefix)) {
+        Some((_, explanation)) => format!("{} ({})", explanation, reason),
+        None if reason.is_empty() => "no reason given".to_string(),
+        None => reason.to_string(),
+    }
+}
+
+/// Mark of whether the event `event_id` we sent this session reached a relay, ✓ once one accepted it and ✗
+/// while only rejections came in. None if no relay answered yet.
+pub fn delivery_glyph(event_id: &str) -> Option<String> {
+    Some(match DELIVERED.lock().unwrap().get(event_id)? {
+        true => "✓".truecolor(128, 128, 128).to_string(),
+        false => "✗".red().to_string(),
+    })
+}
+
+/// The entries of the outbox, oldest first.
+pub fn entries() -> Vec<OutboxEntry> {
+    OUTBOX.lock().unwrap().clone()
+}
+
+/// Takes the event `event_id` out of the outbox. Returns false if it isn't in it.
+pub fn discard(event_id: &str) -> bool {
+    let mut entries = OUTBOX.lock().unwrap();
+    let before = entries.len();
+    entries.retain(|entry| entry.event["id"] != event_id);
+    let removed = entries.len() != before;
+    if removed {
+        save(&mut entries);
+    }
+    removed
+}
@@ -0,0 +1,458 @@
+use std::collections::{ HashMap, HashSet, VecDeque };
+use std::sync::{ LazyLock, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::{ Duration, Instant };
+
+use nostr::prelude::{ ClientMessage, EventBuilder, Timestamp, Url };
+use serde_json::Value;
+use tokio::sync::mpsc::{ unbounded_channel, UnboundedReceiver, UnboundedSender };
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::relays::{ self, RelayReader, RelayWriter };
+use crate::signer::Signer;
+
+/// How long a relay gets to accept the connection before the pool goes on without it.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay before the first attempt to reconnect to a relay, doubled after every failed attempt.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// Window events of a subscription are counted in, to tell whether a relay floods it.
+const OVERLOAD_WINDOW: Duration = Duration::from_secs(10);
+/// How far back a flooded subscription asks for stored events once narrowed.
+const NARROWED_SINCE: u64 = 60 * 60;
+/// Stored events a flooded subscription asks for at most once narrowed.
+const NARROWED_LIMIT: u64 = 200;
+/// Events the pool remembers having passed on, the oldest are forgotten beyond this.
+const MAX_SEEN_EVENTS: usize = 10_000;
+
+/// Events one relay may send for a subscription within OVERLOAD_WINDOW before it is narrowed, 0 for no limit.
+static OVERLOAD_THRESHOLD: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_overload_threshold(threshold: usize) {
+    OVERLOAD_THRESHOLD.store(threshold, Ordering::Relaxed);
+}
+
+/// Signer answering NIP-42 AUTH challenges, and the relays it may answer them for.
+struct AuthConfig {
+    signer: Signer,
+    relays: Vec<String>,
+}
+
+static AUTH: LazyLock<Mutex<Option<AuthConfig>>> = LazyLock::new(|| Mutex::new(None));
+/// Relays told already that they ask for AUTH without being allowed to, so it is only said once.
+static AUTH_REFUSED: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Lets the connections of later pools authenticate to `relays` as `signer` when asked to.
+pub fn set_auth(signer: Signer, relays: &[String]) {
+    let relays = relays.iter().map(|relay| relay.trim_end_matches('/').to_string()).collect();
+    *AUTH.lock().unwrap() = Some(AuthConfig { signer, relays });
+}
+
+/// What the connection tasks report to the pool reader.
+enum PoolMessage {
+    /// The relay was reached after the pool was handed out
+    Attached(usize),
+    Received(usize, Message),
+    Disconnected(usize),
+    Reconnected(usize),
+}
+
+/// Sending half of a relay pool. Messages go out to every relay, or once it is back if it is reconnecting.
+pub struct PoolWriter {
+    /// Relays of the pool with the channel to their connection task
+    senders: Vec<(String, UnboundedSender<Message>)>,
+}
+
+impl PoolWriter {
+    pub async fn send(&mut self, message: Message) -> Result<(), String> {
+        self.senders.retain(|(_, sender)| sender.send(message.clone()).is_ok());
+        match self.senders.is_empty() {
+            true => Err("Lost connection to all relays".to_string()),
+            false => Ok(()),
+        }
+    }
+
+    /// Relays messages go out to, including those reconnecting.
+    pub fn relays(&self) -> Vec<String> {
+        self.senders.iter().map(|(relay, _)| relay.clone()).collect()
+    }
+}
+
+/// Receiving half of a relay pool. Merges what all relays send into one stream, passing each event
+/// of a subscription only once and its EOSE once every connected relay has sent it.
+pub struct PoolReader {
+    relays: Vec<String>,
+    incoming: UnboundedReceiver<PoolMessage>,
+    connected: HashSet<usize>,
+    /// Subscription id and event id of the last MAX_SEEN_EVENTS events passed on
+    seen_events: HashSet<(String, String)>,
+    /// The same as seen_events, oldest first
+    seen_order: VecDeque<(String, String)>,
+    /// Relays that have sent EOSE, per subscription whose EOSE hasn't been passed on yet
+    pending_eose: HashMap<String, HashSet<usize>>,
+    finished_eose: HashSet<String>,
+    ready: VecDeque<Message>,
+}
+
+impl PoolReader {
+    /// Next message from the pool, or None once every relay has given up.
+    pub async fn next(&mut self) -> Option<Message> {
+        loop {
+            if let Some(message) = self.ready.pop_front() {
+                return Some(message);
+            }
+            match self.incoming.recv().await? {
+                PoolMessage::Attached(index) => {
+                    self.connected.insert(index);
+                },
+                PoolMessage::Received(index, message) => self.receive(index, message),
+                PoolMessage::Disconnected(index) => {
+                    crate::ui::print_error(format!("Lost connection to {}, reconnecting...", self.relays[index]));
+                    self.connected.remove(&index);
+                    self.release_eose();
+                },
+                PoolMessage::Reconnected(index) => {
+                    crate::ui::print_error(format!("Reconnected to {}", self.relays[index]));
+                    self.connected.insert(index);
+                },
+            }
+        }
+    }
+
+    fn receive(&mut self, index: usize, message: Message) {
+        let json_val: Value = match serde_json::from_str(&message.to_string()) {
+            Ok(val) => val,
+            Err(_) => {
+                self.ready.push_back(message);
+                return;
+            }
+        };
+        let subscription_id = json_val[1].as_str().unwrap_or_default().to_string();
+        match json_val[0].as_str() {
+            Some("EVENT") => {
+                let event_id = json_val[2]["id"].as_str().unwrap_or_default().to_string();
+                if self.seen_events.insert((subscription_id.clone(), event_id.clone())) {
+                    self.seen_order.push_back((subscription_id, event_id));
+                    if self.seen_order.len() > MAX_SEEN_EVENTS {
+                        let oldest = self.seen_order.pop_front().unwrap();
+                        self.seen_events.remove(&oldest);
+                    }
+                    self.ready.push_back(message);
+                }
+            },
+            Some("EOSE") => {
+                if !self.finished_eose.contains(&subscription_id) {
+                    self.pending_eose.entry(subscription_id).or_default().insert(index);
+                    self.release_eose();
+                }
+            },
+            Some("OK") => {
+                crate::outbox::answered(&self.relays[index], &json_val);
+                crate::nip11::note_rejection(&self.relays[index], json_val[3].as_str().unwrap_or_default());
+                self.ready.push_back(message);
+            },
+            _ => self.ready.push_back(message),
+        }
+    }
+
+    /// Passes on the EOSE of every subscription all connected relays are done with.
+    fn release_eose(&mut self) {
+        let connected = &self.connected;
+        let finished: Vec<String> = self.pending_eose.iter()
+            .filter(|(_, relays)| connected.is_subset(relays))
+            .map(|(subscription_id, _)| subscription_id.clone())
+            .collect();
+        for subscription_id in finished {
+            self.pending_eose.remove(&subscription_id);
+            self.ready.push_back(Message::Text(format!("[\"EOSE\",{}]", Value::String(subscription_id.clone()))));
+            self.finished_eose.insert(subscription_id);
+        }
+    }
+}
+
+/// Connects to all `relays` at once and returns as soon as one of them is reached, the others join the pool
+/// in the background as they connect. Messages sent meanwhile wait for them. Fails only if none of them can be
+/// reached.
+pub async fn connect(relays: &[String]) -> Result<(PoolWriter, PoolReader), String> {
+    let (incoming_tx, incoming_rx) = unbounded_channel();
+    let (attempts_tx, mut attempts_rx) = unbounded_channel();
+    let mut senders = Vec::new();
+    for (index, relay) in relays.iter().enumerate() {
+        let (outgoing_tx, outgoing_rx) = unbounded_channel();
+        senders.push((relay.clone(), outgoing_tx));
+        tokio::spawn(attach(index, relay.clone(), outgoing_rx, incoming_tx.clone(), attempts_tx.clone()));
+    }
+    drop(attempts_tx);
+
+    let mut failures = Vec::new();
+    let first = loop {
+        match attempts_rx.recv().await {
+            Some(Ok(index)) => break index,
+            Some(Err(failure)) => failures.push(failure),
+            None => return Err(format!("Couldn't connect to any relay. {}", failures.join(", "))),
+        }
+    };
+    for failure in failures {
+        crate::ui::print_error(format!("Couldn't connect to {}", failure));
+    }
+    // Relays that are slow to answer are told about once they give up
+    tokio::spawn(async move {
+        while let Some(attempt) = attempts_rx.recv().await {
+            if let Err(failure) = attempt {
+                crate::ui::print_error(format!("Couldn't connect to {}", failure));
+            }
+        }
+    });
+
+    let reader = PoolReader {
+        relays: relays.to_vec(),
+        incoming: incoming_rx,
+        connected: HashSet::from([first]),
+        seen_events: HashSet::new(),
+        seen_order: VecDeque::new(),
+        pending_eose: HashMap::new(),
+        finished_eose: HashSet::new(),
+        ready: VecDeque::new(),
+    };
+    Ok((PoolWriter { senders }, reader))
+}
+
+/// Connects to `relay` and relays messages between it and the pool, telling `attempts` whether it was
+/// reached. If it wasn't, the messages sent to it are dropped.
+async fn attach(index: usize, relay: String, outgoing: UnboundedReceiver<Message>, incoming: UnboundedSender<PoolMessage>, attempts: UnboundedSender<Result<usize, String>>) {
+    match timeout(CONNECT_TIMEOUT, relays::connect(&relay)).await {
+        Ok(Ok((writer, reader))) => {
+            if incoming.send(PoolMessage::Attached(index)).is_err() {
+                return;
+            }
+            attempts.send(Ok(index)).ok();
+            drop(attempts);
+            run_connection(index, relay, writer, reader, outgoing, incoming).await;
+        },
+        Ok(Err(why)) => {
+            attempts.send(Err(format!("{}: {}", relay, why))).ok();
+        },
+        Err(_) => {
+            attempts.send(Err(format!("{}: timed out", relay))).ok();
+        },
+    }
+}
+
+/// Relays messages between one relay and the pool until the pool goes away. Lost connections are
+/// reestablished with exponential backoff, and the subscriptions open at the time requested again.
+/// Events the pool already passed on are filtered out when the relay sends them a second time.
+async fn run_connection(index: usize, relay: String, mut writer: RelayWriter, mut reader: RelayReader, mut outgoing: UnboundedReceiver<Message>, incoming: UnboundedSender<PoolMessage>) {
+    // REQs of the open subscriptions by id, to be sent again after reconnecting
+    let mut subscriptions: HashMap<String, Message> = HashMap::new();
+    // Message the connection was lost while sending, which is sent again after reconnecting
+    let mut unsent: Option<Message> = None;
+    // Start of the current window and events received in it, by subscription id
+    let mut received: HashMap<String, (Instant, usize)> = HashMap::new();
+    // Subscriptions narrowed already, which aren't narrowed again
+    let mut narrowed: HashSet<String> = HashSet::new();
+    // AUTH events are signed in their own task, a remote signer may need this very connection for it
+    let (auth_tx, mut auth_rx) = unbounded_channel::<Message>();
+    // Id of the AUTH event sent last, whose OK means the subscriptions can be requested again
+    let mut auth_id: Option<String> = None;
+    loop {
+        loop {
+            tokio::select! {
+                Some(auth) = auth_rx.recv() => {
+                    auth_id = serde_json::from_str::<Value>(&auth.to_string()).ok().and_then(|json_val| json_val[1]["id"].as_str().map(str::to_string));
+                    if writer.send(auth).await.is_err() {
+                        break;
+                    }
+                },
+                message = outgoing.recv() => match message {
+                    Some(message) => {
+                        let is_req = track_subscription(&mut subscriptions, &message);
+                        if writer.send(message.clone()).await.is_err() {
+                            unsent = Some(message).filter(|_| !is_req);
+                            break;
+                        }
+                    },
+                    // The pool was dropped
+                    None => return,
+                },
+                message = reader.next() => match message {
+                    Some(Ok(message)) => {
+                        let json_val: Value = serde_json::from_str(&message.to_string()).unwrap_or_default();
+                        if json_val[0] == "AUTH" {
+                            answer_auth(&relay, json_val[1].as_str().unwrap_or_default(), auth_tx.clone());
+                            continue;
+                        }
+                        if json_val[0] == "OK" && auth_id.is_some() && json_val[1].as_str() == auth_id.as_deref() {
+                            auth_id = None;
+                            match json_val[2].as_bool() {
+                                // Subscriptions the relay refused before are answered now
+                                Some(true) => for req in subscriptions.values().cloned() {
+                                    writer.send(req).await.ok();
+                                },
+                                _ => crate::ui::print_error(format!("{} didn't accept your authentication: {}", relay, json_val[3].as_str().unwrap_or_default())),
+                            }
+                            continue;
+                        }
+                        if let Some(narrowed_req) = count_event(&mut received, &mut narrowed, &subscriptions, &message) {
+                            crate::ui::print_error(format!("{} sent over {} events of one subscription within {} seconds, asking it only for the last hour and at most {} stored events",
+                                relay, OVERLOAD_THRESHOLD.load(Ordering::Relaxed), OVERLOAD_WINDOW.as_secs(), NARROWED_LIMIT));
+                            // A REQ with the id of an open subscription replaces it
+                            track_subscription(&mut subscriptions, &narrowed_req);
+                            if writer.send(narrowed_req).await.is_err() {
+                                break;
+                            }
+                        }
+                        if incoming.send(PoolMessage::Received(index, message)).is_err() {
+                            return;
+                        }
+                    },
+                    _ => break,
+                },
+            }
+        }
+        if incoming.send(PoolMessage::Disconnected(index)).is_err() {
+            return;
+        }
+
+        let mut delay = RECONNECT_DELAY;
+        (writer, reader) = loop {
+            tokio::time::sleep(delay).await;
+            if incoming.is_closed() {
+                return;
+            }
+            if let Ok(Ok(connection)) = timeout(CONNECT_TIMEOUT, relays::connect(&relay)).await {
+                break connection;
+            }
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        };
+        for message in subscriptions.values().cloned().chain(unsent.take()) {
+            writer.send(message).await.ok();
+        }
+        if incoming.send(PoolMessage::Reconnected(index)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Signs the NIP-42 answer to `challenge` of `relay` and hands it to `auth_tx`, if the relay may be
+/// authenticated to. Otherwise the user is told once that the relay asks for it.
+fn answer_auth(relay: &str, challenge: &str, auth_tx: UnboundedSender<Message>) {
+    let signer = match AUTH.lock().unwrap().as_ref() {
+        Some(auth) if auth.relays.iter().any(|allowed| allowed == relay.trim_end_matches('/')) => auth.signer.clone(),
+        _ => {
+            if AUTH_REFUSED.lock().unwrap().insert(relay.to_string()) {
+                crate::ui::print_error(format!("{} asks you to authenticate and may not send anything until you do. Add it to auth_relays in config.toml to let it know who you are.", relay));
+            }
+            return;
+        }
+    };
+    let url = match Url::parse(relay) {
+        Ok(val) => val,
+        Err(_) => return,
+    };
+    let challenge = challenge.to_string();
+    let relay = relay.to_string();
+    tokio::spawn(async move {
+        match signer.sign(EventBuilder::auth(challenge, url)).await {
+            Ok(event) => {
+                auth_tx.send(Message::Text(ClientMessage::new_auth(event).as_json())).ok();
+            },
+            Err(why) => crate::ui::print_error(format!("Couldn't sign the authentication for {}: {}", relay, why)),
+        }
+    });
+}
+
+/// Remembers the REQ of every subscription opened through `message`, and forgets it once it is closed.
+/// Returns whether `message` is a REQ.
+fn track_subscription(subscriptions: &mut HashMap<String, Message>, message: &Message) -> bool {
+    let json_val: Value = match serde_json::from_str(&message.to_string()) {
+        Ok(val) => val,
+        Err(_) => return false
+    };
+    let subscription_id = match json_val[1].as_str() {
+        Some(val) => val.to_string(),
+        None => return false
+    };
+    match json_val[0].as_str() {
+        Some("REQ") => {
+            subscriptions.insert(subscription_id, message.clone());
+            true
+        },
+        Some("CLOSE") => {
+            subscriptions.remove(&subscription_id);
+            false
+        },
+        _ => false
+    }
+}
+
+/// Counts `message` if it is an event of one of the open `subscriptions`. Returns the narrowed REQ of its
+/// subscription once the relay sends more events for it than the overload threshold allows.
+fn count_event(received: &mut HashMap<String, (Instant, usize)>, narrowed: &mut HashSet<String>, subscriptions: &HashMap<String, Message>, message: &Message) -> Option<Message> {
+    let threshold = OVERLOAD_THRESHOLD.load(Ordering::Relaxed);
+    if threshold == 0 {
+        return None;
+    }
+    let json_val: Value = serde_json::from_str(&message.to_string()).ok()?;
+    if json_val[0] != "EVENT" {
+        return None;
+    }
+    let subscription_id = json_val[1].as_str()?;
+    let req = subscriptions.get(subscription_id)?;
+    let now = Instant::now();
+    let (window_start, count) = received.entry(subscription_id.to_string()).or_insert((now, 0));
+    if now.duration_since(*window_start) > OVERLOAD_WINDOW {
+        (*window_start, *count) = (now, 0);
+    }
+    *count += 1;
+    if *count <= threshold || !narrowed.insert(subscription_id.to_string()) {
+        return None;
+    }
+    narrowed_req(req)
+}
+
+/// `req` asking only for the stored events of the last NARROWED_SINCE seconds, at most NARROWED_LIMIT of them.
+fn narrowed_req(req: &Message) -> Option<Message> {
+    let mut json_val: Value = serde_json::from_str(&req.to_string()).ok()?;
+    let since = Timestamp::now().as_u64().saturating_sub(NARROWED_SINCE);
+    for filter in json_val.as_array_mut()?.iter_mut().skip(2) {
+        let filter = filter.as_object_mut()?;
+        let filter_since = filter.get("since").and_then(Value::as_u64).unwrap_or_default().max(since);
+        let filter_limit = filter.get("limit").and_then(Value::as_u64).unwrap_or(NARROWED_LIMIT).min(NARROWED_LIMIT);
+        filter.insert("since".to_string(), filter_since.into());
+        filter.insert("limit".to_string(), filter_limit.into());
+    }
+    Some(Message::Text(json_val.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: usize) -> Message {
+        Message::Text(format!(r#"["EVENT","sub",{{"id":"{}"}}]"#, id))
+    }
+
+    #[test]
+    fn only_the_latest_events_are_remembered() {
+        let (_, incoming) = unbounded_channel();
+        let mut reader = PoolReader {
+            relays: vec![],
+            incoming,
+            connected: HashSet::new(),
+            seen_events: HashSet::new(),
+            seen_order: VecDeque::new(),
+            pending_eose: HashMap::new(),
+            finished_eose: HashSet::new(),
+            ready: VecDeque::new(),
+        };
+        for id in 0 ..= MAX_SEEN_EVENTS {
+            reader.receive(0, event(id));
+        }
+        reader.receive(0, event(MAX_SEEN_EVENTS));
+        assert_eq!(reader.ready.len(), MAX_SEEN_EVENTS + 1);
+        reader.receive(0, event(0));
+        assert_eq!(reader.ready.len(), MAX_SEEN_EVENTS + 2);
+        assert_eq!(reader.seen_events.len(), MAX_SEEN_EVENTS);
+    }
+}
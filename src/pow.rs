@@ -0,0 +1,141 @@
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, AtomicU8, Ordering };
+use std::time::{ Duration, Instant };
+
+use nostr::prelude::*;
+use serde_json::Value;
+
+use crate::signer::Signer;
+
+/// Highest difficulty taken from a relay's NIP-11 document, a few seconds of mining. Relays asking for more
+/// would leave every message mining for minutes, so pow_difficulty has to be configured to mine to theirs.
+pub const MAX_DISCOVERED_DIFFICULTY: u8 = 24;
+/// How long a message is mined at most before it is given up on.
+const MAX_MINING_TIME: Duration = Duration::from_secs(120);
+/// Nonces tried between checks whether mining was cancelled.
+const CHECK_INTERVAL: u128 = 10_000;
+
+/// Leading zero bits the ids of our messages are mined to have (NIP-13), 0 to send them without proof of work.
+static DIFFICULTY: AtomicU8 = AtomicU8::new(0);
+
+pub fn set_difficulty(difficulty: u8) {
+    DIFFICULTY.store(difficulty, Ordering::Relaxed);
+}
+
+pub fn difficulty() -> u8 {
+    DIFFICULTY.load(Ordering::Relaxed)
+}
+
+/// Mines to the highest min_pow_difficulty the NIP-11 documents `infos` of the relays ask for, as every relay
+/// gets the same event, up to MAX_DISCOVERED_DIFFICULTY. Used when pow_difficulty isn't configured.
+pub fn discover(infos: &[(String, Value)]) {
+    let (relay, difficulty) = match infos.iter()
+        .filter_map(|(relay, info)| info["limitation"]["min_pow_difficulty"].as_u64().map(|difficulty| (relay, difficulty)))
+        .max_by_key(|(_, difficulty)| *difficulty) {
+        Some(val) => val,
+        None => return,
+    };
+    match discovered_difficulty(difficulty) {
+        Ok(0) => {},
+        Ok(difficulty) => {
+            set_difficulty(difficulty);
+            crate::ui::print(format!("A relay wants proof of work, your messages are mined to difficulty {} before they are sent.", difficulty));
+        },
+        Err(why) => crate::ui::print_error(format!("{} {}", relay, why)),
+    }
+}
+
+/// Difficulty to mine to for a relay asking for `difficulty`. Fails if it is over MAX_DISCOVERED_DIFFICULTY.
+fn discovered_difficulty(difficulty: u64) -> Result<u8, String> {
+    match u8::try_from(difficulty) {
+        Ok(difficulty) if difficulty <= MAX_DISCOVERED_DIFFICULTY => Ok(difficulty),
+        _ => Err(format!("wants proof of work of difficulty {}, which takes too long to mine. Set pow_difficulty in config.toml to mine to it anyway, it may not accept your messages otherwise.", difficulty)),
+    }
+}
+
+/// Event of `builder` by `public_key` with a nonce tag giving its id the difficulty, mined on the blocking
+/// thread pool so the screen stays responsive. Unchanged if no difficulty is set. Fails if it is cancelled
+/// with Ctrl-Z or takes longer than MAX_MINING_TIME.
+pub async fn mine(builder: EventBuilder, public_key: XOnlyPublicKey) -> Result<UnsignedEvent, String> {
+    let difficulty = difficulty();
+    let unsigned = builder.to_unsigned_event(public_key);
+    if difficulty == 0 {
+        return Ok(unsigned);
+    }
+    crate::ui::show_pending(format!("Mining proof of work (difficulty {})… (Ctrl-Z cancels)", difficulty));
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let mining = tokio::task::spawn_blocking({
+        let cancelled = cancelled.clone();
+        move || mine_blocking(unsigned, difficulty, &cancelled)
+    });
+    let mined = tokio::select! {
+        mined = mining => mined.unwrap(),
+        _ = crate::app::CANCEL_SEND.notified() => {
+            cancelled.store(true, Ordering::Relaxed);
+            Err("cancelled while mining proof of work".to_string())
+        },
+    };
+    crate::ui::show_pending(String::new());
+    mined
+}
+
+/// `unsigned` with the first nonce tag that gives its id `difficulty` leading zero bits. Fails once
+/// `cancelled` is set or MAX_MINING_TIME is over.
+fn mine_blocking(mut unsigned: UnsignedEvent, difficulty: u8, cancelled: &AtomicBool) -> Result<UnsignedEvent, String> {
+    let started = Instant::now();
+    let mut nonce: u128 = 0;
+    loop {
+        nonce += 1;
+        if nonce.is_multiple_of(CHECK_INTERVAL) {
+            if cancelled.load(Ordering::Relaxed) {
+                return Err("cancelled while mining proof of work".to_string());
+            }
+            if started.elapsed() > MAX_MINING_TIME {
+                return Err(format!("gave up mining proof of work of difficulty {} after {} seconds, lower pow_difficulty", difficulty, MAX_MINING_TIME.as_secs()));
+            }
+            unsigned.created_at = Timestamp::now();
+        }
+        unsigned.tags.push(Tag::POW { nonce, difficulty });
+        let id = EventId::new(&unsigned.pubkey, unsigned.created_at, &unsigned.kind, &unsigned.tags, &unsigned.content);
+        if get_leading_zero_bits(id.inner()) >= difficulty {
+            unsigned.id = id;
+            return Ok(unsigned);
+        }
+        unsigned.tags.pop();
+    }
+}
+
+/// Signs the event of `builder` with `signer`, mined to the difficulty first.
+pub async fn sign(signer: &Signer, builder: EventBuilder) -> Result<Event, String> {
+    signer.sign_unsigned(mine(builder, signer.public_key()).await?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unsigned_note() -> UnsignedEvent {
+        EventBuilder::new_text_note("hi", &[]).to_unsigned_event(Keys::generate().public_key())
+    }
+
+    #[test]
+    fn discovered_difficulty_is_capped() {
+        assert_eq!(discovered_difficulty(0), Ok(0));
+        assert_eq!(discovered_difficulty(MAX_DISCOVERED_DIFFICULTY as u64), Ok(MAX_DISCOVERED_DIFFICULTY));
+        assert!(discovered_difficulty(MAX_DISCOVERED_DIFFICULTY as u64 + 1).is_err());
+        assert!(discovered_difficulty(300).is_err());
+    }
+
+    #[test]
+    fn mined_ids_have_the_difficulty() {
+        let mined = mine_blocking(unsigned_note(), 8, &AtomicBool::new(false)).unwrap();
+        assert!(get_leading_zero_bits(mined.id.inner()) >= 8);
+        assert_eq!(mined.id, EventId::new(&mined.pubkey, mined.created_at, &mined.kind, &mined.tags, &mined.content));
+        assert!(mined.tags.iter().any(|tag| matches!(tag, Tag::POW { difficulty: 8, .. })));
+    }
+
+    #[test]
+    fn mining_stops_when_cancelled() {
+        assert!(mine_blocking(unsigned_note(), u8::MAX, &AtomicBool::new(true)).is_err());
+    }
+}
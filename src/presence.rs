@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+
+use nostr::prelude::*;
+use serde_json::Value;
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::chats::ChatType;
+use crate::export::verify_event;
+use crate::pool;
+use crate::signer::Signer;
+use crate::ui;
+
+/// Kind of presence events. Ephemeral, so relays pass them on to subscribers without storing them.
+pub const PRESENCE_KIND: u64 = 20042;
+/// How often we announce that we are in the shown channel.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+/// How long someone counts as here after their last announcement, so one lost event doesn't drop them.
+const PRESENCE_TIMEOUT: u64 = 3 * 60;
+/// How often the count in the status bar is brought up to date.
+pub const STATUS_REFRESH: Duration = Duration::from_secs(15);
+
+/// Who else is in the shown channel right now, from the presence events announced in it.
+#[derive(Clone)]
+pub struct Presence {
+    /// created_at of the latest announcement of each public key in the shown channel
+    seen: Arc<Mutex<HashMap<String, u64>>>,
+    /// The shown channel, None while a private chat is shown
+    active: Arc<watch::Sender<Option<EventId>>>,
+    /// Whether we announce ourselves too. Others are counted either way.
+    sharing: Arc<AtomicBool>,
+}
+
+fn channel_id(chat: &ChatType) -> Option<EventId> {
+    match chat {
+        ChatType::PublicChannel(channel) => Some(channel.root_event.id),
+        // Announcing a private chat would reveal who it is with
+        ChatType::PrivateChat(_) => None,
+    }
+}
+
+impl Presence {
+    pub fn new(chat: &ChatType, sharing: bool) -> Self {
+        Presence {
+            seen: Arc::new(Mutex::new(HashMap::new())),
+            active: Arc::new(watch::Sender::new(channel_id(chat))),
+            sharing: Arc::new(AtomicBool::new(sharing)),
+        }
+    }
+
+    /// Counts the presence in `chat` from now on, and announces it if sharing.
+    pub fn set_active(&self, chat: &ChatType) {
+        let channel = channel_id(chat);
+        if *self.active.borrow() != channel {
+            self.seen.lock().unwrap().clear();
+            self.active.send_replace(channel);
+        }
+    }
+
+    pub fn is_sharing(&self) -> bool {
+        self.sharing.load(Ordering::SeqCst)
+    }
+
+    pub fn set_sharing(&self, sharing: bool) {
+        self.sharing.store(sharing, Ordering::SeqCst);
+    }
+
+    /// How many are in the shown channel, us included if we announce ourselves. None in private chats.
+    pub fn here_now(&self) -> Option<usize> {
+        self.active.borrow().as_ref()?;
+        let since = Timestamp::now().as_u64().saturating_sub(PRESENCE_TIMEOUT);
+        Some(self.seen.lock().unwrap().values().filter(|created_at| **created_at >= since).count())
+    }
+
+    /// Announces us in the shown channel every so often, if sharing, and counts the announcements of others.
+    pub async fn run(self, relays: Vec<String>, signer: Signer) {
+        let (mut writer, mut reader) = match pool::connect(&relays).await {
+            Ok(val) => val,
+            Err(why) => {
+                ui::print_error(format!("Presence unavailable: {}", why));
+                return;
+            }
+        };
+        let subscription_id = SubscriptionId::generate();
+        let mut active = self.active.subscribe();
+        let mut announce = tokio::time::interval(ANNOUNCE_INTERVAL);
+        let mut subscribed = None;
+        loop {
+            let channel = *active.borrow_and_update();
+            if channel != subscribed {
+                // A REQ with the same id replaces the subscription to the previous channel
+                let message = match channel {
+                    Some(channel) => ClientMessage::new_req(subscription_id.clone(), vec![Filter::new().kind(Kind::Custom(PRESENCE_KIND)).event(channel)]),
+                    None => ClientMessage::close(subscription_id.clone()),
+                };
+                if writer.send(Message::Text(message.as_json())).await.is_err() {
+                    return;
+                }
+                subscribed = channel;
+                announce.reset_immediately();
+            }
+            tokio::select! {
+                _ = announce.tick() => {
+                    if let Some(channel) = channel.filter(|_| self.is_sharing()) {
+                        let builder = EventBuilder::new(Kind::Custom(PRESENCE_KIND), "", &[Tag::Event(channel, None, None)]);
+                        match signer.sign(builder).await {
+                            Ok(event) => writer.send(Message::Text(ClientMessage::new_event(event).as_json())).await.ok(),
+                            Err(why) => {
+                                ui::print_error(format!("Couldn't announce your presence: {}", why));
+                                None
+                            },
+                        };
+                    }
+                },
+                changed = active.changed() => if changed.is_err() {
+                    return;
+                },
+                message = reader.next() => match message {
+                    Some(message) => self.record(&message, channel),
+                    None => return,
+                },
+            }
+        }
+    }
+
+    fn record(&self, message: &Message, channel: Option<EventId>) {
+        let json_val: Value = match serde_json::from_str(&message.to_string()) {
+            Ok(val) => val,
+            Err(_) => return,
+        };
+        let event = &json_val[2];
+        if json_val[0] != "EVENT" || event["kind"].as_u64() != Some(PRESENCE_KIND) {
+            return;
+        }
+        // Relays don't have to honour the filter, and an announcement of the previous channel may still arrive
+        let in_channel = channel.is_some_and(|channel| event["tags"].as_array().is_some_and(|tags| tags.iter()
+            .any(|tag| tag[0] == "e" && tag[1] == channel.to_hex().as_str())));
+        if !in_channel || verify_event(event).is_err() {
+            return;
+        }
+        let (public_key, created_at) = match (event["pubkey"].as_str(), event["created_at"].as_u64()) {
+            (Some(public_key), Some(created_at)) => (public_key, created_at),
+            _ => return,
+        };
+        // Announcements from the future would keep someone here for good
+        let created_at = created_at.min(Timestamp::now().as_u64());
+        let mut seen = self.seen.lock().unwrap();
+        let latest = seen.entry(public_key.to_string()).or_insert(created_at);
+        *latest = (*latest).max(created_at);
+    }
+}
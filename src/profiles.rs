@@ -0,0 +1,161 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use colored::Colorize;
+use futures::future::join_all;
+use nostr::prelude::*;
+use serde_json::Value;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::crypto::PREKEY_KIND;
+use crate::export::verify_event;
+use crate::relays;
+
+/// Parses a public key given in bech32 (npub) or hex.
+pub fn parse_public_key(input: &str) -> Option<XOnlyPublicKey> {
+    XOnlyPublicKey::from_bech32(input).or_else(|_| XOnlyPublicKey::from_str(input)).ok()
+}
+
+/// Direct message schemes a user can be seen using in the events they publish.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DmScheme {
+    /// Nostrachat's kind 420 messages
+    Ratchet,
+    /// NIP-04 kind 4 messages
+    Nip04,
+    /// Kind 4 messages with NIP-44 encrypted content
+    Nip44,
+    /// NIP-17 gift wrapped messages, announced with a kind 10050 DM relay list
+    GiftWrap,
+}
+
+/// Fetches the stored events matching any of `filters` from `relay`, up to EOSE.
+pub async fn fetch_events(relay: &str, filters: Vec<Filter>) -> Vec<Event> {
+    let (mut writer, mut reader) = match relays::connect(relay).await {
+        Ok(val) => val,
+        Err(_) => return Vec::new()
+    };
+    let req = ClientMessage::new_req(SubscriptionId::generate(), filters).as_json();
+    if writer.send(Message::Text(req)).await.is_err() {
+        return Vec::new();
+    }
+
+    let mut events = Vec::new();
+    let receiving = async {
+        while let Some(Ok(message)) = reader.next().await {
+            let json_val: Value = match serde_json::from_str(&message.to_string()) {
+                Ok(val) => val,
+                Err(_) => continue
+            };
+            match json_val[0].as_str() {
+                Some("EVENT") => {
+                    if let Ok(event) = Event::from_value(json_val[2].clone()) {
+                        events.push(event);
+                    }
+                },
+                Some("EOSE") | Some("NOTICE") => break,
+                _ => {}
+            }
+        }
+    };
+    timeout(Duration::from_secs(10), receiving).await.ok();
+    events
+}
+
+/// Fetches the latest kind 0 metadata of `public_key` from `relay`.
+pub async fn fetch_metadata(relay: &str, public_key: XOnlyPublicKey) -> Option<Metadata> {
+    let filter = Filter::new().kind(Kind::Metadata).author(public_key.to_string()).limit(1);
+    let latest = fetch_events(relay, vec![filter]).await.into_iter().max_by_key(|event| event.created_at)?;
+    Metadata::from_json(latest.content).ok()
+}
+
+/// Newest kind 0 event of `public_key` on any of `relays`, None if there is none. Fails if no relay answered.
+pub async fn fetch_metadata_event(relays: &[String], public_key: XOnlyPublicKey) -> Result<Option<Value>, String> {
+    let filter = Filter::new().kind(Kind::Metadata).author(public_key.to_string());
+    let req = Message::Text(ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json());
+    let results = join_all(relays.iter().map(|relay| relays::fetch_stored_events(relay, req.clone()))).await;
+    if results.iter().all(|result| result.is_err()) {
+        return Err("no relay answered".to_string());
+    }
+    Ok(results.into_iter()
+        .filter_map(|result| result.ok())
+        .flatten()
+        .filter(|event| event["kind"].as_u64() == Some(0) && event["pubkey"] == public_key.to_string().as_str())
+        .filter(|event| verify_event(event).is_ok())
+        .max_by_key(|event| event["created_at"].as_u64().unwrap_or_default()))
+}
+
+/// Relays `public_key` reads from as their NIP-65 relay list names them: those marked read or not marked
+/// at all. Empty if they published none.
+pub async fn fetch_inbox_relays(relay: &str, public_key: XOnlyPublicKey) -> Vec<String> {
+    let filter = Filter::new().kind(Kind::RelayList).author(public_key.to_string()).limit(1);
+    let latest = match fetch_events(relay, vec![filter]).await.into_iter().max_by_key(|event| event.created_at) {
+        Some(val) => val,
+        None => return Vec::new(),
+    };
+    let mut inboxes: Vec<String> = Vec::new();
+    for tag in latest.tags.iter().map(|tag| tag.as_vec()) {
+        let read = tag.get(2).is_none_or(|marker| marker == "read");
+        let url = tag.get(1).map(|url| url.trim().trim_end_matches('/').to_string()).unwrap_or_default();
+        if tag[0] == "r" && read && (url.starts_with("wss://") || url.starts_with("ws://")) && !inboxes.contains(&url) {
+            inboxes.push(url);
+        }
+    }
+    inboxes
+}
+
+/// Fetches the signed prekey `public_key` published for starting ratchet sessions with them.
+pub async fn fetch_prekey(relay: &str, public_key: XOnlyPublicKey) -> Option<XOnlyPublicKey> {
+    let filter = Filter::new().kind(Kind::Custom(PREKEY_KIND)).author(public_key.to_string()).limit(1);
+    let latest = fetch_events(relay, vec![filter]).await.into_iter().max_by_key(|event| event.created_at)?;
+    XOnlyPublicKey::from_str(latest.content.trim()).ok()
+}
+
+/// Detects which DM scheme `public_key` uses. A DM relay list means they expect gift wrapped
+/// messages, otherwise the most recent of their NIP-04 messages and the kind 420 messages sent to
+/// them wins. Kind 420 messages are signed with throwaway keys, so only those sent to them can be found.
+pub async fn detect_dm_scheme(relay: &str, public_key: XOnlyPublicKey) -> Option<DmScheme> {
+    let own_events = Filter::new()
+        .kinds(vec![Kind::EncryptedDirectMessage, Kind::Custom(10050)])
+        .author(public_key.to_string())
+        .limit(20);
+    let ratchet_messages = Filter::new().kind(Kind::Custom(420)).pubkey(public_key).limit(1);
+    let events = fetch_events(relay, vec![own_events, ratchet_messages]).await;
+    if events.iter().any(|event| event.kind == Kind::Custom(10050)) {
+        return Some(DmScheme::GiftWrap);
+    }
+    let latest = events.iter().max_by_key(|event| event.created_at)?;
+    match latest.kind {
+        Kind::EncryptedDirectMessage if latest.content.contains("?iv=") => Some(DmScheme::Nip04),
+        Kind::EncryptedDirectMessage => Some(DmScheme::Nip44),
+        _ => Some(DmScheme::Ratchet),
+    }
+}
+
+/// Formats a profile like `Chat::get_info_table` does for chats.
+pub fn get_profile_table(public_key: &XOnlyPublicKey, metadata: Option<&Metadata>) -> String {
+    let mut lines = vec!["Public key: ".green().to_string() + &public_key.to_bech32().unwrap()];
+    let metadata = match metadata {
+        Some(val) => val,
+        None => {
+            lines.push("No profile found on this relay.".to_string());
+            return lines.join("\n");
+        }
+    };
+    let fields = [
+        ("Name: ", &metadata.name),
+        ("Display name: ", &metadata.display_name),
+        ("About: ", &metadata.about),
+        ("Website: ", &metadata.website),
+        ("Picture: ", &metadata.picture),
+        ("NIP-05: ", &metadata.nip05),
+        ("Lightning address: ", &metadata.lud16),
+    ];
+    for (label, value) in fields {
+        if let Some(value) = value {
+            lines.push(label.green().to_string() + value);
+        }
+    }
+    lines.join("\n")
+}
@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use chrono::{ Local, NaiveTime };
+use colored::Colorize;
+use nostr::prelude::*;
+use rustyline::ExternalPrinter;
+use serde::{ Deserialize, Serialize };
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::relays::Connections;
+
+/// How often subscriptions are checked against the quiet hours.
+pub const QUIET_HOURS_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Daily window, in local "HH:MM" times, during which channel subscriptions are closed.
+/// The window may span midnight, e.g. from "23:00" to "07:00".
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QuietHours {
+    pub start: String,
+    pub end: String,
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        let start = NaiveTime::parse_from_str(&self.start, "%H:%M");
+        let end = NaiveTime::parse_from_str(&self.end, "%H:%M");
+        let (start, end) = match (start, end) {
+            (Ok(start), Ok(end)) => (start, end),
+            _ => return false,
+        };
+        if start <= end {
+            start <= time && time < end
+        } else {
+            time >= start || time < end
+        }
+    }
+
+    pub fn is_quiet_now(&self) -> bool {
+        self.contains(Local::now().time())
+    }
+}
+
+/// Closes the subscriptions of the open chats that are public channels while quiet hours last, and
+/// resubscribes with a `since` filter afterwards, so messages from the quiet window still show up.
+pub async fn enforce<T: ExternalPrinter>(hours: QuietHours, connections: Connections, mut printer: T) {
+    let mut quiet_check = tokio::time::interval(QUIET_HOURS_CHECK_INTERVAL);
+    loop {
+        quiet_check.tick().await;
+        let quiet = hours.is_quiet_now();
+        let mut connections = connections.lock().await;
+        let mut paused = 0;
+        let mut resumed = 0;
+        for connection in connections.values_mut().filter(|connection| connection.is_channel) {
+            match (quiet, connection.suspended_since) {
+                (true, None) => {
+                    let close = ClientMessage::close(connection.subscription_id.clone()).as_json();
+                    if connection.writer.send(Message::Text(close)).await.is_ok() {
+                        connection.suspended_since = Some(Timestamp::now());
+                        paused += 1;
+                    }
+                },
+                (false, Some(since)) => {
+                    let filter = connection.filter.clone().since(since);
+                    let req = ClientMessage::new_req(connection.subscription_id.clone(), vec![filter]).as_json();
+                    if connection.writer.send(Message::Text(req)).await.is_ok() {
+                        connection.suspended_since = None;
+                        resumed += 1;
+                    }
+                },
+                _ => {}
+            }
+        }
+        if paused > 0 {
+            printer.print(format!("[{}] Channels paused until {}", "QUIET HOURS".blue(), hours.end)).ok();
+        }
+        if resumed > 0 {
+            printer.print(format!("[{}] Channels resumed", "QUIET HOURS".blue())).ok();
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{ LazyLock, Mutex };
+
+use colored::Colorize;
+use nostr::prelude::Timestamp;
+use serde::{ Deserialize, Serialize };
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+/// Receipts kept at most, the oldest are dropped first.
+const MAX_RECEIPTS: usize = 1000;
+/// Kinds of the events private chats publish: NIP-04/44 DMs, gift wraps and ratchet messages.
+const PRIVATE_KINDS: [u64; 3] = [4, 1059, 420];
+
+/// How far a message we sent in a private chat got.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiptState {
+    /// Signed and handed to the connection
+    Queued,
+    /// Written to the relays
+    Sent,
+    /// A relay answered OK or sent it back
+    Accepted,
+    /// The recipient wrote in the chat since, so they have seen it
+    Seen,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Receipt {
+    pub chat_id: String,
+    /// Each state reached and when
+    pub timeline: Vec<(ReceiptState, u64)>,
+    /// OKs received from relays
+    #[serde(default)]
+    pub accepted_by: usize,
+}
+
+impl Receipt {
+    pub fn state(&self) -> ReceiptState {
+        self.timeline.last().map_or(ReceiptState::Queued, |(state, _)| *state)
+    }
+
+    fn queued_at(&self) -> u64 {
+        self.timeline.first().map_or(0, |(_, at)| *at)
+    }
+
+    /// Moves on to `state` at `at`, unless it got that far already. Returns whether it moved.
+    fn advance(&mut self, state: ReceiptState, at: u64) -> bool {
+        if state <= self.state() {
+            return false;
+        }
+        self.timeline.push((state, at));
+        true
+    }
+}
+
+/// Receipts of our messages, keyed by the id they are shown with.
+static RECEIPTS: LazyLock<Mutex<HashMap<String, Receipt>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+/// Ids of the events published for a message when they differ from the one it is shown with, like those of
+/// its gift wraps.
+static ALIASES: LazyLock<Mutex<HashMap<String, String>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn receipts_path() -> PathBuf {
+    crate::data_dir().join("receipts.json")
+}
+
+/// Fills the receipts with those of earlier sessions.
+pub fn load() {
+    let receipts: HashMap<String, Receipt> = fs::read_to_string(receipts_path()).ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    *RECEIPTS.lock().unwrap() = receipts;
+}
+
+fn save(receipts: &mut HashMap<String, Receipt>) {
+    if receipts.len() > MAX_RECEIPTS {
+        let mut by_age: Vec<(String, u64)> = receipts.iter().map(|(id, receipt)| (id.clone(), receipt.queued_at())).collect();
+        by_age.sort_by_key(|(_, queued_at)| *queued_at);
+        for (id, _) in by_age.into_iter().take(receipts.len() - MAX_RECEIPTS) {
+            receipts.remove(&id);
+        }
+    }
+    if let Err(why) = fs::write(receipts_path(), serde_json::to_string(receipts).unwrap()) {
+        crate::ui::print_error(format!("Couldn't save message receipts: {}", why));
+    }
+}
+
+/// Records that the event `published_id` carries the message shown as `shown_id`.
+pub fn link(published_id: &str, shown_id: &str) {
+    ALIASES.lock().unwrap().insert(published_id.to_string(), shown_id.to_string());
+}
+
+fn shown_id(event_id: &str) -> String {
+    ALIASES.lock().unwrap().get(event_id).cloned().unwrap_or(event_id.to_string())
+}
+
+/// Events of the private messages among `msgs` that are for the chat `chat_id`.
+fn private_events(chat_id: &str, msgs: &[Message]) -> Vec<Value> {
+    msgs.iter()
+        .filter_map(|msg| serde_json::from_str::<Value>(&msg.to_string()).ok())
+        .map(|json_val| json_val[1].clone())
+        .filter(|event| event["kind"].as_u64().is_some_and(|kind| PRIVATE_KINDS.contains(&kind)))
+        // Replies to remote commands go out over the shown chat's connection too
+        .filter(|event| event["kind"] != 4 || event["tags"].as_array().is_some_and(|tags| tags.iter().any(|tag| tag[0] == "p" && tag[1] == chat_id)))
+        .collect()
+}
+
+/// Starts tracking the messages among `msgs`, about to be sent in the private chat `chat_id`.
+pub fn queued(chat_id: &str, msgs: &[Message]) {
+    let now = Timestamp::now().as_u64();
+    let mut receipts = RECEIPTS.lock().unwrap();
+    for event in private_events(chat_id, msgs) {
+        receipts.entry(shown_id(event["id"].as_str().unwrap_or_default())).or_insert(Receipt {
+            chat_id: chat_id.to_string(),
+            timeline: vec![(ReceiptState::Queued, now)],
+            accepted_by: 0,
+        });
+    }
+    save(&mut receipts);
+}
+
+/// Marks the messages among `msgs` as written to the relays.
+pub fn sent(chat_id: &str, msgs: &[Message]) {
+    let now = Timestamp::now().as_u64();
+    let mut receipts = RECEIPTS.lock().unwrap();
+    for event in private_events(chat_id, msgs) {
+        if let Some(receipt) = receipts.get_mut(&shown_id(event["id"].as_str().unwrap_or_default())) {
+            receipt.advance(ReceiptState::Sent, now);
+        }
+    }
+    save(&mut receipts);
+}
+
+/// Counts the OK a relay sent for the event `event_id`.
+pub fn accepted(event_id: &str) {
+    let mut receipts = RECEIPTS.lock().unwrap();
+    if let Some(receipt) = receipts.get_mut(&shown_id(event_id)) {
+        receipt.accepted_by += 1;
+        if receipt.advance(ReceiptState::Accepted, Timestamp::now().as_u64()) {
+            save(&mut receipts);
+        }
+    }
+}
+
+/// Updates the receipts of the private chat `chat_id` for its `event`: one of ours came back from a relay,
+/// or, if it isn't `own`, the recipient has seen everything we sent before it.
+pub fn received(chat_id: &str, event: &Value, own: bool) {
+    let mut receipts = RECEIPTS.lock().unwrap();
+    // Ratchet messages are published with throwaway keys, so ours are known by being tracked
+    if let Some(receipt) = receipts.get_mut(event["id"].as_str().unwrap_or_default()) {
+        if receipt.advance(ReceiptState::Accepted, Timestamp::now().as_u64()) {
+            save(&mut receipts);
+        }
+        return;
+    }
+    if own {
+        return;
+    }
+    let created_at = event["created_at"].as_u64().unwrap_or_default();
+    let mut changed = false;
+    for receipt in receipts.values_mut().filter(|receipt| receipt.chat_id == chat_id && receipt.queued_at() <= created_at) {
+        changed |= receipt.advance(ReceiptState::Seen, created_at);
+    }
+    if changed {
+        save(&mut receipts);
+    }
+}
+
+/// Compact mark of how far the message `event_id` got, None if it isn't one of ours.
+pub fn glyph(event_id: &str) -> Option<String> {
+    let state = RECEIPTS.lock().unwrap().get(event_id)?.state();
+    Some(match state {
+        ReceiptState::Queued => "…".truecolor(128, 128, 128).to_string(),
+        ReceiptState::Sent => "✓".truecolor(128, 128, 128).to_string(),
+        ReceiptState::Accepted => "✓✓".truecolor(128, 128, 128).to_string(),
+        ReceiptState::Seen => "✓✓".cyan().to_string(),
+    })
+}
+
+/// The latest `count` receipts of the chat `chat_id` with the ids of their messages, newest first.
+pub fn latest(chat_id: &str, count: usize) -> Vec<(String, Receipt)> {
+    let mut receipts: Vec<(String, Receipt)> = RECEIPTS.lock().unwrap().iter()
+        .filter(|(_, receipt)| receipt.chat_id == chat_id)
+        .map(|(id, receipt)| (id.clone(), receipt.clone()))
+        .collect();
+    receipts.sort_by_key(|(_, receipt)| std::cmp::Reverse(receipt.queued_at()));
+    receipts.truncate(count);
+    receipts
+}
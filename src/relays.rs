@@ -0,0 +1,479 @@
+use std::collections::{ HashMap, HashSet };
+use std::sync::{ Arc, LazyLock, Mutex };
+use std::time::{ Duration, Instant };
+
+use colored::Colorize;
+use futures::future::join_all;
+use futures::stream::{ SplitSink, SplitStream };
+use futures_util::{ SinkExt, StreamExt };
+use nostr::prelude::{ ClientMessage, EventBuilder, Filter, Kind, SubscriptionId, Timestamp };
+use serde_json::{ json, Value };
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use crate::pool::PoolWriter;
+use crate::signer::Signer;
+use tokio_tungstenite::{ connect_async, tungstenite, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream };
+
+/// How long a relay gets to deliver stored events before its history is considered complete.
+const HISTORY_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long a relay gets to confirm published events.
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(60);
+/// Connect and REQ round trips /relays probe measures on each relay.
+const PROBE_ROUNDS: usize = 5;
+/// How long a probe round may take before the relay counts as unreachable.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Ephemeral kind of the throwaway event publishing is tried with, which relays don't store.
+const WRITE_PROBE_KIND: u64 = 20_000;
+/// How long a relay gets to confirm the write probe before it is warned about.
+const WRITE_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Traffic exchanged with each relay during this session, keyed by relay url.
+static TRAFFIC: LazyLock<Mutex<HashMap<String, Traffic>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Bytes and websocket messages exchanged with one relay, over all connections to it.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Traffic {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+}
+
+/// Sending half of a relay connection, which accounts everything it sends to the relay.
+pub struct RelayWriter {
+    relay: String,
+    sink: SplitSink<Socket, Message>,
+}
+
+impl RelayWriter {
+    pub async fn send(&mut self, message: Message) -> Result<(), tungstenite::Error> {
+        let size = message.len() as u64;
+        self.sink.send(message).await?;
+        let mut traffic = TRAFFIC.lock().unwrap();
+        let entry = traffic.entry(self.relay.clone()).or_default();
+        entry.bytes_out += size;
+        entry.messages_out += 1;
+        Ok(())
+    }
+}
+
+/// Receiving half of a relay connection, which accounts everything it receives from the relay.
+pub struct RelayReader {
+    relay: String,
+    stream: SplitStream<Socket>,
+}
+
+impl RelayReader {
+    pub async fn next(&mut self) -> Option<Result<Message, tungstenite::Error>> {
+        let message = self.stream.next().await;
+        if let Some(Ok(received)) = &message {
+            let mut traffic = TRAFFIC.lock().unwrap();
+            let entry = traffic.entry(self.relay.clone()).or_default();
+            entry.bytes_in += received.len() as u64;
+            entry.messages_in += 1;
+        }
+        message
+    }
+}
+
+/// Opens a websocket connection to `relay`, with its traffic accounted under the relay's url.
+pub async fn connect(relay: &str) -> Result<(RelayWriter, RelayReader), tungstenite::Error> {
+    let (socket, _response) = connect_async(relay).await?;
+    let (sink, stream) = socket.split();
+    Ok((RelayWriter { relay: relay.to_string(), sink }, RelayReader { relay: relay.to_string(), stream }))
+}
+
+/// Lists the traffic of every relay contacted this session, heaviest first, with a total.
+pub fn traffic_report() -> String {
+    let mut traffic: Vec<(String, Traffic)> = TRAFFIC.lock().unwrap().iter().map(|(relay, traffic)| (relay.clone(), *traffic)).collect();
+    if traffic.is_empty() {
+        return "No relay traffic yet".to_string();
+    }
+    traffic.sort_by_key(|(_, traffic)| std::cmp::Reverse(traffic.bytes_in + traffic.bytes_out));
+
+    let mut total = Traffic::default();
+    let mut lines = Vec::new();
+    for (relay, traffic) in traffic.iter() {
+        lines.push(format!("{} {}", relay.green(), format_traffic(traffic)));
+        total.bytes_in += traffic.bytes_in;
+        total.bytes_out += traffic.bytes_out;
+        total.messages_in += traffic.messages_in;
+        total.messages_out += traffic.messages_out;
+    }
+    lines.push(format!("{} {}", "Total".green(), format_traffic(&total)));
+    lines.join("\n")
+}
+
+fn format_traffic(traffic: &Traffic) -> String {
+    format!("{} in ({} messages), {} out ({} messages)", format_bytes(traffic.bytes_in), traffic.messages_in, format_bytes(traffic.bytes_out), traffic.messages_out)
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0 ..= 1023 => format!("{} B", bytes),
+        1024 ..= 1_048_575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+/// Connection the open chat is subscribed on. It is shared with tasks that manage the subscription in the background.
+pub struct ChatConnection {
+    pub writer: PoolWriter,
+    pub subscription_id: SubscriptionId,
+    pub filter: Filter,
+    /// Whether the chat is a public channel, whose subscription may be closed during quiet hours
+    pub is_channel: bool,
+    /// When the subscription was closed for quiet hours, while it is
+    pub suspended_since: Option<Timestamp>,
+}
+
+/// Connections of the open chats, keyed by chat id.
+pub type Connections = Arc<tokio::sync::Mutex<HashMap<String, ChatConnection>>>;
+
+/// Sends `req` to `relay` and collects all stored events it returns up to EOSE.
+pub async fn fetch_stored_events(relay: &str, req: Message) -> Result<Vec<Value>, String> {
+    let (mut writer, mut reader) = connect(relay).await.map_err(|why| why.to_string())?;
+    writer.send(req).await.map_err(|why| why.to_string())?;
+
+    let mut events = Vec::new();
+    let receiving = async {
+        while let Some(Ok(message)) = reader.next().await {
+            let mut json_val: Value = match serde_json::from_str(&message.to_string()) {
+                Ok(val) => val,
+                Err(_) => continue
+            };
+            match json_val[0].as_str() {
+                Some("EVENT") => events.push(json_val[2].take()),
+                Some("EOSE") => return Ok(()),
+                Some("NOTICE") => return Err(format!("NOTICE: {}", json_val[1])),
+                _ => {}
+            }
+        }
+        Err("Connection closed before end of stored events".to_string())
+    };
+    match timeout(HISTORY_TIMEOUT, receiving).await {
+        Ok(result) => result.map(|_| events),
+        Err(_) => Err("Timed out before end of stored events".to_string()),
+    }
+}
+
+/// Sends `req` to `relay` and collects the ids of all stored events it returns up to EOSE.
+pub async fn fetch_event_ids(relay: &str, req: Message) -> Result<HashSet<String>, String> {
+    let events = fetch_stored_events(relay, req).await?;
+    Ok(events.iter().filter_map(|event| event["id"].as_str()).map(|id| id.to_string()).collect())
+}
+
+/// Fetches the history selected by `req` from all `relays` and reports which events each relay is
+/// missing compared to the others.
+pub async fn history_report(relays: &[String], req: Message) -> String {
+    let results = join_all(relays.iter().map(|relay| fetch_event_ids(relay, req.clone()))).await;
+
+    let union: HashSet<&String> = results.iter().filter_map(|result| result.as_ref().ok()).flatten().collect();
+    let mut lines = vec![format!("{} distinct events across all relays", union.len())];
+    for (relay, result) in relays.iter().zip(results.iter()) {
+        let ids = match result {
+            Ok(val) => val,
+            Err(why) => {
+                lines.push(format!("{} {}", relay.red(), why));
+                continue;
+            }
+        };
+        let missing = union.len() - ids.len();
+        let summary = if missing == 0 { "complete".green().to_string() } else { format!("missing {}", missing).yellow().to_string() };
+        lines.push(format!("{} {} events, {}", relay.green(), ids.len(), summary));
+
+        for (other_relay, other_result) in relays.iter().zip(results.iter()) {
+            if let Ok(other_ids) = other_result {
+                let missing_from_other = other_ids.difference(ids).count();
+                if other_relay != relay && missing_from_other > 0 {
+                    lines.push(format!("    is missing {} events that {} has", missing_from_other, other_relay));
+                }
+            }
+        }
+    }
+    lines.join("\n")
+}
+
+/// Missing events /compare prints for each side. Beyond that they are only counted.
+const COMPARE_SAMPLE: usize = 5;
+
+/// Fetches the history selected by `req` from `relay` and `other_relay` and reports the events each of
+/// them has that the other doesn't, with a sample of the newest. Events that aren't authentic are left out,
+/// so a relay can't make up what the other supposedly dropped.
+pub async fn compare_report(relay: &str, other_relay: &str, req: Message) -> String {
+    let (results, other_results) = futures::join!(fetch_stored_events(relay, req.clone()), fetch_stored_events(other_relay, req));
+    let (events, other_events) = match (results, other_results) {
+        (Ok(events), Ok(other_events)) => (events, other_events),
+        (Err(why), _) => return format!("{} {}", relay.red(), why),
+        (_, Err(why)) => return format!("{} {}", other_relay.red(), why),
+    };
+    let authentic = |events: Vec<Value>| -> (HashMap<String, Value>, usize) {
+        let total = events.len();
+        let valid: HashMap<String, Value> = events.into_iter()
+            .filter(|event| crate::export::verify_event(event).is_ok())
+            .map(|event| (event["id"].as_str().unwrap_or_default().to_string(), event))
+            .collect();
+        let invalid = total - valid.len();
+        (valid, invalid)
+    };
+    let (events, invalid) = authentic(events);
+    let (other_events, other_invalid) = authentic(other_events);
+
+    let mut lines = Vec::new();
+    for (name, own, others, invalid) in [(relay, &events, &other_events, invalid), (other_relay, &other_events, &events, other_invalid)] {
+        let mut missing: Vec<&Value> = others.iter().filter(|(id, _)| !own.contains_key(*id)).map(|(_, event)| event).collect();
+        let summary = if missing.is_empty() { "complete".green().to_string() } else { format!("missing {}", missing.len()).yellow().to_string() };
+        lines.push(format!("{} {} events, {}", name.green(), own.len(), summary));
+        if invalid > 0 {
+            lines.push(format!("    sent {} events that aren't authentic", invalid).red().to_string());
+        }
+        missing.sort_by_key(|event| std::cmp::Reverse(event["created_at"].as_u64().unwrap_or_default()));
+        for event in missing.iter().take(COMPARE_SAMPLE) {
+            let kind = event["kind"].as_u64().unwrap_or_default();
+            let content: String = event["content"].as_str().unwrap_or_default().chars().take(60).collect();
+            lines.push(format!("    {} {} {}", format!("[{} {}]", kind, kind_name(kind)).truecolor(128, 128, 128), event["pubkey"].as_str().unwrap_or_default().get(.. 8).unwrap_or_default(), content.replace('\n', " ")));
+        }
+        if missing.len() > COMPARE_SAMPLE {
+            lines.push(format!("    and {} more", missing.len() - COMPARE_SAMPLE).truecolor(128, 128, 128).to_string());
+        }
+    }
+    lines.join("\n")
+}
+
+/// Publishes `events` to `relay` and waits for its OK on each of them. Returns the number of accepted
+/// events and the ids of the rejected ones with the relay's reason.
+pub async fn publish_events(relay: &str, events: &[Value]) -> Result<(usize, Vec<(String, String)>), String> {
+    let (mut writer, mut reader) = connect(relay).await.map_err(|why| why.to_string())?;
+    let mut pending: HashSet<String> = HashSet::new();
+    for event in events {
+        writer.send(Message::Text(json!(["EVENT", event]).to_string())).await.map_err(|why| why.to_string())?;
+        pending.insert(event["id"].as_str().unwrap_or_default().to_string());
+    }
+
+    let mut accepted = 0;
+    let mut rejected = Vec::new();
+    let receiving = async {
+        while !pending.is_empty() {
+            let message = match reader.next().await {
+                Some(Ok(val)) => val,
+                _ => return Err("Connection closed before all events were confirmed".to_string())
+            };
+            let json_val: Value = match serde_json::from_str(&message.to_string()) {
+                Ok(val) => val,
+                Err(_) => continue
+            };
+            if json_val[0] != "OK" || !pending.remove(json_val[1].as_str().unwrap_or_default()) {
+                continue;
+            }
+            match json_val[2].as_bool() {
+                Some(true) => accepted += 1,
+                _ => rejected.push((json_val[1].as_str().unwrap_or_default().to_string(), json_val[3].as_str().unwrap_or_default().to_string())),
+            }
+        }
+        Ok(())
+    };
+    match timeout(PUBLISH_TIMEOUT, receiving).await {
+        Ok(result) => result.map(|_| (accepted, rejected)),
+        Err(_) => Err(format!("Timed out with {} events unconfirmed", pending.len())),
+    }
+}
+
+/// Publishes a throwaway ephemeral event signed by `signer` to each of `relays` and warns about those that
+/// reject it or don't confirm it, as they likely need payment or allowlisting before messages reach them.
+pub async fn probe_writes(relays: Vec<String>, signer: Signer) {
+    let event = match signer.sign(EventBuilder::new(Kind::Custom(WRITE_PROBE_KIND), "nostrachat write probe", &[])).await {
+        Ok(val) => serde_json::to_value(val).unwrap(),
+        Err(why) => {
+            crate::ui::print_error(format!("Couldn't sign the write probe: {}", why));
+            return;
+        }
+    };
+    let events = [event];
+    let results = join_all(relays.iter().map(|relay| timeout(WRITE_PROBE_TIMEOUT, publish_events(relay, &events)))).await;
+    for (relay, result) in relays.iter().zip(results) {
+        let problem = match result {
+            Ok(Ok((_, rejected))) => match rejected.first() {
+                Some((_, reason)) => {
+                    crate::nip11::note_rejection(relay, reason);
+                    format!("rejects events from you ({})", if reason.is_empty() { "no reason given" } else { reason })
+                },
+                None => continue,
+            },
+            // Unreachable relays are reported when connecting
+            Ok(Err(_)) => continue,
+            Err(_) => "didn't confirm an event from you in time".to_string(),
+        };
+        crate::ui::print(format!("{} {}, messages you send may not reach it.", relay, problem).yellow().to_string());
+    }
+}
+
+/// How long /firehose listens to a relay.
+const FIREHOSE_DURATION: Duration = Duration::from_secs(20);
+/// Stored events /firehose asks for, so quiet relays still show something.
+const FIREHOSE_HISTORY: usize = 100;
+/// Events /firehose prints while listening. Beyond that events are only counted.
+const FIREHOSE_SAMPLE: usize = 40;
+
+/// Listens to everything `relay` sends for a while, printing a sample of the events, and summarizes
+/// the kinds seen.
+pub async fn firehose(relay: &str) -> Result<String, String> {
+    let (mut writer, mut reader) = connect(relay).await.map_err(|why| why.to_string())?;
+    let subscription_id = SubscriptionId::generate();
+    let req = ClientMessage::new_req(subscription_id.clone(), vec![Filter::new().limit(FIREHOSE_HISTORY)]).as_json();
+    writer.send(Message::Text(req)).await.map_err(|why| why.to_string())?;
+
+    // Event count and bytes per kind
+    let mut kinds: HashMap<u64, (usize, usize)> = HashMap::new();
+    let mut total = 0;
+    let receiving = async {
+        while let Some(Ok(message)) = reader.next().await {
+            let json_val: Value = match serde_json::from_str(&message.to_string()) {
+                Ok(val) => val,
+                Err(_) => continue
+            };
+            if json_val[0].as_str() != Some("EVENT") {
+                continue;
+            }
+            let kind = match json_val[2]["kind"].as_u64() {
+                Some(val) => val,
+                None => continue
+            };
+            let entry = kinds.entry(kind).or_default();
+            entry.0 += 1;
+            entry.1 += message.len();
+            total += 1;
+            // Later events are sampled ever more sparsely, so a busy relay doesn't flood the terminal
+            if total <= FIREHOSE_SAMPLE || total.is_power_of_two() {
+                let content: String = json_val[2]["content"].as_str().unwrap_or_default().chars().take(60).collect();
+                crate::ui::print(format!("{} {} {}", format!("[{} {}]", kind, kind_name(kind)).truecolor(128, 128, 128), json_val[2]["pubkey"].as_str().unwrap_or_default().get(.. 8).unwrap_or_default(), content.replace('\n', " ")));
+            }
+        }
+    };
+    timeout(FIREHOSE_DURATION, receiving).await.ok();
+    writer.send(Message::Text(ClientMessage::close(subscription_id).as_json())).await.ok();
+
+    let mut kinds: Vec<(u64, (usize, usize))> = kinds.into_iter().collect();
+    kinds.sort_by_key(|(_, (count, _))| std::cmp::Reverse(*count));
+    let mut lines = vec![format!("{} events of {} kinds in {} seconds", total, kinds.len(), FIREHOSE_DURATION.as_secs())];
+    for (kind, (count, bytes)) in kinds {
+        lines.push(format!("{} {} events, {}", format!("{} {}", kind, kind_name(kind)).green(), count, format_bytes(bytes as u64)));
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Short description of the event kinds commonly seen on relays.
+pub fn kind_name(kind: u64) -> &'static str {
+    match kind {
+        0 => "metadata",
+        1 => "text note",
+        3 => "contacts",
+        4 => "encrypted DM",
+        5 => "deletion",
+        6 => "repost",
+        7 => "reaction",
+        40 => "channel creation",
+        41 => "channel metadata",
+        42 => "channel message",
+        1984 => "report",
+        9735 => "zap",
+        10002 => "relay list",
+        20000 ..= 29999 => "ephemeral",
+        30023 => "long-form article",
+        30000 ..= 39999 => "parameterized replaceable",
+        _ => "",
+    }
+}
+
+/// Relays other than `known` that the kind 10002 relay lists of `authors` on `relay` name, most named first.
+pub async fn discover(relay: &str, authors: Vec<String>, known: &[String], max: usize) -> Vec<String> {
+    let filter = Filter::new().kind(Kind::RelayList).authors(authors);
+    let req = Message::Text(ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json());
+    let events = fetch_stored_events(relay, req).await.unwrap_or_default();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for event in events.iter() {
+        let urls = event["tags"].as_array().into_iter().flatten()
+            .filter(|tag| tag[0] == "r")
+            .filter_map(|tag| tag[1].as_str())
+            .map(|url| url.trim().trim_end_matches('/').to_string())
+            .filter(|url| url.starts_with("wss://") || url.starts_with("ws://"));
+        for url in urls.collect::<HashSet<String>>() {
+            *counts.entry(url).or_default() += 1;
+        }
+    }
+    let known: Vec<&str> = known.iter().map(|relay| relay.trim_end_matches('/')).collect();
+    let mut discovered: Vec<(String, usize)> = counts.into_iter().filter(|(url, _)| !known.contains(&url.as_str())).collect();
+    discovered.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    discovered.into_iter().take(max).map(|(url, _)| url).collect()
+}
+
+/// Time it takes to connect to `relay` and get the end of stored events for a REQ of one event.
+async fn probe_once(relay: &str) -> Result<Duration, String> {
+    let started = Instant::now();
+    let (mut writer, mut reader) = connect(relay).await.map_err(|why| why.to_string())?;
+    let req = ClientMessage::new_req(SubscriptionId::generate(), vec![Filter::new().limit(1)]).as_json();
+    writer.send(Message::Text(req)).await.map_err(|why| why.to_string())?;
+    while let Some(message) = reader.next().await {
+        let json_val: Value = match serde_json::from_str(&message.map_err(|why| why.to_string())?.to_string()) {
+            Ok(val) => val,
+            Err(_) => continue
+        };
+        match json_val[0].as_str() {
+            Some("EOSE") => return Ok(started.elapsed()),
+            Some("NOTICE") => return Err(format!("NOTICE: {}", json_val[1])),
+            Some("CLOSED") => return Err(format!("CLOSED: {}", json_val[2])),
+            _ => {}
+        }
+    }
+    Err("Connection closed before end of stored events".to_string())
+}
+
+/// Latencies of `PROBE_ROUNDS` probes of `relay` in a row, or why one failed.
+async fn probe(relay: &str) -> Result<Vec<Duration>, String> {
+    let mut latencies = Vec::new();
+    for _ in 0 .. PROBE_ROUNDS {
+        match timeout(PROBE_TIMEOUT, probe_once(relay)).await {
+            Ok(result) => latencies.push(result?),
+            Err(_) => return Err("Timed out".to_string()),
+        }
+    }
+    Ok(latencies)
+}
+
+/// Probes the `configured` and `discovered` relays at once and ranks them by their mean latency, with the jitter
+/// as the standard deviation of the rounds. Unreachable relays come last.
+pub async fn latency_report(configured: &[String], discovered: &[String]) -> String {
+    let relays: Vec<&String> = configured.iter().chain(discovered.iter()).collect();
+    let results = join_all(relays.iter().map(|relay| probe(relay))).await;
+
+    let mut reachable: Vec<(&String, f64, f64)> = Vec::new();
+    let mut unreachable: Vec<(&String, String)> = Vec::new();
+    for (relay, result) in relays.iter().zip(results) {
+        match result {
+            Ok(latencies) => {
+                let millis: Vec<f64> = latencies.iter().map(|latency| latency.as_secs_f64() * 1000.0).collect();
+                let mean = millis.iter().sum::<f64>() / millis.len() as f64;
+                let jitter = (millis.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / millis.len() as f64).sqrt();
+                reachable.push((relay, mean, jitter));
+            },
+            Err(why) => unreachable.push((relay, why)),
+        }
+    }
+    reachable.sort_by(|(_, a, _), (_, b, _)| a.total_cmp(b));
+
+    let width = relays.iter().map(|relay| relay.len()).max().unwrap_or_default();
+    let mut lines = vec![format!("{} connect and REQ round trips per relay, from this machine", PROBE_ROUNDS)];
+    lines.push(format!("{:>3} {:<width$} {:>8} {:>8}", "#", "Relay", "Mean", "Jitter", width = width));
+    for (rank, (relay, mean, jitter)) in reachable.iter().enumerate() {
+        let origin = match configured.contains(relay) {
+            true => "",
+            false => " (discovered)",
+        };
+        lines.push(format!("{:>3} {} {:>6.0}ms {:>6.0}ms{}", rank + 1, format!("{:<width$}", relay, width = width).green(), mean, jitter, origin));
+    }
+    for (relay, why) in unreachable {
+        lines.push(format!("{:>3} {} {}", "-", format!("{:<width$}", relay, width = width).red(), why));
+    }
+    lines.join("\n")
+}
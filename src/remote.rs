@@ -0,0 +1,132 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use colored::Colorize;
+use nostr::prelude::*;
+use serde_json::Value;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::app::App;
+use crate::chats::DmMode;
+use crate::commands::{ self, Origin };
+use crate::export::verify_event;
+use crate::signer::Signer;
+use crate::{ pool, relays, ui };
+
+/// Lines of output a reply carries at most, so a command that prints a whole chat doesn't flood the sender.
+pub const MAX_REPLY_LINES: usize = 50;
+
+/// A command that came in an encrypted DM.
+pub struct RemoteCommand {
+    pub sender: XOnlyPublicKey,
+    pub input: String,
+    /// Encryption of the DM, which the reply uses too
+    pub mode: DmMode,
+}
+
+/// Listens on `relays` for the DMs `controllers` send to the user of `signer` from now on, and passes them on to `commands`.
+/// Each command is run once, however many relays deliver it, and older ones a relay sends again are ignored.
+pub async fn listen(relays: Vec<String>, signer: Signer, controllers: Vec<XOnlyPublicKey>, commands: UnboundedSender<RemoteCommand>) {
+    let started = Timestamp::now();
+    let mut executed: HashSet<String> = HashSet::new();
+    let (mut writer, mut reader) = match pool::connect(&relays).await {
+        Ok(val) => val,
+        Err(why) => {
+            ui::print_error(format!("Remote control unavailable: {}", why));
+            return;
+        }
+    };
+    let filter = Filter::new()
+        .kind(Kind::EncryptedDirectMessage)
+        .pubkey(signer.public_key())
+        .authors(controllers.iter().map(|controller| controller.to_string()).collect())
+        .since(started);
+    let req = ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json();
+    if let Err(why) = writer.send(Message::Text(req)).await {
+        ui::print_error(format!("Remote control unavailable: {}", why));
+        return;
+    }
+
+    while let Some(message) = reader.next().await {
+        let json_val: Value = match serde_json::from_str(&message.to_string()) {
+            Ok(val) => val,
+            Err(_) => continue,
+        };
+        if json_val[0] != "EVENT" || verify_event(&json_val[2]).is_err() {
+            continue;
+        }
+        // Relays don't have to honour the filter, so a replayed command of earlier would run again
+        if json_val[2]["created_at"].as_u64().is_none_or(|created_at| created_at < started.as_u64()) {
+            continue;
+        }
+        let sender = match XOnlyPublicKey::from_str(json_val[2]["pubkey"].as_str().unwrap_or_default()) {
+            Ok(val) if controllers.contains(&val) => val,
+            _ => continue,
+        };
+        // Every relay of the pool delivers the same command
+        if !executed.insert(json_val[2]["id"].as_str().unwrap_or_default().to_string()) {
+            continue;
+        }
+        let content = json_val[2]["content"].as_str().unwrap_or_default();
+        let (decrypted, mode) = match content.contains("?iv=") {
+            true => (signer.nip04_decrypt(&sender, content).await, DmMode::Nip04),
+            false => (signer.nip44_decrypt(&sender, content).await, DmMode::Nip44),
+        };
+        match decrypted {
+            Ok(input) => {
+                if commands.send(RemoteCommand { sender, input: input.trim().to_string(), mode }).is_err() {
+                    return;
+                }
+            },
+            Err(why) => ui::print_error(format!("Couldn't decrypt a remote command from {}: {}", sender.to_bech32().unwrap(), why)),
+        }
+    }
+}
+
+/// Runs `command` on behalf of its sender and answers them with what it printed.
+pub async fn run(app: &mut App, command: RemoteCommand) {
+    let npub = command.sender.to_bech32().unwrap();
+    ui::print(format!("[{}] {} {}", "REMOTE".blue(), &npub[.. 16], command.input));
+    let mut output = match command.input.starts_with('/') {
+        true => ui::capture(commands::dispatch(app, command.input, Origin::Remote(command.sender))).await,
+        false => vec!["Only commands can be sent, get all of them with /help".to_string()],
+    };
+    if output.is_empty() {
+        output.push("Done.".to_string());
+    }
+    if output.len() > MAX_REPLY_LINES {
+        let more = output.len() - MAX_REPLY_LINES;
+        output.truncate(MAX_REPLY_LINES);
+        output.push(format!("... {} more lines", more));
+    }
+
+    let reply = output.join("\n");
+    let encrypted = match command.mode {
+        DmMode::Nip04 => app.signer.nip04_encrypt(&command.sender, &reply).await,
+        _ => app.signer.nip44_encrypt(&command.sender, &reply).await,
+    };
+    let content = match encrypted {
+        Ok(val) => val,
+        Err(why) => {
+            ui::print_error(format!("Couldn't encrypt the reply to {}: {}", npub, why));
+            return;
+        }
+    };
+    let relays = app.config.relays.clone();
+    let event = match app.signer.sign(EventBuilder::new(Kind::EncryptedDirectMessage, content, &[Tag::PubKey(command.sender, None)])).await {
+        Ok(val) => val,
+        Err(why) => {
+            ui::print_error(format!("Couldn't sign the reply to {}: {}", npub, why));
+            return;
+        }
+    };
+    // Sent on connections of its own, so it isn't taken for a message of the shown chat
+    let events = vec![serde_json::to_value(&event).unwrap()];
+    tokio::spawn(async move {
+        let results = futures::future::join_all(relays.iter().map(|relay| relays::publish_events(relay, &events))).await;
+        if !results.iter().any(|result| result.as_ref().is_ok_and(|(accepted, _)| *accepted > 0)) {
+            ui::print_error(format!("None of the relays accepted the reply to {}.", npub));
+        }
+    });
+}
@@ -0,0 +1,258 @@
+use std::collections::{ HashMap, VecDeque };
+use std::io::Write;
+use std::process::{ Command, Stdio };
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use nostr::prelude::*;
+use serde::{ Deserialize, Serialize };
+
+/// A notification rule from the config. All conditions that are set have to match for the actions to run.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Rule {
+    /// Chat id (channel event id or contact public key in hex) or chat name
+    pub chat: Option<String>,
+    /// Author public key, in bech32 or hex
+    pub author: Option<String>,
+    /// Case-insensitive text the content has to contain
+    pub keyword: Option<String>,
+    pub kind: Option<u64>,
+    /// Whether the event has to mention (or not mention) me
+    pub mention: Option<bool>,
+    pub actions: Vec<Action>,
+    /// Shell command for the `command` action. It gets the event JSON on stdin and the chat in
+    /// `NOSTRACHAT_CHAT_ID`/`NOSTRACHAT_CHAT_NAME`.
+    pub command: Option<String>,
+    /// How often the command may run per minute, further matches are dropped
+    #[serde(default = "default_rate_limit")]
+    pub rate_limit: usize,
+}
+
+fn default_rate_limit() -> usize {
+    10
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Notify,
+    Bell,
+    Command,
+    /// Reads the message out with the configured text-to-speech command
+    Speak,
+    Ignore,
+}
+
+/// Text-to-speech command that gets the text to announce on stdin, e.g. `espeak` or `say`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TtsConfig {
+    pub command: String,
+    /// Announce every incoming direct message
+    #[serde(default)]
+    pub dms: bool,
+    /// Announce every message mentioning me
+    #[serde(default)]
+    pub mentions: bool,
+}
+
+/// What rules are matched against for a single incoming event.
+pub struct RuleContext<'a> {
+    pub chat_id: &'a str,
+    pub chat_name: &'a str,
+    pub event: &'a Event,
+    pub mentioned: bool,
+}
+
+pub struct RulesEngine {
+    rules: Vec<Rule>,
+    tts: Option<TtsConfig>,
+    /// Actions run for every live message mentioning me, "notify" and "bell"
+    mention_alerts: Vec<Action>,
+    /// Recent command runs per rule index, for rate limiting
+    command_runs: Mutex<HashMap<usize, VecDeque<Instant>>>,
+}
+
+impl RulesEngine {
+    pub fn new(rules: Vec<Rule>, tts: Option<TtsConfig>, mention_alerts: Vec<Action>) -> Self {
+        if rules.iter().any(|rule| rule.actions.contains(&Action::Command)) {
+            crate::ui::print_error("Warning: notification rules run commands with your user's permissions, fed with event content from strangers. \
+                Treat stdin as untrusted input and consider running the commands in a sandbox.".to_string());
+        }
+        if mention_alerts.iter().any(|action| !matches!(action, Action::Notify | Action::Bell)) {
+            crate::ui::print_error("mention_alerts can only be \"notify\" and \"bell\", the other actions are left out.".to_string());
+        }
+        RulesEngine {
+            rules,
+            tts,
+            mention_alerts,
+            command_runs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Evaluates all rules for an event, running the actions of matching ones if `run_actions` is set.
+    /// Returns false if a matching rule ignores the event, so it shouldn't be shown or counted.
+    pub fn evaluate(&self, context: &RuleContext, run_actions: bool) -> bool {
+        let mut show = true;
+        let mut speak = false;
+        for (index, rule) in self.rules.iter().enumerate().filter(|(_, rule)| rule.matches(context)) {
+            if rule.actions.contains(&Action::Ignore) {
+                show = false;
+            }
+            if run_actions {
+                for action in rule.actions.iter() {
+                    if *action == Action::Command && !self.allow_command_run(index, rule.rate_limit) {
+                        continue;
+                    }
+                    if *action == Action::Speak {
+                        speak = true;
+                        continue;
+                    }
+                    rule.run(*action, context);
+                }
+            }
+        }
+
+        if run_actions && show {
+            if context.mentioned {
+                for action in &self.mention_alerts {
+                    alert(*action, context);
+                }
+            }
+            // A mention notified of already isn't notified of a second time
+            if !(context.mentioned && self.mention_alerts.contains(&Action::Notify)) {
+                crate::notifications::message(context);
+            }
+        }
+
+        if let Some(tts) = &self.tts {
+            let is_dm = matches!(context.event.kind.as_u64(), 4 | 420);
+            speak = speak || (run_actions && show && ((tts.dms && is_dm) || (tts.mentions && context.mentioned)));
+            if speak {
+                let author = context.event.pubkey.to_bech32().unwrap();
+                let announcement = format!("{} in {} says: {}", &author[4 .. 10], context.chat_name, context.event.content);
+                pipe_to_command(&tts.command, announcement, context);
+            }
+        }
+        show
+    }
+
+    /// Records a command run for the rule at `index`, unless it already ran `rate_limit` times in the last minute.
+    fn allow_command_run(&self, index: usize, rate_limit: usize) -> bool {
+        let mut command_runs = self.command_runs.lock().unwrap();
+        let runs = command_runs.entry(index).or_default();
+        while runs.front().is_some_and(|run| run.elapsed() > Duration::from_secs(60)) {
+            runs.pop_front();
+        }
+        if runs.len() >= rate_limit {
+            return false;
+        }
+        runs.push_back(Instant::now());
+        true
+    }
+}
+
+impl Rule {
+    fn matches(&self, context: &RuleContext) -> bool {
+        if let Some(chat) = &self.chat {
+            if chat != context.chat_id && chat != context.chat_name {
+                return false;
+            }
+        }
+        if let Some(author) = &self.author {
+            let author_key = XOnlyPublicKey::from_bech32(author).or_else(|_| XOnlyPublicKey::from_str(author));
+            match author_key {
+                Ok(key) if key == context.event.pubkey => {},
+                _ => return false,
+            }
+        }
+        if let Some(keyword) = &self.keyword {
+            if !context.event.content.to_lowercase().contains(&keyword.to_lowercase()) {
+                return false;
+            }
+        }
+        if let Some(kind) = self.kind {
+            if context.event.kind.as_u64() != kind {
+                return false;
+            }
+        }
+        if let Some(mention) = self.mention {
+            if mention != context.mentioned {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn run(&self, action: Action, context: &RuleContext) {
+        match action {
+            Action::Notify | Action::Bell => alert(action, context),
+            Action::Command => {
+                if let Some(command) = &self.command {
+                    pipe_to_command(command, context.event.as_json(), context);
+                }
+            },
+            Action::Speak | Action::Ignore => {},
+        }
+    }
+}
+
+/// Shows a desktop notification of the event or rings the terminal bell, the actions that only alert.
+fn alert(action: Action, context: &RuleContext) {
+    match action {
+        Action::Notify => {
+            let author = context.event.pubkey.to_bech32().unwrap();
+            let shown = crate::notifications::show(
+                Some((context.chat_id, context.chat_name)),
+                &format!("nostrachat: {}", context.chat_name),
+                &format!("{}: {}", &author[4 .. 10], context.event.content),
+            );
+            if let Err(why) = shown {
+                crate::ui::print_error(format!("Couldn't show desktop notification: {}", why));
+            }
+        },
+        Action::Bell => {
+            print!("\x07");
+        },
+        _ => {},
+    }
+}
+
+/// Runs `command` in a shell with `input` on its stdin and the chat in its environment.
+fn pipe_to_command(command: &str, input: String, context: &RuleContext) {
+    let spawned = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("NOSTRACHAT_CHAT_ID", context.chat_id)
+        .env("NOSTRACHAT_CHAT_NAME", context.chat_name)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    let mut child = match spawned {
+        Ok(val) => val,
+        Err(why) => {
+            crate::ui::print_error(format!("Couldn't run command {}: {}", command, why));
+            return;
+        }
+    };
+    // Feed and reap the command off the calling task, it may be slow to read its input
+    thread::spawn(move || {
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input.as_bytes()).ok();
+        }
+        child.wait().ok();
+    });
+}
+
+/// Whether `event` is addressed to `public_key`, either by a p tag or by its npub or an nprofile of it in the
+/// content.
+pub fn is_mention(event: &Event, public_key: &XOnlyPublicKey) -> bool {
+    event.tags.iter().any(|tag| matches!(tag, Tag::PubKey(pubkey, _) if pubkey == public_key))
+        || event.content.contains(&public_key.to_bech32().unwrap())
+        || event.content.match_indices("nprofile1").any(|(start, _)| {
+            let nprofile: String = event.content[start ..].chars().take_while(char::is_ascii_alphanumeric).collect();
+            Profile::from_bech32(nprofile).is_ok_and(|profile| profile.public_key == *public_key)
+        })
+}
@@ -0,0 +1,118 @@
+use std::fs;
+use std::io::{ self, Write };
+
+use colored::Colorize;
+use nostr::prelude::*;
+
+use crate::{ nip49, ui };
+
+/// The config.toml shipped with nostrachat, which new configs start from so they keep its comments.
+const TEMPLATE: &str = include_str!("../config.toml");
+
+fn ask(question: &str) -> String {
+    print!("{} ", question);
+    io::stdout().flush().ok();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).expect("Couldn't read from stdin!");
+    answer.trim().to_string()
+}
+
+/// Writes `content` to `path`, readable by the user alone as it holds their private key.
+fn write_private(path: &str, content: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(content.as_bytes())
+}
+
+/// Relays of the shipped config, offered when no others are entered.
+fn default_relays() -> Vec<String> {
+    let template: toml::Value = toml::from_str(TEMPLATE).expect("The shipped config.toml is invalid!");
+    template["relays"].as_array().map_or(Vec::new(), |relays| relays.iter().filter_map(|relay| relay.as_str().map(str::to_string)).collect())
+}
+
+/// `content` with the value of the top level `key` replaced by `value`, keeping its comment.
+fn set_value(content: &str, key: &str, value: &str) -> String {
+    content.lines()
+        .map(|line| match line.split_once('=') {
+            Some((name, rest)) if name.trim() == key => match rest.split_once(" #") {
+                Some((_, comment)) => format!("{} = {} #{}", key, value, comment),
+                None => format!("{} = {}", key, value),
+            },
+            _ => line.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n") + "\n"
+}
+
+/// Walks a new user through creating the config at `path`: a key, generated or pasted and optionally encrypted,
+/// and the relays to use.
+pub fn run(path: &str) -> Result<(), String> {
+    ui::print(format!("Welcome to nostrachat! There is no {} yet, so let's set one up.", path).green().to_string());
+
+    let (keys, generated) = loop {
+        let input = ask("Paste the nsec of an existing key to use it, or press Enter to generate a new one:");
+        if input.is_empty() {
+            break (Keys::generate(), true);
+        }
+        match SecretKey::from_bech32(&input) {
+            Ok(secret_key) => break (Keys::new(secret_key), false),
+            Err(why) => ui::print_error(format!("That isn't a valid nsec: {}", why)),
+        }
+    };
+
+    let defaults = default_relays();
+    let relays: Vec<String> = loop {
+        let input = ask(&format!("Relays to connect to, separated by spaces or commas. Press Enter for {}:", defaults.join(", ")));
+        if input.is_empty() {
+            break defaults.clone();
+        }
+        let relays: Vec<String> = input.split([' ', ',']).filter(|relay| !relay.is_empty()).map(str::to_string).collect();
+        match relays.iter().find(|relay| !Url::parse(relay).is_ok_and(|url| ["ws", "wss"].contains(&url.scheme()))) {
+            Some(invalid) => ui::print_error(format!("{} isn't a relay address, they look like wss://relay.example.com", invalid)),
+            None => break relays,
+        }
+    };
+
+    let secret_key = keys.secret_key().map_err(|why| why.to_string())?;
+    let nsec = secret_key.to_bech32().map_err(|why| why.to_string())?;
+    let encrypted = loop {
+        let passphrase = ui::read_secret("Passphrase to encrypt the key with (recommended), asked for on every start. Press Enter to store it unencrypted:");
+        if passphrase.is_empty() {
+            break None;
+        }
+        if passphrase != ui::read_secret("Repeat it:") {
+            ui::print_error("The passphrases don't match.".to_string());
+            continue;
+        }
+        // A pasted key may have been kept in plain text elsewhere
+        let security = match generated {
+            true => nip49::KeySecurity::Secure,
+            false => nip49::KeySecurity::Unknown,
+        };
+        break Some(nip49::encrypt(&secret_key, &passphrase, nip49::DEFAULT_LOG_N, security)?);
+    };
+    let mut content = set_value(TEMPLATE, "relays", &toml::Value::from(relays).to_string());
+    content = set_value(&content, "privkey", &format!("\"{}\"", encrypted.as_ref().unwrap_or(&nsec)));
+    write_private(path, &content).map_err(|why| format!("Couldn't write {}: {}", path, why))?;
+
+    ui::print(format!("Saved your settings to {}. Everything else in it can be changed there too.", path));
+    ui::print(format!("Your public key, to share with others: {}", keys.public_key().to_bech32().unwrap().green()));
+    match (generated, &encrypted) {
+        (true, None) => {
+            ui::print(format!("Your private key: {}", nsec.yellow()));
+            ui::print("It is the only way to use this identity. Keep a copy somewhere safe, like a password manager,".to_string());
+            ui::print(format!("and never share it: whoever has it can write as you. It is also stored in {} in plain text.", path));
+            ask("Press Enter once you have a backup of it.");
+        },
+        (true, Some(encrypted)) => {
+            ui::print(format!("Your encrypted private key: {}", encrypted.yellow()));
+            ui::print("It is the only way to use this identity. Keep a copy somewhere safe, and remember the passphrase:".to_string());
+            ui::print("the key can't be recovered without it.".to_string());
+            ask("Press Enter once you have a backup of it.");
+        },
+        (false, _) => {},
+    }
+    Ok(())
+}
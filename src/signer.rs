@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::fs;
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+
+use nostr::nips::nip04;
+use nostr::prelude::*;
+use rand::RngCore;
+use serde_json::{ json, Value };
+use tokio::sync::oneshot;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::export::verify_event;
+use crate::pool::{ self, PoolReader, PoolWriter };
+use crate::{ nip44, ui };
+
+/// Kind of NIP-46 requests and responses.
+const NOSTR_CONNECT_KIND: u64 = 24133;
+/// How long the remote signer gets to answer a request, including the time its user takes to approve it.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// How far back responses are subscribed to, in case the signer's clock is behind ours.
+const CLOCK_SKEW: u64 = 60;
+
+/// Requests waiting for the signer's response, keyed by request id.
+type PendingRequests = Arc<Mutex<HashMap<String, oneshot::Sender<Result<String, String>>>>>;
+
+/// Signs events and encrypts messages as the user, with their own key or through a NIP-46 remote signer.
+#[derive(Clone)]
+pub enum Signer {
+    Local(Keys),
+    Remote(Arc<RemoteSigner>),
+}
+
+impl Signer {
+    pub fn public_key(&self) -> XOnlyPublicKey {
+        match self {
+            Signer::Local(keys) => keys.public_key(),
+            Signer::Remote(remote) => remote.user_public_key,
+        }
+    }
+
+    /// The private key, which only a local signer has. Needed by what a remote signer can't do, like the ratchet.
+    pub fn secret_key(&self) -> Option<SecretKey> {
+        match self {
+            Signer::Local(keys) => keys.secret_key().ok(),
+            Signer::Remote(_) => None,
+        }
+    }
+
+    pub async fn sign(&self, builder: EventBuilder) -> Result<Event, String> {
+        self.sign_unsigned(builder.to_unsigned_event(self.public_key())).await
+    }
+
+    /// Signs `unsigned`, which has to be by the user, keeping its timestamp.
+    pub async fn sign_unsigned(&self, unsigned: UnsignedEvent) -> Result<Event, String> {
+        match self {
+            Signer::Local(keys) => unsigned.sign(keys).map_err(|why| why.to_string()),
+            Signer::Remote(remote) => remote.sign(unsigned).await,
+        }
+    }
+
+    pub async fn nip04_encrypt(&self, public_key: &XOnlyPublicKey, plaintext: &str) -> Result<String, String> {
+        match self {
+            Signer::Local(keys) => nip04::encrypt(&keys.secret_key().map_err(|why| why.to_string())?, public_key, plaintext).map_err(|why| why.to_string()),
+            Signer::Remote(remote) => remote.request("nip04_encrypt", vec![public_key.to_string(), plaintext.to_string()]).await,
+        }
+    }
+
+    pub async fn nip04_decrypt(&self, public_key: &XOnlyPublicKey, ciphertext: &str) -> Result<String, String> {
+        match self {
+            Signer::Local(keys) => nip04::decrypt(&keys.secret_key().map_err(|why| why.to_string())?, public_key, ciphertext).map_err(|why| why.to_string()),
+            Signer::Remote(remote) => remote.request("nip04_decrypt", vec![public_key.to_string(), ciphertext.to_string()]).await,
+        }
+    }
+
+    pub async fn nip44_encrypt(&self, public_key: &XOnlyPublicKey, plaintext: &str) -> Result<String, String> {
+        match self {
+            Signer::Local(keys) => nip44::encrypt(&keys.secret_key().map_err(|why| why.to_string())?, public_key, plaintext),
+            Signer::Remote(remote) => remote.request("nip44_encrypt", vec![public_key.to_string(), plaintext.to_string()]).await,
+        }
+    }
+
+    pub async fn nip44_decrypt(&self, public_key: &XOnlyPublicKey, ciphertext: &str) -> Result<String, String> {
+        match self {
+            Signer::Local(keys) => nip44::decrypt(&keys.secret_key().map_err(|why| why.to_string())?, public_key, ciphertext),
+            Signer::Remote(remote) => remote.request("nip44_decrypt", vec![public_key.to_string(), ciphertext.to_string()]).await,
+        }
+    }
+}
+
+/// A session with a NIP-46 remote signer ("bunker"), which holds the user's key and signs for them.
+pub struct RemoteSigner {
+    /// Key the signer talks with, which isn't necessarily the user's
+    signer_public_key: XOnlyPublicKey,
+    user_public_key: XOnlyPublicKey,
+    /// Our side of the session, kept between runs so the signer doesn't have to approve us again
+    client_keys: Keys,
+    writer: tokio::sync::Mutex<PoolWriter>,
+    pending: PendingRequests,
+}
+
+/// Signer public key, relays and optional secret of a bunker://<hex public key>?relay=wss://...&secret=... URI.
+pub fn parse_bunker_uri(uri: &str) -> Result<(XOnlyPublicKey, Vec<String>, Option<String>), String> {
+    let url = Url::parse(uri.trim()).map_err(|why| format!("invalid bunker URI: {}", why))?;
+    if url.scheme() != "bunker" {
+        return Err("a bunker URI starts with bunker://".to_string());
+    }
+    let signer_public_key = XOnlyPublicKey::from_str(url.host_str().unwrap_or_default())
+        .map_err(|_| "the bunker URI doesn't contain the signer's hex public key".to_string())?;
+    let relays: Vec<String> = url.query_pairs().filter(|(key, _)| key == "relay").map(|(_, relay)| relay.to_string()).collect();
+    if relays.is_empty() {
+        return Err("the bunker URI doesn't name any relay".to_string());
+    }
+    let secret = url.query_pairs().find(|(key, _)| key == "secret").map(|(_, secret)| secret.to_string());
+    Ok((signer_public_key, relays, secret))
+}
+
+fn load_or_create_client_keys() -> Result<Keys, String> {
+    let path = crate::data_dir().join("nip46_client_key");
+    if let Some(secret_key) = fs::read_to_string(&path).ok().and_then(|content| SecretKey::from_str(content.trim()).ok()) {
+        return Ok(Keys::new(secret_key));
+    }
+    let keys = Keys::generate();
+    let secret_key = keys.secret_key().map_err(|why| why.to_string())?;
+    fs::write(&path, secret_key.display_secret().to_string()).map_err(|why| format!("Couldn't save the remote signer session key: {}", why))?;
+    Ok(keys)
+}
+
+impl RemoteSigner {
+    /// Connects to the signer of the bunker `uri` and asks it for the user's public key.
+    pub async fn connect(uri: &str) -> Result<RemoteSigner, String> {
+        let (signer_public_key, relays, secret) = parse_bunker_uri(uri)?;
+        let client_keys = load_or_create_client_keys()?;
+        let (mut writer, reader) = pool::connect(&relays).await?;
+        let filter = Filter::new()
+            .kind(Kind::Custom(NOSTR_CONNECT_KIND))
+            .pubkey(client_keys.public_key())
+            .since(Timestamp::from(Timestamp::now().as_u64() - CLOCK_SKEW));
+        writer.send(Message::Text(ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json())).await?;
+        let pending = Arc::new(Mutex::new(HashMap::new()));
+        tokio::spawn(listen(reader, client_keys.clone(), signer_public_key, pending.clone()));
+
+        let mut remote = RemoteSigner {
+            signer_public_key,
+            user_public_key: signer_public_key,
+            client_keys,
+            writer: tokio::sync::Mutex::new(writer),
+            pending,
+        };
+        let mut params = vec![signer_public_key.to_string()];
+        params.extend(secret);
+        remote.request("connect", params).await?;
+        let user_public_key = remote.request("get_public_key", Vec::new()).await?;
+        remote.user_public_key = XOnlyPublicKey::from_str(&user_public_key)
+            .map_err(|_| format!("the remote signer sent an invalid public key: {}", user_public_key))?;
+        Ok(remote)
+    }
+
+    /// Sends the NIP-46 request `method` and waits for the signer's result.
+    async fn request(&self, method: &str, params: Vec<String>) -> Result<String, String> {
+        let mut id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+        let id = id.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        let payload = json!({ "id": id, "method": method, "params": params }).to_string();
+        let secret_key = self.client_keys.secret_key().map_err(|why| why.to_string())?;
+        let content = nip44::encrypt(&secret_key, &self.signer_public_key, &payload)?;
+        let event = EventBuilder::new(Kind::Custom(NOSTR_CONNECT_KIND), content, &[Tag::PubKey(self.signer_public_key, None)])
+            .to_event(&self.client_keys)
+            .map_err(|why| why.to_string())?;
+
+        let (response_tx, response_rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id.clone(), response_tx);
+        self.writer.lock().await.send(Message::Text(ClientMessage::new_event(event).as_json())).await?;
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, response_rx).await;
+        self.pending.lock().unwrap().remove(&id);
+        match response {
+            Ok(Ok(result)) => result.map_err(|why| format!("the remote signer refused {}: {}", method, why)),
+            Ok(Err(_)) => Err("lost connection to the remote signer".to_string()),
+            Err(_) => Err(format!("the remote signer didn't answer {} in time", method)),
+        }
+    }
+
+    async fn sign(&self, unsigned: UnsignedEvent) -> Result<Event, String> {
+        let signed = self.request("sign_event", vec![unsigned.as_json()]).await?;
+        let event = Event::from_json(&signed).map_err(|_| "the remote signer sent an invalid event".to_string())?;
+        // The signer could sign something else than what it was asked to
+        if event.id != unsigned.id || event.verify().is_err() {
+            return Err("the remote signer sent a different event than it was asked to sign".to_string());
+        }
+        Ok(event)
+    }
+}
+
+/// Passes the responses of the signer to the requests waiting for them.
+async fn listen(mut reader: PoolReader, client_keys: Keys, signer_public_key: XOnlyPublicKey, pending: PendingRequests) {
+    let secret_key = client_keys.secret_key().unwrap();
+    while let Some(message) = reader.next().await {
+        let json_val: Value = match serde_json::from_str(&message.to_string()) {
+            Ok(val) => val,
+            Err(_) => continue,
+        };
+        if json_val[0] != "EVENT" || json_val[2]["pubkey"] != signer_public_key.to_string().as_str() || verify_event(&json_val[2]).is_err() {
+            continue;
+        }
+        // Signers that predate NIP-44 answer with NIP-04
+        let content = json_val[2]["content"].as_str().unwrap_or_default();
+        let decrypted = match content.contains("?iv=") {
+            true => nip04::decrypt(&secret_key, &signer_public_key, content).map_err(|why| why.to_string()),
+            false => nip44::decrypt(&secret_key, &signer_public_key, content),
+        };
+        let response: Value = match decrypted.ok().and_then(|decrypted| serde_json::from_str(&decrypted).ok()) {
+            Some(val) => val,
+            None => continue,
+        };
+        // The signer wants the request approved on a web page first, the actual response follows once it is
+        if response["result"] == "auth_url" {
+            ui::print(format!("Your remote signer asks you to approve a request at {}", response["error"].as_str().unwrap_or_default()));
+            continue;
+        }
+        let result = match response["error"].as_str().filter(|why| !why.is_empty()) {
+            Some(why) => Err(why.to_string()),
+            None => Ok(response["result"].as_str().unwrap_or_default().to_string()),
+        };
+        if let Some(response_tx) = pending.lock().unwrap().remove(response["id"].as_str().unwrap_or_default()) {
+            response_tx.send(result).ok();
+        }
+    }
+}
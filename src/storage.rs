@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{ LazyLock, Mutex };
+
+use nostr::prelude::{ Filter, Timestamp };
+use rusqlite::{ params, params_from_iter, Connection, OptionalExtension };
+use rusqlite::types::Value as SqlValue;
+use serde_json::Value;
+
+use crate::cache::cache_dir;
+
+/// Kinds of the events counted as unread messages: channel, ratchet, kind 4 and gift wrapped messages.
+const MESSAGE_KINDS: [u64; 4] = [42, 420, 4, 1059];
+
+/// When each chat was last looked at, by chat id, kept between sessions.
+static LAST_READ: LazyLock<Mutex<HashMap<String, u64>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The SQLite database every chat's events are stored in, opened on first use.
+static DATABASE: LazyLock<Mutex<Connection>> = LazyLock::new(|| Mutex::new(open_database()));
+
+/// Events of one chat kept in the local database, so its history shows up without waiting for relays and
+/// only newer events have to be fetched.
+pub struct ChatStore {
+    chat_id: String,
+}
+
+impl ChatStore {
+    /// Store of the chat with `chat_id`.
+    pub fn open(chat_id: &str) -> ChatStore {
+        ChatStore { chat_id: chat_id.to_string() }
+    }
+
+    /// Stored events matching `filter`, oldest first. Like a relay, only the newest `limit` are returned.
+    pub fn events(&self, filter: &Filter) -> Vec<Value> {
+        match query(&DATABASE.lock().unwrap(), &self.chat_id, filter) {
+            Ok(val) => val,
+            Err(why) => {
+                crate::ui::print_error(format!("Couldn't read cached events: {}", why));
+                Vec::new()
+            }
+        }
+    }
+
+    /// Whether `event` is stored already.
+    pub fn contains(&self, event: &Value) -> bool {
+        let id = event["id"].as_str().unwrap_or_default();
+        DATABASE.lock().unwrap()
+            .query_row("SELECT 1 FROM events WHERE chat_id = ?1 AND id = ?2", params![self.chat_id, id], |_| Ok(()))
+            .optional()
+            .is_ok_and(|found| found.is_some())
+    }
+
+    /// Stores `event` unless it is stored already. Returns whether it was new.
+    pub fn add(&mut self, event: &Value) -> bool {
+        match insert(&DATABASE.lock().unwrap(), &self.chat_id, event) {
+            Ok(val) => val,
+            Err(why) => {
+                crate::ui::print_error(format!("Couldn't cache event: {}", why));
+                // Printed still, even if it can't be cached
+                true
+            }
+        }
+    }
+}
+
+/// File of the database, next to where each chat had a file of events before.
+fn database_path() -> PathBuf {
+    cache_dir().join("events.sqlite")
+}
+
+/// Opens the database, creating it and taking over the event files of earlier versions if needed.
+fn open_database() -> Connection {
+    fs::create_dir_all(cache_dir()).expect("Couldn't create event cache directory!");
+    let connection = Connection::open(database_path()).expect("Couldn't open the event cache!");
+    // Every received event is a transaction of its own, which the write-ahead log keeps cheap
+    connection.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;").expect("Couldn't open the event cache!");
+    create_schema(&connection).expect("Couldn't set up the event cache!");
+    if let Err(why) = import_event_files(&connection) {
+        crate::ui::print_error(format!("Couldn't move the cached events into the database: {}", why));
+    }
+    connection
+}
+
+/// Tables of the events and their single-letter tags, indexed by what filters ask for.
+fn create_schema(connection: &Connection) -> rusqlite::Result<()> {
+    connection.execute_batch("
+        CREATE TABLE IF NOT EXISTS events (
+            chat_id TEXT NOT NULL,
+            id TEXT NOT NULL,
+            pubkey TEXT NOT NULL,
+            kind INTEGER NOT NULL,
+            created_at INTEGER NOT NULL,
+            json TEXT NOT NULL,
+            PRIMARY KEY (chat_id, id)
+        );
+        CREATE INDEX IF NOT EXISTS events_by_time ON events (chat_id, created_at);
+        CREATE INDEX IF NOT EXISTS events_by_kind ON events (chat_id, kind, created_at);
+        CREATE TABLE IF NOT EXISTS tags (
+            chat_id TEXT NOT NULL,
+            event_id TEXT NOT NULL,
+            name TEXT NOT NULL,
+            value TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS tags_by_value ON tags (chat_id, name, value);
+        CREATE INDEX IF NOT EXISTS tags_by_event ON tags (chat_id, event_id);
+        CREATE TABLE IF NOT EXISTS plaintexts (
+            chat_id TEXT NOT NULL,
+            event_id TEXT NOT NULL,
+            sealed BLOB NOT NULL,
+            PRIMARY KEY (chat_id, event_id)
+        );
+    ")
+}
+
+/// Moves the events of the files with one JSON event per line, which chats were cached in before, into
+/// the database.
+fn import_event_files(connection: &Connection) -> Result<(), String> {
+    let files: Vec<PathBuf> = fs::read_dir(cache_dir()).map_err(|why| why.to_string())?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "jsonl"))
+        .collect();
+    for path in files {
+        let chat_id = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(val) => val.to_string(),
+            None => continue,
+        };
+        let transaction = connection.unchecked_transaction().map_err(|why| why.to_string())?;
+        for line in fs::read_to_string(&path).unwrap_or_default().lines() {
+            if let Ok(event) = serde_json::from_str::<Value>(line) {
+                insert(&transaction, &chat_id, &event).map_err(|why| why.to_string())?;
+            }
+        }
+        transaction.commit().map_err(|why| why.to_string())?;
+        fs::remove_file(&path).map_err(|why| why.to_string())?;
+    }
+    Ok(())
+}
+
+/// Stores `event` as one of the chat with `chat_id`, unless it is stored already. Returns whether it was new.
+fn insert(connection: &Connection, chat_id: &str, event: &Value) -> rusqlite::Result<bool> {
+    let id = match event["id"].as_str() {
+        Some(val) => val,
+        None => return Ok(false)
+    };
+    let inserted = connection.execute(
+        "INSERT OR IGNORE INTO events (chat_id, id, pubkey, kind, created_at, json) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![chat_id, id, event["pubkey"].as_str().unwrap_or_default(), event["kind"].as_u64().unwrap_or_default() as i64,
+            event["created_at"].as_u64().unwrap_or_default() as i64, event.to_string()],
+    )? == 1;
+    if inserted {
+        // Filters only ask for single-letter tags
+        let tags = event["tags"].as_array().into_iter().flatten()
+            .filter_map(|tag| Some((tag[0].as_str()?, tag[1].as_str()?)))
+            .filter(|(name, _)| name.chars().count() == 1);
+        for (name, value) in tags {
+            connection.execute("INSERT INTO tags (chat_id, event_id, name, value) VALUES (?1, ?2, ?3, ?4)", params![chat_id, id, name, value])?;
+        }
+    }
+    Ok(inserted)
+}
+
+/// Events of the chat with `chat_id` that match the ids, authors, kinds, tags, since, until and limit of
+/// `filter` as a relay would match them, oldest first.
+fn query(connection: &Connection, chat_id: &str, filter: &Filter) -> rusqlite::Result<Vec<Value>> {
+    let mut conditions = vec!["chat_id = ?".to_string()];
+    let mut values = vec![SqlValue::Text(chat_id.to_string())];
+    let mut any_of = |column: &str, operator: &str, given: Vec<SqlValue>, conditions: &mut Vec<String>| {
+        // Nothing matches an empty list
+        let alternatives = vec![format!("{} {} ?", column, operator); given.len()].join(" OR ");
+        conditions.push(format!("({})", if alternatives.is_empty() { "0".to_string() } else { alternatives }));
+        values.extend(given);
+    };
+    let prefixes = |given: &[String]| given.iter().map(|prefix| SqlValue::Text(format!("{}%", prefix.replace(['%', '_'], "")))).collect();
+    if let Some(ids) = &filter.ids {
+        any_of("id", "LIKE", prefixes(ids), &mut conditions);
+    }
+    if let Some(authors) = &filter.authors {
+        any_of("pubkey", "LIKE", prefixes(authors), &mut conditions);
+    }
+    if let Some(kinds) = &filter.kinds {
+        any_of("kind", "=", kinds.iter().map(|kind| SqlValue::Integer(kind.as_u64() as i64)).collect(), &mut conditions);
+    }
+
+    let mut tags: Vec<(String, Vec<String>)> = Vec::new();
+    if let Some(events) = &filter.events {
+        tags.push(("e".to_string(), events.iter().map(|event_id| event_id.to_hex()).collect()));
+    }
+    if let Some(pubkeys) = &filter.pubkeys {
+        tags.push(("p".to_string(), pubkeys.iter().map(|pubkey| pubkey.to_string()).collect()));
+    }
+    if let Some(hashtags) = &filter.hashtags {
+        tags.push(("t".to_string(), hashtags.clone()));
+    }
+    if let Some(references) = &filter.references {
+        tags.push(("r".to_string(), references.clone()));
+    }
+    for (key, given) in &filter.custom {
+        if let (Some(name), Some(given)) = (key.strip_prefix('#').filter(|name| name.chars().count() == 1), given.as_array()) {
+            tags.push((name.to_string(), given.iter().filter_map(|value| value.as_str().map(str::to_string)).collect()));
+        }
+    }
+    for (name, given) in tags {
+        let placeholders = vec!["?"; given.len()].join(", ");
+        conditions.push(format!("EXISTS (SELECT 1 FROM tags WHERE tags.chat_id = events.chat_id AND tags.event_id = events.id AND tags.name = ? AND tags.value IN ({}))", placeholders));
+        values.push(SqlValue::Text(name));
+        values.extend(given.into_iter().map(SqlValue::Text));
+    }
+
+    if let Some(since) = filter.since {
+        conditions.push("created_at >= ?".to_string());
+        values.push(SqlValue::Integer(since.as_i64()));
+    }
+    if let Some(until) = filter.until {
+        conditions.push("created_at <= ?".to_string());
+        values.push(SqlValue::Integer(until.as_i64()));
+    }
+    // The newest are taken first, so a limit keeps those
+    let mut sql = format!("SELECT json FROM events WHERE {} ORDER BY created_at DESC, id", conditions.join(" AND "));
+    if let Some(limit) = filter.limit {
+        sql.push_str(&format!(" LIMIT {}", limit));
+    }
+    let mut statement = connection.prepare(&sql)?;
+    let rows = statement.query_map(params_from_iter(values), |row| row.get::<_, String>(0))?;
+    let mut events: Vec<Value> = rows.filter_map(|row| row.ok().and_then(|json| serde_json::from_str(&json).ok())).collect();
+    events.reverse();
+    Ok(events)
+}
+
+/// Plaintext of the ratchet message with `event_id` of the chat with `chat_id`, as sealed by the ratchet.
+/// Its message key is used up once it is read, so history shown again comes from here.
+pub fn sealed_plaintext(chat_id: &str, event_id: &str) -> Option<Vec<u8>> {
+    select_sealed(&DATABASE.lock().unwrap(), chat_id, event_id)
+}
+
+/// Keeps the `sealed` plaintext of the ratchet message with `event_id` of the chat with `chat_id`.
+pub fn keep_sealed_plaintext(chat_id: &str, event_id: &str, sealed: &[u8]) {
+    if let Err(why) = insert_sealed(&DATABASE.lock().unwrap(), chat_id, event_id, sealed) {
+        crate::ui::print_error(format!("Couldn't cache the decrypted message: {}", why));
+    }
+}
+
+fn select_sealed(connection: &Connection, chat_id: &str, event_id: &str) -> Option<Vec<u8>> {
+    connection.query_row("SELECT sealed FROM plaintexts WHERE chat_id = ?1 AND event_id = ?2", params![chat_id, event_id], |row| row.get(0))
+        .optional()
+        .ok()
+        .flatten()
+}
+
+fn insert_sealed(connection: &Connection, chat_id: &str, event_id: &str, sealed: &[u8]) -> rusqlite::Result<()> {
+    connection.execute("INSERT OR IGNORE INTO plaintexts (chat_id, event_id, sealed) VALUES (?1, ?2, ?3)", params![chat_id, event_id, sealed])?;
+    Ok(())
+}
+
+fn last_read_path() -> PathBuf {
+    crate::data_dir().join("last_read.json")
+}
+
+/// Fills in when the chats were last read in earlier sessions.
+pub fn load_last_read() {
+    let last_read: HashMap<String, u64> = fs::read_to_string(last_read_path()).ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    *LAST_READ.lock().unwrap() = last_read;
+}
+
+/// Marks what the chat with `chat_id` received so far as read.
+pub fn mark_read(chat_id: &str) {
+    let mut last_read = LAST_READ.lock().unwrap();
+    last_read.insert(chat_id.to_string(), Timestamp::now().as_u64());
+    if let Err(why) = fs::write(last_read_path(), serde_json::to_string(&*last_read).unwrap()) {
+        crate::ui::print_error(format!("Couldn't save which chats were read: {}", why));
+    }
+}
+
+/// Messages of others in the stored events of the chat with `chat_id` that came in after it was last read.
+/// Chats never read have none.
+pub fn unread(chat_id: &str, public_key: &str) -> usize {
+    let since = match LAST_READ.lock().unwrap().get(chat_id) {
+        Some(val) => *val,
+        None => return 0,
+    };
+    let kinds = MESSAGE_KINDS.map(|kind| kind.to_string()).join(", ");
+    DATABASE.lock().unwrap().query_row(
+        &format!("SELECT COUNT(*) FROM events WHERE chat_id = ?1 AND created_at > ?2 AND pubkey != ?3 AND kind IN ({})", kinds),
+        params![chat_id, since as i64, public_key],
+        |row| row.get::<_, i64>(0),
+    ).unwrap_or_default() as usize
+}
+
+/// Events of every chat in the cache, each once.
+pub fn all_events() -> Vec<Value> {
+    let connection = DATABASE.lock().unwrap();
+    let mut statement = match connection.prepare("SELECT json FROM events WHERE rowid IN (SELECT MIN(rowid) FROM events GROUP BY id)") {
+        Ok(val) => val,
+        Err(_) => return Vec::new()
+    };
+    let rows = match statement.query_map([], |row| row.get::<_, String>(0)) {
+        Ok(val) => val,
+        Err(_) => return Vec::new()
+    };
+    rows.filter_map(|row| row.ok().and_then(|json| serde_json::from_str(&json).ok())).collect()
+}
+
+/// Number of events, their size in bytes and the time of the oldest one of every chat in the cache, by chat id.
+pub fn chat_sizes() -> Vec<(String, usize, u64, Option<u64>)> {
+    let connection = DATABASE.lock().unwrap();
+    let mut statement = match connection.prepare("SELECT chat_id, COUNT(*), SUM(LENGTH(json)), MIN(created_at) FROM events GROUP BY chat_id ORDER BY chat_id") {
+        Ok(val) => val,
+        Err(_) => return Vec::new()
+    };
+    let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as usize, row.get::<_, i64>(2)? as u64, row.get::<_, Option<i64>>(3)?.map(|time| time as u64))));
+    match rows {
+        Ok(rows) => rows.filter_map(Result::ok).collect(),
+        Err(_) => Vec::new()
+    }
+}
+
+/// Removes the events of the chat with `chat_id` older than `oldest_kept`, then the oldest of the rest until
+/// they take `max_bytes` at most. Returns the number of events and bytes removed.
+pub fn prune_chat(chat_id: &str, oldest_kept: Option<u64>, max_bytes: Option<u64>) -> Result<(usize, u64), String> {
+    let connection = DATABASE.lock().unwrap();
+    let mut statement = connection.prepare("SELECT id, created_at, LENGTH(json) FROM events WHERE chat_id = ?1 ORDER BY created_at DESC").map_err(|why| why.to_string())?;
+    let events: Vec<(String, u64, u64)> = statement.query_map(params![chat_id], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64, row.get::<_, i64>(2)? as u64)))
+        .map_err(|why| why.to_string())?
+        .filter_map(Result::ok)
+        .collect();
+    drop(statement);
+
+    // Newest first, so whatever goes over a limit is older than what is kept
+    let mut size = 0;
+    let removed: Vec<&(String, u64, u64)> = events.iter().filter(|(_, created_at, length)| {
+        size += length;
+        oldest_kept.is_some_and(|oldest_kept| *created_at < oldest_kept) || max_bytes.is_some_and(|max_bytes| size > max_bytes)
+    }).collect();
+    if removed.is_empty() {
+        return Ok((0, 0));
+    }
+    let transaction = connection.unchecked_transaction().map_err(|why| why.to_string())?;
+    for (id, _, _) in &removed {
+        transaction.execute("DELETE FROM tags WHERE chat_id = ?1 AND event_id = ?2", params![chat_id, id]).map_err(|why| why.to_string())?;
+        transaction.execute("DELETE FROM plaintexts WHERE chat_id = ?1 AND event_id = ?2", params![chat_id, id]).map_err(|why| why.to_string())?;
+        transaction.execute("DELETE FROM events WHERE chat_id = ?1 AND id = ?2", params![chat_id, id]).map_err(|why| why.to_string())?;
+    }
+    transaction.commit().map_err(|why| why.to_string())?;
+    Ok((removed.len(), removed.iter().map(|(_, _, length)| length).sum()))
+}
+
+#[cfg(test)]
+mod tests {
+    use nostr::prelude::{ Kind, Timestamp };
+    use serde_json::json;
+
+    use super::*;
+
+    /// Database holding channel messages with ids "a0" to "a3", created at 10 to 40 and tagged #nostr.
+    fn database() -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        create_schema(&connection).unwrap();
+        for (index, created_at) in [10, 20, 30, 40].into_iter().enumerate() {
+            let event = json!({ "id": format!("a{}", index), "pubkey": "author", "kind": 42, "created_at": created_at, "tags": [["e", "channel"], ["t", "nostr"]], "content": "" });
+            insert(&connection, "chat", &event).unwrap();
+        }
+        connection
+    }
+
+    fn created_at(events: &[Value]) -> Vec<u64> {
+        events.iter().map(|event| event["created_at"].as_u64().unwrap()).collect()
+    }
+
+    #[test]
+    fn events_are_stored_once() {
+        let connection = database();
+        let event = json!({ "id": "a0", "pubkey": "author", "kind": 42, "created_at": 10, "tags": [], "content": "" });
+        assert!(!insert(&connection, "chat", &event).unwrap());
+        assert!(insert(&connection, "other", &event).unwrap());
+        assert_eq!(query(&connection, "chat", &Filter::new()).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn since_and_until_are_inclusive() {
+        let filter = Filter::new().since(Timestamp::from(20)).until(Timestamp::from(30));
+        assert_eq!(created_at(&query(&database(), "chat", &filter).unwrap()), vec![20, 30]);
+    }
+
+    #[test]
+    fn limit_keeps_the_newest() {
+        let filter = Filter::new().limit(2);
+        assert_eq!(created_at(&query(&database(), "chat", &filter).unwrap()), vec![30, 40]);
+    }
+
+    #[test]
+    fn ids_authors_and_kinds_are_matched() {
+        let connection = database();
+        assert_eq!(query(&connection, "chat", &Filter::new().ids(vec!["a1".to_string()])).unwrap().len(), 1);
+        assert_eq!(query(&connection, "chat", &Filter::new().authors(vec!["auth".to_string()])).unwrap().len(), 4);
+        assert!(query(&connection, "chat", &Filter::new().authors(vec!["other".to_string()])).unwrap().is_empty());
+        assert!(query(&connection, "chat", &Filter::new().kind(Kind::EncryptedDirectMessage)).unwrap().is_empty());
+        assert!(query(&connection, "chat", &Filter::new().ids(Vec::<String>::new())).unwrap().is_empty());
+    }
+
+    #[test]
+    fn tags_are_matched() {
+        let connection = database();
+        assert_eq!(query(&connection, "chat", &Filter::new().hashtag("nostr")).unwrap().len(), 4);
+        assert!(query(&connection, "chat", &Filter::new().hashtag("rust")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn sealed_plaintexts_are_kept_once() {
+        let connection = database();
+        assert_eq!(select_sealed(&connection, "chat", "a0"), None);
+        insert_sealed(&connection, "chat", "a0", b"sealed").unwrap();
+        insert_sealed(&connection, "chat", "a0", b"other").unwrap();
+        assert_eq!(select_sealed(&connection, "chat", "a0"), Some(b"sealed".to_vec()));
+        assert_eq!(select_sealed(&connection, "other", "a0"), None);
+    }
+
+    #[test]
+    fn other_chats_are_left_out() {
+        assert!(query(&database(), "other", &Filter::new()).unwrap().is_empty());
+    }
+}
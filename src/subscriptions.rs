@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::sync::{ LazyLock, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+
+use nostr::prelude::{ ClientMessage, SubscriptionId };
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+/// Subscriptions the chats have open on their connections, so they are closed on the relays when a chat is left.
+pub static SUBSCRIPTIONS: LazyLock<Mutex<SubscriptionManager>> = LazyLock::new(|| Mutex::new(SubscriptionManager::default()));
+
+/// Stored events a chat asks the relays for when it opens, and each /more for, 0 for all of them.
+static HISTORY_PAGE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_history_page(page: usize) {
+    HISTORY_PAGE.store(page, Ordering::Relaxed);
+}
+
+pub fn history_page() -> usize {
+    HISTORY_PAGE.load(Ordering::Relaxed)
+}
+
+#[derive(Default)]
+pub struct SubscriptionManager {
+    /// Ids of the open subscriptions by chat id, oldest first
+    active: HashMap<String, Vec<SubscriptionId>>,
+    /// Subscription of the page of older history each chat requested last, by chat id
+    pages: HashMap<String, SubscriptionId>,
+    /// Subscriptions each relay allows on one connection, by the max_subscriptions of its NIP-11 document
+    limits: HashMap<String, usize>,
+    /// Subscriptions opened so far, numbering the ids
+    opened: u64,
+}
+
+impl SubscriptionManager {
+    /// Id of a new subscription of the chat `chat_id` on `relays`, named after the chat, and the CLOSE messages
+    /// of its oldest subscriptions if the new one would go over the subscription limit of one of the relays.
+    pub fn open(&mut self, chat_id: &str, relays: &[String]) -> (SubscriptionId, Vec<Message>) {
+        self.opened += 1;
+        let subscription_id = SubscriptionId::new(format!("{}:{}", &chat_id[.. chat_id.len().min(16)], self.opened));
+        let limit = relays.iter().filter_map(|relay| self.limits.get(relay.trim_end_matches('/'))).min().copied();
+        let active = self.active.entry(chat_id.to_string()).or_default();
+        let mut closes = Vec::new();
+        while limit.is_some_and(|limit| !active.is_empty() && active.len() >= limit) {
+            let closed = active.remove(0);
+            self.pages.retain(|_, page| *page != closed);
+            closes.push(close_message(closed));
+        }
+        active.push(subscription_id.clone());
+        (subscription_id, closes)
+    }
+
+    /// Like `open`, for a page of older history of the chat `chat_id`. The page requested before is closed,
+    /// as its stored events have arrived by then.
+    pub fn open_page(&mut self, chat_id: &str, relays: &[String]) -> (SubscriptionId, Vec<Message>) {
+        let mut closes = Vec::new();
+        if let Some(previous) = self.pages.remove(chat_id) {
+            self.active.entry(chat_id.to_string()).or_default().retain(|active| *active != previous);
+            closes.push(close_message(previous));
+        }
+        let (subscription_id, evicted) = self.open(chat_id, relays);
+        closes.extend(evicted);
+        self.pages.insert(chat_id.to_string(), subscription_id.clone());
+        (subscription_id, closes)
+    }
+
+    /// Whether `subscription_id` asks for a page of older history.
+    pub fn is_page(&self, subscription_id: &str) -> bool {
+        self.pages.values().any(|page| page.to_string() == subscription_id)
+    }
+
+    /// CLOSE messages of every subscription of the chat `chat_id`, which is forgotten.
+    pub fn leave(&mut self, chat_id: &str) -> Vec<Message> {
+        self.pages.remove(chat_id);
+        self.active.remove(chat_id).unwrap_or_default().into_iter().map(close_message).collect()
+    }
+
+    /// Takes the subscription limits from the NIP-11 documents `infos` of their relays.
+    pub fn set_limits(&mut self, infos: &[(String, Value)]) {
+        for (relay, info) in infos {
+            if let Some(limit) = info["limitation"]["max_subscriptions"].as_u64().filter(|limit| *limit > 0) {
+                self.limits.insert(relay.trim_end_matches('/').to_string(), limit as usize);
+            }
+        }
+    }
+}
+
+fn close_message(subscription_id: SubscriptionId) -> Message {
+    Message::Text(ClientMessage::close(subscription_id).as_json())
+}
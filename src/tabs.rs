@@ -0,0 +1,222 @@
+use std::collections::{ HashSet, VecDeque };
+use std::sync::{ Arc, Mutex };
+
+use colored::Colorize;
+use rustyline::ExternalPrinter;
+use tokio::task::JoinHandle;
+
+use crate::chats::{ self, Chat, ChatPrinter, ChatType };
+use crate::flood::{ FloodControl, FloodLimit };
+use crate::ui::{ self, Scrollback };
+
+/// Lines kept per chat to show again when switching back to it.
+pub const MAX_BUFFERED_LINES: usize = 1000;
+
+/// A chat open in its own tab, with the state its printing task shares.
+pub struct Tab {
+    pub chat: ChatType,
+    pub flood_control: Arc<Mutex<FloodControl>>,
+    /// Additional kinds toggled off in this chat
+    pub hidden_kinds: Arc<Mutex<HashSet<u64>>>,
+    pub scrollback: Scrollback,
+    /// Task printing the chat's events, while it is subscribed
+    pub task: Option<JoinHandle<()>>,
+}
+
+impl Tab {
+    pub fn new(chat: ChatType, flood_limit: Option<FloodLimit>) -> Tab {
+        Tab {
+            hidden_kinds: Arc::new(Mutex::new(chats::load_hidden_kinds(&chat.get_id()))),
+            chat,
+            flood_control: Arc::new(Mutex::new(FloodControl::new(flood_limit))),
+            scrollback: Arc::new(Mutex::new(Vec::new())),
+            task: None,
+        }
+    }
+}
+
+/// Output of a tab, kept while another one is shown.
+struct Buffer {
+    id: String,
+    name: String,
+    lines: VecDeque<String>,
+    unread: usize,
+}
+
+#[derive(Default)]
+struct Buffers {
+    tabs: Vec<Buffer>,
+    /// Id of the chat shown on screen
+    active: String,
+}
+
+impl Buffers {
+    /// Tab bar line: every open chat numbered, the shown one highlighted and the others with their unread count.
+    fn tab_bar(&self) -> String {
+        self.tabs.iter().enumerate()
+            .map(|(index, buffer)| match (buffer.id == self.active, buffer.unread) {
+                (true, _) => format!("[{} {}]", index + 1, buffer.name).bold().to_string(),
+                (false, 0) => format!(" {} {} ", index + 1, buffer.name),
+                (false, unread) => format!(" {} {} ({}) ", index + 1, buffer.name, unread.to_string().yellow()),
+            })
+            .collect::<Vec<String>>()
+            .join("")
+    }
+}
+
+/// Chats open at the same time. Each stays subscribed and prints into its own buffer, of which only the
+/// shown chat's reaches the screen. The others count their unread lines in the tab bar.
+#[derive(Default)]
+pub struct Tabs {
+    pub tabs: Vec<Tab>,
+    pub active: usize,
+    buffers: Arc<Mutex<Buffers>>,
+}
+
+impl Tabs {
+    pub fn new() -> Tabs {
+        Tabs::default()
+    }
+
+    /// Index of the open tab of the chat with `chat_id`.
+    pub fn find(&self, chat_id: &str) -> Option<usize> {
+        self.tabs.iter().position(|tab| tab.chat.get_id() == chat_id)
+    }
+
+    /// Adds `tab` after the others, without showing it. Returns its index.
+    pub fn add(&mut self, tab: Tab) -> usize {
+        self.buffers.lock().unwrap().tabs.push(Buffer {
+            id: tab.chat.get_id(),
+            name: tab.chat.clone().get_name(),
+            lines: VecDeque::new(),
+            unread: 0,
+        });
+        self.tabs.push(tab);
+        self.tabs.len() - 1
+    }
+
+    pub fn active(&self) -> &Tab {
+        &self.tabs[self.active]
+    }
+
+    pub fn active_mut(&mut self) -> &mut Tab {
+        &mut self.tabs[self.active]
+    }
+
+    /// Printer into the buffer of the tab at `index`.
+    pub fn printer(&self, index: usize) -> TabPrinter {
+        TabPrinter { buffers: self.buffers.clone(), id: self.tabs[index].chat.get_id() }
+    }
+
+    /// Shows the tab at `index` in `screen` with the output it buffered, and marks it read.
+    pub fn activate(&mut self, index: usize, screen: &ui::ChatScreen) {
+        // What the tab shown so far received was seen, as is what the new one has
+        if let Some(previous) = self.tabs.get(self.active) {
+            crate::storage::mark_read(&previous.chat.get_id());
+        }
+        crate::storage::mark_read(&self.tabs[index].chat.get_id());
+        self.active = index;
+        // Locked throughout, so nothing the chat prints meanwhile is lost or shown twice
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers.active = self.tabs[index].chat.get_id();
+        buffers.tabs[index].unread = 0;
+        screen.show_scrollback(self.tabs[index].scrollback.clone());
+        ui::clear();
+        for line in buffers.tabs[index].lines.iter() {
+            ui::print(line.clone());
+        }
+        ui::set_tab_bar(buffers.tab_bar());
+    }
+
+    /// Closes the tab at `index` and stops its printing task. Returns its chat.
+    pub fn remove(&mut self, index: usize) -> ChatType {
+        let tab = self.tabs.remove(index);
+        if let Some(task) = tab.task {
+            task.abort();
+        }
+        self.buffers.lock().unwrap().tabs.remove(index);
+        if self.active >= self.tabs.len() {
+            self.active = self.tabs.len().saturating_sub(1);
+        }
+        tab.chat
+    }
+
+    /// Shows the current name of the chat of the tab at `index` in the tab bar.
+    pub fn rename(&mut self, index: usize) {
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers.tabs[index].name = self.tabs[index].chat.clone().get_name();
+        ui::set_tab_bar(buffers.tab_bar());
+    }
+
+    /// Renames the tab at `index` and clears its buffer and flood control, as when its chat is subscribed to anew.
+    pub fn reset(&mut self, index: usize, flood_limit: Option<FloodLimit>) {
+        self.tabs[index].flood_control = Arc::new(Mutex::new(FloodControl::new(flood_limit)));
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers.tabs[index].name = self.tabs[index].chat.clone().get_name();
+        buffers.tabs[index].lines.clear();
+        self.tabs[index].scrollback.lock().unwrap().clear();
+    }
+}
+
+/// Prints into the buffer of a tab, and onto the screen while the tab is shown.
+pub struct TabPrinter {
+    buffers: Arc<Mutex<Buffers>>,
+    id: String,
+}
+
+impl ExternalPrinter for TabPrinter {
+    fn print(&mut self, msg: String) -> rustyline::Result<()> {
+        let mut buffers = self.buffers.lock().unwrap();
+        let shown = buffers.active == self.id;
+        let buffer = match buffers.tabs.iter_mut().find(|buffer| buffer.id == self.id) {
+            Some(val) => val,
+            // The tab was closed
+            None => return Ok(()),
+        };
+        buffer.lines.push_back(msg.clone());
+        if buffer.lines.len() > MAX_BUFFERED_LINES {
+            buffer.lines.pop_front();
+        }
+        if shown {
+            ui::print(msg);
+        } else {
+            buffer.unread += 1;
+            ui::set_tab_bar(buffers.tab_bar());
+        }
+        Ok(())
+    }
+}
+
+impl ChatPrinter for TabPrinter {
+    fn print_before(&mut self, msg: String, before: &str) -> rustyline::Result<()> {
+        let mut buffers = self.buffers.lock().unwrap();
+        let shown = buffers.active == self.id;
+        let buffer = match buffers.tabs.iter_mut().find(|buffer| buffer.id == self.id) {
+            Some(val) => val,
+            None => return Ok(()),
+        };
+        let position = match buffer.lines.iter().rposition(|line| line == before) {
+            Some(val) => val,
+            // The later line was dropped from the buffer already
+            None => {
+                drop(buffers);
+                return self.print(chats::mark_earlier(msg));
+            }
+        };
+        buffer.lines.insert(position, msg);
+        if buffer.lines.len() > MAX_BUFFERED_LINES {
+            buffer.lines.pop_front();
+        }
+        if shown {
+            // The pane can only be appended to, so it is filled anew
+            ui::clear();
+            for line in buffer.lines.iter() {
+                ui::print(line.clone());
+            }
+        } else {
+            buffer.unread += 1;
+            ui::set_tab_bar(buffers.tab_bar());
+        }
+        Ok(())
+    }
+}
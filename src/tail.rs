@@ -0,0 +1,60 @@
+use std::collections::HashSet;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::Duration;
+
+use colored::Colorize;
+use nostr::prelude::*;
+use tokio::time::{ timeout_at, Instant };
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::{ error, pool, ui };
+
+/// /tail subscriptions running, the tail pane is shown while there is one.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Shows the events matching `filter` on `relays` in the tail pane for `duration`, then closes the
+/// subscription and says how many there were.
+pub async fn run(relays: Vec<String>, filter: Filter, duration: Duration) {
+    let (mut writer, mut reader) = match pool::connect(&relays).await {
+        Ok(val) => val,
+        Err(why) => {
+            ui::print_error(format!("Couldn't start the tail: {}", why));
+            return;
+        }
+    };
+    let subscription_id = SubscriptionId::generate();
+    let req = ClientMessage::new_req(subscription_id.clone(), vec![filter]).as_json();
+    if let Err(why) = writer.send(Message::Text(req)).await {
+        ui::print_error(format!("Couldn't start the tail: {}", why));
+        return;
+    }
+    if RUNNING.fetch_add(1, Ordering::SeqCst) == 0 {
+        ui::show_tail_pane(true);
+    }
+    let label = format!("[{}]", subscription_id.to_string().chars().take(8).collect::<String>());
+    ui::print_tail(format!("{} Tailing for {}s", label.truecolor(128, 128, 128), duration.as_secs()));
+    let deadline = Instant::now() + duration;
+    let mut seen: HashSet<String> = HashSet::new();
+    while let Ok(Some(message)) = timeout_at(deadline, reader.next()).await {
+        let json_val = match error::parse_relay_message(&message) {
+            Ok(val) => val,
+            Err(_) => continue,
+        };
+        if json_val[0] != "EVENT" || json_val[1] != subscription_id.to_string() {
+            continue;
+        }
+        let event = &json_val[2];
+        if crate::export::verify_event(event).is_err() || !seen.insert(event["id"].as_str().unwrap_or_default().to_string()) {
+            continue;
+        }
+        if crate::blocklist::is_blocked(event["pubkey"].as_str().unwrap_or_default()) {
+            continue;
+        }
+        ui::print_tail(format!("{} {}", label.truecolor(128, 128, 128), crate::commands::event_line(event)));
+    }
+    writer.send(Message::Text(ClientMessage::close(subscription_id).as_json())).await.ok();
+    if RUNNING.fetch_sub(1, Ordering::SeqCst) == 1 {
+        ui::show_tail_pane(false);
+    }
+    ui::print(format!("Tail {} ended with {} events", label, seen.len()));
+}
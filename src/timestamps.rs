@@ -0,0 +1,66 @@
+use std::sync::Mutex;
+
+use chrono::{ DateTime, Local, NaiveDate };
+use serde::{ Deserialize, Serialize };
+
+/// How the time a message was written is shown in front of it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampFormat {
+    /// 14:05
+    #[default]
+    #[serde(rename = "24h")]
+    Hours24,
+    /// 2:05 PM
+    #[serde(rename = "12h")]
+    Hours12,
+    /// 2m ago, as of when the message is printed
+    Relative,
+    Off,
+}
+
+static FORMAT: Mutex<TimestampFormat> = Mutex::new(TimestampFormat::Hours24);
+
+pub fn set_format(format: TimestampFormat) {
+    *FORMAT.lock().unwrap() = format;
+}
+
+fn local(created_at: u64) -> DateTime<Local> {
+    DateTime::from_timestamp(created_at as i64, 0).unwrap_or_default().with_timezone(&Local)
+}
+
+/// Time of `created_at` to show in front of a message, None if timestamps are off.
+pub fn time(created_at: u64) -> Option<String> {
+    let time = local(created_at);
+    match *FORMAT.lock().unwrap() {
+        TimestampFormat::Hours24 => Some(time.format("%H:%M").to_string()),
+        TimestampFormat::Hours12 => Some(time.format("%-I:%M %p").to_string()),
+        TimestampFormat::Relative => Some(relative(Local::now().signed_duration_since(time).num_seconds())),
+        TimestampFormat::Off => None,
+    }
+}
+
+/// `seconds` ago in the largest unit that fits, like 2m ago.
+fn relative(seconds: i64) -> String {
+    match seconds.max(0) {
+        0 ..= 59 => "now".to_string(),
+        seconds @ 60 ..= 3599 => format!("{}m ago", seconds / 60),
+        seconds @ 3600 ..= 86399 => format!("{}h ago", seconds / 3600),
+        seconds => format!("{}d ago", seconds / 86400),
+    }
+}
+
+/// Local day of `created_at`, which messages are separated by.
+pub fn date(created_at: u64) -> NaiveDate {
+    local(created_at).date_naive()
+}
+
+/// Line shown above the first message of `date`.
+pub fn separator(date: NaiveDate) -> String {
+    let label = match Local::now().date_naive().signed_duration_since(date).num_days() {
+        0 => "Today".to_string(),
+        1 => "Yesterday".to_string(),
+        _ => date.format("%A, %d.%m.%Y").to_string(),
+    };
+    format!("── {} ──", label)
+}
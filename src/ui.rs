@@ -1,18 +1,43 @@
+use std::fs;
+use std::cell::RefCell;
+use std::collections::{ BTreeMap, HashMap };
+use std::future::Future;
+use std::io::{ IsTerminal, Write };
+use std::path::PathBuf;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
 use std::sync::mpsc::{self};
 
 use cursive::theme::{ Effect, Style, PaletteColor, load_toml};
-use cursive::views::{ Button, OnEventView, SelectView, TextView, Dialog, LinearLayout, TextContent };
+use cursive::views::{ Button, OnEventView, SelectView, TextView, Dialog, LinearLayout, TextContent, EditView, HideableView, NamedView, Panel, ResizedView, ScrollView };
 use cursive::align::HAlign;
 use cursive::utils::span::SpannedString;
-use cursive::event::EventResult;
-use cursive::traits::Scrollable;
-use cursive::{ Cursive, CursiveRunnable };
+use cursive::utils::markup::ansi;
+use cursive::view::ScrollStrategy;
+use cursive::event::{ EventResult, Key };
+use cursive::traits::{ Nameable, Resizable, Scrollable };
+use cursive::{ CbSink, Cursive, CursiveRunnable };
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rustyline::ExternalPrinter;
+use nostr::prelude::{ Filter, XOnlyPublicKey };
+use serde_json::Value;
+use tokio::sync::mpsc::{ unbounded_channel, UnboundedReceiver, UnboundedSender };
 
 use crate::Config;
 use crate::ascii_art;
-use crate::chats::{ ChatType, Chat, PrivateChat, PublicChannel };
+use crate::commands;
+use crate::filters::FilterForm;
+use crate::chats::{ self, ChatPrinter, ChatType, Chat, PrivateChat, PublicChannel };
+use crate::monitor::ChatActivity;
 
 pub fn select_relay(config: Config) -> String {
+    if is_dumb_terminal() {
+        return match text_menu("Relays", &config.relays) {
+            Some(index) => config.relays[index].clone(),
+            None => no_selection(),
+        };
+    }
     let mut siv: CursiveRunnable = get_configured_siv(&config);
     let mut relay_view: OnEventView<SelectView<String>> = setup_chat(config.relays.clone(), config.relays.clone());
     let (tx, rx) = mpsc::channel();
@@ -34,15 +59,27 @@ pub fn select_relay(config: Config) -> String {
     rx.recv().unwrap().to_string()
 }
 
-pub fn select_chat(config: Config, channel_list: Vec<PublicChannel>, private_chats: Vec<PrivateChat>) -> Option<ChatType> {
+/// Lists the chats to open one of, with the count of `unread` messages by chat id. None asks for more channels.
+pub fn select_chat(config: Config, channel_list: Vec<PublicChannel>, private_chats: Vec<PrivateChat>, unread: HashMap<String, usize>) -> Option<ChatType> {
+    if is_dumb_terminal() {
+        let pins = load_pins();
+        let mut chats: Vec<(String, Option<ChatType>)> = Vec::new();
+        chats.extend(chat_entries(&private_chats, &pins, &unread).into_iter().map(|(label, chat)| (label, Some(ChatType::PrivateChat(chat)))));
+        chats.extend(chat_entries(&channel_list, &pins, &unread).into_iter().map(|(label, channel)| (label, Some(ChatType::PublicChannel(channel)))));
+        chats.push(("Search for more channels".to_string(), None));
+        let labels: Vec<String> = chats.iter().map(|(label, _)| label.clone()).collect();
+        return match text_menu("Chats", &labels) {
+            Some(index) => chats.swap_remove(index).1,
+            None => no_selection(),
+        };
+    }
 
     let mut siv: CursiveRunnable = get_configured_siv(&config);
 
-    let public_chat_names: Vec<String> = channel_list.iter().map(|channel| channel.clone().get_name()).collect();
-    let private_chat_names: Vec<String> = private_chats.iter().map(|chat| chat.clone().get_name()).collect();
-
-    let mut select_public_chat = setup_chat(public_chat_names.clone(), channel_list.clone());
-    let mut select_private_chat = setup_chat(private_chat_names.clone(), private_chats.clone());
+    let pins = Arc::new(Mutex::new(load_pins()));
+    let unread = Arc::new(unread);
+    let mut select_public_chat = setup_pinnable_chat(channel_list.clone(), pins.clone(), unread.clone());
+    let mut select_private_chat = setup_pinnable_chat(private_chats.clone(), pins, unread);
    
     let (tx, rx) = crossbeam_channel::bounded(1);
     // TODO: Find a better way to access the same channel receiver, without tx_clone variables.
@@ -101,6 +138,7 @@ pub fn select_chat(config: Config, channel_list: Vec<PublicChannel>, private_cha
         linear_layout.add_child(button);
     }
     linear_layout.add_child(Dialog::around(TextView::new_with_content(bottom_wall)));
+    linear_layout.add_child(TextView::new("j/k: move   p: pin/unpin   Enter: open").h_align(HAlign::Center));
         
     siv.add_layer(
         linear_layout
@@ -114,6 +152,12 @@ pub fn select_chat(config: Config, channel_list: Vec<PublicChannel>, private_cha
 pub fn select_unknown_channel(config: Config, channels: Vec<PublicChannel>) -> PublicChannel {
 
     let channel_names: Vec<String> = channels.iter().map(|channel| channel.clone().get_name()).collect();
+    if is_dumb_terminal() {
+        return match text_menu("All channels on this relay", &channel_names) {
+            Some(index) => channels[index].clone(),
+            None => no_selection(),
+        };
+    }
 
     let mut channel_view = setup_chat(channel_names.clone(), channels.clone());
     let mut siv: CursiveRunnable = get_configured_siv(&config);
@@ -137,6 +181,756 @@ pub fn select_unknown_channel(config: Config, channels: Vec<PublicChannel>) -> P
     return rx.recv().unwrap();
 }
 
+/// Overlay listing `chats`, filtered fuzzily as the user types. Returns the picked chat, or None on Esc.
+pub fn quick_switcher(config: Config, chats: Vec<ChatType>, activity: HashMap<String, ChatActivity>) -> Option<ChatType> {
+    let mut siv: CursiveRunnable = get_configured_siv(&config);
+    let chats = Arc::new(chats);
+    let labels: Arc<Vec<String>> = Arc::new(chats.iter().map(|chat| {
+        let label = match chat {
+            ChatType::PublicChannel(channel) => format!("# {}", channel.clone().get_name()),
+            ChatType::PrivateChat(private_chat) => format!("@ {}", private_chat.clone().get_label()),
+        };
+        match activity.get(&chat.get_id()) {
+            Some(chat_activity) if chat_activity.mentions > 0 => format!("{} ({} unread, {} mentions)", label, chat_activity.unread, chat_activity.mentions),
+            Some(chat_activity) if chat_activity.unread > 0 => format!("{} ({} unread)", label, chat_activity.unread),
+            _ => label,
+        }
+    }).collect());
+    let (tx, rx) = crossbeam_channel::bounded(1);
+    let tx_clone = tx.clone();
+    let tx_clone2 = tx.clone();
+
+    let mut results: SelectView<usize> = SelectView::new().h_align(HAlign::Left);
+    results.add_all(labels.iter().cloned().zip(0 .. labels.len()));
+    let chats_clone = chats.clone();
+    results.set_on_submit(move |s: &mut Cursive, index: &usize| {
+        tx.send(Some(chats_clone[*index].clone())).expect("Couldn't submit selection.");
+        s.quit();
+    });
+
+    let query = EditView::new()
+        .on_edit(move |s, query, _| {
+            let mut matches: Vec<(i64, usize)> = labels.iter().enumerate()
+                .filter_map(|(index, label)| fuzzy_score(query, label).map(|score| (score, index)))
+                .collect();
+            matches.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+            s.call_on_name("quick_switcher_results", |view: &mut SelectView<usize>| {
+                view.clear();
+                view.add_all(matches.iter().map(|(_, index)| (labels[*index].clone(), *index)));
+            });
+        })
+        .on_submit(move |s, _| {
+            let selection = s.call_on_name("quick_switcher_results", |view: &mut SelectView<usize>| view.selection()).flatten();
+            if let Some(index) = selection {
+                tx_clone.send(Some(chats[*index].clone())).expect("Couldn't submit selection.");
+                s.quit();
+            }
+        });
+
+    siv.add_global_callback(Key::Esc, move |s| {
+        tx_clone2.send(None).expect("Couldn't submit selection.");
+        s.quit();
+    });
+
+    let linear_layout: LinearLayout = LinearLayout::vertical()
+        .child(query)
+        .child(results.with_name("quick_switcher_results").scrollable().max_height(15));
+    siv.add_layer(Dialog::around(linear_layout).title("Switch chat").min_width(40));
+
+    siv.run();
+    rx.recv().unwrap()
+}
+
+/// Overlay listing the activity feed `lines`, newest first. Returns the index of the picked line, or None on Esc.
+pub fn activity_pane(config: Config, lines: Vec<String>) -> Option<usize> {
+    let mut siv: CursiveRunnable = get_configured_siv(&config);
+    let (tx, rx) = crossbeam_channel::bounded(1);
+    let tx_clone = tx.clone();
+
+    let mut entries: SelectView<usize> = SelectView::new().h_align(HAlign::Left);
+    entries.add_all(lines.into_iter().map(ansi::parse).zip(0 ..));
+    entries.set_on_submit(move |s: &mut Cursive, index: &usize| {
+        tx.send(Some(*index)).expect("Couldn't submit selection.");
+        s.quit();
+    });
+    siv.add_global_callback(Key::Esc, move |s| {
+        tx_clone.send(None).expect("Couldn't submit selection.");
+        s.quit();
+    });
+
+    siv.add_layer(Dialog::around(entries.scrollable().max_height(20)).title("Activity (Enter jumps to it)").min_width(60));
+    siv.run();
+    rx.recv().unwrap()
+}
+
+/// Overlay showing `lines` under `title`, until it is closed with Esc.
+pub fn text_overlay(config: Config, title: &str, lines: Vec<String>) {
+    let mut siv: CursiveRunnable = get_configured_siv(&config);
+    siv.add_global_callback(Key::Esc, |s| s.quit());
+    let text = TextView::new(ansi::parse(lines.join("\n")));
+    siv.add_layer(Dialog::around(text.scrollable().max_height(20)).title(format!("{} (Esc closes)", title)).min_width(60));
+    siv.run();
+}
+
+/// Names of the /filter form's fields with their labels and hints.
+const FILTER_FIELDS: [(&str, &str); 6] = [
+    ("filter_kinds", "Kinds (like 1, 42)"),
+    ("filter_authors", "Authors (npubs or hex)"),
+    ("filter_since", "Since (unix time or 2h, 7d ago)"),
+    ("filter_until", "Until"),
+    ("filter_tags", "Tags (like t=nostr,rust; p=npub1...)"),
+    ("filter_limit", "Limit"),
+];
+
+/// What is typed into the /filter form.
+fn filter_form(s: &mut Cursive) -> FilterForm {
+    let mut values = FILTER_FIELDS.iter().map(|(name, _)| s.call_on_name(name, |view: &mut EditView| view.get_content().to_string()).unwrap_or_default());
+    let mut next = || values.next().unwrap_or_default();
+    FilterForm { kinds: next(), authors: next(), since: next(), until: next(), tags: next(), limit: next() }
+}
+
+/// Shows the REQ the /filter form makes, or what is wrong with it.
+fn update_filter_preview(s: &mut Cursive) {
+    let preview = match filter_form(s).to_json() {
+        Ok(filter) => format!("[\"REQ\", \"<subscription id>\", {}]", serde_json::to_string_pretty(&filter).unwrap_or_default()),
+        Err(why) => format!("Not a valid filter yet: {}", why),
+    };
+    s.call_on_name("filter_preview", |view: &mut TextView| view.set_content(preview));
+}
+
+/// Form building a REQ filter field by field, showing the JSON it makes as it is typed. Returns the filter to
+/// subscribe with, or None on Esc.
+pub fn filter_builder(config: Config) -> Option<Filter> {
+    let mut siv: CursiveRunnable = get_configured_siv(&config);
+    let (tx, rx) = crossbeam_channel::bounded(1);
+    let tx_clone = tx.clone();
+
+    let mut form = LinearLayout::vertical();
+    for (name, label) in FILTER_FIELDS {
+        form.add_child(TextView::new(label));
+        form.add_child(EditView::new().on_edit(|s, _, _| update_filter_preview(s)).with_name(name));
+    }
+    form.add_child(TextView::new(" "));
+    form.add_child(TextView::new("[\"REQ\", \"<subscription id>\", {}]").with_name("filter_preview"));
+    let dialog = Dialog::around(form.scrollable().max_height(30))
+        .title("Build a filter (Esc cancels)")
+        .button("Subscribe", move |s| {
+            match filter_form(s).to_filter() {
+                Ok(filter) => {
+                    tx.send(Some(filter)).expect("Couldn't submit filter.");
+                    s.quit();
+                },
+                Err(why) => {
+                    s.call_on_name("filter_preview", |view: &mut TextView| view.set_content(format!("Not a valid filter: {}", why)));
+                },
+            }
+        })
+        .min_width(60);
+    siv.add_global_callback(Key::Esc, move |s| {
+        tx_clone.send(None).expect("Couldn't submit filter.");
+        s.quit();
+    });
+    siv.add_layer(dialog);
+    siv.run();
+    rx.recv().unwrap()
+}
+
+/// Scores `candidate` against `query` if all query characters appear in it in order, case-insensitively.
+/// Consecutive and early matches score higher.
+fn fuzzy_score(query: &str, candidate: &str) -> Option<i64> {
+    let candidate: Vec<char> = candidate.to_lowercase().chars().collect();
+    let mut score: i64 = 0;
+    let mut position = 0;
+    let mut previous_match: Option<usize> = None;
+    for query_char in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let found = candidate[position ..].iter().position(|c| *c == query_char)? + position;
+        score += match previous_match {
+            Some(previous) if previous + 1 == found => 10,
+            _ => 1,
+        };
+        score -= found as i64 / 10;
+        previous_match = Some(found);
+        position = found + 1;
+    }
+    Some(score)
+}
+
+/// Prints into a named text pane of a cursive session, so a `PrintingHandler` can render into it.
+pub struct PanePrinter {
+    sink: CbSink,
+    pane: String,
+}
+
+impl ExternalPrinter for PanePrinter {
+    fn print(&mut self, msg: String) -> rustyline::Result<()> {
+        match append_line(&self.sink, &self.pane, msg) {
+            true => Ok(()),
+            false => Err(rustyline::error::ReadlineError::Io(std::io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+}
+
+impl ChatPrinter for PanePrinter {}
+
+/// Appends `line`, which may contain ANSI colors, to the text pane named `pane`. Returns false if the
+/// cursive session is gone.
+fn append_line(sink: &CbSink, pane: &str, line: String) -> bool {
+    let pane = pane.to_string();
+    sink.send(Box::new(move |s: &mut Cursive| {
+        s.call_on_name(&pane, |view: &mut TextView| {
+            view.append(ansi::parse(line + "\n"));
+        });
+    })).is_ok()
+}
+
+const MESSAGES_PANE: &str = "chat_messages";
+const INPUT_FIELD: &str = "chat_input";
+const STATUS_BAR: &str = "chat_status";
+const TAB_BAR: &str = "chat_tabs";
+/// Line above the input showing how the command being typed is used
+const INPUT_HINT: &str = "chat_hint";
+/// Line above the input hint showing the history loads in progress
+const LOADING_LINE: &str = "chat_loading";
+/// Line above the loading line counting down until a held message is sent
+const PENDING_LINE: &str = "chat_pending";
+/// Pane below the messages the events of running /tail subscriptions are shown in
+const TAIL_PANE: &str = "chat_tail";
+/// Frame around the tail pane, hidden while no /tail runs
+const TAIL_FRAME: &str = "chat_tail_frame";
+type TailFrame = HideableView<ResizedView<Panel<ScrollView<NamedView<TextView>>>>>;
+/// Lines the tail pane is high
+const TAIL_HEIGHT: usize = 8;
+/// Frames of the spinner turning while history loads
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// History loads in progress with the events received so far, keyed by chat name
+static LOADING: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+static SPINNER_FRAME: AtomicUsize = AtomicUsize::new(0);
+
+/// Sink of the open chat screen. Output goes there instead of stdout while there is one.
+static CONSOLE: Mutex<Option<CbSink>> = Mutex::new(None);
+
+tokio::task_local! {
+    /// Lines printed by the current task while its output is captured
+    static CAPTURED: RefCell<Vec<String>>;
+}
+
+/// Runs `future` and returns the lines it printed, which are shown as usual too. What other tasks print
+/// meanwhile isn't included.
+pub async fn capture<F: Future<Output = ()>>(future: F) -> Vec<String> {
+    CAPTURED.scope(RefCell::new(Vec::new()), async move {
+        future.await;
+        CAPTURED.with(|lines| lines.take())
+    }).await
+}
+
+/// Whether the chat screen is open, so output goes to its panes.
+pub fn screen_open() -> bool {
+    CONSOLE.lock().unwrap().is_some()
+}
+
+/// Prints `line` in the chat screen if it is open, on stdout otherwise.
+pub fn print(line: String) {
+    CAPTURED.try_with(|lines| lines.borrow_mut().push(strip_ansi(&line))).ok();
+    let console = CONSOLE.lock().unwrap();
+    if !console.as_ref().is_some_and(|sink| append_line(sink, MESSAGES_PANE, line.clone())) {
+        println!("{}", line);
+    }
+}
+
+/// Asks for a secret like a passphrase on the terminal, without echoing it. The chat screen has to be suspended.
+pub fn read_secret(prompt: &str) -> String {
+    let term = console::Term::stdout();
+    term.write_str(&format!("{} ", prompt)).ok();
+    term.read_secure_line().expect("Couldn't read from the terminal!")
+}
+
+/// Prints an error message in the chat screen if it is open, on stderr otherwise.
+pub fn print_error(line: String) {
+    CAPTURED.try_with(|lines| lines.borrow_mut().push(line.clone())).ok();
+    let console = CONSOLE.lock().unwrap();
+    if !console.as_ref().is_some_and(|sink| append_line(sink, MESSAGES_PANE, colored::Colorize::red(line.as_str()).to_string())) {
+        eprintln!("{}", line);
+    }
+}
+
+/// Empties the message pane of the chat screen, or the terminal if it isn't open and isn't a dumb one.
+pub fn clear() {
+    let console = CONSOLE.lock().unwrap();
+    let cleared = console.as_ref().is_some_and(|sink| sink.send(Box::new(|s: &mut Cursive| {
+        s.call_on_name(MESSAGES_PANE, |view: &mut TextView| view.set_content(""));
+    })).is_ok());
+    if !cleared && !is_dumb_terminal() {
+        print!("{esc}[2J{esc}[1;1H", esc = 27 as char);
+    }
+}
+
+/// Progress of the history load of a chat, shown above the input until it is dropped, which happens too
+/// when the task loading it is aborted.
+pub struct LoadingProgress {
+    chat: String,
+}
+
+impl LoadingProgress {
+    pub fn new(chat: String) -> Self {
+        LoadingProgress { chat }
+    }
+
+    /// Shows `count` events received so far and turns the spinner.
+    pub fn update(&self, count: usize) {
+        show_loading(&self.chat, Some(count));
+    }
+}
+
+impl Drop for LoadingProgress {
+    fn drop(&mut self) {
+        show_loading(&self.chat, None);
+    }
+}
+
+/// Shows that the history of `chat` is loading with `count` events received so far, or that it is done if
+/// None.
+fn show_loading(chat: &str, count: Option<usize>) {
+    let mut loading = LOADING.lock().unwrap();
+    match count {
+        Some(count) => loading.insert(chat.to_string(), count),
+        None => loading.remove(chat),
+    };
+    let line = match loading.is_empty() {
+        true => String::new(),
+        false => {
+            let frame = SPINNER[SPINNER_FRAME.fetch_add(1, Ordering::Relaxed) % SPINNER.len()];
+            let loads: Vec<String> = loading.iter().map(|(chat, count)| format!("{} {} events", chat, thousands(*count))).collect();
+            format!("{} loading history… {} (Ctrl-G starts with what's loaded)", frame, loads.join(", "))
+        },
+    };
+    if let Some(sink) = CONSOLE.lock().unwrap().as_ref() {
+        sink.send(Box::new(move |s: &mut Cursive| {
+            s.call_on_name(LOADING_LINE, |view: &mut TextView| view.set_content(ansi::parse(colored::Colorize::truecolor(line.as_str(), 128, 128, 128).to_string())));
+        })).ok();
+    }
+}
+
+/// Shows `line` about a message waiting to be sent above the input, or clears it if it is empty.
+pub fn show_pending(line: String) {
+    if let Some(sink) = CONSOLE.lock().unwrap().as_ref() {
+        sink.send(Box::new(move |s: &mut Cursive| {
+            s.call_on_name(PENDING_LINE, |view: &mut TextView| view.set_content(ansi::parse(colored::Colorize::yellow(line.as_str()).to_string())));
+        })).ok();
+    }
+}
+
+/// Shows the tail pane emptied, or hides it.
+pub fn show_tail_pane(visible: bool) {
+    if let Some(sink) = CONSOLE.lock().unwrap().as_ref() {
+        sink.send(Box::new(move |s: &mut Cursive| {
+            if visible {
+                s.call_on_name(TAIL_PANE, |view: &mut TextView| view.set_content(""));
+            }
+            s.call_on_name(TAIL_FRAME, |view: &mut TailFrame| view.set_visible(visible));
+        })).ok();
+    }
+}
+
+/// Prints `line` in the tail pane if the chat screen is open, on stdout otherwise.
+pub fn print_tail(line: String) {
+    let console = CONSOLE.lock().unwrap();
+    if !console.as_ref().is_some_and(|sink| append_line(sink, TAIL_PANE, line.clone())) {
+        println!("{}", line);
+    }
+}
+
+/// `number` with its thousands separated by commas, like 1,204.
+fn thousands(number: usize) -> String {
+    let digits: Vec<char> = number.to_string().chars().collect();
+    digits.rchunks(3).rev().map(|group| group.iter().collect::<String>()).collect::<Vec<String>>().join(",")
+}
+
+/// Shows `line` in the tab bar of the chat screen, if it is open.
+pub fn set_tab_bar(line: String) {
+    if let Some(sink) = CONSOLE.lock().unwrap().as_ref() {
+        sink.send(Box::new(move |s: &mut Cursive| {
+            s.call_on_name(TAB_BAR, |view: &mut TextView| view.set_content(ansi::parse(line)));
+        })).ok();
+    }
+}
+
+/// Full screen chat: a status bar, a tab bar, a scrollable message pane and an input line with history on the
+/// arrow keys. Cursive runs on its own thread, so the chat can be driven from async code, and is
+/// suspended while other screens or an editor use the terminal.
+pub struct ChatScreen {
+    sink: CbSink,
+    input: UnboundedReceiver<String>,
+    /// Messages of the shown chat, swapped out when another chat is shown
+    scrollback: Arc<Mutex<Scrollback>>,
+    suspended: mpsc::Receiver<()>,
+    resume: mpsc::Sender<bool>,
+}
+
+impl ChatScreen {
+    pub fn open(config: &Config) -> ChatScreen {
+        let (input_tx, input_rx) = unbounded_channel();
+        let (sink_tx, sink_rx) = mpsc::channel();
+        let (suspended_tx, suspended_rx) = mpsc::channel();
+        let (resume_tx, resume_rx) = mpsc::channel();
+        let scrollback: Arc<Mutex<Scrollback>> = Arc::new(Mutex::new(Arc::new(Mutex::new(Vec::new()))));
+        let config = config.clone();
+        let screen_scrollback = scrollback.clone();
+        std::thread::spawn(move || {
+            let mut siv = get_configured_siv(&config);
+            build_chat_screen(&mut siv, input_tx, screen_scrollback);
+            sink_tx.send(siv.cb_sink().clone()).expect("Couldn't start chat screen.");
+            loop {
+                siv.run();
+                suspended_tx.send(()).ok();
+                match resume_rx.recv() {
+                    Ok(true) => continue,
+                    _ => break,
+                }
+            }
+        });
+        let sink = sink_rx.recv().expect("Couldn't start chat screen.");
+        *CONSOLE.lock().unwrap() = Some(sink.clone());
+        ChatScreen { sink, input: input_rx, scrollback, suspended: suspended_rx, resume: resume_tx }
+    }
+
+    /// Next line submitted in the input field.
+    pub async fn next_line(&mut self) -> String {
+        self.input.recv().await.expect("Chat screen closed.")
+    }
+
+    /// Printer into the message pane, for output from background tasks.
+    pub fn printer(&self) -> PanePrinter {
+        PanePrinter { sink: self.sink.clone(), pane: MESSAGES_PANE.to_string() }
+    }
+
+    /// Makes `scrollback` the messages that can be picked, as the chat they were printed by is shown.
+    pub fn show_scrollback(&self, scrollback: Scrollback) {
+        *self.scrollback.lock().unwrap() = scrollback;
+    }
+
+    /// Shown message whose id is or starts with `id`.
+    pub fn find_message(&self, id: &str) -> Option<Value> {
+        if id.is_empty() {
+            return None;
+        }
+        let scrollback = self.scrollback.lock().unwrap().clone();
+        let messages = scrollback.lock().unwrap();
+        messages.iter().rev()
+            .find(|message| message.event["id"].as_str().is_some_and(|event_id| event_id.starts_with(id)))
+            .map(|message| message.event.clone())
+    }
+
+    /// Author of a shown message, picked by `who`: a number counting back from the newest message, the name
+    /// authors are shown with or the start of their npub, or the id of the message.
+    pub fn find_author(&self, who: &str) -> Option<XOnlyPublicKey> {
+        let scrollback = self.scrollback.lock().unwrap().clone();
+        let messages = scrollback.lock().unwrap();
+        let message = match who.parse::<usize>() {
+            Ok(number) if (1 ..= messages.len()).contains(&number) => messages.get(messages.len() - number),
+            _ => messages.iter().rev().find(|message| chats::short_name(&message.event["pubkey"].to_string()) == who
+                    || message.event["pubkey"].as_str().and_then(crate::names::cached).is_some_and(|name| name.eq_ignore_ascii_case(who)))
+                .or_else(|| messages.iter().rev().find(|message| !who.is_empty() && message.event["id"].as_str().is_some_and(|id| id.starts_with(who)))),
+        }?;
+        crate::profiles::parse_public_key(message.event["pubkey"].as_str()?)
+    }
+
+    pub fn set_status(&self, status: String) {
+        self.sink.send(Box::new(move |s: &mut Cursive| {
+            s.call_on_name(STATUS_BAR, |view: &mut TextView| view.set_content(ansi::parse(status)));
+        })).ok();
+    }
+
+    /// Hands the terminal back until `resume`. Output printed meanwhile shows up once the screen is back.
+    pub fn suspend(&self) {
+        if self.sink.send(Box::new(|s: &mut Cursive| s.quit())).is_ok() {
+            self.suspended.recv().ok();
+        }
+    }
+
+    pub fn resume(&self) {
+        self.resume.send(true).ok();
+    }
+
+    /// Restores the terminal for good, so the program can exit.
+    pub fn close(&self) {
+        self.suspend();
+        *CONSOLE.lock().unwrap() = None;
+        self.resume.send(false).ok();
+    }
+}
+
+fn build_chat_screen(siv: &mut CursiveRunnable, input: UnboundedSender<String>, scrollback: Arc<Mutex<Scrollback>>) {
+    let history: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(load_input_history()));
+    // Position while browsing the history, counted back from the newest entry
+    let browsing = Arc::new(AtomicUsize::new(0));
+
+    let submit_history = history.clone();
+    let submit_browsing = browsing.clone();
+    let submit_input = input.clone();
+    let input_field = EditView::new()
+        .on_edit(|s, text, _| {
+            let hint = commands::hint(text).unwrap_or_default();
+            s.call_on_name(INPUT_HINT, |view: &mut TextView| view.set_content(ansi::parse(colored::Colorize::truecolor(hint.as_str(), 128, 128, 128).to_string())));
+        })
+        .on_submit(move |s, text| {
+            if text.trim().is_empty() {
+                return;
+            }
+            submit_input.send(text.to_string()).ok();
+            submit_history.lock().unwrap().push(text.to_string());
+            submit_browsing.store(0, Ordering::SeqCst);
+            save_input_history_entry(text);
+            s.call_on_name(INPUT_FIELD, |view: &mut EditView| view.set_content(""));
+            s.call_on_name(INPUT_HINT, |view: &mut TextView| view.set_content(""));
+        })
+        .with_name(INPUT_FIELD);
+    let up_history = history.clone();
+    let up_browsing = browsing.clone();
+    let input_field = OnEventView::new(input_field)
+        .on_pre_event(Key::Tab, |s| {
+            let text = s.call_on_name(INPUT_FIELD, |view: &mut EditView| view.get_content().to_string()).unwrap_or_default();
+            if let Some(completed) = commands::complete(&text) {
+                // Setting the content runs on_edit, which updates the hint
+                if let Some(callback) = s.call_on_name(INPUT_FIELD, |view: &mut EditView| view.set_content(completed)) {
+                    callback(s);
+                }
+            }
+        })
+        .on_pre_event(Key::Up, move |s| {
+            let history = up_history.lock().unwrap();
+            let position = (up_browsing.load(Ordering::SeqCst) + 1).min(history.len());
+            up_browsing.store(position, Ordering::SeqCst);
+            if position > 0 {
+                let entry = history[history.len() - position].clone();
+                s.call_on_name(INPUT_FIELD, |view: &mut EditView| view.set_content(entry));
+            }
+        })
+        .on_pre_event(Key::Down, move |s| {
+            let history = history.lock().unwrap();
+            let position = browsing.load(Ordering::SeqCst).saturating_sub(1);
+            browsing.store(position, Ordering::SeqCst);
+            let entry = if position == 0 { String::new() } else { history[history.len() - position].clone() };
+            s.call_on_name(INPUT_FIELD, |view: &mut EditView| view.set_content(entry));
+        });
+
+    let messages = TextView::new("")
+        .with_name(MESSAGES_PANE)
+        .scrollable()
+        .scroll_strategy(ScrollStrategy::StickToBottom);
+    let tail = TextView::new("")
+        .with_name(TAIL_PANE)
+        .scrollable()
+        .scroll_strategy(ScrollStrategy::StickToBottom);
+    let layout = LinearLayout::vertical()
+        .child(TextView::new("").style(Effect::Reverse).with_name(STATUS_BAR).full_width())
+        .child(TextView::new("").with_name(TAB_BAR).full_width())
+        .child(messages.full_height())
+        .child(HideableView::new(Panel::new(tail).title("Tail").fixed_height(TAIL_HEIGHT + 2)).hidden().with_name(TAIL_FRAME))
+        .child(TextView::new("").with_name(PENDING_LINE).full_width())
+        .child(TextView::new("").with_name(LOADING_LINE).full_width())
+        .child(TextView::new("").with_name(INPUT_HINT).full_width())
+        .child(input_field);
+
+    // Ctrl-K opens the quick switcher, as /switch does
+    let switch_input = input.clone();
+    siv.add_global_callback(cursive::event::Event::CtrlChar('k'), move |_| {
+        switch_input.send("/switch".to_string()).ok();
+    });
+    // PageUp loads older history, as /more does
+    let more_input = input.clone();
+    siv.add_global_callback(cursive::event::Key::PageUp, move |_| {
+        more_input.send("/more".to_string()).ok();
+    });
+    // Ctrl-G cuts history loads short, their chats start with the history received so far
+    siv.add_global_callback(cursive::event::Event::CtrlChar('g'), |_| {
+        chats::SKIP_HISTORY.notify_waiters();
+    });
+    // Ctrl-Z cancels the message counting down to be sent, or being mined
+    siv.add_global_callback(cursive::event::Event::CtrlChar('z'), |_| {
+        crate::app::CANCEL_SEND.notify_waiters();
+    });
+    // Flipping through the open chats. Few terminals report Ctrl-Tab, so Ctrl and the arrow keys do too.
+    for (event, command) in [
+        (cursive::event::Event::Ctrl(Key::Tab), "/switch next"),
+        (cursive::event::Event::Ctrl(Key::Right), "/switch next"),
+        (cursive::event::Event::Ctrl(Key::Left), "/switch prev"),
+    ] {
+        let tab_input = input.clone();
+        siv.add_global_callback(event, move |_| {
+            tab_input.send(command.to_string()).ok();
+        });
+    }
+    // Esc picks a message to act on, or closes the open menu
+    siv.add_global_callback(Key::Esc, move |s| {
+        if s.screen().len() > 1 {
+            s.pop_layer();
+            return;
+        }
+        let messages = scrollback.lock().unwrap().lock().unwrap().clone();
+        if !messages.is_empty() {
+            s.add_layer(message_picker(messages, input.clone()));
+        }
+    });
+    siv.add_fullscreen_layer(layout);
+    siv.focus_name(INPUT_FIELD).ok();
+}
+
+/// A message printed in the chat screen, kept so it can be picked in the message actions menu.
+#[derive(Clone)]
+pub struct ShownMessage {
+    /// The message as printed
+    pub line: String,
+    /// The event it was printed from, with its content decrypted
+    pub event: Value,
+    /// created_at of the first shown message of its thread, which shown messages are in order of
+    pub thread_created_at: u64,
+}
+
+pub type Scrollback = Arc<Mutex<Vec<ShownMessage>>>;
+
+/// Actions on a picked message. Most are run as the command they are named after, on the message's id.
+const MESSAGE_ACTIONS: [(&str, &str); 7] = [
+    ("Reply", "/reply"),
+    ("React", "/react"),
+    ("Copy", ""),
+    ("Raw event", "/raw"),
+    ("Zap", "/zap"),
+    ("Message author", "/dm"),
+    ("Delete", "/delete"),
+];
+
+/// List of the shown messages, newest selected, that opens the actions menu of the one picked with Enter.
+fn message_picker(messages: Vec<ShownMessage>, input: UnboundedSender<String>) -> Dialog {
+    let mut picker: SelectView<ShownMessage> = SelectView::new();
+    for message in messages {
+        let first_line = message.line.lines().next().unwrap_or_default().to_string();
+        picker.add_item(ansi::parse(first_line), message);
+    }
+    picker.set_selection(picker.len() - 1);
+    picker.set_on_submit(move |s, message: &ShownMessage| {
+        s.pop_layer();
+        s.add_layer(message_actions(message.clone(), input.clone()));
+    });
+    let picker = setup_message_list(picker).scrollable().scroll_strategy(ScrollStrategy::StickToBottom);
+    Dialog::around(picker).title("Pick a message")
+}
+
+fn message_actions(message: ShownMessage, input: UnboundedSender<String>) -> Dialog {
+    let mut actions: SelectView<&'static str> = SelectView::new();
+    for (label, command) in MESSAGE_ACTIONS {
+        actions.add_item(label, command);
+    }
+    let id = message.event["id"].as_str().unwrap_or_default().to_string();
+    actions.set_on_submit(move |s, command: &&str| {
+        s.pop_layer();
+        match *command {
+            // Replies and reactions need more input, so they are left in the input line to finish
+            "/reply" => fill_input(s, format!("/reply {} ", id)),
+            "/react" => fill_input(s, format!("/react {} +", id)),
+            "" => copy_to_clipboard(message.event["content"].as_str().unwrap_or_default()),
+            command => { input.send(format!("{} {}", command, id)).ok(); },
+        }
+    });
+    Dialog::around(setup_message_list(actions)).title(message.line.lines().next().map(strip_ansi).unwrap_or_default())
+}
+
+/// Adds the vim-style j/k movement of the chat lists to a list of the chat screen.
+fn setup_message_list<T: 'static>(view: SelectView<T>) -> OnEventView<SelectView<T>> {
+    OnEventView::new(view)
+        .on_pre_event_inner('k', |s, _| Some(EventResult::Consumed(Some(s.select_up(1)))))
+        .on_pre_event_inner('j', |s, _| Some(EventResult::Consumed(Some(s.select_down(1)))))
+}
+
+fn fill_input(s: &mut Cursive, text: String) {
+    s.call_on_name(INPUT_FIELD, |view: &mut EditView| {
+        view.set_content(text);
+    });
+    s.focus_name(INPUT_FIELD).ok();
+}
+
+/// Puts `text` on the clipboard with an OSC 52 escape sequence, which works over SSH too where the terminal supports it.
+fn copy_to_clipboard(text: &str) {
+    let sequence = format!("\x1b]52;c;{}\x07", STANDARD.encode(text));
+    let mut stdout = std::io::stdout();
+    if std::io::Write::write_all(&mut stdout, sequence.as_bytes()).and_then(|_| std::io::Write::flush(&mut stdout)).is_err() {
+        print_error("Couldn't copy the message.".to_string());
+    }
+}
+
+fn strip_ansi(line: &str) -> String {
+    ansi::parse(line).source().to_string()
+}
+
+/// File the input history is kept in between sessions.
+fn input_history_file() -> PathBuf {
+    crate::data_dir().join("history.txt")
+}
+
+fn load_input_history() -> Vec<String> {
+    fs::read_to_string(input_history_file()).unwrap_or_default().lines()
+        .filter(|line| !line.is_empty() && *line != "#V2")
+        .map(|line| line.to_string())
+        .collect()
+}
+
+fn save_input_history_entry(entry: &str) {
+    let written = fs::OpenOptions::new().create(true).append(true).open(input_history_file())
+        .and_then(|mut file| std::io::Write::write_all(&mut file, format!("{}\n", entry.replace('\n', " ")).as_bytes()));
+    if let Err(why) = written {
+        print_error(format!("Couldn't save input history: {}", why));
+    }
+}
+
+/// Two chats side by side, each with its own scrollback and input line. Tab moves the input focus to the
+/// other pane and Esc closes the view. Submitted lines are sent to `outgoing` along with their pane index.
+pub fn split_view(config: &Config, titles: [String; 2], outgoing: UnboundedSender<(usize, String)>) -> (CursiveRunnable, Vec<PanePrinter>) {
+    let mut siv: CursiveRunnable = get_configured_siv(config);
+    let mut panes = LinearLayout::horizontal();
+    let mut printers = Vec::new();
+
+    for (index, title) in titles.iter().enumerate() {
+        let pane_name = format!("split_pane_{}", index);
+        let input_name = format!("split_input_{}", index);
+        let outgoing = outgoing.clone();
+        let input_name_clone = input_name.clone();
+        let input = EditView::new()
+            .on_submit(move |s, text| {
+                if text.is_empty() {
+                    return;
+                }
+                outgoing.send((index, text.to_string())).expect("Couldn't submit message.");
+                s.call_on_name(&input_name_clone, |view: &mut EditView| {
+                    view.set_content("");
+                });
+            })
+            .with_name(input_name);
+        let scrollback = TextView::new("")
+            .with_name(pane_name.clone())
+            .scrollable()
+            .scroll_strategy(ScrollStrategy::StickToBottom);
+
+        panes.add_child(Dialog::around(LinearLayout::vertical()
+                .child(scrollback.full_height())
+                .child(input))
+            .title(title.clone())
+            .full_width());
+        printers.push(PanePrinter { sink: siv.cb_sink().clone(), pane: pane_name });
+    }
+
+    let focused_pane = AtomicUsize::new(0);
+    siv.set_on_pre_event(Key::Tab, move |s| {
+        let next = (focused_pane.load(Ordering::SeqCst) + 1) % 2;
+        focused_pane.store(next, Ordering::SeqCst);
+        s.focus_name(&format!("split_input_{}", next)).ok();
+    });
+    siv.add_global_callback(Key::Esc, |s| s.quit());
+
+    siv.add_fullscreen_layer(panes);
+    siv.focus_name("split_input_0").ok();
+    (siv, printers)
+}
+
 pub fn setup_chat<T: Clone + 'static>(label: Vec<String>, item: Vec<T>) -> OnEventView<SelectView<T>> {
     let mut chat_view: SelectView<T> = SelectView::new()
         .h_align(HAlign::Center)
@@ -160,6 +954,107 @@ pub fn setup_chat<T: Clone + 'static>(label: Vec<String>, item: Vec<T>) -> OnEve
         return chat_view_event;
 }
 
+/// Like `setup_chat`, but keeps pinned chats at the top and lets `p` toggle the pin of the selected chat.
+fn setup_pinnable_chat<T: Chat + Clone + Send + Sync + 'static>(items: Vec<T>, pins: Arc<Mutex<Vec<String>>>, unread: Arc<HashMap<String, usize>>) -> OnEventView<SelectView<T>> {
+    let mut chat_view = setup_chat(Vec::new(), Vec::<T>::new());
+    fill_chat_list(chat_view.get_inner_mut(), &items, &pins.lock().unwrap(), &unread);
+
+    chat_view.set_on_pre_event_inner('p', move |s, _| {
+        let selected_id = s.selection()?.get_id();
+        let mut pins = pins.lock().unwrap();
+        match pins.iter().position(|id| id == &selected_id) {
+            Some(index) => { pins.remove(index); },
+            None => pins.push(selected_id.clone()),
+        }
+        save_pins(&pins);
+
+        fill_chat_list(s, &items, &pins, &unread);
+        let new_index = s.iter().position(|(_, item)| item.get_id() == selected_id).unwrap_or(0);
+        let cb = s.set_selection(new_index);
+        Some(EventResult::Consumed(Some(cb)))
+    });
+    chat_view
+}
+
+/// Fills `view` with `items`, pinned ones first and marked with a star, and those with unread messages
+/// showing how many.
+fn fill_chat_list<T: Chat + Clone + 'static>(view: &mut SelectView<T>, items: &[T], pins: &[String], unread: &HashMap<String, usize>) {
+    view.clear();
+    for (label, item) in chat_entries(items, pins, unread) {
+        view.add_item(label, item);
+    }
+}
+
+/// `items` labeled with their unread counts, the pinned ones first and starred.
+fn chat_entries<T: Chat + Clone>(items: &[T], pins: &[String], unread: &HashMap<String, usize>) -> Vec<(String, T)> {
+    let label = |item: &T| match unread.get(&item.get_id()) {
+        Some(count) if *count > 0 => format!("{} ({} unread)", item.clone().get_label(), count),
+        _ => item.clone().get_label(),
+    };
+    let (pinned, unpinned): (Vec<&T>, Vec<&T>) = items.iter().partition(|item| pins.contains(&item.get_id()));
+    pinned.into_iter().map(|item| (format!("★ {}", label(item)), item.clone()))
+        .chain(unpinned.into_iter().map(|item| (label(item), item.clone())))
+        .collect()
+}
+
+/// Whether the selection screens can't be drawn: without a terminal on both ends, on TERM=dumb and in CI.
+pub fn is_dumb_terminal() -> bool {
+    !std::io::stdin().is_terminal()
+        || !std::io::stdout().is_terminal()
+        || std::env::var("TERM").is_ok_and(|term| term == "dumb")
+        || std::env::var_os("CI").is_some()
+}
+
+/// Numbered list of `items` on stdout, for dumb terminals. Returns the index of the item whose number is
+/// read from stdin, or None if stdin ends first.
+fn text_menu(title: &str, items: &[String]) -> Option<usize> {
+    if items.is_empty() {
+        println!("{}: nothing to pick from.", title);
+        return None;
+    }
+    println!("{}:", title);
+    for (index, item) in items.iter().enumerate() {
+        println!("{:>4}  {}", index + 1, item);
+    }
+    loop {
+        print!("Number: ");
+        std::io::stdout().flush().ok();
+        let mut line = String::new();
+        match std::io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => (),
+        }
+        match line.trim().parse::<usize>() {
+            Ok(number) if (1 ..= items.len()).contains(&number) => return Some(number - 1),
+            _ => println!("Enter a number from 1 to {}.", items.len()),
+        }
+    }
+}
+
+/// Quits when nothing was picked from a text menu, as nothing can be opened.
+fn no_selection() -> ! {
+    print_error("Nothing picked.".to_string());
+    std::process::exit(1);
+}
+
+fn pins_path() -> PathBuf {
+    crate::data_dir().join("pins.txt")
+}
+
+/// Ids of pinned chats, one per line in the pins file.
+pub fn load_pins() -> Vec<String> {
+    match fs::read_to_string(pins_path()) {
+        Ok(content) => content.lines().filter(|line| !line.is_empty()).map(|line| line.to_string()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn save_pins(pins: &[String]) {
+    if let Err(why) = fs::write(pins_path(), pins.join("\n")) {
+        print_error(format!("Couldn't save pinned chats: {}", why));
+    }
+}
+
 fn get_configured_siv(config: &Config) -> CursiveRunnable {
     let mut siv: CursiveRunnable = cursive::crossterm();
     let mut theme = match load_toml(&toml::to_string(&config.theme).unwrap()) {
@@ -170,3 +1065,23 @@ fn get_configured_siv(config: &Config) -> CursiveRunnable {
     siv.set_theme(theme); 
     siv
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzzy_score_needs_every_character_in_order() {
+        assert!(fuzzy_score("ntr", "Nostr").is_some());
+        assert!(fuzzy_score("rtn", "Nostr").is_none());
+        assert!(fuzzy_score("nostrx", "Nostr").is_none());
+        assert_eq!(fuzzy_score("", "Nostr"), Some(0));
+    }
+
+    #[test]
+    fn fuzzy_score_prefers_consecutive_and_early_matches() {
+        assert!(fuzzy_score("nos", "nostr") > fuzzy_score("nos", "n_o_s"));
+        assert!(fuzzy_score("chat", "chat room") > fuzzy_score("chat", "a very long name of a chat"));
+        assert_eq!(fuzzy_score("No Str", "nostr"), fuzzy_score("nostr", "nostr"));
+    }
+}
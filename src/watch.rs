@@ -0,0 +1,92 @@
+use std::collections::VecDeque;
+use std::sync::{ LazyLock, Mutex };
+
+use colored::Colorize;
+use nostr::prelude::*;
+use rustyline::ExternalPrinter;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::{ error, names, pool };
+
+/// Entries the Watching feed keeps at most, the oldest are dropped first.
+pub const MAX_WATCHED: usize = 100;
+/// Recent events of the watched accounts the feed starts with.
+const INITIAL_WATCHED: usize = 20;
+
+/// Notes and channel messages of the watched accounts, in the order they were written.
+static FEED: LazyLock<Mutex<VecDeque<Event>>> = LazyLock::new(|| Mutex::new(VecDeque::new()));
+
+/// Subscribes to the kind 1 notes and channel messages of `watched` on `relays`, whichever chat is open, and
+/// keeps them in the Watching feed. New ones are announced on `printer`.
+pub async fn run<T: ExternalPrinter>(relays: Vec<String>, watched: Vec<XOnlyPublicKey>, mut printer: T) {
+    let (mut writer, mut reader) = match pool::connect(&relays).await {
+        Ok(val) => val,
+        Err(why) => {
+            printer.print(format!("[{}] Watching unavailable: {}", "WATCH".red(), why)).ok();
+            return;
+        }
+    };
+    let filter = Filter::new()
+        .kinds(vec![Kind::TextNote, Kind::Custom(42)])
+        .authors(watched.iter().map(|public_key| public_key.to_string()).collect())
+        .limit(INITIAL_WATCHED);
+    let req = ClientMessage::new_req(SubscriptionId::generate(), vec![filter]).as_json();
+    if writer.send(Message::Text(req)).await.is_err() {
+        return;
+    }
+    let mut live = false;
+    while let Some(message) = reader.next().await {
+        let json_val = match error::parse_relay_message(&message) {
+            Ok(val) => val,
+            Err(_) => continue,
+        };
+        if json_val[0] == "EOSE" {
+            live = true;
+            continue;
+        }
+        let event = match Event::from_value(json_val[2].clone()) {
+            Ok(val) if json_val[0] == "EVENT" => val,
+            _ => continue,
+        };
+        if crate::blocklist::is_blocked(&event.pubkey.to_string()) {
+            continue;
+        }
+        if live {
+            printer.print(format!("[{}] {}", "WATCH".cyan(), line(&event))).ok();
+        }
+        push(event);
+    }
+}
+
+fn push(event: Event) {
+    let mut feed = FEED.lock().unwrap();
+    if feed.iter().any(|known| known.id == event.id) {
+        return;
+    }
+    let position = feed.iter().rposition(|known| known.created_at <= event.created_at).map_or(0, |index| index + 1);
+    feed.insert(position, event);
+    if feed.len() > MAX_WATCHED {
+        feed.pop_front();
+    }
+}
+
+/// The Watching feed, newest first, one line per event.
+pub fn feed() -> Vec<String> {
+    FEED.lock().unwrap().iter().rev().map(line).collect()
+}
+
+/// When `event` was written, by whom and where, with the first line of its content.
+fn line(event: &Event) -> String {
+    let time = chrono::DateTime::from_timestamp(event.created_at.as_i64(), 0).map(|time| time.with_timezone(&chrono::Local).format("%d.%m. %H:%M").to_string()).unwrap_or_default();
+    // Channel messages tag the channel first
+    let channel = event.tags.iter().find_map(|tag| match tag {
+        Tag::Event(id, _, _) => Some(id.to_hex()),
+        _ => None,
+    });
+    let place = match (event.kind, channel) {
+        (Kind::Custom(42), Some(channel)) => format!("in #{}", crate::directory::name_of(&channel).unwrap_or_else(|| channel[.. 8].to_string())),
+        _ => "noted".to_string(),
+    };
+    let content = event.content.lines().next().unwrap_or_default();
+    format!("{} {} {}: {}", time.truecolor(128, 128, 128), names::display_name(&event.pubkey.to_string()).green(), place.truecolor(128, 128, 128), content)
+}